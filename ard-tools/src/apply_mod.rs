@@ -0,0 +1,323 @@
+use std::{
+    collections::HashMap,
+    fs,
+    io::{BufWriter, Cursor, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, bail, Result};
+use ardain::{
+    file_alloc::{entry_size, ArdFileAllocator, CompressionStrategy, Placement, SpacePlanner},
+    path::{ArhPath, PathCase},
+    snapshot::Snapshot,
+    ArdReader, ArdWriter, ArhFileSystem, FileFlag,
+};
+use clap::Args;
+use log::{debug, info, warn};
+
+use crate::{delta, ls::format_size, ArdSource, InputData};
+
+#[derive(Args)]
+pub struct ApplyModArgs {
+    /// The mod directory, with files laid out like archive paths (e.g. "<DIR>/bdat/btl.bdat").
+    /// Files ending with .ardelta are binary deltas written by `export-changes --delta-base`,
+    /// which are applied to the file of the same path without that extension.
+    #[arg(required_unless_present = "revert")]
+    dir: Option<PathBuf>,
+    /// Don't take a snapshot of the archive before applying the mod, if it has none. Without
+    /// a snapshot, the mod can't be removed with --revert.
+    #[arg(long)]
+    no_snapshot: bool,
+    /// Undo all mods applied since the snapshot was taken (same as `snapshot revert`)
+    #[arg(long, conflicts_with_all = ["dir", "no_snapshot"])]
+    revert: bool,
+    /// Only list the files that would be added or replaced, and where their data would be
+    /// written, without modifying the archive. The .ard file is not needed.
+    #[arg(long, conflicts_with = "revert")]
+    dry_run: bool,
+    /// Print sizes in a human-readable format
+    #[arg(short = 'H', long)]
+    human_readable: bool,
+    /// Extend the .ard file this many MiB at a time while adding files, instead of file by
+    /// file, so it is less fragmented on disk. The unused space is removed at the end.
+    #[arg(long, value_name = "MIB", default_value_t = 0)]
+    grow_ahead: u64,
+    /// Read back every file after writing it, and stop if it doesn't match, e.g. when the
+    /// .ard file is on an unreliable external drive
+    #[arg(long)]
+    verify_writes: bool,
+}
+
+pub fn run(input: &InputData, args: ApplyModArgs) -> Result<()> {
+    if args.revert {
+        let ard_path = input
+            .in_ard
+            .as_ref()
+            .ok_or_else(|| anyhow!("input .ard must be passed in as --ard"))?;
+        if input.session.is_some() {
+            bail!("snapshots can't be managed in batch mode");
+        }
+        return crate::snapshot::revert(input, ard_path);
+    }
+    let dir = args.dir.unwrap();
+    let mut files = mod_files(&dir)?;
+    for (file, path) in &mut files {
+        if is_delta(file) {
+            let original = path.original();
+            let target = &original[..original.len() - delta::EXTENSION.len() - 1];
+            *path = ArhPath::normalize_with(target, PathCase::Preserve)?;
+        }
+    }
+    // Opened on demand, to read the files that deltas apply to
+    let mut ard = None;
+    info!("Found {} files in {}", files.len(), dir.display());
+
+    let mut conflicts = 0;
+    let mut fs = input.load_fs()?;
+    // Two mod files can't have the same archive path, the archive is case-insensitive
+    let mut seen: HashMap<&str, &Path> = HashMap::new();
+    let mut to_apply = Vec::new();
+    for (file, path) in &files {
+        if let Some(other) = seen.get(path.as_str()) {
+            warn!(
+                "{path}: conflict between {} and {}, skipping the latter",
+                other.display(),
+                file.display()
+            );
+            conflicts += 1;
+            continue;
+        }
+        seen.insert(path.as_str(), file);
+        if let Some(problem) = check_path(&fs, path) {
+            warn!("{path}: {problem}, skipping {}", file.display());
+            conflicts += 1;
+        } else {
+            to_apply.push((file, path));
+        }
+    }
+
+    if args.dry_run {
+        input.require_ard(
+            to_apply
+                .iter()
+                .filter(|(file, _)| is_delta(file))
+                .map(|(_, path)| *path),
+        )?;
+    } else {
+        input.require_ard(to_apply.iter().map(|(_, path)| *path))?;
+    }
+    // Everything is read (and deltas checked against their base) before the first write, so
+    // a bad file can't leave the archive half-modified
+    let to_apply = to_apply
+        .into_iter()
+        .map(|(file, path)| Ok((file, path, read_mod_file(input, &fs, &mut ard, file, path)?)))
+        .collect::<Result<Vec<_>>>()?;
+    drop(ard);
+
+    if args.dry_run {
+        let ard_len = match &input.in_ard {
+            Some(path) => fs::metadata(path)?.len(),
+            // Without the .ard file, assume it ends with the last file
+            None => fs
+                .iter_extents(0)
+                .map(|e| e.range.end)
+                .max()
+                .unwrap_or_default(),
+        };
+        let game = input.game.unwrap_or_default();
+        let mut planner = SpacePlanner::new(&fs, ard_len);
+        let (mut added, mut replaced) = (0, 0);
+        let (mut in_place, mut free_space, mut appended) = (0, 0, 0);
+        for (file, path, data) in &to_apply {
+            let verb = if fs.is_file(path) {
+                replaced += 1;
+                "replace"
+            } else {
+                added += 1;
+                "add"
+            };
+            let size = entry_size(data, CompressionStrategy::Best, game)?;
+            let plan = planner.plan_write(path, size);
+            let placement = match plan.placement {
+                Placement::InPlace => {
+                    in_place += 1;
+                    "in place"
+                }
+                Placement::FreeSpace => {
+                    free_space += 1;
+                    "in free space"
+                }
+                Placement::Append => {
+                    appended += 1;
+                    "appended"
+                }
+            };
+            println!(
+                "{verb} {path} ({}): {} at 0x{:X}, {placement}",
+                file.display(),
+                format_size(size, args.human_readable),
+                plan.offset,
+            );
+        }
+        println!("Dry run: {added} files would be added, {replaced} files would be replaced");
+        println!(
+            "The .ard file would grow by {} ({in_place} files written in place, {free_space} in \
+             free space, {appended} appended)",
+            format_size(planner.ard_len() - ard_len, args.human_readable)
+        );
+        if conflicts != 0 {
+            println!("{conflicts} conflicts, see the warnings above");
+        }
+        return Ok(());
+    }
+
+    let ard_path = input
+        .in_ard
+        .as_ref()
+        .ok_or_else(|| anyhow!("input .ard must be passed in as --ard"))?;
+    let mut snapshot = Snapshot::open(ard_path)?;
+    if snapshot.is_none() && !args.no_snapshot {
+        if input.session.is_some() {
+            warn!("Not taking a snapshot in batch mode, the mod can't be reverted");
+        } else {
+            let arh_path = input
+                .in_arh
+                .as_ref()
+                .ok_or_else(|| anyhow!("input .arh must be passed in as --arh"))?;
+            snapshot = Some(Snapshot::create(ard_path, &fs::read(arh_path)?)?);
+            info!(
+                "Created snapshot in {}, use --revert to undo",
+                Snapshot::dir_for(ard_path).display()
+            );
+        }
+    }
+    // Files that changed since the snapshot were modified by a previously applied mod
+    let original = match &snapshot {
+        Some(snapshot) => Some(ArhFileSystem::load(Cursor::new(snapshot.arh()?))?),
+        None => None,
+    };
+    drop(snapshot);
+    if let Some(original) = &original {
+        for (_, path, _) in &to_apply {
+            let (Some(old), Some(new)) = (original.get_file_info(path), fs.get_file_info(path))
+            else {
+                continue;
+            };
+            if old != new {
+                warn!("{path}: already modified by another mod, overwriting it");
+                conflicts += 1;
+            }
+        }
+    }
+
+    let mut writer = ArdWriter::new(BufWriter::new(input.open_ard_for_write()?))
+        .grow_ahead(args.grow_ahead.saturating_mul(1024 * 1024))
+        .verify_writes(args.verify_writes);
+    let (mut added, mut replaced) = (0, 0);
+    for (_, path, data) in to_apply {
+        match fs.get_file_info(path) {
+            Some(meta) => {
+                debug!("Replacing {path}");
+                let id = meta.id;
+                ArdFileAllocator::new(&mut fs, &mut writer).replace_file(
+                    id,
+                    &data,
+                    CompressionStrategy::Best,
+                )?;
+                if fs.get_file_info(path).unwrap().is_flag(FileFlag::Hidden) {
+                    fs.get_file_info_mut(path)?
+                        .set_flag(FileFlag::Hidden, false);
+                }
+                replaced += 1;
+            }
+            None => {
+                debug!("Adding {path}");
+                let id = fs.create_file(path)?.id;
+                ArdFileAllocator::new(&mut fs, &mut writer).write_new_file(
+                    id,
+                    &data,
+                    CompressionStrategy::Best,
+                )?;
+                added += 1;
+            }
+        }
+    }
+    writer.trim()?;
+    writer.get_mut().flush()?;
+    drop(writer);
+    input.write_fs(&mut fs)?;
+
+    println!("Applied mod: {added} files added, {replaced} files replaced");
+    if conflicts != 0 {
+        println!("{conflicts} conflicts, see the warnings above");
+    }
+    Ok(())
+}
+
+/// Lists the files in the mod directory, with their archive path.
+pub(crate) fn mod_files(dir: &Path) -> Result<Vec<(PathBuf, ArhPath)>> {
+    let mut files = Vec::new();
+    let mut dirs = vec![dir.to_path_buf()];
+    while let Some(current) = dirs.pop() {
+        for entry in fs::read_dir(&current)? {
+            let entry = entry?;
+            let file_type = entry.file_type()?;
+            if file_type.is_dir() {
+                dirs.push(entry.path());
+                continue;
+            }
+            let rel = entry.path().strip_prefix(dir)?.to_path_buf();
+            let rel = rel
+                .to_str()
+                .ok_or_else(|| anyhow!("{}: path is not valid UTF-8", rel.display()))?;
+            // The spelling is only kept with --preserve-case
+            let path = ArhPath::normalize_with(rel, PathCase::Preserve)
+                .map_err(|e| anyhow!("{rel}: {e}"))?;
+            files.push((entry.path(), path));
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+fn is_delta(file: &Path) -> bool {
+    file.extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case(delta::EXTENSION))
+}
+
+/// Reads a mod file. Deltas are applied to the current contents of the file they replace.
+fn read_mod_file(
+    input: &InputData,
+    fs: &ArhFileSystem,
+    ard: &mut Option<ArdReader<Box<dyn ArdSource>>>,
+    file: &Path,
+    path: &ArhPath,
+) -> Result<Vec<u8>> {
+    let data = fs::read(file)?;
+    if !is_delta(file) {
+        return Ok(data);
+    }
+    let meta = fs.get_file_info(path).ok_or_else(|| {
+        anyhow!(
+            "{}: the file it is a delta of doesn't exist",
+            file.display()
+        )
+    })?;
+    if ard.is_none() {
+        *ard = Some(input.load_ard()?);
+    }
+    let base = ard.as_mut().unwrap().entry(meta).read()?;
+    delta::apply(&base, &data).map_err(|e| anyhow!("{}: {e}", file.display()))
+}
+
+/// Returns why the file can't be written to the archive path, if it can't.
+fn check_path(fs: &ArhFileSystem, path: &ArhPath) -> Option<&'static str> {
+    if fs.is_dir(path) {
+        return Some("is a directory in the archive");
+    }
+    let parent_is_file = path
+        .match_indices('/')
+        .skip(1)
+        .any(|(i, _)| fs.is_file(&ArhPath::normalize(&path[..i]).unwrap()));
+    parent_is_file.then_some("a parent directory is a file in the archive")
+}
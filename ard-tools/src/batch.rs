@@ -0,0 +1,146 @@
+use std::{
+    fs::File,
+    io::{stdin, BufRead, BufReader},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use ardain::{opts::SyncPolicy, path::RelativeArhPath, ArhFileSystem};
+use clap::{error::ErrorKind, Args, Parser};
+use log::debug;
+
+use crate::{Commands, InputData, LoadedFs};
+
+#[derive(Args)]
+pub struct BatchArgs {
+    /// A file with one command per line, e.g. "rm /bdat/btl.bdat". Empty lines and lines
+    /// starting with '#' are ignored. If absent or "-", commands are read from stdin.
+    ///
+    /// "cd <DIR>" changes the directory that relative paths start from, and "pwd" prints it.
+    /// "sync" writes the changes made so far to disk.
+    script: Option<PathBuf>,
+    /// When changes are written to disk: after every command that modifies the archive
+    /// (always), after every command that writes file data to the .ard file (on-close), or
+    /// only on "sync" lines (manual). They are always written at the end of the script.
+    #[arg(
+        long,
+        value_name = "POLICY",
+        default_value = "manual",
+        value_parser = SyncPolicy::value_parser()
+    )]
+    sync_policy: SyncPolicy,
+}
+
+/// A command line in a batch script.
+#[derive(Parser)]
+#[command(no_binary_name = true)]
+struct BatchLine {
+    #[command(subcommand)]
+    command: Commands,
+}
+
+pub struct Session {
+    pub fs: Mutex<ArhFileSystem>,
+    /// Whether any command modified the file system since it was last written
+    pub dirty: AtomicBool,
+    /// Whether any command wrote to the .ard file since it was last synced
+    pub ard_written: AtomicBool,
+}
+
+pub fn run(input: &InputData, args: BatchArgs) -> Result<()> {
+    let LoadedFs::Owned(fs) = input.load_fs()? else {
+        bail!("batch commands can't be nested");
+    };
+    let script: Box<dyn BufRead> = match args.script {
+        Some(path) if path.as_os_str() != "-" => Box::new(BufReader::new(File::open(path)?)),
+        _ => Box::new(stdin().lock()),
+    };
+    let mut session = InputData {
+        in_arh: input.in_arh.clone(),
+        in_ard: input.in_ard.clone(),
+        out_arh: input.out_arh.clone(),
+        game: input.game,
+        read_only: input.read_only,
+        strict_paths: input.strict_paths,
+        preserve_case: input.preserve_case,
+        cwd: input.cwd.clone(),
+        session: Some(Session {
+            fs: Mutex::new(*fs),
+            dirty: AtomicBool::new(false),
+            ard_written: AtomicBool::new(false),
+        }),
+    };
+
+    for (i, line) in script.lines().enumerate() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let words =
+            shlex::split(line).ok_or_else(|| anyhow!("line {}: unbalanced quotes", i + 1))?;
+        match words.iter().map(String::as_str).collect::<Vec<_>>()[..] {
+            ["sync"] => {
+                sync(input, session.session.as_ref().unwrap())
+                    .with_context(|| format!("line {}", i + 1))?;
+                continue;
+            }
+            ["pwd"] => {
+                println!("{}", session.cwd());
+                continue;
+            }
+            ["cd"] => {
+                session.cwd = None;
+                continue;
+            }
+            ["cd", dir] => {
+                let dir = session
+                    .resolve(&RelativeArhPath::new(dir))
+                    .with_context(|| format!("line {}", i + 1))?;
+                if !session.load_fs()?.is_dir(&dir) {
+                    bail!("line {}: {dir}: not a directory", i + 1);
+                }
+                session.cwd = Some(dir);
+                continue;
+            }
+            _ => {}
+        }
+        let command = match BatchLine::try_parse_from(words) {
+            Ok(line) => line.command,
+            Err(e) if e.kind() == ErrorKind::DisplayHelp => {
+                e.print()?;
+                continue;
+            }
+            Err(e) => bail!("line {}: {e}", i + 1),
+        };
+        debug!("line {}: {line}", i + 1);
+        crate::run_command(&session, command).with_context(|| format!("line {}", i + 1))?;
+        let state = session.session.as_ref().unwrap();
+        let ard_written = state.ard_written.load(Ordering::Relaxed);
+        if (ard_written || state.dirty.load(Ordering::Relaxed))
+            && args.sync_policy.syncs_after(ard_written)
+        {
+            sync(input, state).with_context(|| format!("line {}", i + 1))?;
+        }
+    }
+
+    sync(input, session.session.as_ref().unwrap())
+}
+
+/// Writes the changes made so far to disk. The .ard file is synced first, so the .arh file
+/// never points to data that isn't on disk yet.
+fn sync(input: &InputData, session: &Session) -> Result<()> {
+    if session.ard_written.swap(false, Ordering::Relaxed) {
+        if let Some(path) = &input.in_ard {
+            File::open(path)?.sync_data()?;
+        }
+    }
+    if session.dirty.swap(false, Ordering::Relaxed) {
+        input.write_fs(&mut session.fs.lock().unwrap())?;
+    }
+    Ok(())
+}
@@ -0,0 +1,317 @@
+use std::{
+    collections::BTreeMap,
+    fs,
+    hint::black_box,
+    io::{self, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, bail, Result};
+use ardain::{
+    consistency::Rng,
+    file_alloc::{ArdFileAllocator, CompressionStrategy},
+    path::ArhPath,
+    ArdWriter, ArhFileSystem, ReadOrder,
+};
+use clap::Args;
+use log::info;
+
+use crate::{
+    ls::format_size,
+    output::{Cell, Style, Table},
+    InputData, LoadedFs,
+};
+
+#[derive(Args)]
+pub struct BenchArgs {
+    /// How many times the archive is loaded. The fastest load is reported.
+    #[arg(long, value_name = "N", default_value_t = 3, value_parser = clap::value_parser!(u32).range(1..))]
+    loads: u32,
+    /// The amount of data read by each read benchmark, in MiB
+    #[arg(long, value_name = "MIB", default_value_t = 256)]
+    read_limit: u64,
+    /// The number of files created by the allocation benchmark. Nothing is written to the
+    /// archive.
+    #[arg(long, value_name = "N", default_value_t = 10000)]
+    allocations: u32,
+    /// Save the results as JSON, to compare them later with --compare
+    #[arg(long, value_name = "FILE")]
+    save: Option<PathBuf>,
+    /// Compare the results with ones saved with --save, e.g. before an upgrade or on
+    /// another machine
+    #[arg(long, value_name = "FILE")]
+    compare: Option<PathBuf>,
+}
+
+/// How long lookups are repeated for
+const LOOKUP_TIME: Duration = Duration::from_secs(1);
+/// The size of each file written by the allocation benchmark
+const ALLOC_FILE_SIZE: usize = 4096;
+
+struct Measure {
+    name: &'static str,
+    value: f64,
+    unit: Unit,
+}
+
+#[derive(Clone, Copy)]
+enum Unit {
+    Millis,
+    /// Operations per second, with the name of the operation
+    PerSec(&'static str),
+    BytesPerSec,
+}
+
+/// An ARD file that discards everything written to it, for the allocation benchmark.
+#[derive(Default)]
+struct NullArd {
+    pos: u64,
+    len: u64,
+}
+
+pub fn run(input: &InputData, args: BenchArgs) -> Result<()> {
+    if input.session.is_some() {
+        bail!("benchmarks can't run in batch mode");
+    }
+    let baseline = match &args.compare {
+        Some(path) => Some(read_results(path)?),
+        None => None,
+    };
+    let mut results = Vec::new();
+    let mut skipped = Vec::new();
+
+    info!("Benchmarking archive load");
+    let mut best = Duration::MAX;
+    let mut loaded = None;
+    for _ in 0..args.loads {
+        let start = Instant::now();
+        loaded = Some(input.load_fs()?);
+        best = best.min(start.elapsed());
+    }
+    let Some(LoadedFs::Owned(mut fs)) = loaded else {
+        unreachable!()
+    };
+    results.push(Measure::new(
+        "load",
+        best.as_secs_f64() * 1000.0,
+        Unit::Millis,
+    ));
+
+    info!("Benchmarking lookups");
+    let mut files = crate::expand_paths(&fs, &[ArhPath::default()], true)?;
+    if files.is_empty() {
+        bail!("the archive has no files");
+    }
+    let mut lookups = 0;
+    let start = Instant::now();
+    while start.elapsed() < LOOKUP_TIME {
+        for path in &files {
+            black_box(fs.get_file_info(black_box(path)));
+        }
+        lookups += files.len();
+    }
+    let rate = per_sec(lookups as u64, start.elapsed());
+    results.push(Measure::new("lookup", rate, Unit::PerSec("lookups")));
+
+    if input.in_ard.is_some() {
+        let limit = args.read_limit * 1024 * 1024;
+        info!("Benchmarking sequential reads");
+        ReadOrder::Offset.sort_files(&fs, &mut files);
+        drop_page_cache(input)?;
+        let (bytes, _, time) = read_files(input, &fs, &files, limit)?;
+        results.push(Measure::new(
+            "sequential read",
+            per_sec(bytes, time),
+            Unit::BytesPerSec,
+        ));
+
+        info!("Benchmarking random reads");
+        // Always the same order, so results can be compared
+        Rng::new(0).shuffle(&mut files);
+        // Otherwise most of the data is still cached from the sequential reads
+        drop_page_cache(input)?;
+        let (bytes, count, time) = read_files(input, &fs, &files, limit)?;
+        results.push(Measure::new(
+            "random read",
+            per_sec(bytes, time),
+            Unit::BytesPerSec,
+        ));
+        let rate = per_sec(count, time);
+        results.push(Measure::new(
+            "random read files",
+            rate,
+            Unit::PerSec("files"),
+        ));
+    } else {
+        skipped.push(("sequential read, random read", "no --ard"));
+    }
+
+    if fs.is_read_only() {
+        skipped.push(("allocation", "the archive is read-only"));
+    } else {
+        info!("Benchmarking allocation");
+        let time = allocate(&mut fs, args.allocations)?;
+        let rate = per_sec(args.allocations.into(), time);
+        results.push(Measure::new("allocation", rate, Unit::PerSec("files")));
+    }
+
+    print_table(&results, baseline.as_ref());
+    for (name, reason) in skipped {
+        println!("Skipped {name} ({reason})");
+    }
+    if let Some(path) = &args.save {
+        let map: BTreeMap<_, _> = results.iter().map(|m| (m.name, m.value)).collect();
+        fs::write(path, serde_json::to_string_pretty(&map)?)?;
+        println!("Saved results to {}", path.display());
+    }
+    Ok(())
+}
+
+impl Measure {
+    fn new(name: &'static str, value: f64, unit: Unit) -> Self {
+        Self { name, value, unit }
+    }
+}
+
+fn per_sec(amount: u64, time: Duration) -> f64 {
+    amount as f64 / time.as_secs_f64().max(1e-9)
+}
+
+impl Unit {
+    fn format(self, value: f64) -> String {
+        match self {
+            Unit::Millis => format!("{value:.1} ms"),
+            Unit::PerSec(what) => format!("{value:.0} {what}/s"),
+            Unit::BytesPerSec => format!("{}/s", format_size(value as u64, true)),
+        }
+    }
+
+    fn higher_is_better(self) -> bool {
+        !matches!(self, Unit::Millis)
+    }
+}
+
+fn print_table(results: &[Measure], baseline: Option<&BTreeMap<String, f64>>) {
+    let columns = if baseline.is_some() { 4 } else { 2 };
+    let mut table = Table::new(&["Benchmark", "Result", "Baseline", "Change"][..columns])
+        .align_right(1..columns);
+    for measure in results {
+        let old = baseline.and_then(|b| b.get(measure.name)).copied();
+        let change = old.filter(|&old| old > 0.0).map(|old| {
+            let ratio = measure.value / old;
+            let faster = if measure.unit.higher_is_better() {
+                ratio
+            } else {
+                1.0 / ratio
+            };
+            match faster {
+                f if f >= 1.0 => {
+                    Cell::new(format!("{:.0}% faster", (f - 1.0) * 100.0), Style::Good)
+                }
+                f => Cell::new(
+                    format!("{:.0}% slower", (1.0 / f - 1.0) * 100.0),
+                    Style::Bad,
+                ),
+            }
+        });
+        let row: [Cell; 4] = [
+            measure.name.into(),
+            measure.unit.format(measure.value).into(),
+            old.map_or_else(|| "-".to_string(), |old| measure.unit.format(old))
+                .into(),
+            change.unwrap_or_else(|| "-".into()),
+        ];
+        table.push_row(row.into_iter().take(columns));
+    }
+    table.print();
+}
+
+/// Reads files in order until `limit` bytes were read from the ARD file. Returns the bytes
+/// read, the number of files and the time it took.
+fn read_files(
+    input: &InputData,
+    fs: &ArhFileSystem,
+    files: &[ArhPath],
+    limit: u64,
+) -> Result<(u64, u64, Duration)> {
+    let mut ard = input.load_ard()?;
+    let (mut bytes, mut count) = (0, 0);
+    let start = Instant::now();
+    for path in files {
+        if bytes >= limit {
+            break;
+        }
+        let meta = fs.get_file_info(path).unwrap();
+        black_box(ard.entry(meta).read().map_err(|e| anyhow!("{path}: {e}"))?);
+        bytes += u64::from(meta.compressed_size);
+        count += 1;
+    }
+    Ok((bytes, count, start.elapsed()))
+}
+
+/// Creates files in a directory that doesn't exist yet, and allocates space for their data.
+fn allocate(fs: &mut ArhFileSystem, count: u32) -> Result<Duration> {
+    let dir = (0..)
+        .map(|i| ArhPath::default().join(&format!("bench{i}")))
+        .find(|dir| !fs.exists(dir))
+        .unwrap();
+    let data = [0xAA; ALLOC_FILE_SIZE];
+    let mut writer = ArdWriter::new(NullArd::default());
+    let start = Instant::now();
+    for i in 0..count {
+        let id = fs.create_file(&dir.join(&format!("{i}.bin")))?.id;
+        ArdFileAllocator::new(fs, &mut writer).write_new_file(
+            id,
+            &data,
+            CompressionStrategy::None,
+        )?;
+    }
+    Ok(start.elapsed())
+}
+
+/// Asks the OS to drop the cached pages of the local .ard file, so the next reads come from
+/// the disk. Remote files aren't cached between readers.
+fn drop_page_cache(input: &InputData) -> Result<()> {
+    if input.in_ard.as_deref().is_some_and(crate::is_url) {
+        return Ok(());
+    }
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::fd::AsRawFd;
+
+        let file = input.open_ard()?;
+        // A length of 0 covers the whole file
+        let res = unsafe { libc::posix_fadvise(file.as_raw_fd(), 0, 0, libc::POSIX_FADV_DONTNEED) };
+        if res != 0 {
+            return Err(io::Error::from_raw_os_error(res).into());
+        }
+    }
+    #[cfg(not(target_os = "linux"))]
+    log::warn!("Can't drop the OS cache on this platform, read results may be cached");
+    Ok(())
+}
+
+fn read_results(path: &Path) -> Result<BTreeMap<String, f64>> {
+    let text = fs::read_to_string(path).map_err(|e| anyhow!("{}: {e}", path.display()))?;
+    serde_json::from_str(&text).map_err(|e| anyhow!("{}: {e}", path.display()))
+}
+
+impl Write for NullArd {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.pos += buf.len() as u64;
+        self.len = self.len.max(self.pos);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for NullArd {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.pos = crate::seek_position(self.pos, self.len, pos)?;
+        Ok(self.pos)
+    }
+}
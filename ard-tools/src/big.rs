@@ -0,0 +1,114 @@
+use std::collections::HashMap;
+
+use anyhow::Result;
+use ardain::{path::RelativeArhPath, FileMeta};
+use clap::{Args, ValueEnum};
+
+use crate::{
+    ls::format_size,
+    output::{paint, Style, Table},
+    InputData,
+};
+
+#[derive(Args)]
+pub struct BigArgs {
+    /// The directory to look in (default: /)
+    #[arg(add = crate::completions::arh_path_completer())]
+    path: Option<RelativeArhPath>,
+    /// How many files and directories to list
+    #[arg(short = 'n', long, default_value_t = 20)]
+    top: usize,
+    /// Which size to rank files and directories by
+    #[arg(long, value_enum, default_value_t = SizeKind::Stored)]
+    by: SizeKind,
+    /// Also include hidden (soft-deleted) files
+    #[arg(short, long)]
+    all: bool,
+    /// Print sizes in human-readable units (e.g. 1.5M)
+    #[arg(short = 'H', long)]
+    human_readable: bool,
+}
+
+#[derive(ValueEnum, Clone, Copy, PartialEq, Eq)]
+enum SizeKind {
+    /// The size in the ARD file, i.e. after compression
+    Stored,
+    /// The size after decompression
+    Extracted,
+}
+
+pub fn run(input: &InputData, args: BigArgs) -> Result<()> {
+    let base = match &args.path {
+        Some(path) => input.resolve(path)?,
+        None => Default::default(),
+    };
+    let fs = input.load_fs()?;
+    let files = crate::expand_paths(&fs, std::slice::from_ref(&base), args.all)?;
+    let size = |meta: &FileMeta| match args.by {
+        SizeKind::Stored => u64::from(meta.compressed_size),
+        SizeKind::Extracted => u64::from(meta.actual_size()),
+    };
+
+    let mut largest: Vec<_> = files
+        .iter()
+        .map(|path| (path, fs.get_file_info(path).unwrap()))
+        .collect();
+    largest.sort_by_key(|&(path, meta)| (std::cmp::Reverse(size(meta)), path));
+
+    // Every file counts towards all of its directories below `base`
+    let base_len = base.as_str().trim_end_matches('/').len();
+    let mut dirs: HashMap<&str, (u64, usize)> = HashMap::new();
+    for &(path, meta) in &largest {
+        let path = path.as_str();
+        for (i, _) in path.match_indices('/').filter(|&(i, _)| i > base_len) {
+            let (total, count) = dirs.entry(&path[..i]).or_default();
+            *total += size(meta);
+            *count += 1;
+        }
+    }
+    let mut dirs: Vec<_> = dirs.into_iter().collect();
+    dirs.sort_by_key(|&(path, (total, _))| (std::cmp::Reverse(total), path));
+
+    let total: u64 = largest.iter().map(|&(_, meta)| size(meta)).sum();
+    let share = |part: u64| {
+        if total == 0 {
+            "--".to_string()
+        } else {
+            format!("{:.1}%", part as f64 / total as f64 * 100.0)
+        }
+    };
+
+    println!("{}\n", paint("Largest files", Style::Header));
+    let mut table = Table::new(&["Size", "Share", "File"])
+        .align_right([0, 1])
+        .shrink(2);
+    for &(path, meta) in largest.iter().take(args.top) {
+        table.push_row([
+            format_size(size(meta), args.human_readable),
+            share(size(meta)),
+            path.to_string(),
+        ]);
+    }
+    table.print();
+
+    println!("\n{}\n", paint("Largest directories", Style::Header));
+    let mut table = Table::new(&["Size", "Share", "Files", "Directory"])
+        .align_right([0, 1, 2])
+        .shrink(3);
+    for &(path, (dir_total, count)) in dirs.iter().take(args.top) {
+        table.push_row([
+            format_size(dir_total, args.human_readable),
+            share(dir_total),
+            count.to_string(),
+            path.to_string(),
+        ]);
+    }
+    table.print();
+
+    println!(
+        "\n{} files, {} in total",
+        files.len(),
+        format_size(total, args.human_readable)
+    );
+    Ok(())
+}
@@ -0,0 +1,212 @@
+use std::{
+    collections::BTreeMap,
+    fs::{self, File},
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use anyhow::{anyhow, bail, Result};
+use ardain::{
+    file_alloc::{ArdFileAllocator, CompressionStrategy},
+    opts::ArhOptions,
+    path::ArhPath,
+    ArdWriter, ArhFileSystem,
+};
+use clap::Args;
+use log::{debug, info, warn};
+
+use crate::{
+    progress::{Progress, ProgressFormat},
+    InputData,
+};
+
+#[derive(Args)]
+pub struct BuildArgs {
+    /// The directory to pack, with files laid out like archive paths (e.g.
+    /// "<DIR>/bdat/btl.bdat"). The archive is written to --arh (or --out-arh) and --ard.
+    dir: PathBuf,
+    /// The order of files in the .ard file, so files that are loaded together are close to
+    /// each other: "path" groups them by directory, sorted by name; "size" groups them by
+    /// directory, smallest first; "hint=<FILE>" writes the files listed in FILE first (one
+    /// archive path or directory per line, in load order), then the rest like "path"
+    #[arg(long, value_name = "LAYOUT", default_value = "path")]
+    layout: Layout,
+    /// Overwrite the output files if they already exist
+    #[arg(short, long)]
+    force: bool,
+    /// Extend the .ard file this many MiB at a time while adding files, instead of file by
+    /// file, so it is less fragmented on disk. The unused space is removed at the end.
+    #[arg(long, value_name = "MIB", default_value_t = 0)]
+    grow_ahead: u64,
+    /// Read back every file after writing it, and stop if it doesn't match, e.g. when the
+    /// .ard file is on an unreliable external drive
+    #[arg(long)]
+    verify_writes: bool,
+    /// Report progress in the given format
+    #[arg(long, value_name = "FORMAT")]
+    progress: Option<ProgressFormat>,
+}
+
+/// The order in which files are written to a new archive.
+#[derive(Clone)]
+enum Layout {
+    Path,
+    Size,
+    /// A list of archive paths (or directories) in the order the game loads them, one per
+    /// line. Empty lines and lines starting with '#' are ignored.
+    Hint(PathBuf),
+}
+
+pub fn run(input: &InputData, args: BuildArgs) -> Result<()> {
+    if input.session.is_some() {
+        bail!("archives can't be built in batch mode");
+    }
+    let arh_path = input
+        .out_arh
+        .as_ref()
+        .or(input.in_arh.as_ref())
+        .ok_or_else(|| anyhow!("output .arh must be passed in as --arh or --out-arh"))?;
+    let ard_path = input
+        .in_ard
+        .as_ref()
+        .ok_or_else(|| anyhow!("output .ard must be passed in as --ard"))?;
+    if input.read_only {
+        return Err(ardain::error::Error::ReadOnly.into());
+    }
+    for path in [arh_path, ard_path] {
+        if !args.force && Path::new(path).exists() {
+            bail!("{path} already exists, use --force to overwrite it");
+        }
+    }
+
+    let mut files = crate::apply_mod::mod_files(&args.dir)?;
+    info!("Found {} files in {}", files.len(), args.dir.display());
+    let mut seen: BTreeMap<&ArhPath, &Path> = BTreeMap::new();
+    for (file, path) in &files {
+        if let Some(other) = seen.insert(path, file) {
+            bail!(
+                "{path}: both {} and {} have this path, the archive is case-insensitive",
+                other.display(),
+                file.display()
+            );
+        }
+    }
+    args.layout.sort(&mut files)?;
+
+    let mut fs = ArhFileSystem::new(ArhOptions {
+        game: input.game.unwrap_or_default(),
+        strict_paths: input.strict_paths,
+        path_case: input.path_case(),
+        ..Default::default()
+    });
+    let mut writer = ArdWriter::new(BufWriter::new(File::create(ard_path)?))
+        .grow_ahead(args.grow_ahead.saturating_mul(1024 * 1024))
+        .verify_writes(args.verify_writes);
+    let total_bytes = files
+        .iter()
+        .map(|(file, _)| Ok(fs::metadata(file)?.len()))
+        .sum::<Result<u64>>()?;
+    let progress = Progress::new(args.progress, files.len(), total_bytes);
+    for (file, path) in &files {
+        debug!("Adding {path}");
+        let data = fs::read(file)?;
+        let id = fs.create_file(path).map_err(|e| anyhow!("{path}: {e}"))?.id;
+        ArdFileAllocator::new(&mut fs, &mut writer).write_new_file(
+            id,
+            &data,
+            CompressionStrategy::Best,
+        )?;
+        progress.file_done(path, data.len() as u64);
+    }
+    writer.trim()?;
+    writer.get_mut().flush()?;
+    drop(writer);
+    info!("Writing {arh_path}");
+    fs.sync(BufWriter::new(File::create(arh_path)?))?;
+    progress.finish();
+    if progress.is_enabled() {
+        return Ok(());
+    }
+
+    let stats = fs.get_dir(&ArhPath::default()).unwrap().stats;
+    println!(
+        "Built archive with {} files ({} bytes in the ARD file)",
+        stats.file_count, stats.compressed_size
+    );
+    Ok(())
+}
+
+impl Layout {
+    fn sort(&self, files: &mut [(PathBuf, ArhPath)]) -> Result<()> {
+        match self {
+            Layout::Path => files.sort_by(|(_, a), (_, b)| split_dir(a).cmp(&split_dir(b))),
+            Layout::Size => {
+                let sizes = files
+                    .iter()
+                    .map(|(file, path)| Ok((path.clone(), fs::metadata(file)?.len())))
+                    .collect::<Result<BTreeMap<_, _>>>()?;
+                files.sort_by_key(|(_, path)| (split_dir(path).0.to_string(), sizes[path]));
+            }
+            Layout::Hint(hint) => {
+                let order = read_hint(hint)?;
+                // Files that aren't listed come last
+                let rank = |path: &ArhPath| {
+                    order
+                        .iter()
+                        .position(|listed| is_within(path, listed))
+                        .unwrap_or(order.len())
+                };
+                files.sort_by_cached_key(|(_, path)| {
+                    let (dir, name) = split_dir(path);
+                    (rank(path), dir.to_string(), name.to_string())
+                });
+                for listed in &order {
+                    if !files.iter().any(|(_, path)| is_within(path, listed)) {
+                        warn!("{}: no files match {listed}", hint.display());
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Layout {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.split_once('=') {
+            None if s == "path" => Ok(Self::Path),
+            None if s == "size" => Ok(Self::Size),
+            Some(("hint", file)) if !file.is_empty() => Ok(Self::Hint(file.into())),
+            _ => Err(format!(
+                "invalid layout \"{s}\", expected path, size or hint=<FILE>"
+            )),
+        }
+    }
+}
+
+/// Returns whether `path` is `other`, or a file inside the directory `other`.
+fn is_within(path: &ArhPath, other: &ArhPath) -> bool {
+    path == other
+        || (path.starts_with(other.as_str())
+            && (other == &ArhPath::default() || path.as_bytes()[other.len()] == b'/'))
+}
+
+/// Splits a path into its parent directory and file name.
+fn split_dir(path: &ArhPath) -> (&str, &str) {
+    path.rsplit_once('/').unwrap()
+}
+
+fn read_hint(hint: &Path) -> Result<Vec<ArhPath>> {
+    let text = fs::read_to_string(hint).map_err(|e| anyhow!("{}: {e}", hint.display()))?;
+    text.lines()
+        .enumerate()
+        .map(|(i, line)| (i, line.trim()))
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+        .map(|(i, line)| {
+            ArhPath::normalize(line).map_err(|e| anyhow!("{}:{}: {e}", hint.display(), i + 1))
+        })
+        .collect()
+}
@@ -0,0 +1,34 @@
+use std::io::{self, Write};
+
+use anyhow::Result;
+use ardain::path::RelativeArhPath;
+use clap::Args;
+
+use crate::{errors::ErrorKind, InputData};
+
+#[derive(Args)]
+pub struct CatArgs {
+    /// The files to print, in order
+    #[arg(required = true, add = crate::completions::arh_path_completer())]
+    paths: Vec<RelativeArhPath>,
+}
+
+pub fn run(input: &InputData, args: CatArgs) -> Result<()> {
+    let paths = input.resolve_all(&args.paths)?;
+    let fs = input.load_fs()?;
+    let files = paths
+        .iter()
+        .map(|path| {
+            fs.get_file_info(path)
+                .ok_or_else(|| ErrorKind::NotFound.error(format!("{path}: file not found")))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    input.require_ard(&paths)?;
+    let mut ard = input.load_ard()?;
+    let mut out = io::stdout().lock();
+    for meta in files {
+        out.write_all(&ard.entry(meta).read()?)?;
+    }
+    out.flush()?;
+    Ok(())
+}
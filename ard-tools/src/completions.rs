@@ -0,0 +1,71 @@
+use std::{
+    ffi::OsStr,
+    fs::File,
+    io::{stdout, BufReader},
+};
+
+use anyhow::Result;
+use ardain::{path::ArhPath, ArhFileSystem, DirEntry, DIR_PLACEHOLDER};
+use clap::{Args, CommandFactory};
+use clap_complete::{
+    engine::{ArgValueCompleter, CompletionCandidate},
+    Shell,
+};
+
+use crate::Cli;
+
+#[derive(Args)]
+pub struct CompletionsArgs {
+    /// The shell to generate completions for
+    shell: Shell,
+}
+
+pub fn run(args: CompletionsArgs) -> Result<()> {
+    clap_complete::generate(args.shell, &mut Cli::command(), "ard-tools", &mut stdout());
+    Ok(())
+}
+
+/// Completes paths inside the archive passed in as `--arh`, for shells that registered
+/// dynamic completions (`COMPLETE=<shell> ard-tools`).
+pub fn arh_path_completer() -> ArgValueCompleter {
+    ArgValueCompleter::new(complete_arh_path)
+}
+
+fn complete_arh_path(current: &OsStr) -> Vec<CompletionCandidate> {
+    let Some(current) = current.to_str() else {
+        return Vec::new();
+    };
+    // Options haven't been parsed yet when completing, look for --arh ourselves
+    let args: Vec<_> = std::env::args().collect();
+    let arh = args
+        .iter()
+        .enumerate()
+        .find_map(|(i, arg)| match arg.strip_prefix("--arh") {
+            Some("") => args.get(i + 1).cloned(),
+            Some(path) => path.strip_prefix('=').map(str::to_string),
+            None => None,
+        });
+    let Some(fs) = arh
+        .and_then(|path| File::open(path).ok())
+        .and_then(|file| ArhFileSystem::load(BufReader::new(file)).ok())
+    else {
+        return Vec::new();
+    };
+
+    let (dir, prefix) = current.rsplit_once('/').unwrap_or(("", current));
+    let Some(node) = ArhPath::normalize(dir).ok().and_then(|p| fs.get_dir(&p)) else {
+        return Vec::new();
+    };
+    let DirEntry::Directory { children } = &node.entry else {
+        return Vec::new();
+    };
+    children
+        .values()
+        .filter(|child| child.name.starts_with(prefix) && child.name != DIR_PLACEHOLDER)
+        .map(|child| {
+            let is_dir = matches!(child.entry, DirEntry::Directory { .. });
+            let suffix = if is_dir { "/" } else { "" };
+            CompletionCandidate::new(format!("{dir}/{}{suffix}", child.name))
+        })
+        .collect()
+}
@@ -0,0 +1,130 @@
+use std::io::{BufWriter, Write};
+
+use anyhow::{anyhow, bail, Result};
+use ardain::{
+    file_alloc::{ArdFileAllocator, CompressionStrategy},
+    path::RelativeArhPath,
+    ArdWriter, FileFlag, FileMeta,
+};
+use clap::Args;
+use log::{debug, info, warn};
+
+use crate::{ls::format_size, InputData};
+
+#[derive(Args)]
+pub struct CopyFromArgs {
+    /// The .arh file of the archive to copy from. Can be an http(s) URL.
+    #[arg(long, value_name = "ARH")]
+    src_arh: String,
+    /// The .ard file of the archive to copy from. Can be an http(s) URL.
+    #[arg(long, value_name = "ARD")]
+    src_ard: String,
+    /// The files or directories to copy, as paths in the source archive
+    #[arg(required = true)]
+    paths: Vec<RelativeArhPath>,
+    /// Where to copy to in this archive (default: the same path as in the source archive).
+    /// If a directory is copied, its files keep their path relative to it.
+    #[arg(long, value_name = "PATH")]
+    dest_path: Option<RelativeArhPath>,
+    /// Also copy hidden (soft-deleted) files inside directories
+    #[arg(short, long)]
+    all: bool,
+    /// Don't replace files that already exist in this archive
+    #[arg(short, long)]
+    no_clobber: bool,
+    /// Print sizes in a human-readable format
+    #[arg(short = 'H', long)]
+    human_readable: bool,
+}
+
+pub fn run(input: &InputData, args: CopyFromArgs) -> Result<()> {
+    if args.dest_path.is_some() && args.paths.len() > 1 {
+        bail!("--dest-path can only be used with a single source path");
+    }
+    let paths = input.resolve_all(&args.paths)?;
+    let dest_path = args
+        .dest_path
+        .as_ref()
+        .map(|p| input.resolve(p))
+        .transpose()?;
+    let source = InputData {
+        in_arh: Some(args.src_arh.clone()),
+        in_ard: Some(args.src_ard.clone()),
+        out_arh: None,
+        game: None,
+        read_only: true,
+        strict_paths: false,
+        preserve_case: false,
+        cwd: None,
+        session: None,
+    };
+    let src_fs = source.load_fs()?;
+    let mut src_ard = source.load_ard()?;
+
+    let mut fs = input.load_fs()?;
+    let game = input.game.unwrap_or_default();
+    let mut to_copy = Vec::new();
+    for root in &paths {
+        let base = dest_path.as_ref().unwrap_or(root);
+        for file in crate::expand_paths(&src_fs, std::slice::from_ref(root), args.all)? {
+            let rel = file.as_str()[root.as_str().len()..].trim_start_matches('/');
+            let dest = if rel.is_empty() {
+                base.clone()
+            } else {
+                base.try_join(rel)?
+            };
+            to_copy.push((file, dest));
+        }
+    }
+    let dest_paths: Vec<_> = to_copy.iter().map(|(_, dest)| dest).collect();
+    input.require_ard(dest_paths)?;
+
+    let mut writer = ArdWriter::new(BufWriter::new(input.open_ard_for_write()?));
+    let (mut copied, mut recompressed, mut skipped, mut size) = (0, 0, 0, 0);
+    for (src, dest) in &to_copy {
+        let meta = *src_fs.get_file_info(src).unwrap();
+        let id = match fs.get_file_info(dest) {
+            Some(_) if args.no_clobber => {
+                info!("{dest}: already exists, skipping");
+                skipped += 1;
+                continue;
+            }
+            Some(existing) => existing.id,
+            None => fs.create_file(dest).map_err(|e| anyhow!("{dest}: {e}"))?.id,
+        };
+        debug!("Copying {src} to {dest}");
+        let compression = src_ard
+            .entry_header(&meta)?
+            .map(|header| header.compression_type);
+        let mut allocator = ArdFileAllocator::new(&mut fs, &mut writer);
+        match compression.filter(|&ty| !game.supports_compression(ty)) {
+            Some(ty) => {
+                // The entry can't be used as-is, e.g. zstd from a newer game
+                warn!("{src}: {ty:?} compression is not supported by {game}, compressing again");
+                let data = src_ard.entry(&meta).read()?;
+                allocator.replace_file(id, &data, CompressionStrategy::Best)?;
+                recompressed += 1;
+            }
+            None => {
+                let extent = FileMeta::with_extent(meta.offset, meta.compressed_size, 0, [])?;
+                let stored = src_ard.entry(&extent).read()?;
+                allocator.replace_file_stored(id, &meta, &stored)?;
+            }
+        }
+        fs.get_file_info_mut(dest)?
+            .set_flag(FileFlag::Hidden, meta.is_flag(FileFlag::Hidden));
+        copied += 1;
+        size += u64::from(fs.get_file_info(dest).unwrap().compressed_size);
+    }
+    writer.trim()?;
+    writer.get_mut().flush()?;
+    drop(writer);
+    input.write_fs(&mut fs)?;
+
+    println!(
+        "Copied {copied} files ({} in the archive, {recompressed} compressed again), \
+         skipped {skipped}",
+        format_size(size, args.human_readable)
+    );
+    Ok(())
+}
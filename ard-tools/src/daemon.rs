@@ -0,0 +1,272 @@
+use std::{
+    fs::{self, File},
+    io::{BufRead, BufReader, BufWriter, Write},
+    path::PathBuf,
+};
+
+use anyhow::{bail, Result};
+use ardain::{
+    file_alloc::{ArdFileAllocator, CompressionStrategy},
+    path::ArhPath,
+    snapshot::SnapshotWriter,
+    ArdWriter, ArhFileSystem, DirEntry, FileFlag,
+};
+use clap::Args;
+use log::{info, warn};
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::{errors::ErrorKind, InputData};
+
+#[derive(Args)]
+pub struct DaemonArgs {
+    /// Where to create the socket that clients connect to
+    #[arg(short, long)]
+    socket: PathBuf,
+}
+
+/// A request sent by a client, as a single line of JSON.
+///
+/// Every request may include an `id` field, which is copied to the response.
+#[derive(Deserialize)]
+struct Envelope {
+    #[serde(default)]
+    id: Value,
+    #[serde(flatten)]
+    request: Request,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum Request {
+    /// List the contents of a directory
+    Ls { path: String },
+    /// Get metadata for a file or directory
+    Stat { path: String },
+    /// Extract a file or directory (recursively) to `out`
+    Extract { path: String, out: PathBuf },
+    /// Replace the contents of a file with the contents of `from` on disk
+    Replace { path: String, from: PathBuf },
+    /// Claim `size` bytes of space for a file, discarding its contents. The file reads
+    /// as zeros until it is replaced.
+    Reserve { path: String, size: u64 },
+    /// Write the .arh file
+    Sync,
+    /// Write the .arh file if anything changed, then stop the daemon
+    Shutdown,
+}
+
+struct Daemon<'a> {
+    input: &'a InputData,
+    fs: &'a mut ArhFileSystem,
+    /// Opened for writing on the first request that writes data
+    ard: Option<SnapshotWriter<File>>,
+    /// Whether data was written since the .arh file was last written. The ARD file is
+    /// written right away, so the .arh file must follow before the daemon exits.
+    dirty: bool,
+    shutdown: bool,
+}
+
+pub fn run(input: &InputData, args: DaemonArgs) -> Result<()> {
+    let mut fs = input.load_fs()?;
+    let mut daemon = Daemon {
+        input,
+        fs: &mut fs,
+        ard: None,
+        dirty: false,
+        shutdown: false,
+    };
+    let res = daemon.listen(&args.socket);
+    // Also if listening failed, so the .arh file matches the ARD file
+    daemon.sync()?;
+    res
+}
+
+impl<'a> Daemon<'a> {
+    #[cfg(unix)]
+    fn listen(&mut self, socket: &std::path::Path) -> Result<()> {
+        use std::os::unix::{fs::FileTypeExt, net::UnixListener};
+
+        // Remove sockets left behind by a previous daemon, but nothing else
+        if fs::metadata(socket).is_ok_and(|m| m.file_type().is_socket()) {
+            fs::remove_file(socket)?;
+        }
+        let listener = UnixListener::bind(socket)?;
+        info!("Listening on {}", socket.display());
+        for stream in listener.incoming() {
+            let stream = stream?;
+            if let Err(e) = self.serve(BufReader::new(&stream), &stream) {
+                warn!("Connection error: {e}");
+            }
+            if self.shutdown {
+                break;
+            }
+        }
+        fs::remove_file(socket)?;
+        Ok(())
+    }
+
+    #[cfg(not(unix))]
+    fn listen(&mut self, _socket: &std::path::Path) -> Result<()> {
+        bail!("daemon mode is only supported on Unix systems")
+    }
+
+    /// Handles requests from a client until it disconnects.
+    fn serve(&mut self, reader: impl BufRead, mut writer: impl Write) -> Result<()> {
+        for line in reader.lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let response = match serde_json::from_str::<Envelope>(&line) {
+                Ok(Envelope { id, request }) => match self.handle(request) {
+                    Ok(result) => json!({ "id": id, "ok": true, "result": result }),
+                    Err(e) => {
+                        json!({ "id": id, "ok": false, "error": e.to_string(), "kind": ErrorKind::of(&e) })
+                    }
+                },
+                Err(e) => json!({ "id": null, "ok": false, "error": e.to_string() }),
+            };
+            writeln!(writer, "{response}")?;
+            writer.flush()?;
+            if self.shutdown {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn handle(&mut self, request: Request) -> Result<Value> {
+        match request {
+            Request::Ls { path } => self.ls(&ArhPath::normalize(path)?),
+            Request::Stat { path } => self.stat(&ArhPath::normalize(path)?),
+            Request::Extract { path, out } => self.extract(&ArhPath::normalize(path)?, out),
+            Request::Replace { path, from } => self.replace(&ArhPath::normalize(path)?, from),
+            Request::Reserve { path, size } => self.reserve(&ArhPath::normalize(path)?, size),
+            Request::Sync => {
+                self.input.write_fs(self.fs)?;
+                self.dirty = false;
+                Ok(Value::Null)
+            }
+            Request::Shutdown => {
+                self.sync()?;
+                self.shutdown = true;
+                Ok(Value::Null)
+            }
+        }
+    }
+
+    fn ls(&self, path: &ArhPath) -> Result<Value> {
+        let dir = self
+            .fs
+            .get_dir(path)
+            .ok_or_else(|| ErrorKind::NotFound.error(format!("{path}: no such directory")))?;
+        let DirEntry::Directory { children } = &dir.entry else {
+            bail!("{path}: not a directory");
+        };
+        let entries: Vec<_> = children
+            .values()
+            .filter(|node| !ArhFileSystem::is_placeholder(&path.join(&node.name)))
+            .map(|node| {
+                let is_dir = matches!(node.entry, DirEntry::Directory { .. });
+                json!({
+                    "name": node.name,
+                    "type": if is_dir { "dir" } else { "file" },
+                    "size": node.stats.uncompressed_size,
+                })
+            })
+            .collect();
+        Ok(entries.into())
+    }
+
+    fn stat(&self, path: &ArhPath) -> Result<Value> {
+        if let Some(meta) = self.fs.get_file_info(path) {
+            // Only available if the ARD file was passed in
+            let header = match self.input.in_ard {
+                Some(_) => self.input.load_ard()?.entry_header(meta)?,
+                None => None,
+            };
+            return Ok(json!({
+                "type": "file",
+                "id": meta.id,
+                "offset": meta.offset,
+                "size": meta.actual_size(),
+                "compressed_size": meta.compressed_size,
+                "compressed": meta.uncompressed_size != 0,
+                "hidden": meta.is_flag(FileFlag::Hidden),
+                "xbc1": header.map(|h| json!({
+                    "compression": format!("{:?}", h.compression_type).to_lowercase(),
+                    "decompressed_size": h.decompressed_size,
+                    "hash": h.decompressed_hash,
+                })),
+            }));
+        }
+        let dir = self.fs.get_dir(path).ok_or_else(|| {
+            ErrorKind::NotFound.error(format!("{path}: no such file or directory"))
+        })?;
+        Ok(json!({
+            "type": "dir",
+            "file_count": dir.stats.file_count,
+            "dir_count": dir.stats.dir_count,
+            "size": dir.stats.uncompressed_size,
+            "compressed_size": dir.stats.compressed_size,
+        }))
+    }
+
+    fn extract(&mut self, path: &ArhPath, out: PathBuf) -> Result<Value> {
+        let mut ard = self.input.load_ard()?;
+        let files = crate::extract::extract_to(self.fs, &mut ard, path, &out)?;
+        Ok(json!({ "files": files }))
+    }
+
+    fn replace(&mut self, path: &ArhPath, from: PathBuf) -> Result<Value> {
+        let id = self.file_id(path)?;
+        let data = fs::read(from)?;
+        self.open_ard()?;
+        self.dirty = true;
+        let mut writer = ArdWriter::new(BufWriter::new(self.ard.as_mut().unwrap()));
+        ArdFileAllocator::new(self.fs, &mut writer).replace_file(
+            id,
+            &data,
+            CompressionStrategy::Best,
+        )?;
+        writer.get_mut().flush()?;
+        Ok(json!({ "size": data.len() }))
+    }
+
+    fn reserve(&mut self, path: &ArhPath, size: u64) -> Result<Value> {
+        let id = self.file_id(path)?;
+        self.open_ard()?;
+        self.dirty = true;
+        let mut writer = ArdWriter::new(BufWriter::new(self.ard.as_mut().unwrap()));
+        ArdFileAllocator::new(self.fs, &mut writer).reserve(id, size)?;
+        writer.into_inner().flush()?;
+        let offset = self.fs.get_file_info(path).unwrap().offset;
+        Ok(json!({ "offset": offset, "size": size }))
+    }
+
+    fn file_id(&self, path: &ArhPath) -> Result<u32> {
+        Ok(self
+            .fs
+            .get_file_info(path)
+            .ok_or_else(|| ErrorKind::NotFound.error(format!("{path}: no such file")))?
+            .id)
+    }
+
+    /// Writes the .arh file, if data was written since it was last written.
+    fn sync(&mut self) -> Result<()> {
+        if self.dirty {
+            self.input.write_fs(self.fs)?;
+            self.dirty = false;
+        }
+        Ok(())
+    }
+
+    /// Opens the ARD file for writing, if it wasn't already.
+    fn open_ard(&mut self) -> Result<()> {
+        if self.ard.is_none() {
+            self.ard = Some(self.input.open_ard_for_write()?);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,180 @@
+use std::{
+    fs::File,
+    io::{BufReader, BufWriter},
+    path::PathBuf,
+};
+
+use anyhow::{bail, Context, Result};
+use ardain::{FileMeta, RawDictNode, RawTables};
+use clap::{Args, Subcommand};
+use log::info;
+use serde::{Deserialize, Serialize};
+
+use crate::InputData;
+
+#[derive(Args)]
+pub struct DebugArgs {
+    #[command(subcommand)]
+    command: DebugCommand,
+}
+
+#[derive(Subcommand)]
+enum DebugCommand {
+    /// Write the raw path dictionary, string table and file table of the .arh file as JSON.
+    /// Works even if the dictionary is corrupted.
+    DumpDict {
+        /// Where to write the dump
+        out: PathBuf,
+    },
+    /// Write an .arh file made of the tables in a dump, e.g. after fixing them by hand. The
+    /// extension section is rebuilt the next time the archive is modified.
+    LoadDict {
+        /// The dump, as written by dump-dict
+        dump: PathBuf,
+        /// Where to write the .arh file
+        out: PathBuf,
+    },
+}
+
+#[derive(Serialize, Deserialize)]
+struct DictDump {
+    /// The decrypted string table, as hex
+    string_table: String,
+    /// The entries of the string table, for reading. Ignored by load-dict.
+    #[serde(default, skip_deserializing)]
+    strings: Vec<StringEntry>,
+    nodes: Vec<NodeDump>,
+    files: Vec<FileDump>,
+}
+
+#[derive(Serialize)]
+struct StringEntry {
+    offset: usize,
+    text: String,
+    /// Absent if the table ends before the ID
+    file_id: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct NodeDump {
+    index: usize,
+    prev: i32,
+    next: i32,
+}
+
+#[derive(Serialize, Deserialize)]
+struct FileDump {
+    id: u32,
+    offset: u64,
+    compressed_size: u32,
+    uncompressed_size: u32,
+    flags: u32,
+}
+
+pub fn run(input: &InputData, args: DebugArgs) -> Result<()> {
+    if input.session.is_some() {
+        bail!("debug commands can't be used in batch mode");
+    }
+    match args.command {
+        DebugCommand::DumpDict { out } => {
+            let Some(path) = &input.in_arh else {
+                bail!("input .arh must be passed in as --arh");
+            };
+            let tables = RawTables::read(BufReader::new(File::open(path)?))?;
+            let dump = DictDump {
+                string_table: tables.strings.iter().map(|b| format!("{b:02x}")).collect(),
+                strings: string_entries(&tables.strings),
+                nodes: tables
+                    .dict_nodes
+                    .iter()
+                    .enumerate()
+                    .map(|(index, node)| NodeDump {
+                        index,
+                        prev: node.prev,
+                        next: node.next,
+                    })
+                    .collect(),
+                files: tables
+                    .files
+                    .iter()
+                    .map(|f| FileDump {
+                        id: f.id,
+                        offset: f.offset,
+                        compressed_size: f.compressed_size,
+                        uncompressed_size: f.uncompressed_size,
+                        flags: f.raw_flags(),
+                    })
+                    .collect(),
+            };
+            serde_json::to_writer_pretty(BufWriter::new(File::create(&out)?), &dump)?;
+            println!(
+                "Dumped {} dictionary nodes, {} strings and {} files",
+                dump.nodes.len(),
+                dump.strings.len(),
+                dump.files.len()
+            );
+        }
+        DebugCommand::LoadDict { dump, out } => {
+            let dump: DictDump = serde_json::from_reader(BufReader::new(File::open(&dump)?))
+                .with_context(|| format!("{}: invalid dump", dump.display()))?;
+            if let Some((pos, node)) = dump.nodes.iter().enumerate().find(|(i, n)| *i != n.index) {
+                bail!(
+                    "dictionary node {} is at position {pos}, nodes must be in order",
+                    node.index
+                );
+            }
+            let tables = RawTables {
+                strings: crate::grep::parse_hex(&dump.string_table).context("string table")?,
+                dict_nodes: dump
+                    .nodes
+                    .iter()
+                    .map(|n| RawDictNode {
+                        next: n.next,
+                        prev: n.prev,
+                    })
+                    .collect(),
+                files: dump
+                    .files
+                    .iter()
+                    .map(|f| {
+                        FileMeta::from_raw(
+                            f.id,
+                            f.offset,
+                            f.compressed_size,
+                            f.uncompressed_size,
+                            f.flags,
+                        )
+                    })
+                    .collect(),
+            };
+            info!("Writing {}", out.display());
+            tables.write(BufWriter::new(File::create(&out)?))?;
+            println!(
+                "Wrote {} dictionary nodes and {} files to {}",
+                dump.nodes.len(),
+                dump.files.len(),
+                out.display()
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Splits the string table into entries, stopping at the first one that isn't terminated.
+fn string_entries(table: &[u8]) -> Vec<StringEntry> {
+    let mut entries = Vec::new();
+    let mut offset = 0;
+    while let Some(len) = table[offset..].iter().position(|&b| b == 0) {
+        let id_start = offset + len + 1;
+        let file_id = table
+            .get(id_start..id_start + 4)
+            .map(|id| u32::from_le_bytes(id.try_into().unwrap()));
+        entries.push(StringEntry {
+            offset,
+            text: String::from_utf8_lossy(&table[offset..offset + len]).into_owned(),
+            file_id,
+        });
+        offset = (id_start + 4).min(table.len());
+    }
+    entries
+}
@@ -0,0 +1,157 @@
+use std::collections::BTreeMap;
+
+use anyhow::{anyhow, Result};
+use ardain::{
+    path::{ArhPath, RelativeArhPath},
+    ArhFileSystem,
+};
+use clap::Args;
+use log::info;
+use rayon::prelude::*;
+
+use crate::{
+    hash::{Digest, Hasher},
+    ls::format_size,
+    InputData,
+};
+
+#[derive(Args)]
+pub struct DedupArgs {
+    /// The files or directories to look for duplicates in (default: /)
+    #[arg(add = crate::completions::arh_path_completer())]
+    paths: Vec<RelativeArhPath>,
+    /// Point duplicate files to the same data in the ARD file, and free the space they
+    /// used to occupy
+    #[arg(long)]
+    apply: bool,
+    /// Also include hidden (soft-deleted) files
+    #[arg(short, long)]
+    all: bool,
+    /// Print sizes in a human-readable format
+    #[arg(short = 'H', long)]
+    human_readable: bool,
+    /// How file contents are hashed. Unless it is sha256, files with the same hash are
+    /// also compared byte by byte.
+    #[arg(long = "hash", value_enum, default_value_t)]
+    hasher: Hasher,
+}
+
+pub fn run(input: &InputData, args: DedupArgs) -> Result<()> {
+    let mut fs = input.load_fs()?;
+    let paths = if args.paths.is_empty() {
+        vec![ArhPath::default()]
+    } else {
+        input.resolve_all(&args.paths)?
+    };
+    let files = crate::expand_paths(&fs, &paths, args.all)?;
+    input.require_ard(&files)?;
+    info!("Hashing {} files", files.len());
+
+    // Files are compared by their extracted contents, so identical files that were
+    // compressed differently are still detected.
+    let hashes = files
+        .par_iter()
+        .map_init(
+            || input.load_ard(),
+            |ard, path| -> Result<(Digest, &ArhPath)> {
+                let ard = ard.as_mut().map_err(|e| anyhow!("{e}"))?;
+                let meta = fs.get_file_info(path).unwrap();
+                let data = ard.entry(meta).read()?;
+                Ok((args.hasher.digest(&data), path))
+            },
+        )
+        .collect::<Result<Vec<_>>>()?;
+    let mut groups: BTreeMap<Digest, Vec<&ArhPath>> = BTreeMap::new();
+    for (hash, path) in hashes {
+        groups.entry(hash).or_default().push(path);
+    }
+    let mut groups: Vec<_> = groups.into_values().filter(|g| g.len() > 1).collect();
+    if !args.hasher.is_collision_resistant() {
+        groups = split_collisions(input, &fs, groups)?;
+    }
+
+    let mut to_share = Vec::new();
+    let (mut group_count, mut reclaimable) = (0, 0);
+    for mut group in groups {
+        // Keep the smallest copy, then the first one in the ARD file
+        group.sort_by_key(|path| {
+            let meta = fs.get_file_info(path).unwrap();
+            (meta.compressed_size, meta.offset)
+        });
+        let canonical = *fs.get_file_info(group[0]).unwrap();
+        println!(
+            "{} identical files ({} each):",
+            group.len(),
+            format_size(canonical.actual_size().into(), args.human_readable)
+        );
+        let mut extents = vec![canonical.offset];
+        for path in &group {
+            println!("  {path}");
+            let meta = fs.get_file_info(path).unwrap();
+            // Files may already share data
+            if !extents.contains(&meta.offset) {
+                extents.push(meta.offset);
+                reclaimable += u64::from(meta.compressed_size);
+            }
+            if meta.offset != canonical.offset {
+                to_share.push(((*path).clone(), group[0].clone()));
+            }
+        }
+        group_count += 1;
+    }
+    println!(
+        "{group_count} groups of identical files, {} can be reclaimed",
+        format_size(reclaimable, args.human_readable)
+    );
+
+    if args.apply && !to_share.is_empty() {
+        let ard_len = input.open_ard()?.metadata()?.len();
+        let mut old_extents = Vec::new();
+        for (path, source) in &to_share {
+            let meta = fs.get_file_info(path).unwrap();
+            old_extents.push(meta.offset..meta.offset + u64::from(meta.compressed_size));
+            fs.share_extent(path, source)?;
+        }
+        // Only free the old data if no other file still uses it
+        for region in fs.unreferenced_regions(ard_len) {
+            for old in &old_extents {
+                let overlap = old.start.max(region.start)..old.end.min(region.end);
+                fs.free_region(overlap)?;
+            }
+        }
+        info!("Deduplicated {} files", to_share.len());
+        input.write_fs(&mut fs)?;
+    }
+    Ok(())
+}
+
+/// Splits groups of files with the same hash into groups of files with the same contents,
+/// for hashes that different contents can share.
+fn split_collisions<'a>(
+    input: &InputData,
+    fs: &ArhFileSystem,
+    groups: Vec<Vec<&'a ArhPath>>,
+) -> Result<Vec<Vec<&'a ArhPath>>> {
+    let mut ard = input.load_ard()?;
+    let mut split = Vec::new();
+    for group in groups {
+        let mut by_contents: Vec<(Vec<u8>, Vec<&ArhPath>)> = Vec::new();
+        for path in group {
+            let data = ard.entry(fs.get_file_info(path).unwrap()).read()?;
+            match by_contents
+                .iter_mut()
+                .find(|(contents, _)| *contents == data)
+            {
+                Some((_, paths)) => paths.push(path),
+                None => by_contents.push((data, vec![path])),
+            }
+        }
+        split.extend(
+            by_contents
+                .into_iter()
+                .map(|(_, paths)| paths)
+                .filter(|g| g.len() > 1),
+        );
+    }
+    Ok(split)
+}
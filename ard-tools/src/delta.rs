@@ -0,0 +1,257 @@
+//! Binary deltas between two versions of a file, so patches only carry what changed.
+//!
+//! A delta starts with a header that identifies the base it applies to, followed by
+//! operations that either copy a range of the base or insert new bytes:
+//!
+//! ```text
+//! "ARDDELTA" | base size (u64) | base XXH3 (u64) | target size (u64)
+//! 0 | offset (u64) | length (u64)   copy from the base
+//! 1 | length (u64) | bytes          insert
+//! ```
+//!
+//! All numbers are little-endian.
+
+use std::collections::HashMap;
+
+use anyhow::{anyhow, bail, ensure, Result};
+use xxhash_rust::xxh3::xxh3_64;
+
+/// The extension of delta files in a patch, after the extension of the file they replace
+pub const EXTENSION: &str = "ardelta";
+
+const MAGIC: &[u8; 8] = b"ARDDELTA";
+const OP_COPY: u8 = 0;
+const OP_INSERT: u8 = 1;
+
+/// Matches shorter than this are stored as inserted bytes
+const BLOCK: usize = 32;
+/// The base of the rolling hash
+const PRIME: u64 = 0x100000001b3;
+/// `PRIME^(BLOCK - 1)`, to remove the oldest byte from the rolling hash
+const PRIME_POW: u64 = {
+    let mut pow: u64 = 1;
+    let mut i = 1;
+    while i < BLOCK {
+        pow = pow.wrapping_mul(PRIME);
+        i += 1;
+    }
+    pow
+};
+
+/// Computes a delta that turns `base` into `target`.
+///
+/// Blocks of the base are indexed by a rolling hash, which finds them anywhere in the
+/// target. Matches are then extended byte by byte in both directions.
+pub fn encode(base: &[u8], target: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    out.extend(MAGIC);
+    out.extend((base.len() as u64).to_le_bytes());
+    out.extend(xxh3_64(base).to_le_bytes());
+    out.extend((target.len() as u64).to_le_bytes());
+
+    let mut index = HashMap::new();
+    for start in (0..base.len().saturating_sub(BLOCK - 1)).step_by(BLOCK) {
+        index
+            .entry(block_hash(&base[start..start + BLOCK]))
+            .or_insert(start);
+    }
+
+    let mut insert_start = 0;
+    let mut i = 0;
+    let mut hash = target.get(..BLOCK).map_or(0, block_hash);
+    while i + BLOCK <= target.len() {
+        let found = index
+            .get(&hash)
+            .copied()
+            .filter(|&pos| base[pos..pos + BLOCK] == target[i..i + BLOCK]);
+        let Some(pos) = found else {
+            if let Some(&next) = target.get(i + BLOCK) {
+                hash = hash
+                    .wrapping_sub(u64::from(target[i]).wrapping_mul(PRIME_POW))
+                    .wrapping_mul(PRIME)
+                    .wrapping_add(u64::from(next));
+            }
+            i += 1;
+            continue;
+        };
+        let (mut start, mut base_start) = (i, pos);
+        while start > insert_start && base_start > 0 && target[start - 1] == base[base_start - 1] {
+            start -= 1;
+            base_start -= 1;
+        }
+        let mut len = i + BLOCK - start;
+        while start + len < target.len()
+            && base_start + len < base.len()
+            && target[start + len] == base[base_start + len]
+        {
+            len += 1;
+        }
+        push_insert(&mut out, &target[insert_start..start]);
+        out.push(OP_COPY);
+        out.extend((base_start as u64).to_le_bytes());
+        out.extend((len as u64).to_le_bytes());
+
+        i = start + len;
+        insert_start = i;
+        hash = target.get(i..i + BLOCK).map_or(0, block_hash);
+    }
+    push_insert(&mut out, &target[insert_start..]);
+    out
+}
+
+/// Rebuilds the target of a delta from its base.
+pub fn apply(base: &[u8], delta: &[u8]) -> Result<Vec<u8>> {
+    let mut reader = delta
+        .strip_prefix(MAGIC)
+        .ok_or_else(|| anyhow!("not a delta file"))?;
+    let base_len = read_u64(&mut reader)?;
+    let base_hash = read_u64(&mut reader)?;
+    let target_len = read_u64(&mut reader)?;
+    if base_len != base.len() as u64 || base_hash != xxh3_64(base) {
+        bail!("the delta was made for a different version of the file (already patched?)");
+    }
+
+    // The header isn't trusted for the allocation: a delta can't make more than its own
+    // inserted bytes plus copies of the base, short of copying the same range repeatedly
+    let capacity = target_len.min((base.len() + delta.len()) as u64);
+    let mut out = Vec::with_capacity(capacity as usize);
+    while let Some((&op, rest)) = reader.split_first() {
+        reader = rest;
+        match op {
+            OP_COPY => {
+                let offset = usize::try_from(read_u64(&mut reader)?)?;
+                let len = usize::try_from(read_u64(&mut reader)?)?;
+                let range = offset.checked_add(len).map(|end| offset..end);
+                let Some(data) = range.and_then(|range| base.get(range)) else {
+                    bail!("corrupted delta: copy past the end of the base");
+                };
+                out.extend_from_slice(data);
+            }
+            OP_INSERT => {
+                let len = usize::try_from(read_u64(&mut reader)?)?;
+                ensure!(len <= reader.len(), "corrupted delta: truncated insert");
+                let (data, rest) = reader.split_at(len);
+                out.extend_from_slice(data);
+                reader = rest;
+            }
+            op => bail!("corrupted delta: unknown operation {op}"),
+        }
+        ensure!(
+            out.len() as u64 <= target_len,
+            "corrupted delta: more than {target_len} bytes"
+        );
+    }
+    ensure!(
+        out.len() as u64 == target_len,
+        "corrupted delta: expected {target_len} bytes, got {}",
+        out.len()
+    );
+    Ok(out)
+}
+
+fn push_insert(out: &mut Vec<u8>, data: &[u8]) {
+    if !data.is_empty() {
+        out.push(OP_INSERT);
+        out.extend((data.len() as u64).to_le_bytes());
+        out.extend(data);
+    }
+}
+
+fn block_hash(block: &[u8]) -> u64 {
+    block.iter().fold(0, |hash: u64, &b| {
+        hash.wrapping_mul(PRIME).wrapping_add(u64::from(b))
+    })
+}
+
+fn read_u64(reader: &mut &[u8]) -> Result<u64> {
+    ensure!(reader.len() >= 8, "corrupted delta: truncated");
+    let (bytes, rest) = reader.split_at(8);
+    *reader = rest;
+    Ok(u64::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{apply, encode};
+
+    /// Bytes that don't repeat, so matches only come from the base
+    fn noise(len: usize, seed: u64) -> Vec<u8> {
+        let mut state = seed;
+        (0..len)
+            .map(|_| {
+                state = state
+                    .wrapping_mul(6364136223846793005)
+                    .wrapping_add(1442695040888963407);
+                (state >> 56) as u8
+            })
+            .collect()
+    }
+
+    fn roundtrip(base: &[u8], target: &[u8]) -> Vec<u8> {
+        let delta = encode(base, target);
+        assert_eq!(apply(base, &delta).unwrap(), target);
+        delta
+    }
+
+    #[test]
+    fn delta_roundtrip() {
+        let base = noise(10_000, 1);
+
+        // Identical: a single copy
+        let delta = roundtrip(&base, &base);
+        assert!(delta.len() < 64);
+
+        // Changed in the middle
+        let mut changed = base.clone();
+        changed[5000..5010].copy_from_slice(b"0123456789");
+        let delta = roundtrip(&base, &changed);
+        assert!(delta.len() < 128);
+
+        // Grown at both ends
+        let mut grown = noise(300, 2);
+        grown.extend(&base);
+        grown.extend(noise(200, 3));
+        let delta = roundtrip(&base, &grown);
+        assert!(delta.len() < 1024);
+
+        // Shrunk, with a part moved
+        let mut shrunk = base[6000..8000].to_vec();
+        shrunk.extend(&base[100..3000]);
+        roundtrip(&base, &shrunk);
+
+        // Nothing in common, and empty inputs
+        roundtrip(&base, &noise(1000, 4));
+        roundtrip(&base, &[]);
+        roundtrip(&[], &base);
+        roundtrip(&[], &[]);
+    }
+
+    #[test]
+    fn delta_base_mismatch() {
+        let base = noise(1000, 1);
+        let mut target = base.clone();
+        target[10] ^= 1;
+        let delta = encode(&base, &target);
+
+        // Same size, different contents
+        assert!(apply(&target, &delta).is_err());
+        // Different size
+        assert!(apply(&base[1..], &delta).is_err());
+        // Truncated delta
+        assert!(apply(&base, &delta[..delta.len() - 1]).is_err());
+        assert!(apply(&base, b"not a delta").is_err());
+    }
+
+    #[test]
+    fn delta_huge_target() {
+        let base = noise(1000, 1);
+        let mut delta = encode(&base, &base);
+        // The target size is the last field of the header
+        delta[24..32].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert!(apply(&base, &delta).is_err());
+
+        // Copies that go past the target size fail before the output grows further
+        delta[24..32].copy_from_slice(&10u64.to_le_bytes());
+        assert!(apply(&base, &delta).is_err());
+    }
+}
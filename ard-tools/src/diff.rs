@@ -0,0 +1,222 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::PathBuf,
+};
+
+use anyhow::{anyhow, Result};
+use ardain::path::ArhPath;
+use clap::Args;
+use log::info;
+use rayon::prelude::*;
+
+use crate::{
+    export_changes::load_baseline,
+    hash::Digest,
+    manifest::ManifestEntry,
+    output::{paint, Style},
+    InputData,
+};
+
+#[derive(Args)]
+pub struct DiffArgs {
+    /// The older version: an .arh file, or a manifest written by `manifest export`. With a
+    /// manifest that has hashes, contents are compared, and moved files are found by their
+    /// hash. Otherwise, only metadata is compared, and moved files are found by the location
+    /// of their data.
+    baseline: PathBuf,
+    /// Report moved files as removed and added
+    #[arg(long)]
+    no_renames: bool,
+    /// Also compare hidden (soft-deleted) files
+    #[arg(short, long)]
+    all: bool,
+}
+
+/// What identifies a file that was moved: the hash of its contents and its size, or the
+/// location of its data in the ARD file.
+#[derive(PartialEq, Eq, Hash)]
+enum MoveKey {
+    Contents(Digest, u32),
+    Location(u64, u32),
+}
+
+pub fn run(input: &InputData, args: DiffArgs) -> Result<()> {
+    let fs = input.load_fs()?;
+    let is_hidden = |e: &ManifestEntry| e.flags.split('|').any(|f| f == "hidden");
+    let mut baseline: HashMap<_, _> = load_baseline(&args.baseline)?
+        .into_iter()
+        .filter(|e| args.all || !is_hidden(e))
+        .map(|e| (e.path.clone(), e))
+        .collect();
+    // Hash with the same algorithm as the manifest
+    let hasher = baseline
+        .values()
+        .find_map(|e| e.hash.as_ref())
+        .map(Digest::hasher);
+    if hasher.is_none() {
+        info!("The baseline has no hashes, comparing metadata only");
+    }
+
+    let files = crate::expand_paths(&fs, &[ArhPath::default()], args.all)?;
+    if hasher.is_some() {
+        input.require_ard(&files)?;
+    }
+    let current = files
+        .par_iter()
+        .map_init(
+            || hasher.map(|hasher| (hasher, input.load_ard())),
+            |state, path| -> Result<ManifestEntry> {
+                let meta = fs.get_file_info(path).unwrap();
+                let hash = match state {
+                    Some((hasher, ard)) => {
+                        let ard = ard.as_mut().map_err(|e| anyhow!("{e}"))?;
+                        Some(hasher.digest(&ard.entry(meta).read()?))
+                    }
+                    None => None,
+                };
+                Ok(ManifestEntry::new(path, meta, hash))
+            },
+        )
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut added = Vec::new();
+    let mut modified = Vec::new();
+    for new in current {
+        let Some(old) = baseline.remove(&new.path) else {
+            added.push(new);
+            continue;
+        };
+        let changed = if old.hash.is_some() {
+            old.hash != new.hash
+        } else {
+            old.offset != new.offset
+                || old.compressed_size != new.compressed_size
+                || old.uncompressed_size != new.uncompressed_size
+        };
+        if changed || old.flags != new.flags {
+            modified.push(new.path);
+        }
+    }
+    let mut removed: Vec<_> = baseline.into_values().collect();
+    let renames = if args.no_renames {
+        Vec::new()
+    } else {
+        find_renames(&mut removed, &mut added)
+    };
+
+    let mut lines: Vec<(&str, String)> = Vec::new();
+    lines.extend(
+        added
+            .iter()
+            .map(|e| (e.path.as_str(), format!("A {}", e.path))),
+    );
+    lines.extend(
+        removed
+            .iter()
+            .map(|e| (e.path.as_str(), format!("D {}", e.path))),
+    );
+    lines.extend(
+        modified
+            .iter()
+            .map(|path| (path.as_str(), format!("M {path}"))),
+    );
+    // Report whole directories that were moved, instead of each of their files
+    let mut moves: BTreeMap<(&str, &str), Vec<(&str, &str)>> = BTreeMap::new();
+    for (old, new) in &renames {
+        moves
+            .entry(moved_dirs(old, new))
+            .or_default()
+            .push((old, new));
+    }
+    let mut dir_moves = 0;
+    for ((old_dir, new_dir), files) in &moves {
+        if let [(old, new)] = files[..] {
+            lines.push((old, format!("R {old} -> {new}")));
+        } else {
+            dir_moves += 1;
+            let count = files.len();
+            lines.push((
+                old_dir,
+                format!("R {old_dir}/ -> {new_dir}/ ({count} files)"),
+            ));
+        }
+    }
+    lines.sort();
+    for (_, line) in &lines {
+        let style = match line.as_bytes()[0] {
+            b'A' => Style::Good,
+            b'D' => Style::Bad,
+            _ => Style::Flag,
+        };
+        println!("{}", paint(line, style));
+    }
+    println!(
+        "{} added, {} removed, {} modified, {} moved ({dir_moves} directories)",
+        added.len(),
+        removed.len(),
+        modified.len(),
+        renames.len()
+    );
+    Ok(())
+}
+
+/// Pairs removed files with added files that have the same contents, and takes them out of
+/// both lists. Returns the old and new path of each pair.
+fn find_renames(
+    removed: &mut Vec<ManifestEntry>,
+    added: &mut Vec<ManifestEntry>,
+) -> Vec<(String, String)> {
+    let mut candidates: HashMap<MoveKey, Vec<usize>> = HashMap::new();
+    for (i, old) in removed.iter().enumerate() {
+        if let Some(key) = move_key(old) {
+            candidates.entry(key).or_default().push(i);
+        }
+    }
+    let mut taken = vec![false; removed.len()];
+    let mut renames = Vec::new();
+    added.retain(|new| {
+        let Some(olds) = move_key(new).and_then(|key| candidates.get_mut(&key)) else {
+            return true;
+        };
+        if olds.is_empty() {
+            return true;
+        }
+        // Prefer a file with the same name, e.g. when a directory was renamed
+        let name = |path: &str| path.rsplit('/').next().unwrap_or_default().to_string();
+        let pos = olds
+            .iter()
+            .position(|&i| name(&removed[i].path) == name(&new.path))
+            .unwrap_or(0);
+        let i = olds.remove(pos);
+        taken[i] = true;
+        renames.push((removed[i].path.clone(), new.path.clone()));
+        false
+    });
+    let mut taken = taken.into_iter();
+    removed.retain(|_| !taken.next().unwrap());
+    renames
+}
+
+fn move_key(entry: &ManifestEntry) -> Option<MoveKey> {
+    // Empty files all look the same
+    if entry.size == 0 {
+        return None;
+    }
+    Some(match &entry.hash {
+        Some(hash) => MoveKey::Contents(hash.clone(), entry.size),
+        None => MoveKey::Location(entry.offset, entry.compressed_size),
+    })
+}
+
+/// Returns the directories a file was moved between: the paths without the names they have
+/// in common at the end. For example, `/a/b/c.bin` -> `/x/b/c.bin` gives `/a` -> `/x`.
+fn moved_dirs<'a>(old: &'a str, new: &'a str) -> (&'a str, &'a str) {
+    let (mut old_end, mut new_end) = (old.len(), new.len());
+    while let (Some(o), Some(n)) = (old[..old_end].rfind('/'), new[..new_end].rfind('/')) {
+        if o == 0 || n == 0 || old[o..old_end] != new[n..new_end] {
+            break;
+        }
+        (old_end, new_end) = (o, n);
+    }
+    (&old[..old_end], &new[..new_end])
+}
@@ -0,0 +1,130 @@
+//! Exit codes and error reporting, see `--errors`.
+
+use std::{fmt::Display, io, process::ExitCode};
+
+use ardain::error::Error;
+use clap::ValueEnum;
+use serde::Serialize;
+use serde_json::json;
+
+/// How errors are printed, see `--errors`.
+#[derive(ValueEnum, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ErrorFormat {
+    /// A message with its causes
+    #[default]
+    Text,
+    /// One JSON object per line, with the kind of error and its exit code
+    Json,
+}
+
+/// What kind of failure a command ended with, which determines the exit code.
+#[derive(Serialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub enum ErrorKind {
+    Other,
+    /// A file or directory doesn't exist, in the archive or on disk
+    NotFound,
+    /// The archive can't be parsed, or its metadata and data don't match
+    Corrupted,
+    /// The disk is full, or the archive can't hold more data
+    OutOfSpace,
+    /// The command went through, but some of the files couldn't be processed
+    PartialFailure,
+}
+
+/// An error that was raised with a known kind, see [`ErrorKind::error`].
+#[derive(Debug)]
+struct Failure {
+    kind: ErrorKind,
+    message: String,
+}
+
+impl ErrorKind {
+    /// An error of this kind with the given message.
+    pub fn error(self, message: impl Display) -> anyhow::Error {
+        Failure {
+            kind: self,
+            message: message.to_string(),
+        }
+        .into()
+    }
+
+    /// The process exit code. Clap already uses 2 for invalid arguments.
+    pub fn exit_code(self) -> u8 {
+        match self {
+            ErrorKind::Other => 1,
+            ErrorKind::NotFound => 3,
+            ErrorKind::Corrupted => 4,
+            ErrorKind::OutOfSpace => 5,
+            ErrorKind::PartialFailure => 6,
+        }
+    }
+
+    /// Returns the kind of the first error in the chain that has one.
+    pub fn of(err: &anyhow::Error) -> Self {
+        err.chain()
+            .find_map(|cause| {
+                if let Some(failure) = cause.downcast_ref::<Failure>() {
+                    Some(failure.kind)
+                } else if let Some(err) = cause.downcast_ref::<Error>() {
+                    Self::of_archive(err)
+                } else {
+                    cause.downcast_ref::<io::Error>().and_then(Self::of_io)
+                }
+            })
+            .unwrap_or(ErrorKind::Other)
+    }
+
+    fn of_archive(err: &Error) -> Option<Self> {
+        Some(match err {
+            Error::Io(e) => return Self::of_io(e),
+            Error::FsNoEntry => ErrorKind::NotFound,
+            Error::Parse(_)
+            | Error::ArdDecompress(_)
+            | Error::ChecksumMismatch { .. }
+            | Error::FileMetaInvalid(_)
+            | Error::InvalidTables(_) => ErrorKind::Corrupted,
+            Error::LimitExceeded(_) => ErrorKind::OutOfSpace,
+            _ => return None,
+        })
+    }
+
+    fn of_io(err: &io::Error) -> Option<Self> {
+        Some(match err.kind() {
+            io::ErrorKind::NotFound => ErrorKind::NotFound,
+            io::ErrorKind::StorageFull
+            | io::ErrorKind::QuotaExceeded
+            | io::ErrorKind::FileTooLarge => ErrorKind::OutOfSpace,
+            // Reading past the end of the data
+            io::ErrorKind::UnexpectedEof | io::ErrorKind::InvalidData => ErrorKind::Corrupted,
+            _ => return None,
+        })
+    }
+}
+
+/// Prints the error that ended the command, and returns the matching exit code.
+pub fn report(err: &anyhow::Error, format: ErrorFormat) -> ExitCode {
+    let kind = ErrorKind::of(err);
+    match format {
+        ErrorFormat::Text => eprintln!("Error: {err:?}"),
+        ErrorFormat::Json => {
+            let causes: Vec<_> = err.chain().skip(1).map(|e| e.to_string()).collect();
+            let error = json!({
+                "kind": kind,
+                "code": kind.exit_code(),
+                "message": err.to_string(),
+                "causes": causes,
+            });
+            eprintln!("{error}");
+        }
+    }
+    ExitCode::from(kind.exit_code())
+}
+
+impl Display for Failure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for Failure {}
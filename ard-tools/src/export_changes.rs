@@ -0,0 +1,192 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{BufReader, BufWriter, Read, Seek},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Result};
+use ardain::{path::ArhPath, ArdReader, ArhFileSystem, FileFlag};
+use clap::Args;
+use log::{debug, info, warn};
+use rayon::prelude::*;
+
+use crate::{
+    delta,
+    manifest::{self, ManifestEntry},
+    zip::ZipWriter,
+    InputData,
+};
+
+#[derive(Args)]
+pub struct ExportChangesArgs {
+    /// The unmodified .arh file, or a manifest written by `manifest export`. Manifests
+    /// with hashes also detect files that were overwritten in place.
+    #[arg(long)]
+    baseline: PathBuf,
+    /// The output directory, or a .zip file. Archive paths are recreated inside it.
+    #[arg(short, long)]
+    out: PathBuf,
+    /// The .ard file of the baseline. Modified files are then exported as binary deltas
+    /// against their baseline contents (`<path>.ardelta`), when that is smaller. `apply-patch`
+    /// turns them back into full files.
+    #[arg(long, value_name = "ARD")]
+    delta_base: Option<PathBuf>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Change {
+    Added,
+    Modified,
+}
+
+pub fn run(input: &InputData, args: ExportChangesArgs) -> Result<()> {
+    let fs = input.load_fs()?;
+    let baseline: HashMap<_, _> = load_baseline(&args.baseline)?
+        .into_iter()
+        .map(|e| (e.path.clone(), e))
+        .collect();
+    let has_hashes = baseline.values().any(|e| e.hash.is_some());
+
+    let files = crate::expand_paths(&fs, &[ArhPath::default()], false)?;
+    if has_hashes {
+        input.require_ard(&files)?;
+    }
+    let changes = files
+        .par_iter()
+        .map_init(
+            || input.load_ard(),
+            |ard, path| -> Result<Option<(&ArhPath, Change)>> {
+                let meta = fs.get_file_info(path).unwrap();
+                let Some(old) = baseline.get(path.as_str()) else {
+                    return Ok(Some((path, Change::Added)));
+                };
+                let new = ManifestEntry::new(path, meta, None);
+                let changed = match &old.hash {
+                    // Contents may have been replaced in place, metadata is not enough
+                    Some(hash) => {
+                        let ard = ard.as_mut().map_err(|e| anyhow!("{e}"))?;
+                        *hash != hash.hasher().digest(&ard.entry(meta).read()?)
+                    }
+                    None => {
+                        old.offset != new.offset
+                            || old.compressed_size != new.compressed_size
+                            || old.uncompressed_size != new.uncompressed_size
+                            || old.flags != new.flags
+                    }
+                };
+                Ok(changed.then_some((path, Change::Modified)))
+            },
+        )
+        .collect::<Result<Vec<_>>>()?;
+    let mut changes: Vec<_> = changes.into_iter().flatten().collect();
+    // Reading in offset order is much faster on HDDs
+    changes.sort_by_key(|(path, _)| fs.get_file_info(path).unwrap().offset);
+
+    let removed = baseline
+        .values()
+        .filter(|e| !e.flags.split('|').any(|f| f == "hidden"))
+        .filter(|e| {
+            ArhPath::normalize(&e.path)
+                .ok()
+                .and_then(|p| fs.get_file_info(&p))
+                .is_none_or(|meta| meta.is_flag(FileFlag::Hidden))
+        })
+        .count();
+    if removed != 0 {
+        warn!("{removed} files were removed since the baseline, they are not part of the export");
+    }
+    if !has_hashes {
+        info!("The baseline has no hashes, comparing metadata only");
+    }
+
+    input.require_ard(changes.iter().map(|(path, _)| *path))?;
+    let delta_base = match &args.delta_base {
+        Some(path) => Some((&baseline, ArdReader::new(BufReader::new(File::open(path)?)))),
+        None => None,
+    };
+    let deltas = write_changes(input, &fs, &changes, delta_base, &args.out)?;
+    let added = changes.iter().filter(|(_, c)| *c == Change::Added).count();
+    println!(
+        "Exported {} files ({added} added, {} modified) to {}",
+        changes.len(),
+        changes.len() - added,
+        args.out.display()
+    );
+    if args.delta_base.is_some() {
+        println!("{deltas} modified files were exported as deltas");
+    }
+    Ok(())
+}
+
+/// Reads the files of a baseline: an .arh file, or a manifest written by `manifest export`.
+pub(crate) fn load_baseline(path: &Path) -> Result<Vec<ManifestEntry>> {
+    let is_arh = path
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("arh"));
+    if !is_arh {
+        return manifest::read_manifest(path, None);
+    }
+    info!("Loading baseline {}", path.display());
+    let baseline = ArhFileSystem::load(BufReader::new(File::open(path)?))?;
+    let files = crate::expand_paths(&baseline, &[ArhPath::default()], true)?;
+    Ok(files
+        .iter()
+        .map(|path| ManifestEntry::new(path, baseline.get_file_info(path).unwrap(), None))
+        .collect())
+}
+
+/// Writes the contents of the changed files, and returns how many were written as deltas.
+fn write_changes(
+    input: &InputData,
+    fs: &ArhFileSystem,
+    changes: &[(&ArhPath, Change)],
+    mut delta_base: Option<(&HashMap<String, ManifestEntry>, ArdReader<impl Read + Seek>)>,
+    out: &Path,
+) -> Result<usize> {
+    let mut ard = input.load_ard()?;
+    let is_zip = out
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("zip"));
+    let mut zip = is_zip
+        .then(|| File::create(out).map(|f| ZipWriter::new(BufWriter::new(f))))
+        .transpose()?;
+    let mut deltas = 0;
+    for (path, change) in changes {
+        let data = ard.entry(fs.get_file_info(path).unwrap()).read()?;
+        let mut rel = path.trim_start_matches('/').to_string();
+        debug!("Exporting {path}");
+        let old = delta_base
+            .as_mut()
+            .filter(|_| *change == Change::Modified)
+            .and_then(|(baseline, ard)| Some((baseline.get(path.as_str())?, ard)));
+        let data = match old {
+            Some((old, base_ard)) => {
+                let delta = delta::encode(&base_ard.entry(&old.file_meta()).read()?, &data);
+                if delta.len() < data.len() {
+                    rel = format!("{rel}.{}", delta::EXTENSION);
+                    deltas += 1;
+                    delta
+                } else {
+                    data
+                }
+            }
+            None => data,
+        };
+        // Also checked for zip files, whose entries are extracted the same way
+        let out_path = crate::join_output_path(out, &rel)?;
+        match &mut zip {
+            Some(zip) => zip.add_file(&rel, &data)?,
+            None => {
+                if let Some(parent) = out_path.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                fs::write(out_path, data)?;
+            }
+        }
+    }
+    if let Some(zip) = zip {
+        zip.finish()?;
+    }
+    Ok(deltas)
+}
@@ -0,0 +1,101 @@
+use anyhow::{bail, Result};
+use clap::{Args, Subcommand};
+
+use crate::{ls::format_size, InputData};
+
+#[derive(Args)]
+pub struct ExtArgs {
+    #[command(subcommand)]
+    command: ExtCommand,
+}
+
+#[derive(Subcommand)]
+enum ExtCommand {
+    /// Check that the block allocation table agrees with the file table
+    Verify {
+        /// Rebuild the block allocation table from the file table if they disagree
+        #[arg(long)]
+        repair: bool,
+        /// Print sizes in a human-readable format
+        #[arg(short = 'H', long)]
+        human_readable: bool,
+    },
+    /// Rebuild the block allocation table with a different block size
+    SetBlockSize {
+        /// The new block size, as an exponent base 2 (e.g. 9 for 512-byte blocks)
+        pow: u16,
+        /// Allow free space to be marked as occupied, if it only partially covers the
+        /// new blocks
+        #[arg(long)]
+        force: bool,
+        /// Print sizes in a human-readable format
+        #[arg(short = 'H', long)]
+        human_readable: bool,
+    },
+}
+
+pub fn run(input: &InputData, args: ExtArgs) -> Result<()> {
+    let mut fs = input.load_fs()?;
+    match args.command {
+        ExtCommand::Verify {
+            repair,
+            human_readable,
+        } => {
+            let drift = fs.check_block_table();
+            for (regions, what) in [
+                (&drift.unmarked, "marked as free, but used by files"),
+                (
+                    &drift.leaked,
+                    "marked as occupied, but not used by any file",
+                ),
+            ] {
+                for region in regions {
+                    println!(
+                        "0x{:X}-0x{:X}  {}  {what}",
+                        region.start,
+                        region.end,
+                        format_size(region.end - region.start, human_readable)
+                    );
+                }
+            }
+            if drift.is_empty() {
+                println!("Block allocation table is consistent");
+                return Ok(());
+            }
+            let count = drift.unmarked.len() + drift.leaked.len();
+            if !repair {
+                bail!("{count} inconsistent regions found, use --repair to fix them");
+            }
+            fs.repair_block_table()?;
+            input.write_fs(&mut fs)?;
+            println!("Repaired {count} inconsistent regions");
+        }
+        ExtCommand::SetBlockSize {
+            pow,
+            force,
+            human_readable,
+        } => {
+            let change = fs.set_block_size(pow, force)?;
+            let size = |pow: u16| format_size(1 << pow, human_readable);
+            println!(
+                "Block size: {} -> {}",
+                size(change.old_block_size_pow),
+                size(change.new_block_size_pow)
+            );
+            if change.lost_bytes != 0 {
+                println!(
+                    "{} of free space is now marked as occupied",
+                    format_size(change.lost_bytes, human_readable)
+                );
+            }
+            let (old, new) = (change.old_ext_size, change.new_ext_size);
+            println!(
+                "ARH size: {}{}",
+                if new < old { "-" } else { "+" },
+                format_size(old.abs_diff(new).into(), human_readable)
+            );
+            input.write_fs(&mut fs)?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,570 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs::{self, File},
+    io::{self, BufReader, Read, Seek},
+    num::NonZeroUsize,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    thread,
+    time::Duration,
+};
+
+use anyhow::{anyhow, bail, Result};
+use ardain::{
+    find_nested_streams, opts,
+    path::{ArhPath, PathPattern, RelativeArhPath},
+    visit::ParallelVisitor,
+    ArdReader, ArhFileSystem, FileMeta, PositionalReader, ReadOrder,
+};
+use clap::{
+    builder::{PossibleValuesParser, TypedValueParser},
+    Args,
+};
+use glob::Pattern;
+use log::{debug, error, info, warn};
+
+use crate::{
+    errors::ErrorKind,
+    progress::{Progress, ProgressFormat},
+    InputData,
+};
+
+#[derive(Args)]
+pub struct ExtractArgs {
+    /// The files or directories to extract (default: /, unless --files-from is present)
+    #[arg(add = crate::completions::arh_path_completer())]
+    paths: Vec<RelativeArhPath>,
+    /// Also extract the files listed in FILE ("-" for stdin), with one archive path or
+    /// pattern (e.g. "/bdat/*.bdat", or "*.wismt" at any depth) per line. Empty lines are
+    /// ignored.
+    #[arg(long, value_name = "FILE")]
+    files_from: Option<PathBuf>,
+    /// The output directory. Archive paths are recreated inside it.
+    #[arg(short, long)]
+    out: PathBuf,
+    /// Remove the first N directories from each path before writing it. Files with no
+    /// path components left are skipped.
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    strip_components: usize,
+    /// Write every file directly inside the output directory, discarding its archive path
+    #[arg(long, conflicts_with = "strip_components")]
+    flatten: bool,
+    /// Skip files whose path matches the given glob pattern (e.g. "*.wismda"). Can be
+    /// passed multiple times.
+    #[arg(long, value_name = "GLOB")]
+    exclude: Vec<Pattern>,
+    /// Skip files smaller than this size (uncompressed). Accepts K, M and G suffixes.
+    #[arg(long, value_name = "SIZE", value_parser = opts::parse_size)]
+    min_size: Option<u64>,
+    /// Skip files larger than this size (uncompressed). Accepts K, M and G suffixes.
+    #[arg(long, value_name = "SIZE", value_parser = opts::parse_size)]
+    max_size: Option<u64>,
+    /// Skip files that already exist in the output directory with the same size as the
+    /// archive entry. The ARHX section doesn't store file checksums, so contents are not
+    /// compared.
+    #[arg(short, long)]
+    update: bool,
+    /// Ask before overwriting files that already exist in the output directory
+    #[arg(short, long)]
+    interactive: bool,
+    /// Also extract XBC1 streams found inside extracted files. Each stream is written
+    /// next to its file, as "<file name>.<index>[.<stream name>]".
+    #[arg(long)]
+    deep: bool,
+    /// Report progress in the given format
+    #[arg(long, value_name = "FORMAT")]
+    progress: Option<ProgressFormat>,
+    /// The number of files to extract at the same time (default: one per CPU). Lower values
+    /// can be faster on slow storage, e.g. HDDs or network shares.
+    #[arg(short = 'j', long, value_name = "N", value_parser = clap::value_parser!(u16).range(1..))]
+    threads: Option<u16>,
+    /// The order in which files are extracted: by offset in the ARD file (fastest on HDDs),
+    /// by path (predictable progress, fully deterministic with -j 1), or largest first
+    /// (spreads the work evenly between threads)
+    #[arg(long, value_name = "ORDER", default_value = "offset", value_parser = order_parser())]
+    order: ReadOrder,
+    /// Also extract hidden (soft-deleted) files
+    #[arg(short, long)]
+    all: bool,
+    /// Rename files whose path is not valid on Windows (reserved names like "con", invalid
+    /// characters, paths that are too long), as is always done on Windows. Useful when
+    /// extracting to a drive that will be used on Windows, or formatted as exFAT.
+    #[arg(long)]
+    windows_safe: bool,
+    /// Write the archive path and output path of every renamed file to this file, separated
+    /// by a tab
+    #[arg(long, value_name = "FILE")]
+    rename_report: Option<PathBuf>,
+}
+
+pub fn run(input: &InputData, args: ExtractArgs) -> Result<()> {
+    let fs = input.load_fs()?;
+    let paths = if args.paths.is_empty() && args.files_from.is_none() {
+        vec![ArhPath::default()]
+    } else {
+        input.resolve_all(&args.paths)?
+    };
+    let mut files = crate::expand_paths(&fs, &paths, args.all)?;
+    if let Some(list) = &args.files_from {
+        files.extend(read_file_list(&fs, list, args.all)?);
+        files.sort();
+        files.dedup();
+    }
+    args.order.sort_files(&fs, &mut files);
+    let mut files: Vec<_> = files
+        .into_iter()
+        .filter(|path| {
+            let size = u64::from(fs.get_file_info(path).unwrap().actual_size());
+            args.min_size.is_none_or(|min| size >= min)
+                && args.max_size.is_none_or(|max| size <= max)
+                && !args.exclude.iter().any(|p| p.matches(path))
+        })
+        .filter_map(|path| {
+            let out_path = output_path(&args, &path).transpose()?;
+            Some(out_path.map(|out_path| (path, out_path)))
+        })
+        .collect::<Result<_>>()?;
+    let renamed = if cfg!(windows) || args.windows_safe {
+        crate::sanitize::sanitize_paths(&args.out, &mut files)?
+    } else {
+        Vec::new()
+    };
+    for (path, out_path) in &renamed {
+        info!("{path}: renamed to {}", out_path.display());
+    }
+    if let Some(report) = &args.rename_report {
+        let mut out = String::new();
+        for (path, out_path) in &renamed {
+            out += &format!("{path}\t{}\n", out_path.display());
+        }
+        fs::write(report, out)?;
+    }
+    if args.flatten {
+        let mut seen = HashSet::new();
+        for (path, out_path) in &files {
+            if !seen.insert(out_path) {
+                bail!(
+                    "{path}: another file would be extracted to {}",
+                    out_path.display()
+                );
+            }
+        }
+    }
+
+    if args.interactive {
+        let mut kept = Vec::with_capacity(files.len());
+        for (path, out_path) in files {
+            let size = fs.get_file_info(&path).unwrap().actual_size();
+            let skipped = args.update && is_up_to_date(&out_path, size);
+            if out_path.exists()
+                && !skipped
+                && !input.confirm(&format!("overwrite {}?", out_path.display()))?
+            {
+                info!("{path}: not overwriting {}", out_path.display());
+                continue;
+            }
+            kept.push((path, out_path));
+        }
+        files = kept;
+    }
+
+    let total_bytes = files
+        .iter()
+        .map(|(path, _)| u64::from(fs.get_file_info(path).unwrap().actual_size()))
+        .sum();
+    info!("Extracting {} files ({total_bytes} bytes)", files.len());
+    let progress = Progress::new(args.progress, files.len(), total_bytes);
+
+    input.require_ard(files.iter().map(|(path, _)| path))?;
+    let ard = SharedArd::from_input(input)?;
+    let out_paths: HashMap<_, _> = files
+        .iter()
+        .map(|(path, out)| (path.as_str(), out))
+        .collect();
+    let paths: Vec<_> = files.iter().map(|(path, _)| path.clone()).collect();
+    let mut visitor = ParallelVisitor::new().order(args.order);
+    if let Some(threads) = args.threads.and_then(|n| NonZeroUsize::new(n.into())) {
+        visitor = visitor.threads(threads);
+    }
+    // The ARD file is shared between threads, so they don't need their own state. Other
+    // files are still extracted if some fail, so one bad entry doesn't stop a full dump.
+    let results = visitor.visit(
+        &fs,
+        &paths,
+        || (),
+        |_, path, _| extract_file(&fs, &ard, &args, &progress, path, out_paths[path.as_str()]),
+    );
+    let mut errors = Vec::new();
+    let results: Vec<_> = paths
+        .iter()
+        .zip(results)
+        .filter_map(|(path, res)| res.map_err(|e| errors.push(e.context(path.clone()))).ok())
+        .collect();
+
+    progress.finish();
+    if !errors.is_empty() && results.is_empty() {
+        // Nothing was extracted, so report the first error as the cause
+        for e in &errors[1..] {
+            error!("{e:#}");
+        }
+        return Err(errors.swap_remove(0));
+    }
+    for e in &errors {
+        error!("{e:#}");
+    }
+    let res = if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(ErrorKind::PartialFailure.error(format!(
+            "{} of {} files could not be extracted",
+            errors.len(),
+            files.len()
+        )))
+    };
+    if progress.is_enabled() {
+        return res;
+    }
+    let extracted = results.iter().flatten().count();
+    println!("Extracted {extracted} files");
+    if !renamed.is_empty() {
+        println!(
+            "Renamed {} files to be valid on Windows, see -v or --rename-report",
+            renamed.len()
+        );
+    }
+    if args.update {
+        println!("Skipped {} up-to-date files", files.len() - extracted);
+    }
+    if args.deep {
+        let nested: usize = results.iter().flatten().sum();
+        println!("Extracted {nested} nested streams");
+    }
+    res
+}
+
+/// The ARD file, shared between extraction threads.
+enum SharedArd {
+    /// All threads share the same handle, using positional reads
+    Positional(File),
+    /// For files that don't support positional reads, e.g. on some network shares. Only one
+    /// thread can read at a time.
+    Locked(Mutex<ArdReader<BufReader<File>>>),
+    /// Each read uses its own clone, so threads make range requests in parallel
+    #[cfg(feature = "http")]
+    Remote(crate::http::HttpArd),
+}
+
+impl SharedArd {
+    /// The number of times a read is attempted if it fails with a transient error
+    const READ_ATTEMPTS: u32 = 5;
+    const FIRST_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+    fn from_input(input: &InputData) -> Result<Self> {
+        #[cfg(feature = "http")]
+        if let Some(remote) = input.remote_ard()? {
+            return Ok(Self::Remote(remote));
+        }
+        Self::open(input.open_ard()?)
+    }
+
+    fn open(file: File) -> Result<Self> {
+        let mut probe = [0u8; 1];
+        match PositionalReader::new(&file).read_exact(&mut probe) {
+            Err(e) if e.kind() != io::ErrorKind::UnexpectedEof => {
+                warn!("Positional reads are not supported ({e}), reading one file at a time");
+                Ok(Self::Locked(Mutex::new(ArdReader::new(BufReader::new(
+                    file,
+                )))))
+            }
+            _ => Ok(Self::Positional(file)),
+        }
+    }
+
+    /// Reads a file entry, retrying with an increasing delay if the read fails with a
+    /// transient error (e.g. a timeout on a network share).
+    fn read(&self, path: &ArhPath, meta: &FileMeta) -> Result<Vec<u8>> {
+        let mut delay = Self::FIRST_RETRY_DELAY;
+        for _ in 1..Self::READ_ATTEMPTS {
+            match self.read_once(meta) {
+                Err(e) if is_transient(&e) => {
+                    warn!("{path}: {e}, retrying in {delay:?}");
+                    thread::sleep(delay);
+                    delay *= 2;
+                }
+                res => return Ok(res?),
+            }
+        }
+        Ok(self.read_once(meta)?)
+    }
+
+    fn read_once(&self, meta: &FileMeta) -> ardain::error::Result<Vec<u8>> {
+        match self {
+            Self::Positional(file) => ArdReader::new(BufReader::new(PositionalReader::new(file)))
+                .entry(meta)
+                .read(),
+            Self::Locked(reader) => reader.lock().unwrap().entry(meta).read(),
+            #[cfg(feature = "http")]
+            Self::Remote(ard) => ArdReader::new(ard.clone()).entry(meta).read(),
+        }
+    }
+}
+
+/// Returns whether an error is worth retrying, e.g. an interrupted or timed out read.
+fn is_transient(error: &ardain::error::Error) -> bool {
+    let io_error = match error {
+        ardain::error::Error::Io(e) => Some(e),
+        e => std::iter::successors(std::error::Error::source(e), |e| e.source())
+            .find_map(|e| e.downcast_ref::<io::Error>()),
+    };
+    io_error.is_some_and(|e| {
+        matches!(
+            e.kind(),
+            io::ErrorKind::Interrupted
+                | io::ErrorKind::WouldBlock
+                | io::ErrorKind::TimedOut
+                | io::ErrorKind::ConnectionReset
+                | io::ErrorKind::ConnectionAborted
+                | io::ErrorKind::NotConnected
+        )
+    })
+}
+
+/// Extracts a single file, returning the number of nested streams that were extracted, or
+/// `None` if the file was skipped.
+fn extract_file(
+    fs: &ArhFileSystem,
+    ard: &SharedArd,
+    args: &ExtractArgs,
+    progress: &Progress,
+    path: &ArhPath,
+    out_path: &Path,
+) -> Result<Option<usize>> {
+    let meta = fs.get_file_info(path).unwrap();
+    let size = meta.actual_size();
+    if args.update && is_up_to_date(out_path, size) {
+        progress.file_done(path, size.into());
+        return Ok(None);
+    }
+    let data = ard.read(path, meta)?;
+    if let Some(parent) = out_path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    debug!("{path} -> {}", out_path.display());
+    fs::write(out_path, &data)?;
+    let streams = if args.deep {
+        find_nested_streams(&data)
+    } else {
+        Vec::new()
+    };
+    for (i, stream) in streams.iter().enumerate() {
+        let mut name = out_path.file_name().unwrap().to_os_string();
+        name.push(format!(".{i}"));
+        if !stream.name.is_empty() {
+            name.push(".");
+            name.push(stream.name.replace(['/', '\\'], "_"));
+        }
+        fs::write(out_path.with_file_name(name), &stream.data)?;
+    }
+    progress.file_done(path, size.into());
+    Ok(Some(streams.len()))
+}
+
+/// Reads a list of archive paths and patterns (see [`PathPattern`]), one per line, and
+/// returns the files they refer to.
+fn read_file_list(fs: &ArhFileSystem, list: &Path, include_hidden: bool) -> Result<Vec<ArhPath>> {
+    let text = if list == Path::new("-") {
+        io::read_to_string(io::stdin())?
+    } else {
+        fs::read_to_string(list).map_err(|e| anyhow!("{}: {e}", list.display()))?
+    };
+    let mut paths = Vec::new();
+    let mut patterns = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        if line.is_empty() {
+            continue;
+        }
+        let line_err = |e: &dyn std::fmt::Display| anyhow!("{}:{}: {e}", list.display(), i + 1);
+        if line.contains(['*', '?']) {
+            let pattern = PathPattern::new(line).map_err(|e| line_err(&e))?;
+            patterns.push((line, pattern));
+        } else {
+            paths.push(ArhPath::normalize(line).map_err(|e| line_err(&e))?);
+        }
+    }
+
+    let mut files = crate::expand_paths(fs, &paths, include_hidden)?;
+    if !patterns.is_empty() {
+        let all = crate::expand_paths(fs, &[ArhPath::default()], include_hidden)?;
+        for (line, pattern) in &patterns {
+            let before = files.len();
+            files.extend(all.iter().filter(|p| pattern.matches(p)).cloned());
+            if files.len() == before {
+                warn!("{}: no files match \"{line}\"", list.display());
+            }
+        }
+    }
+    Ok(files)
+}
+
+/// Returns where the given file should be extracted to, or `None` if the file should be
+/// skipped.
+fn output_path(args: &ExtractArgs, path: &ArhPath) -> Result<Option<PathBuf>> {
+    let components = path.split('/').skip(1);
+    if args.flatten {
+        let Some(name) = components.last() else {
+            return Ok(None);
+        };
+        return crate::join_output_path(&args.out, name).map(Some);
+    }
+    let rel: Vec<_> = components.skip(args.strip_components).collect();
+    if rel.is_empty() {
+        return Ok(None);
+    }
+    crate::join_output_path(&args.out, &rel.join("/")).map(Some)
+}
+
+/// Extracts a file to `out`, or all files in a directory (recursively) inside `out`.
+///
+/// Returns the number of extracted files.
+pub(crate) fn extract_to(
+    fs: &ArhFileSystem,
+    ard: &mut ArdReader<impl Read + Seek>,
+    path: &ArhPath,
+    out: &Path,
+) -> Result<usize> {
+    let files = crate::expand_paths(fs, std::slice::from_ref(path), true)?;
+    for file in &files {
+        let out_path = if fs.is_file(path) {
+            out.to_path_buf()
+        } else {
+            crate::join_output_path(out, file[path.len()..].trim_start_matches('/'))?
+        };
+        if let Some(parent) = out_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let meta = fs.get_file_info(file).unwrap();
+        fs::write(out_path, ard.entry(meta).read()?)?;
+    }
+    Ok(files.len())
+}
+
+fn order_parser() -> impl TypedValueParser<Value = ReadOrder> {
+    PossibleValuesParser::new(ReadOrder::ALL.map(ReadOrder::name))
+        .map(|s| ReadOrder::ALL.into_iter().find(|o| o.name() == s).unwrap())
+}
+
+fn is_up_to_date(out_path: &Path, size: u32) -> bool {
+    fs::metadata(out_path).is_ok_and(|m| m.is_file() && m.len() == u64::from(size))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs::{self, File},
+        hint::black_box,
+        io::{BufReader, BufWriter, Cursor, Write},
+        time::Instant,
+    };
+
+    use ardain::{
+        consistency::Rng, file_alloc::CompressionStrategy, mem::MemArchive, opts::ArhOptions,
+        path::ArhPath, ArdReader, FileMeta, RawTables,
+    };
+    use rayon::prelude::*;
+
+    use super::SharedArd;
+
+    #[test]
+    fn extract_parent_component() {
+        // New files can't have ".." components, so rename the directory in the string table
+        let mut archive = MemArchive::new(ArhOptions::default());
+        let path = ArhPath::normalize("/zz/x").unwrap();
+        archive
+            .write_file(&path, b"data", CompressionStrategy::None)
+            .unwrap();
+        let (arh, ard) = archive.into_bytes().unwrap();
+        let mut tables = RawTables::read(Cursor::new(arh)).unwrap();
+        let pos = tables
+            .strings
+            .windows(4)
+            .position(|w| w == b"zz/x")
+            .unwrap();
+        tables.strings[pos..pos + 2].copy_from_slice(b"..");
+        let mut arh = Cursor::new(Vec::new());
+        tables.write(&mut arh).unwrap();
+        let archive = MemArchive::load(arh.get_ref(), ard).unwrap();
+        let bad = ArhPath::normalize("/../x").unwrap();
+        assert!(archive.fs().is_file(&bad));
+
+        let dir = std::env::temp_dir().join(format!("ard-tools-extract-{}", std::process::id()));
+        let out = dir.join("out");
+        let res = super::extract_to(
+            archive.fs(),
+            &mut archive.reader(),
+            &ArhPath::default(),
+            &out,
+        );
+        assert!(res.is_err());
+        assert!(!dir.join("x").exists());
+
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    /// Compares reading every entry in parallel through one shared handle with positional
+    /// reads to opening a handle for each rayon job, as extraction did before. Run with
+    /// `cargo test --release -p ard-tools positional_read_bench -- --ignored --nocapture`.
+    #[test]
+    #[ignore]
+    fn positional_read_bench() {
+        // 200k raw entries of 64 B to 16 KiB, about 1.6 GiB
+        let path =
+            std::env::temp_dir().join(format!("ard-tools-read-bench-{}.ard", std::process::id()));
+        let mut rng = Rng::new(2366);
+        let mut metas = Vec::new();
+        let mut out = BufWriter::new(File::create(&path).unwrap());
+        let mut offset = 0;
+        for _ in 0..200_000 {
+            let len = 64 + rng.below(16 * 1024 - 64) as u32;
+            out.write_all(&vec![offset as u8; len as usize]).unwrap();
+            metas.push(FileMeta::with_extent(offset, len, 0, []).unwrap());
+            offset += u64::from(len);
+        }
+        out.into_inner().unwrap().sync_all().unwrap();
+
+        let per_job = || {
+            metas
+                .par_iter()
+                .map_init(
+                    || ArdReader::new(BufReader::new(File::open(&path).unwrap())),
+                    |ard, meta| ard.entry(meta).read().unwrap().len(),
+                )
+                .sum::<usize>()
+        };
+        let ard = SharedArd::open(File::open(&path).unwrap()).unwrap();
+        assert!(matches!(ard, SharedArd::Positional(_)));
+        let shared = || {
+            metas
+                .par_iter()
+                .map(|meta| ard.read_once(meta).unwrap().len())
+                .sum::<usize>()
+        };
+        assert_eq!(per_job(), shared());
+
+        let time = |read: &dyn Fn() -> usize| {
+            let start = Instant::now();
+            for _ in 0..10 {
+                black_box(read());
+            }
+            start.elapsed() / 10
+        };
+        println!(
+            "{} entries, {} threads: handle per job {:?}, shared handle {:?}",
+            metas.len(),
+            rayon::current_num_threads(),
+            time(&per_job),
+            time(&shared)
+        );
+
+        let _ = fs::remove_file(path);
+    }
+}
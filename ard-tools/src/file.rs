@@ -0,0 +1,106 @@
+use std::{cmp::Reverse, collections::BTreeMap};
+
+use anyhow::{anyhow, Result};
+use ardain::path::{ArhPath, RelativeArhPath};
+use clap::Args;
+use log::info;
+use rayon::prelude::*;
+
+use crate::InputData;
+
+#[derive(Args)]
+pub struct FileArgs {
+    /// The files or directories to identify (default: /)
+    #[arg(add = crate::completions::arh_path_completer())]
+    paths: Vec<RelativeArhPath>,
+    /// Print the number of files for each detected type instead of listing files
+    #[arg(short, long)]
+    summary: bool,
+    /// Also identify hidden (soft-deleted) files
+    #[arg(short, long)]
+    all: bool,
+}
+
+/// Known file types, identified by their magic bytes at the start of the file.
+const MAGICS: &[(&[u8], &str)] = &[
+    (b"xbc1", "XBC1 compressed container"),
+    (b"BDAT", "BDAT table"),
+    (b"DMXM", "MXMD model (.wimdo)"),
+    (b"DRSM", "MSRD stream container (.wismt)"),
+    (b"DMSM", "MSMD map (.wismhd)"),
+    (b"1RAS", "SAR1 archive"),
+    (b"HCPS", "SPCH shader archive"),
+    (b"LAHD", "DHAL layout (.wilay)"),
+    (b"PGAL", "LAGP layout (.wilay)"),
+    (b"LTPC", "LTPC texture archive"),
+    (b"DDS ", "DDS texture"),
+    (b"\x89PNG", "PNG image"),
+    (b"RIFF", "RIFF audio (.wem)"),
+    (b"OggS", "Ogg audio"),
+    (b"BKHD", "Wwise sound bank"),
+];
+
+pub fn run(input: &InputData, args: FileArgs) -> Result<()> {
+    let fs = input.load_fs()?;
+    let paths = if args.paths.is_empty() {
+        vec![ArhPath::default()]
+    } else {
+        input.resolve_all(&args.paths)?
+    };
+    let files = crate::expand_paths(&fs, &paths, args.all)?;
+    input.require_ard(&files)?;
+    info!("Identifying {} files", files.len());
+
+    let types = files
+        .par_iter()
+        .map_init(
+            || input.load_ard(),
+            |ard, path| -> Result<(&ArhPath, &'static str)> {
+                let ard = ard.as_mut().map_err(|e| anyhow!("{e}"))?;
+                let meta = fs.get_file_info(path).unwrap();
+                let data = ard.entry(meta).read()?;
+                Ok((path, identify(&data)))
+            },
+        )
+        .collect::<Result<Vec<_>>>()?;
+
+    if args.summary {
+        let mut histogram = BTreeMap::<&str, usize>::new();
+        for (_, ty) in &types {
+            *histogram.entry(ty).or_default() += 1;
+        }
+        let mut histogram = histogram.into_iter().collect::<Vec<_>>();
+        histogram.sort_by_key(|&(_, count)| Reverse(count));
+        let width = histogram.iter().map(|(ty, _)| ty.len()).max().unwrap_or(0);
+        for (ty, count) in histogram {
+            println!("{ty:<width$}  {count}");
+        }
+        return Ok(());
+    }
+
+    for (path, ty) in types {
+        println!("{path}: {ty}");
+    }
+    Ok(())
+}
+
+/// Returns a description of the type of data, based on its magic bytes.
+pub(crate) fn identify(data: &[u8]) -> &'static str {
+    if data.is_empty() {
+        return "empty";
+    }
+    if let Some((_, ty)) = MAGICS.iter().find(|(magic, _)| data.starts_with(magic)) {
+        return ty;
+    }
+    // Switch textures have their header at the end of the file
+    if data.ends_with(b"LBIM") {
+        return "MIBL texture";
+    }
+    if data
+        .iter()
+        .all(|b| b.is_ascii() && (!b.is_ascii_control() || b.is_ascii_whitespace()))
+    {
+        return "ASCII text";
+    }
+    "data"
+}
@@ -0,0 +1,289 @@
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Result};
+use ardain::{
+    path::{ArhPath, RelativeArhPath, ARH_PATH_MAX_LEN},
+    ArhFileSystem,
+};
+use clap::Args;
+use log::info;
+use rayon::prelude::*;
+use xxhash_rust::xxh64::xxh64;
+
+use crate::{
+    hash::{Digest, Hasher},
+    manifest::read_manifest,
+    output::{Cell, Style, Table},
+    printf::Template,
+    InputData,
+};
+
+#[derive(Args)]
+pub struct FindArgs {
+    /// The files or directories to search (default: /)
+    #[arg(add = crate::completions::arh_path_completer())]
+    paths: Vec<RelativeArhPath>,
+    /// The hash of the contents to look for (after decompression): SHA-256 (64 hex digits),
+    /// XXH64 (16 hex digits), or <ALGORITHM>:<HEX> as written by `manifest export`
+    #[arg(
+        long = "hash",
+        value_name = "HASH",
+        value_parser = parse_hash,
+        required_unless_present_any = ["files", "too_long"]
+    )]
+    hashes: Vec<Hash>,
+    /// Look for files with the same contents as this local file, e.g. from an old dump
+    #[arg(long = "file", value_name = "FILE")]
+    files: Vec<PathBuf>,
+    /// A manifest written by `manifest export`. Its hashes are used for files that haven't
+    /// changed since, instead of reading them.
+    #[arg(long, value_name = "FILE")]
+    manifest: Option<PathBuf>,
+    /// Also search hidden (soft-deleted) files
+    #[arg(short, long)]
+    all: bool,
+    /// Instead of comparing contents, list files whose path is within MARGIN characters of
+    /// the 256-character limit, e.g. before adding files with long names next to them
+    #[arg(
+        long,
+        value_name = "MARGIN",
+        num_args = 0..=1,
+        default_missing_value = "16",
+        conflicts_with_all = ["hashes", "files", "manifest"]
+    )]
+    too_long: Option<usize>,
+    /// Print each matching file with a format string instead of a table, see `ls --printf`
+    #[arg(long, value_name = "FORMAT", value_parser = Template::parse)]
+    printf: Option<Template>,
+}
+
+#[derive(Clone, PartialEq, Eq)]
+enum Hash {
+    Digest(Digest),
+    Xxh64(u64),
+}
+
+/// Something to look for, with the name it is reported under.
+struct Target {
+    name: String,
+    hash: Hash,
+    /// The size of the contents, if known. Only files with this size are read.
+    size: Option<u32>,
+}
+
+pub fn run(input: &InputData, args: FindArgs) -> Result<()> {
+    let fs = input.load_fs()?;
+    let paths = if args.paths.is_empty() {
+        vec![ArhPath::default()]
+    } else {
+        input.resolve_all(&args.paths)?
+    };
+    if let Some(margin) = args.too_long {
+        return find_too_long(&fs, &paths, margin, &args);
+    }
+    let known = match &args.manifest {
+        Some(manifest) => known_hashes(manifest, &fs)?,
+        None => BTreeMap::new(),
+    };
+    let mut targets: Vec<_> = args
+        .hashes
+        .iter()
+        .map(|hash| Target {
+            name: hash.to_string(),
+            hash: hash.clone(),
+            size: None,
+        })
+        .collect();
+    // Hash local files like the manifest, so its hashes can be used
+    let file_hasher = known
+        .values()
+        .next()
+        .map_or_else(Hasher::default, Digest::hasher);
+    for file in &args.files {
+        let data = fs::read(file).map_err(|e| anyhow!("{}: {e}", file.display()))?;
+        targets.push(Target {
+            name: file.display().to_string(),
+            hash: Hash::Digest(file_hasher.digest(&data)),
+            size: Some(data.len().try_into()?),
+        });
+    }
+
+    // Files whose size doesn't match can be skipped, unless some hash has an unknown size
+    let sizes: Option<Vec<u32>> = targets.iter().map(|t| t.size).collect();
+    let candidates: Vec<_> = crate::expand_paths(&fs, &paths, args.all)?
+        .into_iter()
+        .filter(|path| {
+            let size = fs.get_file_info(path).unwrap().actual_size();
+            sizes.as_ref().is_none_or(|sizes| sizes.contains(&size))
+        })
+        .collect();
+
+    let mut hashers: Vec<_> = targets
+        .iter()
+        .filter_map(|t| match &t.hash {
+            Hash::Digest(digest) => Some(digest.hasher()),
+            Hash::Xxh64(_) => None,
+        })
+        .collect();
+    hashers.sort();
+    hashers.dedup();
+    let need_xxh64 = targets.iter().any(|t| matches!(t.hash, Hash::Xxh64(_)));
+    let (from_manifest, to_read): (Vec<_>, Vec<_>) = candidates.iter().partition(|path| {
+        !need_xxh64
+            && known
+                .get(*path)
+                .is_some_and(|hash| hashers == [hash.hasher()])
+    });
+    input.require_ard(to_read.iter().copied())?;
+    info!(
+        "Hashing {} files ({} hashes taken from the manifest)",
+        to_read.len(),
+        from_manifest.len()
+    );
+
+    let read = to_read
+        .par_iter()
+        .map_init(
+            || input.load_ard(),
+            |ard, &path| -> Result<(&ArhPath, Vec<Hash>)> {
+                let ard = ard.as_mut().map_err(|e| anyhow!("{e}"))?;
+                let data = ard.entry(fs.get_file_info(path).unwrap()).read()?;
+                let mut hashes: Vec<_> = hashers
+                    .iter()
+                    .map(|hasher| Hash::Digest(hasher.digest(&data)))
+                    .collect();
+                if need_xxh64 {
+                    hashes.push(Hash::Xxh64(xxh64(&data, 0)));
+                }
+                Ok((path, hashes))
+            },
+        )
+        .collect::<Result<Vec<_>>>()?;
+    let hashed = from_manifest
+        .into_iter()
+        .map(|path| (path, vec![Hash::Digest(known[path].clone())]))
+        .chain(read);
+
+    let mut matches: BTreeMap<usize, Vec<&ArhPath>> = BTreeMap::new();
+    for (path, hashes) in hashed {
+        let size = fs.get_file_info(path).unwrap().actual_size();
+        for (i, target) in targets.iter().enumerate() {
+            if target.size.is_none_or(|s| s == size) && hashes.contains(&target.hash) {
+                matches.entry(i).or_default().push(path);
+            }
+        }
+    }
+    if let Some(template) = &args.printf {
+        for paths in matches.values_mut() {
+            paths.sort();
+            for path in paths {
+                print!("{}", template.render(path, fs.get_file_info(path)));
+            }
+        }
+        return Ok(());
+    }
+    let mut table = Table::new(&["Target", "File"]);
+    for (i, target) in targets.iter().enumerate() {
+        match matches.get_mut(&i) {
+            Some(paths) => {
+                paths.sort();
+                for path in paths {
+                    table.push_row([
+                        target.name.as_str().into(),
+                        Cell::new(path.as_str(), Style::Good),
+                    ]);
+                }
+            }
+            None => table.push_row([
+                target.name.as_str().into(),
+                Cell::new("no match", Style::Bad),
+            ]),
+        }
+    }
+    table.print();
+    Ok(())
+}
+
+/// Lists the files that have at most `margin` characters left before the path limit, longest
+/// first.
+fn find_too_long(
+    fs: &ArhFileSystem,
+    paths: &[ArhPath],
+    margin: usize,
+    args: &FindArgs,
+) -> Result<()> {
+    let mut files: Vec<_> = crate::expand_paths(fs, paths, args.all)?
+        .into_iter()
+        .filter(|path| path.remaining_len() <= margin)
+        .collect();
+    files.sort_by(|a, b| b.len().cmp(&a.len()).then_with(|| a.cmp(b)));
+    if let Some(template) = &args.printf {
+        for path in &files {
+            print!("{}", template.render(path, fs.get_file_info(path)));
+        }
+        return Ok(());
+    }
+    let mut table = Table::new(&["File", "Length", "Left"])
+        .align_right([1, 2])
+        .shrink(0);
+    for path in &files {
+        let style = if path.remaining_len() == 0 {
+            Style::Bad
+        } else {
+            Style::Plain
+        };
+        table.push_row([
+            Cell::new(path.as_str(), style),
+            path.len().to_string().into(),
+            path.remaining_len().to_string().into(),
+        ]);
+    }
+    table.print();
+    println!(
+        "\n{} files within {margin} characters of the {ARH_PATH_MAX_LEN}-character limit",
+        files.len()
+    );
+    Ok(())
+}
+
+/// Reads the hashes of a manifest, keeping only files whose metadata still matches.
+fn known_hashes(manifest: &Path, fs: &ArhFileSystem) -> Result<BTreeMap<ArhPath, Digest>> {
+    let mut known = BTreeMap::new();
+    for entry in read_manifest(manifest, None)? {
+        let Some(hash) = entry.hash else {
+            continue;
+        };
+        let path = ArhPath::normalize(&entry.path)?;
+        let unchanged = fs.get_file_info(&path).is_some_and(|meta| {
+            meta.id == entry.id
+                && meta.offset == entry.offset
+                && meta.compressed_size == entry.compressed_size
+                && meta.uncompressed_size == entry.uncompressed_size
+        });
+        if unchanged {
+            known.insert(path, hash);
+        }
+    }
+    Ok(known)
+}
+
+fn parse_hash(s: &str) -> Result<Hash> {
+    let hex = s.trim();
+    if hex.len() == 16 && hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Ok(Hash::Xxh64(u64::from_str_radix(hex, 16)?));
+    }
+    Digest::parse(hex).map(Hash::Digest)
+}
+
+impl std::fmt::Display for Hash {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Hash::Digest(digest) => write!(f, "{digest}"),
+            Hash::Xxh64(hash) => write!(f, "{hash:016x}"),
+        }
+    }
+}
@@ -0,0 +1,244 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs::{self, File},
+    io::{BufWriter, Write},
+    path::PathBuf,
+};
+
+use anyhow::{anyhow, bail, Result};
+use ardain::{
+    path::{ArhPath, PathFilter, PathPattern, RelativeArhPath},
+    ArhFileSystem, FileFlag,
+};
+use clap::{
+    builder::{PossibleValuesParser, TypedValueParser},
+    Args, Subcommand, ValueEnum,
+};
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::{errors::ErrorKind, InputData};
+
+#[derive(Args)]
+pub struct FlagsArgs {
+    #[command(subcommand)]
+    command: FlagsCommand,
+}
+
+#[derive(Subcommand)]
+enum FlagsCommand {
+    /// Write the flags of files to a JSON file
+    Export {
+        /// Where to write the flags
+        out: PathBuf,
+        /// Only export files in these directories (default: /)
+        #[arg(add = crate::completions::arh_path_completer())]
+        paths: Vec<RelativeArhPath>,
+        /// The flags to export (default: all). Importing the file only changes these flags.
+        #[arg(long = "flag", value_enum, value_delimiter = ',')]
+        flags: Vec<FlagName>,
+        /// Also export files that have none of the flags, so importing clears them
+        #[arg(long)]
+        all_files: bool,
+        #[command(flatten)]
+        filter: FilterArgs,
+    },
+    /// Set flags on files, or on the files in directories (recursively)
+    Set(ChangeArgs),
+    /// Clear flags on files, or on the files in directories (recursively)
+    Clear(ChangeArgs),
+    /// Set the flags of files from a file written by `flags export`
+    Import {
+        /// The exported flags
+        file: PathBuf,
+        /// Fail if a listed file doesn't exist in the archive, instead of skipping it
+        #[arg(long)]
+        strict: bool,
+    },
+}
+
+#[derive(Args)]
+struct ChangeArgs {
+    /// The flags to change
+    #[arg(value_parser = changeable_flag_parser(), value_delimiter = ',')]
+    flags: Vec<FlagName>,
+    /// The files or directories to change
+    #[arg(add = crate::completions::arh_path_completer(), required = true)]
+    paths: Vec<RelativeArhPath>,
+    #[command(flatten)]
+    filter: FilterArgs,
+}
+
+/// Options to select some of the files in a directory.
+#[derive(Args)]
+pub(crate) struct FilterArgs {
+    /// Only include files that match one of these patterns. `*` and `?` match within a
+    /// name, `**` matches any number of directories, and a pattern without slashes
+    /// matches names in any directory (e.g. `*.bdat`).
+    #[arg(long, value_name = "GLOB")]
+    include: Vec<PathPattern>,
+    /// Leave out files that match one of these patterns, even if they are included
+    #[arg(long, value_name = "GLOB")]
+    exclude: Vec<PathPattern>,
+}
+
+/// A flag as named on the command line and in exported files.
+#[derive(ValueEnum, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum FlagName {
+    Hidden,
+    Xbc1,
+}
+
+/// The format of `flags export`.
+#[derive(Serialize, Deserialize)]
+struct FlagsFile {
+    /// The flags this file is about. On import, they are set or cleared for each listed
+    /// file, and other flags are left unchanged.
+    flags: BTreeSet<FlagName>,
+    /// The flags that are set for each file
+    files: BTreeMap<String, BTreeSet<FlagName>>,
+}
+
+pub fn run(input: &InputData, args: FlagsArgs) -> Result<()> {
+    let mut fs = input.load_fs()?;
+    match args.command {
+        FlagsCommand::Export {
+            out,
+            paths,
+            flags,
+            all_files,
+            filter,
+        } => {
+            let filter = filter.to_filter();
+            let paths = if paths.is_empty() {
+                vec![ArhPath::default()]
+            } else {
+                input.resolve_all(&paths)?
+            };
+            let flags: BTreeSet<_> = if flags.is_empty() {
+                FlagName::value_variants().iter().copied().collect()
+            } else {
+                flags.into_iter().collect()
+            };
+            let mut files = BTreeMap::new();
+            for path in crate::expand_paths(&fs, &paths, true)? {
+                if !filter.matches(&path) {
+                    continue;
+                }
+                let meta = fs.get_file_info(&path).unwrap();
+                let set: BTreeSet<_> = flags
+                    .iter()
+                    .copied()
+                    .filter(|flag| meta.is_flag(flag.file_flag()))
+                    .collect();
+                if all_files || !set.is_empty() {
+                    files.insert(path.to_string(), set);
+                }
+            }
+            let count = files.len();
+            let mut writer = BufWriter::new(File::create(&out)?);
+            serde_json::to_writer_pretty(&mut writer, &FlagsFile { flags, files })?;
+            writeln!(writer)?;
+            writer.flush()?;
+            println!("Exported the flags of {count} files to {}", out.display());
+        }
+        FlagsCommand::Set(args) => change(input, &mut fs, args, true)?,
+        FlagsCommand::Clear(args) => change(input, &mut fs, args, false)?,
+        FlagsCommand::Import { file, strict } => {
+            let text = fs::read_to_string(&file).map_err(|e| anyhow!("{}: {e}", file.display()))?;
+            let import: FlagsFile =
+                serde_json::from_str(&text).map_err(|e| anyhow!("{}: {e}", file.display()))?;
+            // Exports include the XBC1 flag by default, but it follows the stored data
+            let (changeable, fixed): (Vec<_>, Vec<_>) = import
+                .flags
+                .iter()
+                .copied()
+                .partition(|f| FlagName::CHANGEABLE.contains(f));
+            if !fixed.is_empty() {
+                warn!("Ignoring flags that can't be changed: {fixed:?}");
+            }
+            let (mut changed, mut missing) = (0, 0);
+            for (path, set) in &import.files {
+                if let Some(flag) = set.iter().find(|f| !import.flags.contains(f)) {
+                    bail!("{path}: flag {flag:?} is not in the list of exported flags");
+                }
+                let path = ArhPath::normalize(path)?;
+                if !fs.is_file(&path) {
+                    if strict {
+                        return Err(ErrorKind::NotFound.error(format!("{path}: no such file")));
+                    }
+                    warn!("{path}: no such file, skipping");
+                    missing += 1;
+                    continue;
+                }
+                let meta = fs.get_file_info_mut(&path)?;
+                let old = meta.raw_flags();
+                for &flag in &changeable {
+                    meta.set_flag(flag.file_flag(), set.contains(&flag));
+                }
+                if meta.raw_flags() != old {
+                    debug!("{path}: flags changed");
+                    changed += 1;
+                }
+            }
+            input.write_fs(&mut fs)?;
+            info!(
+                "{} files already had the imported flags",
+                import.files.len() - changed - missing
+            );
+            println!("Changed the flags of {changed} files");
+            if missing != 0 {
+                println!("Skipped {missing} files that are not in the archive");
+            }
+        }
+    }
+    Ok(())
+}
+
+fn change(input: &InputData, fs: &mut ArhFileSystem, args: ChangeArgs, value: bool) -> Result<()> {
+    let filter = args.filter.to_filter();
+    let mut changed = 0;
+    for path in &input.resolve_all(&args.paths)? {
+        if !fs.exists(path) {
+            return Err(ErrorKind::NotFound.error(format!("{path}: no such file or directory")));
+        }
+        for flag in &args.flags {
+            changed += fs.set_flag_matching(path, &filter, flag.file_flag(), value)?;
+        }
+    }
+    input.write_fs(fs)?;
+    println!("Changed {changed} flags");
+    Ok(())
+}
+
+impl FilterArgs {
+    pub(crate) fn is_empty(&self) -> bool {
+        self.include.is_empty() && self.exclude.is_empty()
+    }
+
+    pub(crate) fn to_filter(&self) -> PathFilter {
+        PathFilter {
+            include: self.include.clone(),
+            exclude: self.exclude.clone(),
+        }
+    }
+}
+
+impl FlagName {
+    /// The flags that can be set or cleared. The others describe how the data is stored, so
+    /// changing them would make the file unreadable.
+    const CHANGEABLE: [FlagName; 1] = [FlagName::Hidden];
+
+    pub(crate) fn file_flag(self) -> FileFlag {
+        match self {
+            FlagName::Hidden => FileFlag::Hidden,
+            FlagName::Xbc1 => FileFlag::HasXbc1Header,
+        }
+    }
+}
+
+fn changeable_flag_parser() -> impl TypedValueParser<Value = FlagName> {
+    PossibleValuesParser::new(FlagName::CHANGEABLE.map(|f| f.to_possible_value().unwrap()))
+        .map(|s| FlagName::from_str(&s, false).unwrap())
+}
@@ -0,0 +1,44 @@
+use anyhow::Result;
+use clap::{ArgGroup, Args};
+
+use crate::{ls::format_size, InputData};
+
+#[derive(Args)]
+#[command(group(ArgGroup::new("tables").required(true).multiple(true)))]
+pub struct GcArgs {
+    /// Remove file table entries that no path points to, and give the remaining files
+    /// consecutive IDs. File IDs change, so manifests and snapshots no longer match.
+    #[arg(long, group = "tables")]
+    file_table: bool,
+    /// Regenerate the path dictionary and string table, like `rebuild`
+    #[arg(long, group = "tables")]
+    dictionary: bool,
+    /// Print sizes in a human-readable format
+    #[arg(short = 'H', long)]
+    human_readable: bool,
+}
+
+pub fn run(input: &InputData, args: GcArgs) -> Result<()> {
+    let mut fs = input.load_fs()?;
+    if args.file_table {
+        let compaction = fs.compact_file_table()?;
+        println!(
+            "File table: {} -> {} entries ({} files renumbered)",
+            compaction.old_len, compaction.new_len, compaction.renumbered
+        );
+    }
+    if args.dictionary {
+        let rebuild = fs.rebuild_dictionary()?;
+        println!(
+            "Dictionary nodes: {} -> {}",
+            rebuild.old_node_count, rebuild.new_node_count
+        );
+        println!(
+            "String table: {} -> {}",
+            format_size(rebuild.old_string_table_len as u64, args.human_readable),
+            format_size(rebuild.new_string_table_len as u64, args.human_readable)
+        );
+    }
+    input.write_fs(&mut fs)?;
+    Ok(())
+}
@@ -0,0 +1,116 @@
+use anyhow::{anyhow, bail, Context, Result};
+use ardain::path::{ArhPath, RelativeArhPath};
+use clap::Args;
+use log::info;
+use rayon::prelude::*;
+
+use crate::InputData;
+
+#[derive(Args)]
+pub struct GrepArgs {
+    /// The pattern to search for. Interpreted as text, unless --hex is present
+    pattern: String,
+    /// The files or directories to search in (default: /)
+    #[arg(add = crate::completions::arh_path_completer())]
+    paths: Vec<RelativeArhPath>,
+    /// Interpret the pattern as a sequence of hex bytes (e.g. "78 62 63 31" or "78626331")
+    #[arg(short = 'x', long)]
+    hex: bool,
+    /// Ignore ASCII case when matching text patterns
+    #[arg(short, long, conflicts_with = "hex")]
+    ignore_case: bool,
+    /// Only print the names of files that contain a match
+    #[arg(short = 'l', long)]
+    files_with_matches: bool,
+    /// Also search hidden (soft-deleted) files
+    #[arg(short, long)]
+    all: bool,
+}
+
+pub fn run(input: &InputData, args: GrepArgs) -> Result<()> {
+    let fs = input.load_fs()?;
+    let pattern = if args.hex {
+        parse_hex(&args.pattern)
+            .with_context(|| format!("invalid hex pattern: {}", args.pattern))?
+    } else if args.ignore_case {
+        args.pattern.to_ascii_lowercase().into_bytes()
+    } else {
+        args.pattern.clone().into_bytes()
+    };
+    if pattern.is_empty() {
+        return Err(anyhow!("empty pattern"));
+    }
+    let paths = if args.paths.is_empty() {
+        vec![ArhPath::default()]
+    } else {
+        input.resolve_all(&args.paths)?
+    };
+    let files = crate::expand_paths(&fs, &paths, args.all)?;
+    input.require_ard(&files)?;
+    info!("Searching {} files", files.len());
+
+    // Each thread gets its own handle to the ARD file
+    let results = files
+        .par_iter()
+        .map_init(
+            || input.load_ard(),
+            |ard, path| -> Result<(&ArhPath, Vec<usize>)> {
+                let ard = ard.as_mut().map_err(|e| anyhow!("{e}"))?;
+                let meta = fs.get_file_info(path).unwrap();
+                let mut data = ard.entry(meta).read()?;
+                if args.ignore_case {
+                    data.make_ascii_lowercase();
+                }
+                Ok((path, find_all(&data, &pattern, args.files_with_matches)))
+            },
+        )
+        .collect::<Result<Vec<_>>>()?;
+
+    for (path, matches) in results {
+        if args.files_with_matches {
+            if !matches.is_empty() {
+                println!("{path}");
+            }
+            continue;
+        }
+        for offset in matches {
+            println!("{path}:0x{offset:X}");
+        }
+    }
+    Ok(())
+}
+
+/// Returns the offsets of all occurrences of `pattern` in `data`, or just the first one if
+/// `first_only` is set.
+fn find_all(data: &[u8], pattern: &[u8], first_only: bool) -> Vec<usize> {
+    let matches = data
+        .windows(pattern.len())
+        .enumerate()
+        .filter(|(_, w)| *w == pattern)
+        .map(|(i, _)| i);
+    if first_only {
+        matches.take(1).collect()
+    } else {
+        matches.collect()
+    }
+}
+
+/// Parses a sequence of hex bytes, ignoring whitespace (e.g. "78 62 63 31" or "78626331").
+pub(crate) fn parse_hex(hex: &str) -> Result<Vec<u8>> {
+    let digits = hex
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .collect::<String>();
+    if !digits.is_ascii() {
+        bail!("not a hex string");
+    }
+    if digits.len() % 2 != 0 {
+        bail!("odd number of hex digits");
+    }
+    (0..digits.len())
+        .step_by(2)
+        .map(|i| {
+            u8::from_str_radix(&digits[i..i + 2], 16).map_err(|_| anyhow!("invalid hex at {i}"))
+        })
+        .collect()
+}
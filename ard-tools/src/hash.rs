@@ -0,0 +1,151 @@
+//! Hashes of file contents, shared by the commands that compare them, see `--hash`.
+//!
+//! Archives don't store hashes of their files (the ARHX section has no checksum table), so
+//! they are kept in manifests, which record the algorithm next to each hash.
+
+use std::fmt::{self, Display};
+
+use anyhow::{anyhow, bail, Result};
+use clap::ValueEnum;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use sha2::{Digest as _, Sha256};
+use xxhash_rust::xxh3::xxh3_64;
+
+/// How file contents are hashed
+#[derive(ValueEnum, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug, Default)]
+pub enum Hasher {
+    /// XXH3 (64 bits): the fastest, to find changes or duplicates
+    #[default]
+    Xxh3,
+    /// CRC-32, the checksum of ZIP files
+    Crc32,
+    /// SHA-256: slower, but files that were tampered with can't keep the same hash
+    Sha256,
+}
+
+/// The hash of some data, along with the algorithm it was computed with.
+///
+/// It is written as `<algorithm>:<hex digits>`, e.g. `crc32:1c291ca3`.
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub struct Digest {
+    hasher: Hasher,
+    bytes: Box<[u8]>,
+}
+
+impl Hasher {
+    pub fn digest(self, data: &[u8]) -> Digest {
+        let bytes = match self {
+            Hasher::Xxh3 => xxh3_64(data).to_be_bytes().into(),
+            Hasher::Crc32 => crc32(data).to_be_bytes().into(),
+            Hasher::Sha256 => Sha256::digest(data).to_vec().into(),
+        };
+        Digest {
+            hasher: self,
+            bytes,
+        }
+    }
+
+    /// Whether files with the same hash can be assumed to be identical. Otherwise, their
+    /// contents must be compared too.
+    pub fn is_collision_resistant(self) -> bool {
+        self == Hasher::Sha256
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Hasher::Xxh3 => "xxh3",
+            Hasher::Crc32 => "crc32",
+            Hasher::Sha256 => "sha256",
+        }
+    }
+
+    /// The size of a digest, in bytes
+    fn len(self) -> usize {
+        match self {
+            Hasher::Xxh3 => 8,
+            Hasher::Crc32 => 4,
+            Hasher::Sha256 => 32,
+        }
+    }
+}
+
+impl Digest {
+    pub fn hasher(&self) -> Hasher {
+        self.hasher
+    }
+
+    /// Parses a digest in the format it is displayed in. A bare SHA-256 hash (64 hex
+    /// digits) is accepted too, as written by `manifest export` before `--hash` existed.
+    pub fn parse(s: &str) -> Result<Self> {
+        let s = s.trim();
+        let (hasher, hex) = match s.split_once(':') {
+            Some((name, hex)) => {
+                let hasher = Hasher::from_str(name, true)
+                    .map_err(|_| anyhow!("invalid hash \"{s}\": unknown algorithm {name}"))?;
+                (hasher, hex)
+            }
+            None if s.len() == Hasher::Sha256.len() * 2 => (Hasher::Sha256, s),
+            None => bail!("invalid hash \"{s}\": expected <algorithm>:<hex digits>"),
+        };
+        if hex.len() != hasher.len() * 2 || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+            bail!(
+                "invalid hash \"{s}\": expected {} hex digits for {}",
+                hasher.len() * 2,
+                hasher.name()
+            );
+        }
+        let bytes = (0..hasher.len())
+            .map(|i| u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).unwrap())
+            .collect();
+        Ok(Self { hasher, bytes })
+    }
+}
+
+impl Display for Digest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:", self.hasher.name())?;
+        self.bytes.iter().try_for_each(|b| write!(f, "{b:02x}"))
+    }
+}
+
+impl Serialize for Digest {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Digest {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        Self::parse(&s).map_err(serde::de::Error::custom)
+    }
+}
+
+const CRC_TABLE: [u32; 256] = crc_table();
+
+const fn crc_table() -> [u32; 256] {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB88320
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+/// CRC-32 as used by ZIP files (and zlib)
+pub fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0, |crc, &b| {
+        CRC_TABLE[usize::from(crc as u8 ^ b)] ^ (crc >> 8)
+    })
+}
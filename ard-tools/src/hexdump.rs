@@ -0,0 +1,79 @@
+use std::io::{self, Write};
+
+use anyhow::{anyhow, bail, Result};
+use ardain::path::RelativeArhPath;
+use clap::Args;
+
+use crate::{errors::ErrorKind, InputData};
+
+#[derive(Args)]
+pub struct HexdumpArgs {
+    /// The file to dump
+    #[arg(add = crate::completions::arh_path_completer())]
+    path: RelativeArhPath,
+    /// Where to start, in bytes from the start of the decompressed file (e.g. 64 or 0x40)
+    #[arg(short = 's', long, value_name = "N", default_value = "0", value_parser = parse_number)]
+    offset: u64,
+    /// How many bytes to dump
+    #[arg(short = 'n', long, value_name = "M", default_value = "256", value_parser = parse_number)]
+    length: u64,
+    /// Dump the data as stored in the ARD file, e.g. to look at the XBC1 header of a
+    /// compressed file, instead of decompressing it
+    #[arg(long)]
+    raw: bool,
+}
+
+pub fn run(input: &InputData, args: HexdumpArgs) -> Result<()> {
+    let path = input.resolve(&args.path)?;
+    let fs = input.load_fs()?;
+    let mut meta = *fs
+        .get_file_info(&path)
+        .ok_or_else(|| ErrorKind::NotFound.error(format!("{path}: file not found")))?;
+    input.require_ard([&path])?;
+    if args.raw {
+        // Read the entry as if it was stored uncompressed
+        meta.uncompressed_size = 0;
+    }
+    let size = u64::from(meta.actual_size());
+    if args.offset > size {
+        bail!(
+            "offset {} is past the end of the file ({size} bytes)",
+            args.offset
+        );
+    }
+    let data = input
+        .load_ard()?
+        .entry(&meta)
+        .skip_take(args.offset, args.length)
+        .read()?;
+    let mut out = io::stdout().lock();
+    for line in hex_lines(&data, args.offset) {
+        writeln!(out, "{line}")?;
+    }
+    Ok(())
+}
+
+/// Formats data as lines of 16 bytes, with the offset of each line (starting from
+/// `offset`), the bytes in hex, and the printable ASCII characters.
+pub(crate) fn hex_lines(data: &[u8], offset: u64) -> impl Iterator<Item = String> + '_ {
+    data.chunks(16).enumerate().map(move |(i, chunk)| {
+        let hex: Vec<_> = chunk.iter().map(|b| format!("{b:02x}")).collect();
+        let ascii: String = chunk
+            .iter()
+            .map(|&b| if b.is_ascii_graphic() { b as char } else { '.' })
+            .collect();
+        format!(
+            "{:08x}  {:<47}  {ascii}",
+            offset + i as u64 * 16,
+            hex.join(" ")
+        )
+    })
+}
+
+fn parse_number(s: &str) -> Result<u64> {
+    let res = match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => s.parse(),
+    };
+    res.map_err(|e| anyhow!("invalid number \"{s}\": {e}"))
+}
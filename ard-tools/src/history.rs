@@ -0,0 +1,103 @@
+use anyhow::Result;
+use ardain::{
+    path::{ArhPath, RelativeArhPath},
+    OpLogEntry,
+};
+use clap::{ArgGroup, Args};
+
+use crate::{
+    output::{Cell, Style, Table},
+    InputData,
+};
+
+#[derive(Args)]
+#[command(group(ArgGroup::new("change").args(["enable", "disable", "clear"])))]
+pub struct HistoryArgs {
+    /// Only list operations on this file or directory (including its files), or that moved
+    /// something to it
+    #[arg(add = crate::completions::arh_path_completer())]
+    path: Option<RelativeArhPath>,
+    /// Start recording operations in the archive
+    #[arg(long)]
+    enable: bool,
+    /// Stop recording operations. The recorded history is kept.
+    #[arg(long)]
+    disable: bool,
+    /// Remove the recorded history
+    #[arg(long, conflicts_with = "path")]
+    clear: bool,
+}
+
+pub fn run(input: &InputData, args: HistoryArgs) -> Result<()> {
+    let path = args.path.as_ref().map(|p| input.resolve(p)).transpose()?;
+    let mut fs = input.load_fs()?;
+    if args.enable || args.disable {
+        fs.set_history_enabled(args.enable)?;
+        input.write_fs(&mut fs)?;
+        println!(
+            "History {}",
+            if args.enable { "enabled" } else { "disabled" }
+        );
+        return Ok(());
+    }
+    if args.clear {
+        let count = fs.history().len();
+        fs.clear_history()?;
+        input.write_fs(&mut fs)?;
+        println!("Removed {count} entries");
+        return Ok(());
+    }
+
+    let entries: Vec<_> = fs
+        .history()
+        .iter()
+        .filter(|e| path.as_ref().is_none_or(|path| touches(e, path)))
+        .collect();
+    let mut table = Table::new(&["Time", "Operation", "Path", "New path"]).shrink(2);
+    for entry in &entries {
+        table.push_row([
+            format_time(entry.timestamp).into(),
+            Cell::new(entry.kind.name(), Style::Flag),
+            entry.path.as_str().into(),
+            entry.new_path.as_deref().unwrap_or_default().into(),
+        ]);
+    }
+    table.print();
+    println!("\n{} entries", entries.len());
+    if !fs.is_history_enabled() {
+        println!("Recording is disabled, use --enable to start it");
+    }
+    Ok(())
+}
+
+/// Returns whether the operation applied to `path`, or to something inside it.
+fn touches(entry: &OpLogEntry, path: &ArhPath) -> bool {
+    // The root is "/", other directories don't end with a slash
+    let dir = path.trim_end_matches('/');
+    let within = |p: &str| {
+        p.strip_prefix(dir)
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+    };
+    within(&entry.path) || entry.new_path.as_deref().is_some_and(within)
+}
+
+/// Formats seconds since the Unix epoch as a UTC date and time.
+fn format_time(timestamp: u64) -> String {
+    let (days, secs) = (timestamp / 86400, timestamp % 86400);
+    // Days to a civil date, from Howard Hinnant's `civil_from_days`
+    let z = days + 719468;
+    let era = z / 146097;
+    let doe = z % 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+    format!(
+        "{year}-{month:02}-{day:02} {:02}:{:02}:{:02}",
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
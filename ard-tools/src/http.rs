@@ -0,0 +1,144 @@
+//! Read-only access to archives hosted on a web server, using HTTP range requests.
+
+use std::{
+    io::{self, Read, Seek, SeekFrom},
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::{anyhow, bail, Result};
+use log::{debug, info};
+use ureq::Agent;
+
+/// The smallest range fetched at once. ARD entries start with a small header, which is read
+/// before the data, so this saves a request for most small files.
+const MIN_FETCH: u64 = 64 * 1024;
+/// How long to wait for a connection, or for the server to respond to a request
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A remote .ard file, read with range requests as needed.
+///
+/// Clones share the connection pool, so each thread can read with its own clone.
+#[derive(Clone)]
+pub struct HttpArd {
+    agent: Agent,
+    url: String,
+    len: u64,
+    pos: u64,
+    /// The last fetched range, and its offset in the file
+    cached: Option<(u64, Arc<[u8]>)>,
+}
+
+impl HttpArd {
+    /// Fails if the server doesn't report the size of the file, or doesn't support range
+    /// requests.
+    pub fn open(url: &str) -> Result<Self> {
+        let agent = new_agent();
+        let res = agent.head(url).call().map_err(|e| anyhow!("{url}: {e}"))?;
+        let len = res
+            .headers()
+            .get("content-length")
+            .and_then(|v| v.to_str().ok()?.parse().ok())
+            .ok_or_else(|| anyhow!("{url}: the server didn't send the size of the file"))?;
+        let ranges = res
+            .headers()
+            .get("accept-ranges")
+            .and_then(|v| v.to_str().ok());
+        if ranges == Some("none") {
+            bail!("{url}: the server doesn't support range requests");
+        }
+        info!("Opened remote ARD {url} ({len} bytes)");
+        Ok(Self {
+            agent,
+            url: url.to_string(),
+            len,
+            pos: 0,
+            cached: None,
+        })
+    }
+
+    /// Fetches `len` bytes starting at `offset`.
+    fn fetch(&self, offset: u64, len: u64) -> io::Result<Vec<u8>> {
+        let end = offset + len - 1;
+        debug!("{}: fetching bytes {offset}-{end}", self.url);
+        let mut res = self
+            .agent
+            .get(&self.url)
+            .header("Range", format!("bytes={offset}-{end}"))
+            .call()
+            .map_err(into_io)?;
+        // A server that ignores the range sends the whole file
+        if res.status().as_u16() != 206 {
+            return Err(io::Error::other(format!(
+                "{}: expected a partial response, got status {}",
+                self.url,
+                res.status()
+            )));
+        }
+        let mut data = Vec::with_capacity(len as usize);
+        res.body_mut()
+            .as_reader()
+            .take(len)
+            .read_to_end(&mut data)?;
+        if data.len() as u64 != len {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        Ok(data)
+    }
+}
+
+impl Read for HttpArd {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() || self.pos >= self.len {
+            return Ok(0);
+        }
+        let hit = self
+            .cached
+            .as_ref()
+            .is_some_and(|(start, data)| (*start..*start + data.len() as u64).contains(&self.pos));
+        if !hit {
+            let len = (buf.len() as u64).max(MIN_FETCH).min(self.len - self.pos);
+            self.cached = Some((self.pos, self.fetch(self.pos, len)?.into()));
+        }
+        let (start, data) = self.cached.as_ref().unwrap();
+        let available = &data[(self.pos - start) as usize..];
+        let read = available.len().min(buf.len());
+        buf[..read].copy_from_slice(&available[..read]);
+        self.pos += read as u64;
+        Ok(read)
+    }
+}
+
+impl Seek for HttpArd {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.pos = crate::seek_position(self.pos, self.len, pos)?;
+        Ok(self.pos)
+    }
+}
+
+/// Downloads a whole remote file, e.g. the .arh file of a remote archive.
+pub fn download(url: &str) -> Result<Vec<u8>> {
+    info!("Downloading {url}");
+    let mut res = new_agent()
+        .get(url)
+        .call()
+        .map_err(|e| anyhow!("{url}: {e}"))?;
+    Ok(res.body_mut().with_config().limit(u64::MAX).read_to_vec()?)
+}
+
+fn new_agent() -> Agent {
+    Agent::config_builder()
+        .timeout_connect(Some(TIMEOUT))
+        .timeout_recv_response(Some(TIMEOUT))
+        .build()
+        .into()
+}
+
+/// Converts a request error, keeping the error kinds that extract retries on.
+fn into_io(error: ureq::Error) -> io::Error {
+    match error {
+        ureq::Error::Io(e) => e,
+        e @ ureq::Error::Timeout(_) => io::Error::new(io::ErrorKind::TimedOut, e),
+        e => io::Error::other(e),
+    }
+}
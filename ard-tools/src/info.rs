@@ -0,0 +1,82 @@
+use anyhow::Result;
+use ardain::path::ArhPath;
+use clap::Args;
+
+use crate::{ls::format_size, output::Table, InputData};
+
+#[derive(Args)]
+pub struct InfoArgs {
+    /// Print sizes in human-readable units (e.g. 1.5M)
+    #[arg(short = 'H', long)]
+    human_readable: bool,
+}
+
+pub fn run(input: &InputData, args: InfoArgs) -> Result<()> {
+    let fs = input.load_fs()?;
+    let size = |bytes| format_size(bytes, args.human_readable);
+    let percent = |part: u64, total: u64| {
+        if total == 0 {
+            "--".to_string()
+        } else {
+            format!("{:.1}%", part as f64 / total as f64 * 100.0)
+        }
+    };
+    let root = fs.get_dir(&ArhPath::default()).unwrap().stats;
+    let alloc = fs.alloc_stats();
+
+    let mut table = Table::new(&[]);
+    table.push_row(["Files".into(), root.file_count.to_string()]);
+    table.push_row(["Directories".into(), root.dir_count.to_string()]);
+    let usage = fs.file_table_usage();
+    table.push_row([
+        "File table".into(),
+        format!(
+            "{} entries: {} in use, {} recycled, {} unreferenced",
+            usage.entries,
+            usage.live,
+            usage.recycled.len(),
+            usage.unreferenced.len()
+        ),
+    ]);
+    table.push_row([
+        "Size".into(),
+        format!(
+            "{} extracted, {} in the archive",
+            size(root.uncompressed_size),
+            size(root.compressed_size)
+        ),
+    ]);
+    table.push_row(["Block size".into(), size(alloc.block_size.into())]);
+    table.push_row(["Occupied".into(), size(alloc.occupied_bytes)]);
+    table.push_row([
+        "Rounding waste".into(),
+        format!(
+            "{} ({} of occupied space)",
+            size(alloc.rounding_waste),
+            percent(alloc.rounding_waste, alloc.occupied_bytes)
+        ),
+    ]);
+    table.push_row([
+        "Free space".into(),
+        format!(
+            "{} in {} runs, largest {}",
+            size(alloc.free_bytes),
+            alloc.free_runs,
+            size(alloc.largest_free_run)
+        ),
+    ]);
+    table.push_row([
+        "Fragmentation".into(),
+        format!("{:.1}%", alloc.fragmentation() * 100.0),
+    ]);
+    let counts = alloc.counts;
+    table.push_row([
+        "Allocations".into(),
+        format!(
+            "{} in place, {} reused free space, {} appended",
+            counts.in_place, counts.reused, counts.appended
+        ),
+    ]);
+    table.print();
+    Ok(())
+}
@@ -0,0 +1,206 @@
+use std::io::Cursor;
+
+use anyhow::Result;
+use ardain::{find_nested_streams, path::RelativeArhPath};
+use binrw::BinRead;
+use clap::Args;
+use xc3_lib::{
+    dhal::Dhal,
+    lagp::Lagp,
+    mibl::Mibl,
+    msrd::{Msrd, StreamingInner},
+    sar1::Sar1,
+    xbc1::Xbc1,
+};
+
+use crate::{errors::ErrorKind, file::identify, ls::format_size, InputData};
+
+#[derive(Args)]
+pub struct InspectArgs {
+    /// The file to inspect
+    #[arg(add = crate::completions::arh_path_completer())]
+    path: RelativeArhPath,
+    /// How many levels of nested data (e.g. the XBC1 streams of a .wismt file, then their
+    /// contents) to inspect
+    #[arg(short, long, default_value_t = 2)]
+    depth: usize,
+    /// Print sizes in human-readable units (e.g. 1.5M)
+    #[arg(short = 'H', long)]
+    human_readable: bool,
+}
+
+/// What was found in a piece of data.
+#[derive(Default)]
+struct Summary {
+    details: Vec<String>,
+    /// Embedded data, with a description of where it was found
+    children: Vec<(String, Vec<u8>)>,
+}
+
+pub fn run(input: &InputData, args: InspectArgs) -> Result<()> {
+    let path = input.resolve(&args.path)?;
+    let fs = input.load_fs()?;
+    let meta = fs
+        .get_file_info(&path)
+        .ok_or_else(|| ErrorKind::NotFound.error(format!("{path}: file not found")))?;
+    input.require_ard([&path])?;
+    let data = input.load_ard()?.entry(meta).read()?;
+    println!("{path}:");
+    print_summary(&data, &args, 0);
+    Ok(())
+}
+
+/// Prints what is in `data`, and recursively in its children. `depth` is how many levels
+/// of nesting `data` is in.
+fn print_summary(data: &[u8], args: &InspectArgs, depth: usize) {
+    let indent = "  ".repeat(depth * 2 + 1);
+    println!(
+        "{indent}{}, {}",
+        identify(data),
+        format_size(data.len() as u64, args.human_readable)
+    );
+    let summary = summarize(data);
+    for detail in &summary.details {
+        println!("{indent}  {detail}");
+    }
+    if depth == args.depth {
+        if !summary.children.is_empty() {
+            println!("{indent}  (nested data not shown, see --depth)");
+        }
+        return;
+    }
+    for (name, child) in &summary.children {
+        println!("{indent}  {name}:");
+        print_summary(child, args, depth + 1);
+    }
+}
+
+/// Parses the known container formats, and lists the streams of the others.
+///
+/// Model streams (.wismt) and layouts (.wilay) are also scanned for XBC1 streams, which
+/// become the children. If their headers can't be parsed, the scan is all that is shown.
+fn summarize(data: &[u8]) -> Summary {
+    let mut summary = Summary::default();
+    let mut reader = Cursor::new(data);
+    if data.starts_with(b"xbc1") {
+        match Xbc1::read(&mut reader) {
+            Ok(xbc1) => {
+                summary.details.push(format!(
+                    "name \"{}\", {:?} compression",
+                    xbc1.name, xbc1.compression_type
+                ));
+                match xbc1.decompress() {
+                    Ok(contents) => summary.children.push(("contents".to_string(), contents)),
+                    Err(e) => summary.details.push(format!("can't decompress: {e}")),
+                }
+            }
+            Err(e) => summary.details.push(format!("can't parse: {e}")),
+        }
+    } else if data.starts_with(b"1RAS") {
+        match Sar1::read_le(&mut reader) {
+            Ok(sar1) => {
+                summary.details.push(format!(
+                    "archive \"{}\", {} entries",
+                    sar1.name,
+                    sar1.entries.len()
+                ));
+                summary.children.extend(
+                    sar1.entries
+                        .into_iter()
+                        .map(|entry| (format!("entry \"{}\"", entry.name), entry.entry_data)),
+                );
+            }
+            Err(e) => summary.details.push(format!("can't parse: {e}")),
+        }
+    } else if data.ends_with(b"LBIM") {
+        match Mibl::read_le(&mut reader) {
+            Ok(mibl) => {
+                let footer = &mibl.footer;
+                summary.details.push(format!(
+                    "{}x{}x{} {:?} {:?}, {} mipmaps",
+                    footer.width,
+                    footer.height,
+                    footer.depth,
+                    footer.view_dimension,
+                    footer.image_format,
+                    footer.mipmap_count
+                ));
+            }
+            Err(e) => summary.details.push(format!("can't parse: {e}")),
+        }
+    } else {
+        if data.starts_with(b"DRSM") {
+            match Msrd::read_le(&mut reader) {
+                Ok(msrd) => summary.details.extend(msrd_details(&msrd)),
+                Err(e) => summary
+                    .details
+                    .push(format!("can't parse model streams: {e}")),
+            }
+        } else if data.starts_with(b"LAHD") {
+            match Dhal::read_le(&mut reader) {
+                Ok(dhal) => summary.details.push(format!(
+                    "layout, {} textures, {} uncompressed textures",
+                    dhal.textures.map_or(0, |t| t.textures.len()),
+                    dhal.uncompressed_textures.map_or(0, |t| t.textures.len())
+                )),
+                Err(e) => summary.details.push(format!("can't parse layout: {e}")),
+            }
+        } else if data.starts_with(b"LAGP") {
+            match Lagp::read_le(&mut reader) {
+                Ok(lagp) => summary.details.push(format!(
+                    "layout, {} textures",
+                    lagp.textures.map_or(0, |t| t.textures.len())
+                )),
+                Err(e) => summary.details.push(format!("can't parse layout: {e}")),
+            }
+        }
+        // Containers like .wismt and .wilay files embed compressed streams
+        let streams = find_nested_streams(data);
+        if !streams.is_empty() {
+            let textures = streams
+                .iter()
+                .filter(|stream| stream.data.ends_with(b"LBIM"))
+                .count();
+            summary.details.push(format!(
+                "{} XBC1 streams, {textures} of which are textures",
+                streams.len()
+            ));
+        }
+        summary.children.extend(streams.into_iter().map(|stream| {
+            let name = if stream.name.is_empty() {
+                format!("stream at {:#x}", stream.offset)
+            } else {
+                format!("stream \"{}\" at {:#x}", stream.name, stream.offset)
+            };
+            (name, stream.data)
+        }));
+    }
+    summary
+}
+
+/// Describes the streams and textures listed in the header of a .wismt file.
+fn msrd_details(msrd: &Msrd) -> Vec<String> {
+    let (streams, textures) = match &msrd.streaming.inner {
+        StreamingInner::Streaming(data) => (
+            Some(data.streams.len()),
+            data.texture_resources
+                .low_textures
+                .as_ref()
+                .map(|t| &t.textures[..])
+                .unwrap_or_default(),
+        ),
+        StreamingInner::StreamingLegacy(data) => (None, &data.low_textures.textures[..]),
+    };
+    let mut details = vec![match streams {
+        Some(streams) => format!(
+            "model streams, {streams} streams, {} textures",
+            textures.len()
+        ),
+        None => format!("legacy model streams, {} textures", textures.len()),
+    }];
+    if !textures.is_empty() {
+        let names: Vec<_> = textures.iter().map(|t| t.name.as_str()).collect();
+        details.push(format!("textures: {}", names.join(", ")));
+    }
+    details
+}
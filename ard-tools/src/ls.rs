@@ -1,82 +1,317 @@
-use std::borrow::Cow;
+use std::{borrow::Cow, collections::VecDeque};
 
-use anyhow::{anyhow, Result};
-use ardain::{path::ArhPath, DirEntry, FileFlag, FileMeta};
-use clap::Args;
+use anyhow::Result;
+use ardain::{
+    path::{ArhPath, RelativeArhPath},
+    ArdReader, ArhFileSystem, CompressionType, DirEntry, DirNode, FileFlag, FileMeta,
+};
+use clap::{Args, ValueEnum};
 
-use crate::InputData;
+use crate::{
+    errors::ErrorKind,
+    flags::FlagName,
+    output::{self, Cell, Style, Table},
+    printf::Template,
+    ArdSource, InputData,
+};
 
 #[derive(Args)]
 pub struct ListArgs {
-    #[arg(value_parser = crate::parse_path)]
-    working_directory: Option<ArhPath>,
-    /// Only print file and directory names
+    #[arg(add = crate::completions::arh_path_completer())]
+    working_directory: Option<RelativeArhPath>,
+    /// Only print file and directory names. On a terminal, they are printed in columns.
     #[arg(short, long)]
     raw: bool,
+    /// List subdirectories recursively
+    #[arg(short = 'R', long)]
+    recursive: bool,
+    /// Sort entries by the given key
+    #[arg(long, value_enum, default_value_t = SortKey::Name)]
+    sort: SortKey,
+    /// Reverse the sort order
+    #[arg(long)]
+    reverse: bool,
+    /// Only list files that have all of the given flags
+    #[arg(long, value_enum, value_delimiter = ',')]
+    filter_flags: Vec<FlagName>,
+    /// Also list hidden (soft-deleted) files
+    #[arg(short, long)]
+    all: bool,
+    /// Print sizes in human-readable units (e.g. 1.5M)
+    #[arg(short = 'H', long)]
+    human_readable: bool,
+    /// Print additional columns: file ID, storage type, compressed and uncompressed sizes,
+    /// and compression ratio. If --ard is present, the storage type is read from the entry's
+    /// XBC1 header.
+    #[arg(short, long)]
+    long: bool,
+    /// Print each entry with a format string instead of a table: %p (path), %s (size),
+    /// %S (stored size), %o (ARD offset), %i (ID) and %f (flags). Add \n to end lines.
+    #[arg(
+        long,
+        value_name = "FORMAT",
+        value_parser = Template::parse,
+        conflicts_with_all = ["raw", "long"]
+    )]
+    printf: Option<Template>,
+}
+
+#[derive(ValueEnum, Clone, Copy, PartialEq, Eq)]
+enum SortKey {
+    Name,
+    Size,
+    Offset,
 }
 
-#[derive(Default)]
-struct Table<'a> {
-    rows: Vec<Vec<Cow<'a, str>>>,
-    lens: Vec<usize>,
+struct ListEntry<'a> {
+    node: &'a DirNode,
+    file: Option<&'a FileMeta>,
 }
 
 pub fn run(input: &InputData, args: ListArgs) -> Result<()> {
+    let wd = match &args.working_directory {
+        Some(path) => input.resolve(path)?,
+        None => ArhPath::default(),
+    };
     let fs = input.load_fs()?;
-    let wd = args.working_directory.unwrap_or_default();
-
+    let mut ard = match &input.in_ard {
+        Some(_) if args.long => Some(input.load_ard()?),
+        _ => None,
+    };
     let dir = fs
         .get_dir(&wd)
-        .ok_or_else(|| anyhow!("directory not found"))?;
+        .ok_or_else(|| ErrorKind::NotFound.error(format!("{wd}: directory not found")))?;
+
+    let mut queue = VecDeque::from([(wd, dir)]);
+    let mut first = true;
+    while let Some((path, dir)) = queue.pop_front() {
+        if !first && !args.raw && args.printf.is_none() {
+            println!();
+        }
+        first = false;
+        let subdirs = list_dir(&fs, &mut ard, &args, &path, dir)?;
+        if args.recursive {
+            for subdir in subdirs.into_iter().rev() {
+                queue.push_front((path.join(&subdir.name), subdir));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Prints the contents of a directory, returning its subdirectories in the order they
+/// were listed.
+fn list_dir<'a>(
+    fs: &'a ArhFileSystem,
+    ard: &mut Option<ArdReader<Box<dyn ArdSource>>>,
+    args: &ListArgs,
+    path: &ArhPath,
+    dir: &'a DirNode,
+) -> Result<Vec<&'a DirNode>> {
     let DirEntry::Directory { children } = &dir.entry else {
         unreachable!()
     };
 
-    if !args.raw {
-        println!("In {wd}:\n");
+    if !args.raw && args.printf.is_none() {
+        println!("In {}:\n", output::paint(path, Style::Header));
+    }
+
+    let mut entries = children
+        .values()
+        .filter(|node| !ArhFileSystem::is_placeholder(&path.join(&node.name)))
+        .map(|node| ListEntry {
+            node,
+            file: matches!(node.entry, DirEntry::File)
+                .then(|| fs.get_file_info(&path.join(&node.name)).unwrap()),
+        })
+        .filter(|e| {
+            args.all
+                || args.filter_flags.contains(&FlagName::Hidden)
+                || e.file.is_none_or(|f| !f.is_flag(FileFlag::Hidden))
+        })
+        // Directories are kept so -R still descends into them, but only files are printed
+        .filter(|e| {
+            args.filter_flags.is_empty()
+                || e.file.is_none_or(|f| {
+                    args.filter_flags
+                        .iter()
+                        .all(|flag| f.is_flag(flag.file_flag()))
+                })
+        })
+        .collect::<Vec<_>>();
+    // Children are already sorted by name
+    match args.sort {
+        SortKey::Name => {}
+        SortKey::Size => entries.sort_by_key(|e| e.size()),
+        SortKey::Offset => entries.sort_by_key(|e| e.file.map(|f| f.offset)),
+    }
+    if args.reverse {
+        entries.reverse();
+    }
+
+    if let Some(template) = &args.printf {
+        let shown = entries
+            .iter()
+            .filter(|e| args.filter_flags.is_empty() || e.file.is_some());
+        for entry in shown {
+            print!(
+                "{}",
+                template.render(&path.join(&entry.node.name), entry.file)
+            );
+        }
+        return Ok(entries
+            .iter()
+            .filter(|e| e.file.is_none())
+            .map(|e| e.node)
+            .collect());
     }
 
     let mut dirs = 0;
     let mut files = 0;
+    let mut subdirs = Vec::new();
 
-    let mut table = Table::default();
-
-    if !args.raw {
-        table.push_row(vec!["Name", "Type", "Flags", "Size", "ARD Offset"]);
-        table.push_row(vec!["----", "----", "-----", "----", "----------"]);
+    let mut table = if args.long {
+        Table::new(&[
+            "Name",
+            "Type",
+            "Flags",
+            "ID",
+            "Storage",
+            "Compressed",
+            "Uncompressed",
+            "Ratio",
+            "ARD Offset",
+        ])
+        .align_right([3, 5, 6, 7, 8])
+    } else {
+        Table::new(&["Name", "Type", "Flags", "Size", "ARD Offset"]).align_right([3, 4])
     }
+    .shrink(0);
+    let mut names = Vec::new();
 
-    for child in children {
-        match child.entry {
-            DirEntry::File => {
-                let file = fs.get_file_info(&wd.join(&child.name)).unwrap();
-                let file_size = file.actual_size();
-                table.push_row::<Cow<_>>(vec![
-                    child.name.as_str().into(),
+    for entry in entries {
+        let child = entry.node;
+        match entry.file {
+            Some(_) => files += 1,
+            None if !args.filter_flags.is_empty() => {
+                subdirs.push(child);
+                continue;
+            }
+            None => {
+                dirs += 1;
+                subdirs.push(child);
+            }
+        }
+        let name = Cell::new(child.name.as_str(), entry.style());
+        if args.raw {
+            names.push(name);
+            continue;
+        }
+        match entry.file {
+            Some(file) if args.long => {
+                let size = file.actual_size();
+                let ratio = if size == 0 {
+                    "--".to_string()
+                } else {
+                    format!(
+                        "{:.1}%",
+                        f64::from(file.compressed_size) / f64::from(size) * 100.0
+                    )
+                };
+                table.push_row(vec![
+                    name,
                     "File".into(),
-                    get_flags_display(file).into(),
-                    format!("{file_size}").into(),
+                    Cell::new(get_flags_display(file), Style::Flag),
+                    format!("{}", file.id).into(),
+                    get_storage_display(file, ard)?.into(),
+                    format_size(file.compressed_size.into(), args.human_readable).into(),
+                    format_size(size.into(), args.human_readable).into(),
+                    ratio.into(),
                     format!("{:X}", file.offset).into(),
                 ]);
-                files += 1;
             }
-            DirEntry::Directory { .. } => {
-                table.push_row(vec![&child.name, "Directory", "", "--"]);
-                dirs += 1;
+            Some(file) => {
+                table.push_row(vec![
+                    name,
+                    "File".into(),
+                    Cell::new(get_flags_display(file), Style::Flag),
+                    format_size(file.actual_size().into(), args.human_readable).into(),
+                    format!("{:X}", file.offset).into(),
+                ]);
+            }
+            None => {
+                table.push_row(vec![name, "Directory".into(), "".into(), "--".into()]);
             }
         }
     }
 
-    table.print();
-
-    if !args.raw {
+    if args.raw {
+        output::print_grid(&names);
+    } else {
+        table.print();
         println!("\n{dirs} directories, {files} files");
+        println!(
+            "{} files in total ({} extracted, {} in the archive)",
+            dir.stats.file_count,
+            format_size(dir.stats.uncompressed_size, args.human_readable),
+            format_size(dir.stats.compressed_size, args.human_readable),
+        );
     }
 
-    Ok(())
+    Ok(subdirs)
 }
 
-fn get_flags_display(meta: &FileMeta) -> String {
+pub(crate) fn format_size(size: u64, human_readable: bool) -> String {
+    if !human_readable {
+        return format!("{size}");
+    }
+    const UNITS: [&str; 4] = ["K", "M", "G", "T"];
+    if size < 1024 {
+        return format!("{size}B");
+    }
+    let mut size = size as f64 / 1024.0;
+    for unit in &UNITS[..UNITS.len() - 1] {
+        if size < 1024.0 {
+            return format!("{size:.1}{unit}");
+        }
+        size /= 1024.0;
+    }
+    format!("{size:.1}{}", UNITS[UNITS.len() - 1])
+}
+
+/// Returns how the file is stored in the archive.
+///
+/// If the ARD file is available, this is read from the entry's XBC1 header, otherwise it's
+/// inferred from the file's metadata.
+fn get_storage_display(
+    meta: &FileMeta,
+    ard: &mut Option<ArdReader<Box<dyn ArdSource>>>,
+) -> Result<Cow<'static, str>> {
+    let Some(ard) = ard else {
+        return Ok(if meta.uncompressed_size != 0 {
+            "Compressed"
+        } else if meta.is_flag(FileFlag::HasXbc1Header) {
+            "Wrapped"
+        } else {
+            "Raw"
+        }
+        .into());
+    };
+    Ok(match ard.entry_header(meta)? {
+        None => "Raw".into(),
+        Some(header) if header.compression_type == CompressionType::Uncompressed => {
+            "Wrapped".into()
+        }
+        Some(header) => format!(
+            "Compressed ({})",
+            format!("{:?}", header.compression_type).to_lowercase()
+        )
+        .into(),
+    })
+}
+
+pub(crate) fn get_flags_display(meta: &FileMeta) -> String {
     let mut res = String::new();
     if meta.is_flag(FileFlag::Hidden) {
         res.push('H');
@@ -87,25 +322,19 @@ fn get_flags_display(meta: &FileMeta) -> String {
     res
 }
 
-impl<'a> Table<'a> {
-    fn push_row<S: Into<Cow<'a, str>>>(&mut self, row: impl IntoIterator<Item = S>) {
-        let row: Vec<_> = row.into_iter().map(Into::into).collect();
-        for (i, cell) in row.iter().enumerate() {
-            if i >= self.lens.len() {
-                self.lens.push(cell.len());
-            } else {
-                self.lens[i] = cell.len().max(self.lens[i]);
-            }
+impl<'a> ListEntry<'a> {
+    fn style(&self) -> Style {
+        match self.file {
+            None => Style::Directory,
+            Some(file) if file.is_flag(FileFlag::Hidden) => Style::Hidden,
+            Some(_) => Style::Plain,
         }
-        self.rows.push(row);
     }
 
-    fn print(self) {
-        for row in self.rows {
-            for (i, cell) in row.into_iter().enumerate() {
-                print!("{:<1$}  ", cell, self.lens[i]);
-            }
-            println!();
+    fn size(&self) -> u64 {
+        match self.file {
+            Some(file) => file.actual_size().into(),
+            None => self.node.stats.uncompressed_size,
         }
     }
 }
@@ -1,14 +1,76 @@
 use std::{
-    fs::File,
-    io::{BufReader, BufWriter},
+    fs::{File, OpenOptions},
+    io::{self, BufReader, BufWriter, Cursor, Read, Seek, SeekFrom, Write},
+    ops::{Deref, DerefMut},
+    path::{Path, PathBuf},
+    process::ExitCode,
+    sync::{atomic::Ordering, MutexGuard},
 };
 
-use anyhow::{anyhow, Result};
-use ardain::{path::ArhPath, ArhFileSystem};
-use clap::{command, Args, Parser, Subcommand};
+use anyhow::{anyhow, bail, Result};
+use ardain::{
+    error::Error,
+    game::Game,
+    opts::ArhOptions,
+    path::{ArhPath, PathCase, RelativeArhPath, ARH_PATH_ROOT},
+    snapshot::{Snapshot, SnapshotWriter},
+    ArdReader, ArhFileSystem, FileFlag,
+};
+use clap::{
+    builder::{PossibleValuesParser, TypedValueParser},
+    ArgAction, Args, CommandFactory, Parser, Subcommand,
+};
+use clap_complete::CompleteEnv;
+use env_logger::Env;
+use errors::{ErrorFormat, ErrorKind};
+use log::{info, warn};
+use output::ColorChoice;
 
+mod apply_mod;
+mod batch;
+mod bench;
+mod big;
+mod build;
+mod cat;
+mod completions;
+mod copy_from;
+mod daemon;
+mod debug;
+mod dedup;
+mod delta;
+mod diff;
+mod errors;
+mod export_changes;
+mod ext;
+mod extract;
+mod file;
+mod find;
+mod flags;
+mod gc;
+mod grep;
+mod hash;
+mod hexdump;
+mod history;
+#[cfg(feature = "http")]
+mod http;
+mod info;
+mod inspect;
 mod ls;
+mod manifest;
+mod map;
+mod orphans;
+mod output;
+mod printf;
+mod progress;
+mod project;
+mod rebuild;
 mod rm;
+mod sanitize;
+mod snapshot;
+mod trash;
+mod tui;
+mod verify;
+mod zip;
 
 #[derive(Parser)]
 #[command(
@@ -21,60 +83,520 @@ mod rm;
 struct Cli {
     #[command(subcommand)]
     command: Option<Commands>,
+    /// Print more information about what is being done (-v), or debug information (-vv)
+    #[arg(short, long, action = ArgAction::Count, global = true)]
+    verbose: u8,
+    /// Only log errors, not warnings
+    #[arg(short, long, global = true, conflicts_with = "verbose")]
+    quiet: bool,
+    /// When to color the output
+    #[arg(long, global = true, value_enum, value_name = "WHEN", default_value_t = ColorChoice::Auto)]
+    color: ColorChoice,
+    /// How to print the error that ended the command. With `json`, an object with the kind
+    /// of error, its exit code and message is printed on stderr.
+    #[arg(long, global = true, value_enum, value_name = "FORMAT", default_value_t = ErrorFormat::Text)]
+    errors: ErrorFormat,
     #[clap(flatten)]
     input: InputData,
 }
 
 #[derive(Args)]
 struct InputData {
-    /// Input .arh file, required for most commands
+    /// Input .arh file, required for most commands. Can be an http(s) URL, in which case
+    /// the archive is read-only.
     #[arg(long = "arh", global = true)]
     in_arh: Option<String>,
-    /// Input .ard file (data archive)
+    /// Input .ard file (data archive). Can be an http(s) URL for commands that only read
+    /// file data (e.g. ls, extract, cat): only the needed parts are downloaded.
     #[arg(long = "ard", global = true)]
     in_ard: Option<String>,
     /// Output .arh file, for commands that write data and metadata. If absent, the input
     /// .arh file will be overwritten!
     #[arg(long = "out-arh", global = true)]
     out_arh: Option<String>,
+    /// The game the archive is from, which determines the compression types that can be
+    /// used for new files (default: xc3)
+    #[arg(long, global = true, value_parser = game_parser())]
+    game: Option<Game>,
+    /// Load the archive read-only. Commands that would modify it fail instead.
+    #[arg(long, global = true)]
+    read_only: bool,
+    /// Reject new file names that the game might not be able to look up: only lowercase
+    /// letters, digits, `_`, `-` and `.` are allowed
+    #[arg(long, global = true)]
+    strict_paths: bool,
+    /// Keep the spelling of new file names (and of existing ones) instead of changing them
+    /// to lowercase. Names are still looked up case-insensitively, but the game can't find
+    /// files whose names have uppercase characters.
+    #[arg(long, global = true)]
+    preserve_case: bool,
+    /// The directory of the archive that relative paths (e.g. `../map/foo.wismhd`) start
+    /// from (default: /). It is itself relative to the root.
+    #[arg(long, global = true, value_name = "DIR", value_parser = parse_cwd)]
+    cwd: Option<ArhPath>,
+    /// In batch mode, the file system shared by all commands
+    #[arg(skip)]
+    session: Option<batch::Session>,
+}
+
+/// The data of a .ard file returned by [`InputData::load_ard`], local or remote.
+pub trait ArdSource: Read + Seek + Send {}
+
+impl<T: Read + Seek + Send> ArdSource for T {}
+
+/// A file system returned by [`InputData::load_fs`].
+pub enum LoadedFs<'a> {
+    Owned(Box<ArhFileSystem>),
+    Shared(MutexGuard<'a, ArhFileSystem>),
 }
 
 #[derive(Subcommand)]
 enum Commands {
+    /// Add or replace files from a directory laid out like the archive, e.g. a mod
+    #[clap(visible_alias = "apply-patch")]
+    ApplyMod(apply_mod::ApplyModArgs),
+    /// Run multiple commands over the same loaded archive
+    Batch(batch::BatchArgs),
+    /// Measure load, lookup, read and allocation performance on this archive and machine
+    Bench(bench::BenchArgs),
+    /// List the largest files and directories, e.g. to find what to recompress or strip
+    Big(big::BigArgs),
+    /// Create a new archive from a directory laid out like the archive
+    Build(build::BuildArgs),
+    /// Print the contents of files
+    Cat(cat::CatArgs),
+    /// Generate shell completion scripts
+    Completions(completions::CompletionsArgs),
+    /// Copy files from another archive, without decompressing and compressing them again
+    CopyFrom(copy_from::CopyFromArgs),
+    /// Keep the archive loaded and serve requests over a socket
+    Daemon(daemon::DaemonArgs),
+    /// Inspect the raw structures of the .arh file, e.g. to diagnose corrupted archives
+    Debug(debug::DebugArgs),
+    /// Find identical files, and optionally make them share the same data
+    Dedup(dedup::DedupArgs),
+    /// Compare the archive with an older version, detecting moved files and directories
+    Diff(diff::DiffArgs),
+    /// Extract files that were added or modified since a baseline, e.g. to distribute a mod
+    ExportChanges(export_changes::ExportChangesArgs),
+    /// Manage the ARH extension section used for allocating space in the ARD file
+    Ext(ext::ExtArgs),
+    /// Extract files from the archive
+    #[clap(visible_alias = "x")]
+    Extract(extract::ExtractArgs),
+    /// Identify file types based on their contents
+    File(file::FileArgs),
+    /// Find the files whose contents match a hash or a local file
+    Find(find::FindArgs),
+    /// Set, clear, export or import file flags (e.g. which files are hidden)
+    Flags(flags::FlagsArgs),
+    /// Reclaim entries left behind by removed files in the archive's tables
+    Gc(gc::GcArgs),
+    /// Search for text or byte patterns in file contents
+    Grep(grep::GrepArgs),
+    /// Print part of a file as hex and ASCII, without extracting it
+    Hexdump(hexdump::HexdumpArgs),
+    /// List the operations recorded in the archive, or turn recording on or off
+    History(history::HistoryArgs),
+    /// Print totals for the archive, and how space in the ARD file is used
+    Info(info::InfoArgs),
+    /// Summarize the structure of a file: nested streams, archive entries and textures
+    Inspect(inspect::InspectArgs),
     /// List all files in a directory
     #[clap(visible_alias = "ls")]
     List(ls::ListArgs),
+    /// Export or check a listing of all files, with their metadata and hashes
+    Manifest(manifest::ManifestArgs),
+    /// Export the physical layout of the ARD file, e.g. to visualize fragmentation
+    Map(map::MapArgs),
+    /// Find data in the ARD file that isn't referenced by any file
+    Orphans(orphans::OrphansArgs),
+    /// Create an archive from a project written by unpack-project
+    PackProject(project::PackArgs),
+    /// Regenerate the path dictionary and string table, e.g. to repair or shrink them
+    Rebuild(rebuild::RebuildArgs),
     /// Remove files or directories
     #[clap(visible_alias = "rm")]
     Remove(rm::RemoveArgs),
+    /// Record the state of the archive before modding it, and revert to it later
+    Snapshot(snapshot::SnapshotArgs),
+    /// Manage hidden (soft-deleted) files
+    Trash(trash::TrashArgs),
+    /// Browse the archive in an interactive terminal UI
+    Tui(tui::TuiArgs),
+    /// Extract the archive into an editable project that keeps file IDs, flags and order
+    UnpackProject(project::UnpackArgs),
+    /// Check files for inconsistencies between the ARH metadata and the ARD data
+    #[clap(visible_alias = "fsck")]
+    Verify(verify::VerifyArgs),
 }
 
-fn main() -> Result<()> {
+fn main() -> ExitCode {
+    CompleteEnv::with_factory(Cli::command).complete();
     let cli = Cli::parse();
+    let level = match (cli.quiet, cli.verbose) {
+        (true, _) => "error",
+        (_, 0) => "warn",
+        (_, 1) => "info",
+        (_, 2) => "debug",
+        _ => "trace",
+    };
+    env_logger::Builder::from_env(Env::default().default_filter_or(level))
+        .format_timestamp(None)
+        .format_target(false)
+        .init();
+    output::init(cli.color);
+
+    let res = match cli.command {
+        Some(command) => run_command(&cli.input, command),
+        None => Ok(()),
+    };
+    match res {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => errors::report(&e, cli.errors),
+    }
+}
 
-    match cli.command {
-        Some(Commands::List(args)) => ls::run(&cli.input, args),
-        Some(Commands::Remove(args)) => rm::run(&cli.input, args),
-        _ => Ok(()),
+fn run_command(input: &InputData, command: Commands) -> Result<()> {
+    match command {
+        Commands::ApplyMod(args) => apply_mod::run(input, args),
+        Commands::Batch(args) => batch::run(input, args),
+        Commands::Bench(args) => bench::run(input, args),
+        Commands::Big(args) => big::run(input, args),
+        Commands::Build(args) => build::run(input, args),
+        Commands::Cat(args) => cat::run(input, args),
+        Commands::Completions(args) => completions::run(args),
+        Commands::CopyFrom(args) => copy_from::run(input, args),
+        Commands::Daemon(args) => daemon::run(input, args),
+        Commands::Debug(args) => debug::run(input, args),
+        Commands::Dedup(args) => dedup::run(input, args),
+        Commands::Diff(args) => diff::run(input, args),
+        Commands::ExportChanges(args) => export_changes::run(input, args),
+        Commands::Ext(args) => ext::run(input, args),
+        Commands::Extract(args) => extract::run(input, args),
+        Commands::File(args) => file::run(input, args),
+        Commands::Find(args) => find::run(input, args),
+        Commands::Flags(args) => flags::run(input, args),
+        Commands::Gc(args) => gc::run(input, args),
+        Commands::Grep(args) => grep::run(input, args),
+        Commands::Hexdump(args) => hexdump::run(input, args),
+        Commands::History(args) => history::run(input, args),
+        Commands::Info(args) => info::run(input, args),
+        Commands::Inspect(args) => inspect::run(input, args),
+        Commands::List(args) => ls::run(input, args),
+        Commands::Manifest(args) => manifest::run(input, args),
+        Commands::Map(args) => map::run(input, args),
+        Commands::Orphans(args) => orphans::run(input, args),
+        Commands::PackProject(args) => project::run_pack(input, args),
+        Commands::Rebuild(args) => rebuild::run(input, args),
+        Commands::Remove(args) => rm::run(input, args),
+        Commands::Snapshot(args) => snapshot::run(input, args),
+        Commands::Trash(args) => trash::run(input, args),
+        Commands::Tui(args) => tui::run(input, args),
+        Commands::UnpackProject(args) => project::run_unpack(input, args),
+        Commands::Verify(args) => verify::run(input, args),
     }
 }
 
 impl InputData {
-    pub fn load_fs(&self) -> Result<ArhFileSystem> {
-        match &self.in_arh {
-            Some(path) => Ok(ArhFileSystem::load(BufReader::new(File::open(path)?))?),
-            None => Err(anyhow!("input .arh must be passed in as --arh")),
+    pub fn load_fs(&self) -> Result<LoadedFs<'_>> {
+        if let Some(session) = &self.session {
+            return Ok(LoadedFs::Shared(session.fs.lock().unwrap()));
+        }
+        let Some(path) = &self.in_arh else {
+            return Err(anyhow!("input .arh must be passed in as --arh"));
+        };
+        let remote = is_url(path);
+        let opts = ArhOptions {
+            game: self.game.unwrap_or_default(),
+            read_only: self.read_only || remote,
+            strict_paths: self.strict_paths,
+            path_case: self.path_case(),
+            ..Default::default()
+        };
+        let fs = if remote {
+            ArhFileSystem::load_with_options(Cursor::new(download(path)?), opts)
+                .map_err(Error::from)?
+        } else {
+            info!("Loading {path}");
+            ArhFileSystem::load_with_options(BufReader::new(File::open(path)?), opts)
+                .map_err(Error::from)?
+        };
+        if let Some(cwd) = self.cwd.as_ref().filter(|cwd| !fs.is_dir(cwd)) {
+            bail!("{cwd}: not a directory (--cwd)");
+        }
+        let stats = fs.get_dir(&ArhPath::default()).unwrap().stats;
+        info!(
+            "Loaded {} files in {} directories",
+            stats.file_count, stats.dir_count
+        );
+        Ok(LoadedFs::Owned(Box::new(fs)))
+    }
+
+    /// Returns the directory relative paths start from, see `--cwd`.
+    pub fn cwd(&self) -> &ArhPath {
+        self.cwd.as_ref().unwrap_or(&ARH_PATH_ROOT)
+    }
+
+    /// Resolves a path from the command line against [`Self::cwd`].
+    pub fn resolve(&self, path: &RelativeArhPath) -> Result<ArhPath> {
+        // Paths are still compared in lowercase, the spelling is only used with --preserve-case
+        path.resolve(self.cwd()).map_err(|e| anyhow!("{path}: {e}"))
+    }
+
+    /// Resolves paths from the command line, see [`Self::resolve`].
+    pub fn resolve_all<'a>(
+        &self,
+        paths: impl IntoIterator<Item = &'a RelativeArhPath>,
+    ) -> Result<Vec<ArhPath>> {
+        paths.into_iter().map(|path| self.resolve(path)).collect()
+    }
+
+    fn path_case(&self) -> PathCase {
+        if self.preserve_case {
+            PathCase::Preserve
+        } else {
+            PathCase::Lowercase
+        }
+    }
+
+    pub fn load_ard(&self) -> Result<ArdReader<Box<dyn ArdSource>>> {
+        #[cfg(feature = "http")]
+        if let Some(ard) = self.remote_ard()? {
+            return Ok(ArdReader::new(Box::new(ard)));
+        }
+        Ok(ArdReader::new(Box::new(BufReader::new(self.open_ard()?))))
+    }
+
+    /// Opens the input .ard file if it is a URL.
+    #[cfg(feature = "http")]
+    pub fn remote_ard(&self) -> Result<Option<http::HttpArd>> {
+        match &self.in_ard {
+            Some(url) if is_url(url) => Ok(Some(http::HttpArd::open(url)?)),
+            _ => Ok(None),
+        }
+    }
+
+    /// Fails if the input .ard file is missing, listing the files whose data is needed.
+    ///
+    /// Without a .ard file, commands can still edit metadata (flags, paths, deletions).
+    /// Commands that read or write file data call this before doing anything else.
+    pub fn require_ard<'p>(&self, files: impl IntoIterator<Item = &'p ArhPath>) -> Result<()> {
+        const SHOWN: usize = 10;
+        if self.in_ard.is_some() {
+            return Ok(());
+        }
+        let files: Vec<_> = files.into_iter().collect();
+        if files.is_empty() {
+            return Ok(());
+        }
+        for path in &files {
+            info!("{path}: ARD required");
+        }
+        let mut list: String = files
+            .iter()
+            .take(SHOWN)
+            .map(|p| format!("\n  {p}"))
+            .collect();
+        if files.len() > SHOWN {
+            list += &format!(
+                "\n  ...and {} more (use -v to list all)",
+                files.len() - SHOWN
+            );
+        }
+        Err(anyhow!(
+            "ARD required: the data of {} files is needed, pass the .ard file with --ard:{list}",
+            files.len()
+        ))
+    }
+
+    /// Asks a yes/no question on the terminal. Anything but "y" or "yes", including the end
+    /// of input, is a no.
+    pub fn confirm(&self, question: &str) -> Result<bool> {
+        if self.session.is_some() {
+            bail!("commands can't prompt for confirmation in batch mode");
+        }
+        eprint!("{question} [y/N] ");
+        io::stderr().flush()?;
+        let mut answer = String::new();
+        io::stdin().read_line(&mut answer)?;
+        Ok(matches!(
+            answer.trim().to_ascii_lowercase().as_str(),
+            "y" | "yes"
+        ))
+    }
+
+    /// Opens the input .ard file without wrapping it, e.g. to share the handle between
+    /// threads with [`ardain::PositionalReader`].
+    ///
+    /// Fails if the .ard file is remote, see [`Self::load_ard`].
+    pub fn open_ard(&self) -> Result<File> {
+        match &self.in_ard {
+            Some(url) if is_url(url) && cfg!(feature = "http") => {
+                bail!("{url}: this command needs a local .ard file")
+            }
+            Some(url) if is_url(url) => bail!("{url}: {NO_HTTP}"),
+            Some(path) => Ok(File::open(path)?),
+            None => Err(anyhow!("input .ard must be passed in as --ard")),
         }
     }
 
+    /// Opens the input .ard file for writing.
+    ///
+    /// If the archive has a snapshot, the data that gets overwritten is recorded in it.
+    pub fn open_ard_for_write(&self) -> Result<SnapshotWriter<File>> {
+        if self.read_only {
+            return Err(Error::ReadOnly.into());
+        }
+        let path = self
+            .in_ard
+            .as_ref()
+            .ok_or_else(|| anyhow!("input .ard must be passed in as --ard"))?;
+        if is_url(path) {
+            return Err(Error::ReadOnly.into());
+        }
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        let snapshot = Snapshot::open(path)?;
+        if snapshot.is_some() {
+            info!(
+                "Recording overwritten data in {}",
+                Snapshot::dir_for(path).display()
+            );
+        }
+        if let Some(session) = &self.session {
+            session.ard_written.store(true, Ordering::Relaxed);
+        }
+        Ok(SnapshotWriter::new(file, snapshot))
+    }
+
+    /// Writes the file system to the output .arh file.
+    ///
+    /// In batch mode, this is deferred until the next sync point, see `batch --sync-policy`.
     pub fn write_fs(&self, fs: &mut ArhFileSystem) -> Result<()> {
-        match self.out_arh.as_ref().or(self.in_arh.as_ref()) {
-            Some(path) => Ok(fs.sync(BufWriter::new(File::create(path)?))?),
-            None => Err(anyhow!("input .arh must be passed in as --arh")),
+        if fs.is_read_only() {
+            return Err(Error::ReadOnly.into());
+        }
+        if let Some(session) = &self.session {
+            session.dirty.store(true, Ordering::Relaxed);
+            return Ok(());
         }
+        let Some(path) = self.out_arh.as_ref().or(self.in_arh.as_ref()) else {
+            return Err(anyhow!("input .arh must be passed in as --arh"));
+        };
+        if self.out_arh.is_none() {
+            warn!("Overwriting input ARH {path} (use --out-arh to write elsewhere)");
+        }
+        info!("Writing {path}");
+        Ok(fs.sync(BufWriter::new(File::create(path)?))?)
     }
 }
 
-pub(crate) fn parse_path(s: &str) -> Result<ArhPath> {
-    Ok(ArhPath::normalize(s)?)
+impl<'a> Deref for LoadedFs<'a> {
+    type Target = ArhFileSystem;
+
+    fn deref(&self) -> &Self::Target {
+        match self {
+            Self::Owned(fs) => fs,
+            Self::Shared(fs) => fs,
+        }
+    }
+}
+
+impl<'a> DerefMut for LoadedFs<'a> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        match self {
+            Self::Owned(fs) => fs,
+            Self::Shared(fs) => fs,
+        }
+    }
+}
+
+/// Parses `--cwd`, which is relative to the root of the archive.
+fn parse_cwd(s: &str) -> Result<ArhPath> {
+    Ok(RelativeArhPath::new(s).resolve(&ARH_PATH_ROOT)?)
+}
+
+/// Joins a path relative to `out`, with `/` separators (e.g. the rest of an archive path),
+/// onto `out`.
+///
+/// Fails on components that aren't plain file names (empty, `.`, `..`, or containing a
+/// separator of the host), so entries of a crafted archive can't be written outside `out`.
+pub(crate) fn join_output_path(out: &Path, rel: &str) -> Result<PathBuf> {
+    rel.split('/').try_fold(out.to_path_buf(), |path, c| {
+        if c.is_empty() || c == "." || c == ".." || c.contains(std::path::is_separator) {
+            return Err(ErrorKind::Corrupted.error(format!(
+                "{rel}: \"{c}\" can't be used as a file name inside {}",
+                out.display()
+            )));
+        }
+        Ok(path.join(c))
+    })
+}
+
+fn game_parser() -> impl TypedValueParser<Value = Game> {
+    PossibleValuesParser::new(Game::ALL.map(Game::name)).map(|s| s.parse::<Game>().unwrap())
+}
+
+/// Returns the full paths of the given files, and of all files in the given directories
+/// (recursively).
+///
+/// Hidden files inside directories are skipped unless `include_hidden` is `true`. Directory
+/// placeholders are always skipped.
+pub(crate) fn expand_paths(
+    fs: &ArhFileSystem,
+    paths: &[ArhPath],
+    include_hidden: bool,
+) -> Result<Vec<ArhPath>> {
+    let mut files = Vec::new();
+    for path in paths {
+        if fs.is_file(path) {
+            files.push(path.clone());
+            continue;
+        }
+        let dir = fs.get_dir(path).ok_or_else(|| {
+            ErrorKind::NotFound.error(format!("{path}: no such file or directory"))
+        })?;
+        files.extend(
+            dir.children_paths()
+                .into_iter()
+                .map(|child| path.join(&child))
+                .filter(|child| !ArhFileSystem::is_placeholder(child))
+                .filter(|child| {
+                    include_hidden
+                        || !fs
+                            .get_file_info(child)
+                            .is_some_and(|f| f.is_flag(FileFlag::Hidden))
+                }),
+        );
+    }
+    files.sort();
+    files.dedup();
+    Ok(files)
+}
+
+/// The error for URL inputs, in builds without the `http` feature
+const NO_HTTP: &str = "reading from URLs needs the http feature of ard-tools";
+
+/// Returns whether an `--arh` or `--ard` argument is a URL rather than a local path.
+pub(crate) fn is_url(path: &str) -> bool {
+    path.starts_with("http://") || path.starts_with("https://")
+}
+
+/// Downloads a whole remote file, e.g. the .arh file of a remote archive.
+pub(crate) fn download(url: &str) -> Result<Vec<u8>> {
+    #[cfg(feature = "http")]
+    return http::download(url);
+    #[cfg(not(feature = "http"))]
+    bail!("{url}: {NO_HTTP}")
+}
+
+/// Returns the position a seek moves to, for readers and writers that keep track of their
+/// position and length themselves.
+pub(crate) fn seek_position(pos: u64, len: u64, to: SeekFrom) -> io::Result<u64> {
+    let new_pos = match to {
+        SeekFrom::Start(pos) => Some(pos),
+        SeekFrom::End(delta) => len.checked_add_signed(delta),
+        SeekFrom::Current(delta) => pos.checked_add_signed(delta),
+    };
+    new_pos.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "seek to a negative offset"))
 }
@@ -0,0 +1,369 @@
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{BufRead, BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use ardain::{path::ArhPath, ArhFileSystem, FileFlag, FileMeta};
+use clap::{Args, Subcommand, ValueEnum};
+use log::info;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    hash::{Digest, Hasher},
+    progress::ParallelArgs,
+    InputData,
+};
+
+#[derive(Args)]
+pub struct ManifestArgs {
+    #[command(subcommand)]
+    command: ManifestCommand,
+}
+
+#[derive(Subcommand)]
+enum ManifestCommand {
+    /// Write the metadata (and content hash) of every file, including hidden files
+    Export {
+        /// Where to write the manifest
+        out: PathBuf,
+        /// The manifest format (default: based on the file extension, or CSV)
+        #[arg(short, long, value_enum)]
+        format: Option<ManifestFormat>,
+        /// Don't hash file contents. The ARD file is not needed in this case.
+        #[arg(long)]
+        no_hash: bool,
+        /// How file contents are hashed. Manifests are meant to be kept and compared
+        /// against other copies, so the default is sha256 rather than the faster xxh3.
+        #[arg(
+            long = "hash",
+            value_enum,
+            default_value_t = Hasher::Sha256,
+            conflicts_with = "no_hash"
+        )]
+        hasher: Hasher,
+        #[command(flatten)]
+        parallel: ParallelArgs,
+    },
+    /// Check the archive against a previously exported manifest
+    Verify {
+        /// The manifest to check against
+        manifest: PathBuf,
+        /// The manifest format (default: based on the file extension, or CSV)
+        #[arg(short, long, value_enum)]
+        format: Option<ManifestFormat>,
+        /// Also compare IDs, offsets, stored sizes and flags. Without this, only the
+        /// contents of each file are compared, so repacked archives still match.
+        #[arg(long)]
+        strict: bool,
+        #[command(flatten)]
+        parallel: ParallelArgs,
+    },
+}
+
+#[derive(ValueEnum, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ManifestFormat {
+    /// One line per file, with a header
+    Csv,
+    /// An array with one object per file
+    Json,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq)]
+pub(crate) struct ManifestEntry {
+    pub path: String,
+    pub id: u32,
+    pub offset: u64,
+    pub compressed_size: u32,
+    pub uncompressed_size: u32,
+    /// The size of the file after decompression
+    pub size: u32,
+    /// Flag names, separated by '|'
+    pub flags: String,
+    /// The hash of the file contents after decompression
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hash: Option<Digest>,
+}
+
+const CSV_HEADER: &str = "path,id,offset,compressed_size,uncompressed_size,size,flags,hash";
+
+pub fn run(input: &InputData, args: ManifestArgs) -> Result<()> {
+    let fs = input.load_fs()?;
+    match args.command {
+        ManifestCommand::Export {
+            out,
+            format,
+            no_hash,
+            hasher,
+            parallel,
+        } => {
+            let hasher = (!no_hash).then_some(hasher);
+            let entries = build_manifest(input, &fs, hasher, &parallel)?;
+            let mut writer = BufWriter::new(File::create(&out)?);
+            match format.unwrap_or_else(|| guess_format(&out)) {
+                ManifestFormat::Csv => write_csv(&mut writer, &entries)?,
+                ManifestFormat::Json => {
+                    serde_json::to_writer_pretty(&mut writer, &entries)?;
+                    writeln!(writer)?;
+                }
+            }
+            writer.flush()?;
+            info!("Wrote {} entries to {}", entries.len(), out.display());
+        }
+        ManifestCommand::Verify {
+            manifest,
+            format,
+            strict,
+            parallel,
+        } => {
+            let expected = read_manifest(&manifest, format)?;
+            // Hash with the same algorithm as the manifest
+            let hasher = expected
+                .iter()
+                .find_map(|e| e.hash.as_ref())
+                .map(Digest::hasher);
+            let actual = build_manifest(input, &fs, hasher, &parallel)?;
+            verify(expected, actual, strict)?;
+        }
+    }
+    Ok(())
+}
+
+/// Reads a manifest written by `manifest export`.
+pub(crate) fn read_manifest(
+    path: &Path,
+    format: Option<ManifestFormat>,
+) -> Result<Vec<ManifestEntry>> {
+    let reader = BufReader::new(File::open(path)?);
+    match format.unwrap_or_else(|| guess_format(path)) {
+        ManifestFormat::Csv => read_csv(reader),
+        ManifestFormat::Json => Ok(serde_json::from_reader(reader)?),
+    }
+}
+
+fn guess_format(path: &Path) -> ManifestFormat {
+    match path.extension() {
+        Some(ext) if ext.eq_ignore_ascii_case("json") => ManifestFormat::Json,
+        _ => ManifestFormat::Csv,
+    }
+}
+
+fn build_manifest(
+    input: &InputData,
+    fs: &ArhFileSystem,
+    hasher: Option<Hasher>,
+    parallel: &ParallelArgs,
+) -> Result<Vec<ManifestEntry>> {
+    let files = crate::expand_paths(fs, &[ArhPath::default()], true)?;
+    if hasher.is_some() {
+        input.require_ard(&files)?;
+    }
+    info!("Listing {} files", files.len());
+    let total_bytes = files
+        .iter()
+        .map(|path| u64::from(fs.get_file_info(path).unwrap().actual_size()))
+        .sum();
+    let progress = parallel.progress(files.len(), total_bytes);
+    let entries = parallel.visitor().try_visit(
+        fs,
+        &files,
+        || hasher.map(|hasher| (hasher, input.load_ard())),
+        |state, path, meta| -> Result<ManifestEntry> {
+            let hash = match state {
+                Some((hasher, ard)) => {
+                    let ard = ard.as_mut().map_err(|e| anyhow!("{e}"))?;
+                    Some(hasher.digest(&ard.entry(meta).read()?))
+                }
+                None => None,
+            };
+            progress.file_done(path, meta.actual_size().into());
+            Ok(ManifestEntry::new(path, meta, hash))
+        },
+    )?;
+    progress.finish();
+    Ok(entries)
+}
+
+fn verify(expected: Vec<ManifestEntry>, actual: Vec<ManifestEntry>, strict: bool) -> Result<()> {
+    let mut actual: BTreeMap<_, _> = actual.into_iter().map(|e| (e.path.clone(), e)).collect();
+    let total = expected.len();
+    let mut problems = 0;
+    for old in expected {
+        let Some(new) = actual.remove(&old.path) else {
+            println!("{}: missing", old.path);
+            problems += 1;
+            continue;
+        };
+        let mut diffs = Vec::new();
+        if old.size != new.size {
+            diffs.push(format!("size {} -> {}", old.size, new.size));
+        }
+        if old.hash.is_some() && old.hash != new.hash {
+            diffs.push("contents differ".to_string());
+        }
+        if strict {
+            for (name, old, new) in [
+                ("id", old.id.into(), new.id.into()),
+                ("offset", old.offset, new.offset),
+                (
+                    "compressed size",
+                    old.compressed_size.into(),
+                    new.compressed_size.into(),
+                ),
+                (
+                    "uncompressed size",
+                    old.uncompressed_size.into(),
+                    new.uncompressed_size.into(),
+                ),
+            ] {
+                if old != new {
+                    diffs.push(format!("{name} {old} -> {new}"));
+                }
+            }
+            if old.flags != new.flags {
+                diffs.push(format!("flags \"{}\" -> \"{}\"", old.flags, new.flags));
+            }
+        }
+        if !diffs.is_empty() {
+            println!("{}: {}", old.path, diffs.join(", "));
+            problems += 1;
+        }
+    }
+    for path in actual.keys() {
+        println!("{path}: not in manifest");
+        problems += 1;
+    }
+    if problems != 0 {
+        bail!("{problems} files do not match the manifest");
+    }
+    println!("All {total} files match the manifest");
+    Ok(())
+}
+
+impl ManifestEntry {
+    pub(crate) fn new(path: &ArhPath, meta: &FileMeta, hash: Option<Digest>) -> Self {
+        let flags: Vec<_> = [
+            (FileFlag::Hidden, "hidden"),
+            (FileFlag::HasXbc1Header, "xbc1"),
+        ]
+        .into_iter()
+        .filter(|&(flag, _)| meta.is_flag(flag))
+        .map(|(_, name)| name)
+        .collect();
+        Self {
+            path: path.to_string(),
+            id: meta.id,
+            offset: meta.offset,
+            compressed_size: meta.compressed_size,
+            uncompressed_size: meta.uncompressed_size,
+            size: meta.actual_size(),
+            flags: flags.join("|"),
+            hash,
+        }
+    }
+
+    /// The location of the file's data, e.g. to read it from the .ard file the manifest was
+    /// exported with.
+    pub(crate) fn file_meta(&self) -> FileMeta {
+        let mut meta = FileMeta::default();
+        meta.id = self.id;
+        meta.offset = self.offset;
+        meta.compressed_size = self.compressed_size;
+        meta.uncompressed_size = self.uncompressed_size;
+        meta.set_flag(
+            FileFlag::HasXbc1Header,
+            self.flags.split('|').any(|f| f == "xbc1"),
+        );
+        meta
+    }
+}
+
+fn write_csv(out: &mut impl Write, entries: &[ManifestEntry]) -> Result<()> {
+    writeln!(out, "{CSV_HEADER}")?;
+    for e in entries {
+        writeln!(
+            out,
+            "{},{},{},{},{},{},{},{}",
+            csv_field(&e.path),
+            e.id,
+            e.offset,
+            e.compressed_size,
+            e.uncompressed_size,
+            e.size,
+            e.flags,
+            e.hash.as_ref().map(Digest::to_string).unwrap_or_default()
+        )?;
+    }
+    Ok(())
+}
+
+fn read_csv(reader: impl BufRead) -> Result<Vec<ManifestEntry>> {
+    let mut lines = reader.lines();
+    if lines.next().transpose()?.as_deref() != Some(CSV_HEADER) {
+        bail!("not a manifest: expected header \"{CSV_HEADER}\"");
+    }
+    let mut entries = Vec::new();
+    for (i, line) in lines.enumerate() {
+        let line = line?;
+        if line.is_empty() {
+            continue;
+        }
+        let entry = parse_csv_line(&line).with_context(|| format!("manifest line {}", i + 2))?;
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+fn parse_csv_line(line: &str) -> Result<ManifestEntry> {
+    // Only the path may be quoted, the other fields never contain commas
+    let (path, rest) = match line.strip_prefix('"') {
+        Some(quoted) => {
+            let mut path = String::new();
+            let mut chars = quoted.char_indices().peekable();
+            let rest = loop {
+                match chars.next() {
+                    Some((_, '"')) if chars.peek().is_some_and(|&(_, c)| c == '"') => {
+                        chars.next();
+                        path.push('"');
+                    }
+                    Some((i, '"')) => break quoted[i + 1..].strip_prefix(','),
+                    Some((_, c)) => path.push(c),
+                    None => break None,
+                }
+            };
+            (path, rest.ok_or_else(|| anyhow!("bad quoted path"))?)
+        }
+        None => {
+            let (path, rest) = line
+                .split_once(',')
+                .ok_or_else(|| anyhow!("missing fields"))?;
+            (path.to_string(), rest)
+        }
+    };
+    let fields: Vec<_> = rest.split(',').collect();
+    let [id, offset, compressed_size, uncompressed_size, size, flags, hash] = fields[..] else {
+        bail!("expected 8 fields, found {}", fields.len() + 1);
+    };
+    Ok(ManifestEntry {
+        path,
+        id: id.parse()?,
+        offset: offset.parse()?,
+        compressed_size: compressed_size.parse()?,
+        uncompressed_size: uncompressed_size.parse()?,
+        size: size.parse()?,
+        flags: flags.to_string(),
+        hash: (!hash.is_empty())
+            .then(|| Digest::parse(hash))
+            .transpose()?,
+    })
+}
+
+fn csv_field(text: &str) -> String {
+    if text.contains([',', '"', '\n']) {
+        format!("\"{}\"", text.replace('"', "\"\""))
+    } else {
+        text.to_string()
+    }
+}
@@ -0,0 +1,147 @@
+use std::{
+    fs::File,
+    io::{stdout, BufWriter, Write},
+    path::PathBuf,
+};
+
+use anyhow::Result;
+use ardain::Extent;
+use clap::{Args, ValueEnum};
+use serde::Serialize;
+
+use crate::InputData;
+
+#[derive(Args)]
+pub struct MapArgs {
+    /// The output format
+    #[arg(short, long, value_enum, default_value_t = MapFormat::Json)]
+    format: MapFormat,
+    /// Where to write the map (default: standard output)
+    #[arg(short, long)]
+    out: Option<PathBuf>,
+}
+
+#[derive(ValueEnum, Clone, Copy, PartialEq, Eq)]
+enum MapFormat {
+    /// A list of extents with their offsets and owning files
+    Json,
+    /// An image of the ARD file, with one colored bar per extent
+    Svg,
+}
+
+#[derive(Serialize)]
+struct JsonMap<'a> {
+    ard_size: u64,
+    extents: Vec<JsonExtent<'a>>,
+}
+
+#[derive(Serialize)]
+struct JsonExtent<'a> {
+    start: u64,
+    end: u64,
+    #[serde(rename = "type")]
+    ty: &'static str,
+    files: Vec<&'a str>,
+}
+
+/// SVG layout: the ARD file is drawn as a strip wrapped into rows
+const SVG_WIDTH: u64 = 1024;
+const SVG_ROWS: u64 = 128;
+const SVG_ROW_HEIGHT: u64 = 8;
+
+pub fn run(input: &InputData, args: MapArgs) -> Result<()> {
+    let fs = input.load_fs()?;
+    let ard_len = input.open_ard()?.metadata()?.len();
+    let extents: Vec<_> = fs.iter_extents(ard_len).collect();
+
+    let mut out: Box<dyn Write> = match &args.out {
+        Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+        None => Box::new(BufWriter::new(stdout().lock())),
+    };
+    match args.format {
+        MapFormat::Json => write_json(&mut out, &extents, ard_len)?,
+        MapFormat::Svg => write_svg(&mut out, &extents, ard_len)?,
+    }
+    out.flush()?;
+    Ok(())
+}
+
+fn write_json(out: &mut impl Write, extents: &[Extent], ard_len: u64) -> Result<()> {
+    let map = JsonMap {
+        ard_size: ard_len,
+        extents: extents
+            .iter()
+            .map(|extent| JsonExtent {
+                start: extent.range.start,
+                end: extent.range.end,
+                ty: if extent.files.is_empty() {
+                    "free"
+                } else {
+                    "used"
+                },
+                files: extent.files.iter().map(|p| p.as_str()).collect(),
+            })
+            .collect(),
+    };
+    serde_json::to_writer_pretty(&mut *out, &map)?;
+    writeln!(out)?;
+    Ok(())
+}
+
+fn write_svg(out: &mut impl Write, extents: &[Extent], ard_len: u64) -> Result<()> {
+    let bytes_per_row = ard_len.div_ceil(SVG_ROWS).max(1);
+    let rows = ard_len.div_ceil(bytes_per_row).max(1);
+    let (width, height) = (SVG_WIDTH, rows * SVG_ROW_HEIGHT);
+    writeln!(
+        out,
+        r#"<svg xmlns="http://www.w3.org/2000/svg" width="{width}" height="{height}" viewBox="0 0 {width} {height}">"#
+    )?;
+    writeln!(
+        out,
+        r##"<rect width="{width}" height="{height}" fill="#ffffff"/>"##
+    )?;
+    for extent in extents {
+        let color = match extent.files.len() {
+            0 => "#d0d0d0",
+            1 => "#4682b4",
+            // Shared by several files
+            _ => "#2e8b57",
+        };
+        let title = if extent.files.is_empty() {
+            "(free)".to_string()
+        } else {
+            let paths: Vec<_> = extent.files.iter().map(|p| p.as_str()).collect();
+            escape_xml(&paths.join("\n"))
+        };
+        writeln!(out, "<g fill=\"{color}\">")?;
+        writeln!(
+            out,
+            "<title>0x{:X}-0x{:X}\n{title}</title>",
+            extent.range.start, extent.range.end
+        )?;
+        // An extent may span several rows
+        let mut start = extent.range.start;
+        while start < extent.range.end {
+            let row = start / bytes_per_row;
+            let row_end = ((row + 1) * bytes_per_row).min(extent.range.end);
+            let x = (start % bytes_per_row) as f64 / bytes_per_row as f64 * width as f64;
+            let w = (row_end - start) as f64 / bytes_per_row as f64 * width as f64;
+            writeln!(
+                out,
+                r#"<rect x="{x:.2}" y="{}" width="{w:.2}" height="{}"/>"#,
+                row * SVG_ROW_HEIGHT,
+                SVG_ROW_HEIGHT - 1
+            )?;
+            start = row_end;
+        }
+        writeln!(out, "</g>")?;
+    }
+    writeln!(out, "</svg>")?;
+    Ok(())
+}
+
+fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
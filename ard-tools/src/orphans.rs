@@ -0,0 +1,128 @@
+use std::{
+    io::{Read, Seek, SeekFrom},
+    ops::Range,
+};
+
+use anyhow::{anyhow, Result};
+use ardain::{error::Error, find_nested_streams, path::RelativeArhPath};
+use clap::Args;
+use log::{info, warn};
+
+use crate::{file::identify, ls::format_size, InputData};
+
+#[derive(Args)]
+pub struct OrphansArgs {
+    /// Try to identify the contents of each region, by looking for XBC1 streams
+    #[arg(short, long)]
+    identify: bool,
+    /// Mark the regions as free in the block allocation table, so new files can use them
+    #[arg(long)]
+    reclaim: bool,
+    /// Print sizes in a human-readable format
+    #[arg(short = 'H', long)]
+    human_readable: bool,
+    /// Recreate files that were removed with `rm --keep-data`, instead of listing regions.
+    /// The .ard file is not needed.
+    #[arg(long, value_name = "PATH", conflicts_with_all = ["identify", "reclaim"])]
+    recover: Vec<RelativeArhPath>,
+}
+
+pub fn run(input: &InputData, args: OrphansArgs) -> Result<()> {
+    let recover = input.resolve_all(&args.recover)?;
+    let mut fs = input.load_fs()?;
+    if !recover.is_empty() {
+        for path in &recover {
+            fs.recover_file(path).map_err(|e| match e {
+                Error::FsNoEntry => anyhow!("{path}: not a file removed with --keep-data"),
+                e => anyhow!("{path}: {e}"),
+            })?;
+            println!("Recovered {path}");
+        }
+        return input.write_fs(&mut fs);
+    }
+    let mut ard = input.open_ard()?;
+    let ard_len = ard.metadata()?.len();
+
+    let mut orphans = Vec::new();
+    for region in fs.unreferenced_regions(ard_len) {
+        // All-zero regions are just padding between files
+        if is_zeroed(&mut ard, &region)? {
+            continue;
+        }
+        println!(
+            "0x{:X}-0x{:X}  {}",
+            region.start,
+            region.end,
+            format_size(region.end - region.start, args.human_readable)
+        );
+        for orphan in fs
+            .orphaned_files()
+            .iter()
+            .filter(|o| region.contains(&o.meta.offset))
+        {
+            println!(
+                "  0x{:X}: removed file {} ({}), use --recover to restore it",
+                orphan.meta.offset,
+                orphan.path,
+                format_size(orphan.meta.compressed_size.into(), args.human_readable)
+            );
+        }
+        if args.identify {
+            let mut data = vec![0; (region.end - region.start).try_into()?];
+            ard.seek(SeekFrom::Start(region.start))?;
+            ard.read_exact(&mut data)?;
+            let streams = find_nested_streams(&data);
+            if streams.is_empty() {
+                println!("  {}", identify(&data));
+            }
+            for stream in streams {
+                let name = if stream.name.is_empty() {
+                    String::new()
+                } else {
+                    format!(" \"{}\"", stream.name)
+                };
+                println!(
+                    "  0x{:X}: XBC1 stream{name}, {} ({})",
+                    region.start + stream.offset,
+                    identify(&stream.data),
+                    format_size(stream.data.len() as u64, args.human_readable)
+                );
+            }
+        }
+        orphans.push(region);
+    }
+
+    let total: u64 = orphans.iter().map(|r| r.end - r.start).sum();
+    println!(
+        "{} unreferenced regions, {} in total",
+        orphans.len(),
+        format_size(total, args.human_readable)
+    );
+    if args.reclaim && !orphans.is_empty() {
+        info!("Marking {} regions as free", orphans.len());
+        let kept = fs.orphaned_files().len();
+        if kept != 0 {
+            warn!("{kept} files removed with --keep-data can't be recovered anymore");
+        }
+        for region in orphans {
+            fs.free_region(region)?;
+        }
+        input.write_fs(&mut fs)?;
+    }
+    Ok(())
+}
+
+fn is_zeroed(reader: &mut (impl Read + Seek), region: &Range<u64>) -> Result<bool> {
+    let mut buf = vec![0; 1 << 20];
+    reader.seek(SeekFrom::Start(region.start))?;
+    let mut left = region.end - region.start;
+    while left > 0 {
+        let chunk = &mut buf[..usize::try_from(left).unwrap_or(usize::MAX).min(1 << 20)];
+        reader.read_exact(chunk)?;
+        if chunk.iter().any(|&b| b != 0) {
+            return Ok(false);
+        }
+        left -= chunk.len() as u64;
+    }
+    Ok(true)
+}
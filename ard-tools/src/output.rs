@@ -0,0 +1,255 @@
+//! Shared formatting for command output: colors, tables and terminal-width-aware layout.
+
+use std::{
+    borrow::Cow,
+    env,
+    io::{stdout, IsTerminal},
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use clap::ValueEnum;
+use ratatui::crossterm::terminal;
+
+/// When to color the output, see `--color`.
+#[derive(ValueEnum, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ColorChoice {
+    /// If the output is a terminal, and NO_COLOR is not set
+    #[default]
+    Auto,
+    Never,
+    Always,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Style {
+    Plain,
+    Header,
+    Directory,
+    /// Hidden (soft-deleted) files
+    Hidden,
+    Flag,
+    Good,
+    Bad,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Align {
+    Left,
+    Right,
+}
+
+/// A table cell: text, and how it is colored.
+pub struct Cell<'a> {
+    text: Cow<'a, str>,
+    style: Style,
+}
+
+/// Rows of cells, printed in aligned columns.
+#[derive(Default)]
+pub struct Table<'a> {
+    header: Vec<&'static str>,
+    align: Vec<Align>,
+    /// The column that is shortened if the table is wider than the terminal
+    shrink: Option<usize>,
+    rows: Vec<Vec<Cell<'a>>>,
+}
+
+static COLOR: AtomicBool = AtomicBool::new(false);
+
+/// The space between columns
+const GAP: usize = 2;
+
+/// Decides whether output is colored, from `--color` and the environment.
+pub fn init(choice: ColorChoice) {
+    let enabled = match choice {
+        ColorChoice::Always => true,
+        ColorChoice::Never => false,
+        ColorChoice::Auto => {
+            // https://no-color.org
+            stdout().is_terminal() && env::var_os("NO_COLOR").is_none_or(|v| v.is_empty())
+        }
+    };
+    COLOR.store(enabled, Ordering::Relaxed);
+}
+
+/// Returns `text` in the given style, if colors are enabled.
+pub fn paint(text: &str, style: Style) -> Cow<'_, str> {
+    match style.code() {
+        Some(code) if COLOR.load(Ordering::Relaxed) => format!("\x1b[{code}m{text}\x1b[0m").into(),
+        _ => text.into(),
+    }
+}
+
+/// Returns the width of the terminal, or `None` if the output is not a terminal.
+pub fn terminal_width() -> Option<usize> {
+    if !stdout().is_terminal() {
+        return None;
+    }
+    let width = terminal::size().ok().map(|(cols, _)| usize::from(cols));
+    width.filter(|&w| w > 0)
+}
+
+/// Prints names in as many columns as fit in the terminal, filling each column from top to
+/// bottom like `ls`. If the output is not a terminal, names are printed one per line.
+pub fn print_grid(cells: &[Cell]) {
+    let Some(width) = terminal_width() else {
+        for cell in cells {
+            println!("{}", cell.painted());
+        }
+        return;
+    };
+    let Some(column_width) = cells.iter().map(|c| c.text.len() + GAP).max() else {
+        return;
+    };
+    let columns = (width / column_width).max(1);
+    let rows = cells.len().div_ceil(columns);
+    for row in 0..rows {
+        let line: Vec<_> = cells
+            .iter()
+            .skip(row)
+            .step_by(rows)
+            .map(|cell| cell.padded(column_width - GAP, Align::Left))
+            .collect();
+        println!("{}", line.join(&" ".repeat(GAP)).trim_end());
+    }
+}
+
+impl Style {
+    /// The ANSI SGR parameters for the style
+    fn code(self) -> Option<&'static str> {
+        Some(match self {
+            Style::Plain => return None,
+            Style::Header => "1",
+            Style::Directory => "1;34",
+            Style::Hidden => "2",
+            Style::Flag => "33",
+            Style::Good => "32",
+            Style::Bad => "31",
+        })
+    }
+}
+
+impl<'a> Cell<'a> {
+    pub fn new(text: impl Into<Cow<'a, str>>, style: Style) -> Self {
+        Self {
+            text: text.into(),
+            style,
+        }
+    }
+
+    fn painted(&self) -> Cow<'_, str> {
+        paint(&self.text, self.style)
+    }
+
+    /// Pads the cell to `width`, cutting it short with "~" if it is longer.
+    fn padded(&self, width: usize, align: Align) -> String {
+        let text = if self.text.len() > width {
+            let cut = width.saturating_sub(1);
+            Cow::Owned(format!("{}~", &self.text[..cut]))
+        } else {
+            Cow::Borrowed(&*self.text)
+        };
+        let padding = " ".repeat(width - text.len().min(width));
+        let text = paint(&text, self.style);
+        match align {
+            Align::Left => format!("{text}{padding}"),
+            Align::Right => format!("{padding}{text}"),
+        }
+    }
+}
+
+impl<'a> From<&'a str> for Cell<'a> {
+    fn from(text: &'a str) -> Self {
+        Self::new(text, Style::Plain)
+    }
+}
+
+impl From<String> for Cell<'_> {
+    fn from(text: String) -> Self {
+        Self::new(text, Style::Plain)
+    }
+}
+
+impl<'a> From<Cow<'a, str>> for Cell<'a> {
+    fn from(text: Cow<'a, str>) -> Self {
+        Self::new(text, Style::Plain)
+    }
+}
+
+impl<'a> Table<'a> {
+    /// A table with the given column names, printed above a line of dashes. Without
+    /// names, only the rows are printed.
+    pub fn new(header: &[&'static str]) -> Self {
+        Self {
+            header: header.to_vec(),
+            ..Default::default()
+        }
+    }
+
+    /// Aligns the given columns to the right, e.g. for numbers.
+    pub fn align_right(mut self, columns: impl IntoIterator<Item = usize>) -> Self {
+        for i in columns {
+            if self.align.len() <= i {
+                self.align.resize(i + 1, Align::Left);
+            }
+            self.align[i] = Align::Right;
+        }
+        self
+    }
+
+    /// Shortens the given column if the table doesn't fit in the terminal.
+    pub fn shrink(mut self, column: usize) -> Self {
+        self.shrink = Some(column);
+        self
+    }
+
+    pub fn push_row<C: Into<Cell<'a>>>(&mut self, row: impl IntoIterator<Item = C>) {
+        self.rows.push(row.into_iter().map(Into::into).collect());
+    }
+
+    pub fn print(self) {
+        let mut rows = Vec::new();
+        if !self.header.is_empty() {
+            let style = |text: Cow<'a, str>| Cell::new(text, Style::Header);
+            rows.push(self.header.iter().map(|&h| style(h.into())).collect());
+            rows.push(
+                self.header
+                    .iter()
+                    .map(|h| style("-".repeat(h.len()).into()))
+                    .collect(),
+            );
+        }
+        rows.extend(self.rows);
+
+        let mut widths: Vec<usize> = Vec::new();
+        for row in &rows {
+            for (i, cell) in row.iter().enumerate() {
+                match widths.get_mut(i) {
+                    Some(width) => *width = (*width).max(cell.text.len()),
+                    None => widths.push(cell.text.len()),
+                }
+            }
+        }
+        if let (Some(column), Some(max)) = (self.shrink, terminal_width()) {
+            let total = widths.iter().sum::<usize>() + GAP * widths.len().saturating_sub(1);
+            if let Some(width) = widths.get_mut(column) {
+                // Keep at least a few characters
+                *width = width
+                    .saturating_sub(total.saturating_sub(max))
+                    .max(8.min(*width));
+            }
+        }
+
+        for row in &rows {
+            let line: Vec<_> = row
+                .iter()
+                .enumerate()
+                .map(|(i, cell)| {
+                    let align = self.align.get(i).copied().unwrap_or(Align::Left);
+                    cell.padded(widths[i], align)
+                })
+                .collect();
+            println!("{}", line.join(&" ".repeat(GAP)).trim_end());
+        }
+    }
+}
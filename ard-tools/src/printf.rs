@@ -0,0 +1,101 @@
+//! Format strings for `--printf`, to print file attributes in a custom layout.
+
+use anyhow::{bail, Result};
+use ardain::{path::ArhPath, FileMeta};
+
+use crate::ls::get_flags_display;
+
+/// A parsed `--printf` format string.
+#[derive(Clone, Debug)]
+pub struct Template {
+    pieces: Vec<Piece>,
+}
+
+#[derive(Clone, Debug)]
+enum Piece {
+    Text(String),
+    /// `%p`
+    Path,
+    /// `%s`: the size after decompression
+    Size,
+    /// `%S`: the size in the ARD file
+    StoredSize,
+    /// `%o`: the offset in the ARD file, in hex
+    Offset,
+    /// `%i`
+    Id,
+    /// `%f`: the same letters as `ls`
+    Flags,
+}
+
+impl Template {
+    /// Parses a format string. `%%`, `\n`, `\t` and `\\` stand for the characters they are
+    /// usually escapes for.
+    pub fn parse(s: &str) -> Result<Self> {
+        let mut pieces = Vec::new();
+        let mut text = String::new();
+        let mut chars = s.chars();
+        while let Some(c) = chars.next() {
+            let field = match c {
+                '%' => match chars.next() {
+                    Some('%') => {
+                        text.push('%');
+                        continue;
+                    }
+                    Some('p') => Piece::Path,
+                    Some('s') => Piece::Size,
+                    Some('S') => Piece::StoredSize,
+                    Some('o') => Piece::Offset,
+                    Some('i') => Piece::Id,
+                    Some('f') => Piece::Flags,
+                    Some(other) => {
+                        bail!("unknown field %{other}, expected one of %p %s %S %o %i %f")
+                    }
+                    None => bail!("the format ends with a lone %"),
+                },
+                '\\' => {
+                    text.push(match chars.next() {
+                        Some('n') => '\n',
+                        Some('t') => '\t',
+                        Some('\\') => '\\',
+                        Some(other) => bail!("unknown escape \\{other}"),
+                        None => bail!("the format ends with a lone \\"),
+                    });
+                    continue;
+                }
+                c => {
+                    text.push(c);
+                    continue;
+                }
+            };
+            if !text.is_empty() {
+                pieces.push(Piece::Text(std::mem::take(&mut text)));
+            }
+            pieces.push(field);
+        }
+        if !text.is_empty() {
+            pieces.push(Piece::Text(text));
+        }
+        Ok(Self { pieces })
+    }
+
+    /// Formats the attributes of a file, or of a directory if `file` is `None`. Fields that
+    /// directories don't have are printed as `-`.
+    pub fn render(&self, path: &ArhPath, file: Option<&FileMeta>) -> String {
+        let mut out = String::new();
+        for piece in &self.pieces {
+            let field = match (piece, file) {
+                (Piece::Text(text), _) => text.clone(),
+                (Piece::Path, _) => path.to_string(),
+                (Piece::Size, Some(file)) => file.actual_size().to_string(),
+                (Piece::StoredSize, Some(file)) => file.compressed_size.to_string(),
+                (Piece::Offset, Some(file)) => format!("{:X}", file.offset),
+                (Piece::Id, Some(file)) => file.id.to_string(),
+                (Piece::Flags, Some(file)) => get_flags_display(file),
+                (_, None) => "-".to_string(),
+            };
+            out.push_str(&field);
+        }
+        out
+    }
+}
@@ -0,0 +1,124 @@
+use std::{
+    io::{stdout, Write},
+    num::NonZeroUsize,
+    sync::Mutex,
+    time::Instant,
+};
+
+use ardain::{path::ArhPath, visit::ParallelVisitor};
+use clap::{Args, ValueEnum};
+use serde_json::json;
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ProgressFormat {
+    /// Newline-delimited JSON events on stdout
+    Json,
+}
+
+/// Options for commands that read many files in parallel.
+#[derive(Args, Default)]
+pub struct ParallelArgs {
+    /// The number of files to read at the same time (default: one per CPU). Lower values
+    /// can be faster on slow storage, e.g. HDDs or network shares.
+    #[arg(short = 'j', long, value_name = "N", value_parser = clap::value_parser!(u16).range(1..))]
+    pub threads: Option<u16>,
+    /// Report progress in the given format
+    #[arg(long, value_name = "FORMAT")]
+    pub progress: Option<ProgressFormat>,
+}
+
+/// Reports progress for long-running commands.
+///
+/// Events are emitted as they happen, so this can be shared between threads.
+pub struct Progress {
+    format: Option<ProgressFormat>,
+    start: Instant,
+    total_files: usize,
+    total_bytes: u64,
+    done: Mutex<(usize, u64)>,
+}
+
+impl Progress {
+    pub fn new(format: Option<ProgressFormat>, total_files: usize, total_bytes: u64) -> Self {
+        let progress = Self {
+            format,
+            start: Instant::now(),
+            total_files,
+            total_bytes,
+            done: Mutex::new((0, 0)),
+        };
+        progress.emit(json!({
+            "event": "start",
+            "total_files": total_files,
+            "total_bytes": total_bytes,
+        }));
+        progress
+    }
+
+    /// Whether progress is reported at all. Commands should avoid printing anything else
+    /// to stdout if this is `true`.
+    pub fn is_enabled(&self) -> bool {
+        self.format.is_some()
+    }
+
+    pub fn file_done(&self, path: &ArhPath, bytes: u64) {
+        if !self.is_enabled() {
+            return;
+        }
+        // Hold the lock while printing, so events are written in order
+        let mut done = self.done.lock().unwrap();
+        done.0 += 1;
+        done.1 += bytes;
+        let (files_done, bytes_done) = *done;
+        let elapsed = self.start.elapsed().as_secs_f64();
+        let eta = (bytes_done > 0).then(|| {
+            elapsed * self.total_bytes.saturating_sub(bytes_done) as f64 / bytes_done as f64
+        });
+        self.emit(json!({
+            "event": "file",
+            "path": path.as_str(),
+            "files_done": files_done,
+            "total_files": self.total_files,
+            "bytes_done": bytes_done,
+            "total_bytes": self.total_bytes,
+            "eta_secs": eta,
+        }));
+    }
+
+    pub fn finish(&self) {
+        let (files_done, bytes_done) = *self.done.lock().unwrap();
+        self.emit(json!({
+            "event": "done",
+            "files_done": files_done,
+            "bytes_done": bytes_done,
+            "elapsed_secs": self.start.elapsed().as_secs_f64(),
+        }));
+    }
+
+    fn emit(&self, event: serde_json::Value) {
+        match self.format {
+            Some(ProgressFormat::Json) => {
+                let mut out = stdout().lock();
+                // Nothing to do if stdout is closed
+                let _ = writeln!(out, "{event}");
+                let _ = out.flush();
+            }
+            None => {}
+        }
+    }
+}
+
+impl ParallelArgs {
+    /// A visitor that reads files in offset order, with the chosen number of threads.
+    pub fn visitor(&self) -> ParallelVisitor {
+        let visitor = ParallelVisitor::new();
+        match self.threads.and_then(|n| NonZeroUsize::new(n.into())) {
+            Some(threads) => visitor.threads(threads),
+            None => visitor,
+        }
+    }
+
+    pub fn progress(&self, total_files: usize, total_bytes: u64) -> Progress {
+        Progress::new(self.progress, total_files, total_bytes)
+    }
+}
@@ -0,0 +1,533 @@
+//! Editable on-disk projects: the files of an archive as a directory tree, with the metadata
+//! needed to pack them back into the same archive.
+//!
+//! A project directory contains:
+//! - `files/`, the decompressed contents of every file (including hidden files)
+//! - `stored/<ID>`, the data of compressed files as it was stored in the ARD file, so
+//!   unchanged files don't have to be recompressed
+//! - `original.arh`, the .arh file the project was unpacked from, whose path dictionary is
+//!   kept as long as no files are added or removed
+//! - `project.json`, the manifest entry of every file (ID, location, flags and hash)
+
+use std::{
+    collections::BTreeMap,
+    fs::{self, File},
+    io::{BufReader, BufWriter, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use ardain::{
+    file_alloc::{ArdFileAllocator, CompressionStrategy},
+    opts::ArhOptions,
+    path::{ArhPath, PathCase},
+    ArdWriter, ArhFileSystem, CompressionType, FileFlag, FileMeta, ReadOrder, Xbc1Info,
+};
+use binrw::BinRead;
+use clap::Args;
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    hash::Hasher,
+    manifest::ManifestEntry,
+    progress::{Progress, ProgressFormat},
+    InputData,
+};
+
+#[derive(Args)]
+pub struct UnpackArgs {
+    /// The project directory to create
+    dir: PathBuf,
+    /// Write into the directory even if it is not empty
+    #[arg(short, long)]
+    force: bool,
+}
+
+#[derive(Args)]
+pub struct PackArgs {
+    /// A project directory written by `unpack-project`. The archive is written to --arh (or
+    /// --out-arh) and --ard.
+    dir: PathBuf,
+    /// Overwrite the output files if they already exist
+    #[arg(short, long)]
+    force: bool,
+    /// Report progress in the given format
+    #[arg(long, value_name = "FORMAT")]
+    progress: Option<ProgressFormat>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Project {
+    /// The length of the ARD file, which can end with padding after the last file
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    ard_len: Option<u64>,
+    /// Every file of the archive when it was unpacked, in ID order
+    files: Vec<ManifestEntry>,
+}
+
+const PROJECT_FILE: &str = "project.json";
+const FILES_DIR: &str = "files";
+const STORED_DIR: &str = "stored";
+const ORIGINAL_ARH: &str = "original.arh";
+/// Holds files created to keep IDs in place, which are deleted before the archive is saved
+const FILLER_DIR: &str = "/pack-project-filler";
+
+pub fn run_unpack(input: &InputData, args: UnpackArgs) -> Result<()> {
+    let fs = input.load_fs()?;
+    let is_empty = fs::read_dir(&args.dir).map_or(true, |mut d| d.next().is_none());
+    if !args.force && !is_empty {
+        bail!(
+            "{} is not empty, use --force to write into it",
+            args.dir.display()
+        );
+    }
+    let mut files = crate::expand_paths(&fs, &[ArhPath::default()], true)?;
+    input.require_ard(&files)?;
+    let mut ard = input.load_ard()?;
+    let ard_len = ard.get_mut().seek(SeekFrom::End(0))?;
+    ReadOrder::Offset.sort_files(&fs, &mut files);
+    fs::create_dir_all(&args.dir)?;
+    if let Some(arh) = original_arh(input)? {
+        fs::write(args.dir.join(ORIGINAL_ARH), arh)?;
+    }
+
+    let hasher = Hasher::default();
+    let mut entries = Vec::with_capacity(files.len());
+    for path in &files {
+        debug!("Unpacking {path}");
+        let meta = fs.get_file_info(path).unwrap();
+        let data = ard.entry(meta).read()?;
+        let out = project_path(&args.dir.join(FILES_DIR), path)?;
+        if let Some(parent) = out.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(&out, &data)?;
+        if meta.uncompressed_size != 0 {
+            // Read the entry as-is instead of decompressing it
+            let extent = FileMeta::with_extent(meta.offset, meta.compressed_size, 0, [])?;
+            let stored = ard.entry(&extent).read()?;
+            fs::create_dir_all(args.dir.join(STORED_DIR))?;
+            fs::write(args.dir.join(STORED_DIR).join(meta.id.to_string()), stored)?;
+        }
+        entries.push(ManifestEntry::new(path, meta, Some(hasher.digest(&data))));
+    }
+    entries.sort_by_key(|e| e.id);
+
+    let mut writer = BufWriter::new(File::create(args.dir.join(PROJECT_FILE))?);
+    let project = Project {
+        ard_len: Some(ard_len),
+        files: entries,
+    };
+    serde_json::to_writer_pretty(&mut writer, &project)?;
+    writeln!(writer)?;
+    writer.flush()?;
+    println!(
+        "Unpacked {} files to {}",
+        files.len(),
+        args.dir.join(FILES_DIR).display()
+    );
+    Ok(())
+}
+
+pub fn run_pack(input: &InputData, args: PackArgs) -> Result<()> {
+    if input.session.is_some() {
+        bail!("archives can't be built in batch mode");
+    }
+    let arh_path = input
+        .out_arh
+        .as_ref()
+        .or(input.in_arh.as_ref())
+        .ok_or_else(|| anyhow!("output .arh must be passed in as --arh or --out-arh"))?;
+    let ard_path = input
+        .in_ard
+        .as_ref()
+        .ok_or_else(|| anyhow!("output .ard must be passed in as --ard"))?;
+    if input.read_only {
+        return Err(ardain::error::Error::ReadOnly.into());
+    }
+    for path in [arh_path, ard_path] {
+        if !args.force && Path::new(path).exists() {
+            bail!("{path} already exists, use --force to overwrite it");
+        }
+    }
+
+    let project_file = args.dir.join(PROJECT_FILE);
+    let project: Project = serde_json::from_reader(BufReader::new(
+        File::open(&project_file).with_context(|| project_file.display().to_string())?,
+    ))
+    .with_context(|| format!("{}: invalid project file", project_file.display()))?;
+    let mut on_disk: BTreeMap<ArhPath, PathBuf> =
+        crate::apply_mod::mod_files(&args.dir.join(FILES_DIR))?
+            .into_iter()
+            .map(|(file, path)| (path, file))
+            .collect();
+
+    let mut kept = Vec::new();
+    for entry in &project.files {
+        let path = ArhPath::normalize_with(&entry.path, PathCase::Preserve)
+            .map_err(|e| anyhow!("{}: {e}", entry.path))?;
+        let Some(file) = on_disk.remove(&path) else {
+            info!("{path}: removed from the project");
+            continue;
+        };
+        kept.push((path, file, entry));
+    }
+    // Files that were added to the project come after the original ones
+    let added: Vec<_> = on_disk.into_iter().collect();
+    for (path, _) in &added {
+        info!("{path}: added to the project");
+    }
+
+    let opts = ArhOptions {
+        game: input.game.unwrap_or_default(),
+        strict_paths: input.strict_paths,
+        path_case: input.path_case(),
+        ..Default::default()
+    };
+    // If the project still has the same paths and IDs, the original dictionary and string
+    // table are kept. Otherwise, the archive is created from scratch.
+    let original = args.dir.join(ORIGINAL_ARH);
+    let original_fs = if kept.len() == project.files.len() && added.is_empty() && original.exists()
+    {
+        let reader = BufReader::new(File::open(&original)?);
+        Some(
+            ArhFileSystem::load_with_options(reader, opts.clone())
+                .map_err(|e| anyhow!("{}: {e}", original.display()))?,
+        )
+    } else {
+        None
+    };
+    let original_fs = original_fs.filter(|fs| {
+        kept.iter()
+            .all(|(path, _, entry)| fs.get_file_info(path).is_some_and(|m| m.id == entry.id))
+    });
+    let keeps_dictionary = original_fs.is_some();
+    let mut fillers = Vec::new();
+    let mut fs = match original_fs {
+        Some(fs) => fs,
+        None => create_files(ArhFileSystem::new(opts), &kept, &added, &mut fillers)?,
+    };
+    let mut flags_changed = false;
+    if keeps_dictionary {
+        for (path, _, entry) in &kept {
+            let hidden = entry.flags.split('|').any(|f| f == "hidden");
+            let meta = fs.get_file_info_mut(path)?;
+            if meta.is_flag(FileFlag::Hidden) != hidden {
+                meta.set_flag(FileFlag::Hidden, hidden);
+                flags_changed = true;
+            }
+        }
+    }
+
+    let total_bytes = kept
+        .iter()
+        .map(|(_, file, _)| file)
+        .chain(added.iter().map(|(_, file)| file))
+        .map(|file| Ok(fs::metadata(file)?.len()))
+        .sum::<Result<u64>>()?;
+    let progress = Progress::new(args.progress, kept.len() + added.len(), total_bytes);
+    let mut writer = ArdWriter::new(BufWriter::new(File::create(ard_path)?));
+    // Unchanged files go back where they were, with the data they were stored with
+    kept.sort_by_key(|(_, _, entry)| entry.offset);
+    let mut changed = Vec::new();
+    for (path, file, entry) in kept {
+        let data = fs::read(&file)?;
+        let unchanged = entry.size as usize == data.len()
+            && entry
+                .hash
+                .as_ref()
+                .is_some_and(|hash| hash.hasher().digest(&data) == *hash);
+        if !unchanged {
+            debug!("{path}: changed");
+            let strategy = stored_like(&args.dir, entry)?;
+            changed.push((path, data, strategy));
+            continue;
+        }
+        let size = data.len() as u64;
+        let stored = if entry.uncompressed_size != 0 {
+            let stored_file = args.dir.join(STORED_DIR).join(entry.id.to_string());
+            fs::read(&stored_file).with_context(|| stored_file.display().to_string())?
+        } else {
+            data
+        };
+        if stored.len() != entry.compressed_size as usize {
+            bail!("{path}: stored data doesn't match the project file");
+        }
+        let extent = entry.file_meta();
+        writer.extend_to(extent.offset + u64::from(extent.compressed_size))?;
+        writer.entry(extent.offset)?.write_all(&stored)?;
+        // The original archive already has this extent, and a new one has no extension
+        // section yet (the allocator builds it from the file table later), so the extent can
+        // be set directly instead of through set_file_extent, which scans the file table for
+        // every file.
+        let meta = fs.get_file_info_mut(&path)?;
+        let old = *meta;
+        meta.offset = extent.offset;
+        meta.compressed_size = extent.compressed_size;
+        meta.uncompressed_size = extent.uncompressed_size;
+        meta.set_flag(
+            FileFlag::HasXbc1Header,
+            extent.is_flag(FileFlag::HasXbc1Header),
+        );
+        let new = *meta;
+        fs.update_file_stats(&path, &old, &new);
+        progress.file_done(&path, size);
+    }
+    let changed_count = changed.len();
+    for (path, file) in &added {
+        changed.push((path.clone(), fs::read(file)?, CompressionStrategy::Best));
+    }
+    for (path, data, strategy) in changed {
+        let id = fs.get_file_info(&path).unwrap().id;
+        let mut allocator = ArdFileAllocator::new(&mut fs, &mut writer);
+        if keeps_dictionary {
+            // The file still has its old extent, which can be reused
+            allocator.replace_file(id, &data, strategy)?;
+        } else {
+            allocator.write_new_file(id, &data, strategy)?;
+        }
+        progress.file_done(&path, data.len() as u64);
+    }
+    for filler in &fillers {
+        fs.delete_file(filler)?;
+    }
+    if !fillers.is_empty() {
+        fs.delete_empty_dir(&ArhPath::normalize(FILLER_DIR)?)?;
+    }
+
+    writer.trim()?;
+    writer.get_mut().flush()?;
+    let unedited = keeps_dictionary && !flags_changed && changed_count == 0;
+    if unedited {
+        // Keep the padding at the end, so the .ard file is the same as the original
+        let ard_file = writer.get_mut().get_mut();
+        let len = ard_file.metadata()?.len();
+        if let Some(original_len) = project.ard_len.filter(|&l| l > len) {
+            ard_file.set_len(original_len)?;
+        }
+    }
+    drop(writer);
+    info!("Writing {arh_path}");
+    if unedited {
+        // Syncing would write the tables without encryption
+        fs::copy(&original, arh_path)?;
+    } else {
+        fs.sync(BufWriter::new(File::create(arh_path)?))?;
+    }
+    progress.finish();
+    if progress.is_enabled() {
+        return Ok(());
+    }
+
+    let stats = fs.get_dir(&ArhPath::default()).unwrap().stats;
+    println!(
+        "Packed {} files ({changed_count} changed, {} added)",
+        stats.file_count,
+        added.len()
+    );
+    Ok(())
+}
+
+/// Creates the files of the project in an empty archive.
+///
+/// Files are created in ID order, so they get their old IDs. Removed files leave a gap,
+/// which is filled with files that are added to `fillers`, to be deleted at the end. Files
+/// that were added to the project come after the original ones.
+fn create_files(
+    mut fs: ArhFileSystem,
+    kept: &[(ArhPath, PathBuf, &ManifestEntry)],
+    added: &[(ArhPath, PathBuf)],
+    fillers: &mut Vec<ArhPath>,
+) -> Result<ArhFileSystem> {
+    let mut next_id = 0;
+    for (path, _, entry) in kept {
+        while next_id < entry.id {
+            let filler = ArhPath::normalize(format!("{FILLER_DIR}/{next_id}"))?;
+            fs.create_file(&filler)?;
+            fillers.push(filler);
+            next_id += 1;
+        }
+        let meta = fs.create_file(path).map_err(|e| anyhow!("{path}: {e}"))?;
+        meta.set_flag(
+            FileFlag::Hidden,
+            entry.flags.split('|').any(|f| f == "hidden"),
+        );
+        if meta.id != entry.id {
+            warn!("{path}: ID changed from {} to {}", entry.id, meta.id);
+        }
+        next_id = meta.id + 1;
+    }
+    for (path, _) in added {
+        fs.create_file(path).map_err(|e| anyhow!("{path}: {e}"))?;
+    }
+    Ok(fs)
+}
+
+/// Returns the contents of the input .arh file, or `None` in batch mode, where the archive
+/// may have changed since it was loaded.
+fn original_arh(input: &InputData) -> Result<Option<Vec<u8>>> {
+    match (&input.session, &input.in_arh) {
+        (None, Some(path)) if crate::is_url(path) => Ok(Some(crate::download(path)?)),
+        (None, Some(path)) => Ok(Some(fs::read(path)?)),
+        _ => Ok(None),
+    }
+}
+
+/// Returns how to store a changed file like it was stored when the project was unpacked:
+/// with the same compression, in a XBC1 header without compression, or raw.
+fn stored_like(dir: &Path, entry: &ManifestEntry) -> Result<CompressionStrategy> {
+    if entry.uncompressed_size != 0 {
+        let stored_file = dir.join(STORED_DIR).join(entry.id.to_string());
+        let mut stored = BufReader::new(
+            File::open(&stored_file).with_context(|| stored_file.display().to_string())?,
+        );
+        let header = Xbc1Info::read(&mut stored)
+            .with_context(|| format!("{}: invalid XBC1 header", stored_file.display()))?;
+        return Ok(CompressionStrategy::Standard(header.compression_type));
+    }
+    if entry.flags.split('|').any(|f| f == "xbc1") {
+        return Ok(CompressionStrategy::Standard(CompressionType::Uncompressed));
+    }
+    Ok(CompressionStrategy::None)
+}
+
+/// Where a file is written inside the project's `files` directory.
+fn project_path(root: &Path, path: &ArhPath) -> Result<PathBuf> {
+    crate::join_output_path(root, path.trim_start_matches('/'))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use ardain::{path::ArhPath, FileFlag};
+
+    use super::{run_pack, run_unpack, PackArgs, UnpackArgs, FILES_DIR};
+    use crate::InputData;
+
+    const RES: &str = "../ardain/tests/res";
+
+    fn input(arh: String, ard: String) -> InputData {
+        InputData {
+            in_arh: Some(arh),
+            in_ard: Some(ard),
+            out_arh: None,
+            game: None,
+            read_only: false,
+            strict_paths: false,
+            preserve_case: false,
+            cwd: None,
+            session: None,
+        }
+    }
+
+    #[test]
+    fn pack_roundtrip() {
+        let dir = std::env::temp_dir().join(format!("ard-tools-project-{}", std::process::id()));
+        let project = dir.join("project");
+        let (arh, ard) = (
+            format!("{RES}/bf3_dlc04.arh"),
+            format!("{RES}/bf3_dlc04.ard"),
+        );
+        let (out_arh, out_ard) = (
+            dir.join("out.arh").display().to_string(),
+            dir.join("out.ard").display().to_string(),
+        );
+        let original = input(arh, ard.clone());
+        run_unpack(
+            &original,
+            UnpackArgs {
+                dir: project.clone(),
+                force: false,
+            },
+        )
+        .unwrap();
+
+        // Change a compressed file, which must stay compressed
+        let original_fs = original.load_fs().unwrap();
+        let files = crate::expand_paths(&original_fs, &[ArhPath::default()], true).unwrap();
+        let changed = files
+            .iter()
+            .find(|p| original_fs.get_file_info(p).unwrap().uncompressed_size != 0)
+            .unwrap();
+        let changed_file = super::project_path(&project.join(FILES_DIR), changed).unwrap();
+        let mut data = fs::read(&changed_file).unwrap();
+        data.extend_from_slice(b"changed");
+        fs::write(&changed_file, &data).unwrap();
+
+        let packed = input(out_arh.clone(), out_ard.clone());
+        run_pack(
+            &packed,
+            PackArgs {
+                dir: project,
+                force: false,
+                progress: None,
+            },
+        )
+        .unwrap();
+
+        let packed_fs = packed.load_fs().unwrap();
+        let original_ard = fs::read(&ard).unwrap();
+        let packed_ard = fs::read(&out_ard).unwrap();
+        for path in &files {
+            let old = original_fs.get_file_info(path).unwrap();
+            let new = packed_fs.get_file_info(path).unwrap();
+            if path == changed {
+                assert_eq!(new.id, old.id);
+                assert_ne!(new.uncompressed_size, 0);
+                assert_eq!(
+                    new.is_flag(FileFlag::HasXbc1Header),
+                    old.is_flag(FileFlag::HasXbc1Header)
+                );
+                continue;
+            }
+            assert_eq!(new, old, "{path}");
+            let range = old.offset as usize..(old.offset + u64::from(old.compressed_size)) as usize;
+            assert_eq!(packed_ard[range.clone()], original_ard[range], "{path}");
+        }
+        let mut ard = packed.load_ard().unwrap();
+        let meta = packed_fs.get_file_info(changed).unwrap();
+        assert_eq!(ard.entry(meta).read().unwrap(), data);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn pack_unedited() {
+        let dir =
+            std::env::temp_dir().join(format!("ard-tools-project-unedited-{}", std::process::id()));
+        let project = dir.join("project");
+        let (arh, ard) = (
+            format!("{RES}/bf3_dlc04.arh"),
+            format!("{RES}/bf3_dlc04.ard"),
+        );
+        let (out_arh, out_ard) = (
+            dir.join("out.arh").display().to_string(),
+            dir.join("out.ard").display().to_string(),
+        );
+        run_unpack(
+            &input(arh.clone(), ard.clone()),
+            UnpackArgs {
+                dir: project.clone(),
+                force: false,
+            },
+        )
+        .unwrap();
+        run_pack(
+            &input(out_arh.clone(), out_ard.clone()),
+            PackArgs {
+                dir: project,
+                force: false,
+                progress: None,
+            },
+        )
+        .unwrap();
+
+        // Compared without assert_eq, which would print every byte
+        assert!(fs::read(&out_arh).unwrap() == fs::read(&arh).unwrap());
+        assert!(fs::read(&out_ard).unwrap() == fs::read(&ard).unwrap());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
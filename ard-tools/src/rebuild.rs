@@ -0,0 +1,28 @@
+use anyhow::Result;
+use clap::Args;
+
+use crate::{ls::format_size, InputData};
+
+#[derive(Args)]
+pub struct RebuildArgs {
+    /// Print sizes in a human-readable format
+    #[arg(short = 'H', long)]
+    human_readable: bool,
+}
+
+pub fn run(input: &InputData, args: RebuildArgs) -> Result<()> {
+    let mut fs = input.load_fs()?;
+    let rebuild = fs.rebuild_dictionary()?;
+    println!(
+        "Dictionary nodes: {} -> {}",
+        rebuild.old_node_count, rebuild.new_node_count
+    );
+    println!(
+        "String table: {} -> {}",
+        format_size(rebuild.old_string_table_len as u64, args.human_readable),
+        format_size(rebuild.new_string_table_len as u64, args.human_readable)
+    );
+    input.write_fs(&mut fs)?;
+    println!("Rebuilt the dictionary with {} files", rebuild.file_count);
+    Ok(())
+}
@@ -1,19 +1,27 @@
-use anyhow::{anyhow, Result};
-use ardain::{path::ArhPath, ArhFileSystem, DirEntry, FileFlag};
-use clap::{ArgGroup, Args};
+use std::io::{BufWriter, Seek, Write};
 
-use crate::InputData;
+use anyhow::{anyhow, bail, Result};
+use ardain::{
+    file_alloc::ArdFileAllocator,
+    path::{ArhPath, PathFilter, RelativeArhPath, ARH_PATH_ROOT},
+    snapshot::Snapshot,
+    ArdWriter, ArhFileSystem, FileFlag,
+};
+use clap::{ArgGroup, Args, ValueEnum};
+use log::{debug, info, warn};
+
+use crate::{errors::ErrorKind, flags::FilterArgs, InputData};
 
 #[derive(Args)]
 #[clap(group(
     ArgGroup::new("my-group")
         .required(false)
-        .args(&["soft", "restore"]),
+        .args(&["soft", "restore", "scrub", "keep_data"]),
 ))]
 pub struct RemoveArgs {
     /// The files or directories to remove
-    #[arg(value_parser = crate::parse_path)]
-    paths: Vec<ArhPath>,
+    #[arg(add = crate::completions::arh_path_completer())]
+    paths: Vec<RelativeArhPath>,
     /// Remove all contents of each directory, including subdirectories. (Required to remove
     /// non-empty directories)
     #[arg(short, long)]
@@ -26,61 +34,193 @@ pub struct RemoveArgs {
     /// operates recursively.
     #[arg(short = 'z', long)]
     restore: bool,
+    #[command(flatten)]
+    filter: FilterArgs,
+    /// Also overwrite the data of removed files with zeros, so it can't be recovered from the
+    /// ARD file (e.g. for personal files that were added by mistake)
+    #[arg(long)]
+    scrub: bool,
+    /// Keep the data of removed files in the ARD file, and record them in the archive's list
+    /// of orphaned files, so they can be recovered with `orphans --recover`
+    #[arg(long)]
+    keep_data: bool,
+    /// Ask before removing each file ("always", the default), or once for each path given
+    /// on the command line ("roots")
+    #[arg(short, long, value_name = "WHEN", num_args = 0..=1, require_equals = true, default_missing_value = "always", conflicts_with = "restore")]
+    interactive: Option<Interactive>,
+    /// Allow removing the root directory, i.e. every file in the archive
+    #[arg(long)]
+    no_preserve_root: bool,
+}
+
+#[derive(ValueEnum, Clone, Copy, PartialEq, Eq)]
+enum Interactive {
+    Always,
+    Roots,
 }
 
 pub fn run(input: &InputData, args: RemoveArgs) -> Result<()> {
+    if !args.filter.is_empty() && !args.soft && !args.restore {
+        bail!("--include and --exclude can only be used with --soft or --restore");
+    }
+    let filter = args.filter.to_filter();
+    let paths = input.resolve_all(&args.paths)?;
     let mut fs = input.load_fs()?;
-    for path in &args.paths {
+    let mut ard = None;
+    if args.scrub {
+        ard = Some(ArdWriter::new(BufWriter::new(input.open_ard_for_write()?)));
+        let ard_path = input.in_ard.as_ref().unwrap();
+        if Snapshot::open(ard_path)?.is_some() {
+            warn!("The archive has a snapshot, scrubbed data stays in it until it is dropped");
+        }
+    }
+    for path in &paths {
+        if *path == ARH_PATH_ROOT && !args.restore && !args.no_preserve_root {
+            bail!(
+                "refusing to remove every file in the archive: use --no-preserve-root to override"
+            );
+        }
+        if args.interactive == Some(Interactive::Roots)
+            && !input.confirm(&format!("remove {}?", describe(&fs, path)))?
+        {
+            continue;
+        }
+        info!("Removing {path}");
         if args.soft {
-            set_hidden_flag(&mut fs, path, true)?;
+            hide(input, &mut fs, &args, &filter, path)?;
         } else if args.restore {
-            set_hidden_flag(&mut fs, path, false)?;
+            set_hidden_flag(&mut fs, path, &filter, false)?;
         } else {
-            delete(&mut fs, &args, path)?;
+            delete(input, &mut fs, &args, path, &mut ard)?;
         }
     }
+    if let Some(ard) = &mut ard {
+        ard.get_mut().flush()?;
+    }
     input.write_fs(&mut fs)?;
     Ok(())
 }
 
-fn delete(fs: &mut ArhFileSystem, args: &RemoveArgs, path: &ArhPath) -> Result<()> {
+fn delete<W: Write + Seek>(
+    input: &InputData,
+    fs: &mut ArhFileSystem,
+    args: &RemoveArgs,
+    path: &ArhPath,
+    ard: &mut Option<ArdWriter<W>>,
+) -> Result<()> {
     if fs.is_file(path) {
-        fs.delete_file(path)?;
+        if confirm_file(input, args, path)? {
+            delete_file(fs, args, path, ard)?;
+        }
     } else if fs.is_dir(path) {
-        let dir = fs.get_dir(path).unwrap();
-        let DirEntry::Directory { children } = &dir.entry else {
-            unreachable!()
-        };
-        if !args.recursive && !children.is_empty() {
+        if !args.recursive && !fs.is_dir_empty(path) {
             return Err(anyhow!(
                 "refusing to delete non-empty directory {path}: use --recursive to empty it first"
             ));
         }
+        let mut kept = 0;
         if args.recursive {
+            let dir = fs.get_dir(path).unwrap();
             for child in dir.children_paths() {
-                fs.delete_file(&path.join(&child))?;
+                let child = path.join(&child);
+                if confirm_file(input, args, &child)? {
+                    delete_file(fs, args, &child, ard)?;
+                } else {
+                    kept += 1;
+                }
             }
         }
-        fs.delete_empty_dir(path)?;
+        if kept == 0 {
+            fs.delete_empty_dir(path)?;
+        } else {
+            info!("{path}: keeping directory, {kept} files were not removed");
+        }
     } else {
-        return Err(anyhow!("{path}: no such file or directory"));
+        return Err(ErrorKind::NotFound.error(format!("{path}: no such file or directory")));
     }
     Ok(())
 }
 
-fn set_hidden_flag(fs: &mut ArhFileSystem, path: &ArhPath, hidden: bool) -> Result<()> {
-    if fs.is_file(path) {
-        fs.get_file_info_mut(path)
-            .unwrap()
-            .set_flag(FileFlag::Hidden, true);
-    } else if fs.is_dir(path) {
-        let dir = fs.get_dir(path).unwrap();
-        for child in dir.children_paths() {
-            let meta = fs.get_file_info_mut(&path.join(&child)).unwrap();
-            meta.set_flag(FileFlag::Hidden, hidden);
+fn delete_file<W: Write + Seek>(
+    fs: &mut ArhFileSystem,
+    args: &RemoveArgs,
+    path: &ArhPath,
+    ard: &mut Option<ArdWriter<W>>,
+) -> Result<()> {
+    // E.g. directory placeholders
+    let has_data = fs
+        .get_file_info(path)
+        .is_some_and(|meta| meta.compressed_size != 0);
+    match ard {
+        Some(ard) if has_data => {
+            if !ArdFileAllocator::new(fs, ard).delete_file_scrub(path)? {
+                warn!("{path}: data is used by other files, not scrubbing it");
+            }
         }
-    } else {
-        return Err(anyhow!("{path}: no such file or directory"));
+        None if args.keep_data && has_data => fs.delete_file_keep_data(path)?,
+        _ => fs.delete_file(path)?,
+    }
+    Ok(())
+}
+
+/// Like [`set_hidden_flag`], asking for each file with `--interactive`.
+fn hide(
+    input: &InputData,
+    fs: &mut ArhFileSystem,
+    args: &RemoveArgs,
+    filter: &PathFilter,
+    path: &ArhPath,
+) -> Result<()> {
+    if args.interactive != Some(Interactive::Always) || !fs.is_dir(path) {
+        if confirm_file(input, args, path)? {
+            set_hidden_flag(fs, path, filter, true)?;
+        }
+        return Ok(());
+    }
+    for child in fs.matching_files(path, filter)? {
+        if confirm_file(input, args, &child)? {
+            fs.get_file_info_mut(&child)?
+                .set_flag(FileFlag::Hidden, true);
+        }
+    }
+    Ok(())
+}
+
+/// Asks whether a single file should be removed, if prompting for each file.
+fn confirm_file(input: &InputData, args: &RemoveArgs, path: &ArhPath) -> Result<bool> {
+    if args.interactive != Some(Interactive::Always) || ArhFileSystem::is_placeholder(path) {
+        return Ok(true);
+    }
+    input.confirm(&format!("remove file {path}?"))
+}
+
+/// Describes a path given on the command line, for `--interactive=roots`.
+fn describe(fs: &ArhFileSystem, path: &ArhPath) -> String {
+    match fs.get_dir(path) {
+        Some(dir) if !fs.is_file(path) => {
+            let count = dir
+                .children_paths()
+                .iter()
+                .filter(|c| !ArhFileSystem::is_placeholder(&path.join(c)))
+                .count();
+            format!("directory {path} ({count} files)")
+        }
+        _ => format!("file {path}"),
+    }
+}
+
+/// Sets or clears the hidden flag of a file, or of the files in a directory that match
+/// `filter`.
+pub(crate) fn set_hidden_flag(
+    fs: &mut ArhFileSystem,
+    path: &ArhPath,
+    filter: &PathFilter,
+    hidden: bool,
+) -> Result<()> {
+    if !fs.exists(path) {
+        return Err(ErrorKind::NotFound.error(format!("{path}: no such file or directory")));
     }
+    let changed = fs.set_flag_matching(path, filter, FileFlag::Hidden, hidden)?;
+    debug!("{path}: changed the hidden flag of {changed} files");
     Ok(())
 }
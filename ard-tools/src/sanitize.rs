@@ -0,0 +1,241 @@
+//! Output paths that are valid on Windows file systems (NTFS, exFAT, FAT32).
+//!
+//! Archive paths can contain names that Windows reserves (e.g. "con.txt"), characters it
+//! doesn't allow, or be too long once joined with the output directory. Such paths are
+//! rewritten instead of failing halfway through an extraction.
+
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Result;
+use ardain::path::ArhPath;
+use sha2::{Digest, Sha256};
+
+/// The longest path that can be used without long path support, excluding the terminating
+/// null (`MAX_PATH` is 260)
+const MAX_PATH_LEN: usize = 259;
+const MAX_NAME_LEN: usize = 255;
+/// Shortened names are at least this long, even if the path is still too long
+const MIN_NAME_LEN: usize = 24;
+const INVALID_CHARS: &str = r#"<>:"\|?*"#;
+const RESERVED_NAMES: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Rewrites output paths inside `out` so they are valid on Windows.
+///
+/// Files whose path is already valid keep it. Renamed files never take the path of another
+/// file: they get a suffix derived from their archive path instead. Returns the renamed
+/// files, with their new path.
+pub fn sanitize_paths(
+    out: &Path,
+    files: &mut [(ArhPath, PathBuf)],
+) -> Result<Vec<(ArhPath, PathBuf)>> {
+    let base_len = path_len(&std::path::absolute(out)?);
+    let mut renamed = Vec::new();
+    let mut changed = Vec::new();
+    let mut taken = HashSet::new();
+    for (i, (path, out_path)) in files.iter_mut().enumerate() {
+        let rel = out_path.strip_prefix(out)?;
+        let components: Vec<_> = rel.iter().map(|c| c.to_string_lossy()).collect();
+        let mut names: Vec<_> = components.iter().map(|c| sanitize_name(c)).collect();
+        shorten_path(base_len, path, &components, &mut names);
+        if names == components {
+            taken.insert(out_path.to_string_lossy().to_lowercase());
+            continue;
+        }
+        *out_path = names.iter().fold(out.to_path_buf(), |p, c| p.join(c));
+        changed.push(i);
+    }
+    for i in changed {
+        let (path, out_path) = &mut files[i];
+        if !taken.insert(out_path.to_string_lossy().to_lowercase()) {
+            let name = out_path.file_name().unwrap().to_string_lossy();
+            let max_len = (utf16_len(&name) + 9).min(MAX_NAME_LEN);
+            let name = with_suffix(&name, path, max_len);
+            out_path.set_file_name(name);
+            taken.insert(out_path.to_string_lossy().to_lowercase());
+        }
+        renamed.push((path.clone(), out_path.clone()));
+    }
+    Ok(renamed)
+}
+
+/// Replaces characters and names that are not allowed in a file name on Windows.
+fn sanitize_name(name: &str) -> String {
+    let mut out: String = name
+        .chars()
+        .map(|c| {
+            if c < ' ' || INVALID_CHARS.contains(c) {
+                '_'
+            } else {
+                c
+            }
+        })
+        .collect();
+    // Windows strips trailing dots and spaces
+    let trimmed = out.trim_end_matches(['.', ' ']).len();
+    if trimmed != out.len() {
+        let padding = "_".repeat(out.len() - trimmed);
+        out.replace_range(trimmed.., &padding);
+    }
+    // Reserved names are also reserved with an extension, e.g. "con.txt"
+    let stem = out.split('.').next().unwrap();
+    if RESERVED_NAMES
+        .iter()
+        .any(|r| r.eq_ignore_ascii_case(stem.trim_end()))
+    {
+        out.insert(stem.len(), '_');
+    }
+    out
+}
+
+/// Shortens names that are too long, and the file name if the whole path is too long.
+///
+/// `components` are the original names, so a directory is always shortened the same way.
+fn shorten_path(
+    base_len: usize,
+    path: &ArhPath,
+    components: &[impl AsRef<str>],
+    names: &mut [String],
+) {
+    let Some((name, dirs)) = names.split_last_mut() else {
+        return;
+    };
+    for (i, dir) in dirs.iter_mut().enumerate() {
+        if utf16_len(dir) > MAX_NAME_LEN {
+            let dir_path: Vec<_> = components[..=i].iter().map(AsRef::as_ref).collect();
+            *dir = with_suffix(dir, &dir_path.join("/"), MAX_NAME_LEN);
+        }
+    }
+    let dirs_len: usize = dirs.iter().map(|d| utf16_len(d) + 1).sum();
+    let max_len = MAX_PATH_LEN
+        .saturating_sub(base_len + 1 + dirs_len)
+        .clamp(MIN_NAME_LEN, MAX_NAME_LEN);
+    if utf16_len(name) > max_len {
+        *name = with_suffix(name, path, max_len);
+    }
+}
+
+/// Adds a short hash of `key` (e.g. the archive path) before the extension, truncating the
+/// name so it is at most `max_len` UTF-16 units long.
+fn with_suffix(name: &str, key: &str, max_len: usize) -> String {
+    let hash = Sha256::digest(key.as_bytes());
+    let suffix = format!(
+        "~{:02x}{:02x}{:02x}{:02x}",
+        hash[0], hash[1], hash[2], hash[3]
+    );
+    let (stem, ext) = match name.rfind('.') {
+        Some(i) if i > 0 && name.len() - i <= 16 => name.split_at(i),
+        _ => (name, ""),
+    };
+    let budget = max_len.saturating_sub(utf16_len(&suffix) + utf16_len(ext));
+    let mut stem_len = 0;
+    let stem: String = stem
+        .chars()
+        .take_while(|c| {
+            stem_len += c.len_utf16();
+            stem_len <= budget
+        })
+        .collect();
+    format!("{stem}{suffix}{ext}")
+}
+
+fn utf16_len(s: &str) -> usize {
+    s.encode_utf16().count()
+}
+
+fn path_len(path: &Path) -> usize {
+    utf16_len(&path.to_string_lossy())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+
+    use ardain::path::ArhPath;
+
+    use super::{path_len, sanitize_name, sanitize_paths, utf16_len, MAX_NAME_LEN, MAX_PATH_LEN};
+
+    /// Sanitizes the output paths of archive paths, relative to `out`. The output path of
+    /// a file may be longer than archive paths can be, e.g. with nested streams.
+    fn sanitize(out: &Path, files: &[(&str, &str)]) -> Vec<PathBuf> {
+        let mut files: Vec<_> = files
+            .iter()
+            .map(|&(path, out_path)| (ArhPath::normalize(path).unwrap(), out.join(out_path)))
+            .collect();
+        sanitize_paths(out, &mut files).unwrap();
+        files.into_iter().map(|(_, out_path)| out_path).collect()
+    }
+
+    #[test]
+    fn reserved_names() {
+        assert_eq!(sanitize_name("con"), "con_");
+        assert_eq!(sanitize_name("CON.txt"), "CON_.txt");
+        assert_eq!(sanitize_name("com1.tar.gz"), "com1_.tar.gz");
+        assert_eq!(sanitize_name("console.txt"), "console.txt");
+        assert_eq!(sanitize_name("a<b>:c|d"), "a_b__c_d");
+    }
+
+    #[test]
+    fn trailing_dots_and_spaces() {
+        assert_eq!(sanitize_name("name."), "name_");
+        assert_eq!(sanitize_name("name. "), "name__");
+        assert_eq!(sanitize_name(".keep"), ".keep");
+        // Not a parent directory on any system
+        assert_eq!(sanitize_name(".."), "__");
+        assert_eq!(sanitize_name("."), "_");
+    }
+
+    #[test]
+    fn collision_suffix() {
+        let out = Path::new("/out");
+        let files = [
+            ("/dir/a:b.txt", "dir/a:b.txt"),
+            ("/dir/a_b.txt", "dir/a_b.txt"),
+            ("/dir/c.txt", "dir/c.txt"),
+        ];
+        let paths = sanitize(out, &files);
+        // Valid paths are kept, renamed ones get a suffix instead of taking another path
+        assert_eq!(paths[1], out.join("dir/a_b.txt"));
+        assert_eq!(paths[2], out.join("dir/c.txt"));
+        let renamed = paths[0].file_name().unwrap().to_str().unwrap();
+        assert!(
+            renamed.starts_with("a_b~") && renamed.ends_with(".txt"),
+            "{renamed}"
+        );
+        // The suffix only depends on the archive path
+        assert_eq!(sanitize(out, &[files[1], files[0]])[1], paths[0]);
+    }
+
+    #[test]
+    fn shorten() {
+        let out = Path::new("/out");
+        let long_name = format!("{}.dds", "n".repeat(300));
+        let paths = sanitize(out, &[("/a.dds", &long_name)]);
+        let name = paths[0].file_name().unwrap().to_str().unwrap();
+        assert!(utf16_len(name) <= MAX_NAME_LEN, "{name}");
+        assert!(name.contains('~') && name.ends_with(".dds"), "{name}");
+
+        // Directories are shortened the same way for every file in them
+        let dir = "d".repeat(300);
+        let paths = sanitize(
+            out,
+            &[
+                ("/a.bin", &format!("{dir}/a.bin")),
+                ("/b.bin", &format!("{dir}/b.bin")),
+            ],
+        );
+        assert_eq!(paths[0].parent(), paths[1].parent());
+        let dir = paths[0].parent().unwrap().file_name().unwrap();
+        assert!(utf16_len(&dir.to_string_lossy()) <= MAX_NAME_LEN);
+
+        // The file name is shortened so the whole path fits
+        let out_path = format!("{}/{}.bin", "d".repeat(200), "n".repeat(100));
+        let path = &sanitize(out, &[("/a.bin", &out_path)])[0];
+        assert!(path_len(path) <= MAX_PATH_LEN, "{}", path.display());
+    }
+}
@@ -0,0 +1,87 @@
+use std::fs::{self, OpenOptions};
+
+use anyhow::{anyhow, bail, Result};
+use ardain::snapshot::Snapshot;
+use clap::{Args, Subcommand};
+use log::{info, warn};
+
+use crate::InputData;
+
+#[derive(Args)]
+pub struct SnapshotArgs {
+    #[command(subcommand)]
+    command: SnapshotCommand,
+}
+
+#[derive(Subcommand)]
+enum SnapshotCommand {
+    /// Record the current state of the archive, replacing any existing snapshot. From now
+    /// on, data overwritten in the ARD file is copied to the snapshot first.
+    Create,
+    /// Show how much data the snapshot holds
+    Status,
+    /// Restore the archive to the state recorded in the snapshot. The snapshot is kept,
+    /// so the archive can be modified and reverted again.
+    Revert,
+    /// Delete the snapshot, keeping all modifications
+    Drop,
+}
+
+pub fn run(input: &InputData, args: SnapshotArgs) -> Result<()> {
+    if input.session.is_some() {
+        bail!("snapshots can't be managed in batch mode");
+    }
+    let ard_path = input
+        .in_ard
+        .as_ref()
+        .ok_or_else(|| anyhow!("input .ard must be passed in as --ard"))?;
+    let dir = Snapshot::dir_for(ard_path);
+    match args.command {
+        SnapshotCommand::Create => {
+            let arh_path = input
+                .in_arh
+                .as_ref()
+                .ok_or_else(|| anyhow!("input .arh must be passed in as --arh"))?;
+            let snapshot = Snapshot::create(ard_path, &fs::read(arh_path)?)?;
+            info!(
+                "Created snapshot in {} (ARD size: {} bytes)",
+                dir.display(),
+                snapshot.ard_len()
+            );
+        }
+        SnapshotCommand::Status => {
+            let snapshot = open(ard_path)?;
+            println!("Snapshot: {}", dir.display());
+            println!("ARD size: {}", snapshot.ard_len());
+            println!("Saved data: {} bytes", snapshot.saved_bytes());
+        }
+        SnapshotCommand::Revert => revert(input, ard_path)?,
+        SnapshotCommand::Drop => {
+            open(ard_path)?.remove()?;
+            info!("Deleted {}", dir.display());
+        }
+    }
+    Ok(())
+}
+
+fn open(ard_path: &str) -> Result<Snapshot> {
+    Snapshot::open(ard_path)?.ok_or_else(|| anyhow!("{ard_path} has no snapshot"))
+}
+
+/// Restores the archive to the state recorded in its snapshot.
+pub(crate) fn revert(input: &InputData, ard_path: &str) -> Result<()> {
+    let Some(path) = input.out_arh.as_ref().or(input.in_arh.as_ref()) else {
+        return Err(anyhow!("input .arh must be passed in as --arh"));
+    };
+    let mut snapshot = open(ard_path)?;
+    let mut ard = OpenOptions::new().read(true).write(true).open(ard_path)?;
+    let restored = snapshot.saved_bytes();
+    let arh = snapshot.revert(&mut ard)?;
+    if input.out_arh.is_none() {
+        warn!("Overwriting input ARH {path} (use --out-arh to write elsewhere)");
+    }
+    info!("Writing {path}");
+    fs::write(path, arh)?;
+    info!("Restored {restored} bytes of ARD data");
+    Ok(())
+}
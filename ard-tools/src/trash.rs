@@ -0,0 +1,105 @@
+use anyhow::Result;
+use ardain::{
+    path::{ArhPath, RelativeArhPath},
+    ArhFileSystem, FileFlag,
+};
+use clap::{Args, Subcommand};
+use log::debug;
+
+use crate::{errors::ErrorKind, flags::FilterArgs, rm::set_hidden_flag, InputData};
+
+#[derive(Args)]
+pub struct TrashArgs {
+    #[command(subcommand)]
+    command: TrashCommand,
+}
+
+#[derive(Subcommand)]
+enum TrashCommand {
+    /// List hidden files
+    List {
+        /// Only list files in these directories (default: /)
+        #[arg(add = crate::completions::arh_path_completer())]
+        paths: Vec<RelativeArhPath>,
+    },
+    /// Remove the hidden flag from files or directories (recursively)
+    Restore {
+        /// The files or directories to restore
+        #[arg(add = crate::completions::arh_path_completer(), required = true)]
+        paths: Vec<RelativeArhPath>,
+        #[command(flatten)]
+        filter: FilterArgs,
+    },
+    /// Permanently delete hidden files
+    Purge {
+        /// Only delete hidden files in these directories (default: /)
+        #[arg(add = crate::completions::arh_path_completer())]
+        paths: Vec<RelativeArhPath>,
+    },
+}
+
+pub fn run(input: &InputData, args: TrashArgs) -> Result<()> {
+    let mut fs = input.load_fs()?;
+    match args.command {
+        TrashCommand::List { paths } => {
+            for path in with_default_root(input.resolve_all(&paths)?) {
+                for file in hidden_files(&fs, &path)? {
+                    println!("{file}");
+                }
+            }
+        }
+        TrashCommand::Restore { paths, filter } => {
+            let filter = filter.to_filter();
+            for path in &input.resolve_all(&paths)? {
+                set_hidden_flag(&mut fs, path, &filter, false)?;
+            }
+            input.write_fs(&mut fs)?;
+        }
+        TrashCommand::Purge { paths } => {
+            let mut purged = 0;
+            for path in with_default_root(input.resolve_all(&paths)?) {
+                for file in hidden_files(&fs, &path)? {
+                    debug!("Deleting {file}");
+                    fs.delete_file(&file)?;
+                    purged += 1;
+                }
+            }
+            input.write_fs(&mut fs)?;
+            println!("Purged {purged} files");
+        }
+    }
+    Ok(())
+}
+
+/// Returns the full paths of all hidden files at or below `path`.
+fn hidden_files(fs: &ArhFileSystem, path: &ArhPath) -> Result<Vec<ArhPath>> {
+    // Directory placeholders are hidden too, but they are not deleted files
+    let is_hidden = |path: &ArhPath| {
+        !ArhFileSystem::is_placeholder(path)
+            && fs
+                .get_file_info(path)
+                .is_some_and(|f| f.is_flag(FileFlag::Hidden))
+    };
+    if fs.is_file(path) {
+        return Ok(is_hidden(path).then(|| path.clone()).into_iter().collect());
+    }
+    let dir = fs
+        .get_dir(path)
+        .ok_or_else(|| ErrorKind::NotFound.error(format!("{path}: no such file or directory")))?;
+    let mut files = dir
+        .children_paths()
+        .into_iter()
+        .map(|child| path.join(&child))
+        .filter(is_hidden)
+        .collect::<Vec<_>>();
+    files.sort();
+    Ok(files)
+}
+
+fn with_default_root(paths: Vec<ArhPath>) -> Vec<ArhPath> {
+    if paths.is_empty() {
+        vec![ArhPath::default()]
+    } else {
+        paths
+    }
+}
@@ -0,0 +1,403 @@
+use std::path::PathBuf;
+
+use anyhow::{anyhow, Result};
+use ardain::{
+    path::{ArhPath, RelativeArhPath},
+    ArdReader, ArhFileSystem, DirEntry, FileFlag,
+};
+use clap::Args;
+use ratatui::{
+    crossterm::event::{self, Event, KeyCode, KeyEventKind},
+    layout::{Constraint, Layout, Rect},
+    style::{Style, Stylize},
+    text::Line,
+    widgets::{Block, List, ListItem, ListState, Paragraph, Wrap},
+    DefaultTerminal, Frame,
+};
+
+use crate::{file::identify, hexdump::hex_lines, ls::format_size, ArdSource, InputData, LoadedFs};
+
+/// How many bytes of each file are loaded for the preview pane
+const PREVIEW_SIZE: u64 = 4096;
+
+#[derive(Args)]
+pub struct TuiArgs {
+    /// The directory to open (default: /)
+    #[arg(add = crate::completions::arh_path_completer())]
+    path: Option<RelativeArhPath>,
+}
+
+struct App<'a> {
+    input: &'a InputData,
+    fs: LoadedFs<'a>,
+    ard: Option<ArdReader<Box<dyn ArdSource>>>,
+    cwd: ArhPath,
+    entries: Vec<Entry>,
+    list: ListState,
+    /// The preview for the last selected file, so it's not read again on every frame
+    preview: Option<(ArhPath, String)>,
+    prompt: Option<Prompt>,
+    status: String,
+    /// Whether the file system was modified and needs to be saved
+    dirty: bool,
+    quit: bool,
+}
+
+struct Entry {
+    name: String,
+    is_dir: bool,
+    hidden: bool,
+}
+
+struct Prompt {
+    kind: PromptKind,
+    buffer: String,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum PromptKind {
+    Extract,
+    Rename,
+    ConfirmDelete,
+    ConfirmSave,
+}
+
+pub fn run(input: &InputData, args: TuiArgs) -> Result<()> {
+    let cwd = match &args.path {
+        Some(path) => input.resolve(path)?,
+        None => ArhPath::default(),
+    };
+    let fs = input.load_fs()?;
+    if !fs.is_dir(&cwd) {
+        return Err(anyhow!("{cwd}: not a directory"));
+    }
+    let mut app = App {
+        input,
+        fs,
+        // The browser is still useful without file contents
+        ard: input.load_ard().ok(),
+        cwd,
+        entries: Vec::new(),
+        list: ListState::default(),
+        preview: None,
+        prompt: None,
+        status: "q: quit | enter: open | backspace: up | x: extract | r: rename | d: delete"
+            .to_string(),
+        dirty: false,
+        quit: false,
+    };
+    app.refresh(None);
+
+    let mut terminal = ratatui::init();
+    let res = app.main_loop(&mut terminal);
+    ratatui::restore();
+    res
+}
+
+impl<'a> App<'a> {
+    fn main_loop(&mut self, terminal: &mut DefaultTerminal) -> Result<()> {
+        while !self.quit {
+            terminal.draw(|frame| self.draw(frame))?;
+            if let Event::Key(key) = event::read()? {
+                if key.kind == KeyEventKind::Press {
+                    self.handle_key(key.code)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Reloads the entries of the current directory, then selects the entry with the given
+    /// name (or keeps the current selection, if possible).
+    fn refresh(&mut self, select: Option<&str>) {
+        let dir = self.fs.get_dir(&self.cwd).unwrap();
+        let DirEntry::Directory { children } = &dir.entry else {
+            unreachable!()
+        };
+        self.entries = children
+            .values()
+            .filter(|node| !ArhFileSystem::is_placeholder(&self.cwd.join(&node.name)))
+            .map(|node| Entry {
+                name: node.name.clone(),
+                is_dir: matches!(node.entry, DirEntry::Directory { .. }),
+                hidden: self
+                    .fs
+                    .get_file_info(&self.cwd.join(&node.name))
+                    .is_some_and(|meta| meta.is_flag(FileFlag::Hidden)),
+            })
+            .collect();
+        // Directories first, like `ls`
+        self.entries.sort_by_key(|e| !e.is_dir);
+        let selected = select
+            .and_then(|name| self.entries.iter().position(|e| e.name == name))
+            .or(self.list.selected())
+            .map(|i| i.min(self.entries.len().saturating_sub(1)));
+        self.list.select(selected.or(Some(0)));
+        self.preview = None;
+    }
+
+    fn selected(&self) -> Option<(&Entry, ArhPath)> {
+        let entry = self.entries.get(self.list.selected()?)?;
+        Some((entry, self.cwd.join(&entry.name)))
+    }
+
+    fn handle_key(&mut self, key: KeyCode) -> Result<()> {
+        if let Some(prompt) = &mut self.prompt {
+            let kind = prompt.kind;
+            match (kind, key) {
+                (_, KeyCode::Esc) => self.prompt = None,
+                (PromptKind::ConfirmDelete | PromptKind::ConfirmSave, KeyCode::Char('n')) => {
+                    self.prompt = None;
+                    self.quit = kind == PromptKind::ConfirmSave;
+                }
+                (PromptKind::ConfirmDelete | PromptKind::ConfirmSave, KeyCode::Char('y'))
+                | (PromptKind::Extract | PromptKind::Rename, KeyCode::Enter) => {
+                    let prompt = self.prompt.take().unwrap();
+                    self.status = match self.submit(prompt) {
+                        Ok(msg) => msg,
+                        Err(e) => format!("Error: {e}"),
+                    };
+                }
+                (PromptKind::Extract | PromptKind::Rename, KeyCode::Backspace) => {
+                    prompt.buffer.pop();
+                }
+                (PromptKind::Extract | PromptKind::Rename, KeyCode::Char(c)) => {
+                    prompt.buffer.push(c)
+                }
+                _ => {}
+            }
+            return Ok(());
+        }
+
+        match key {
+            KeyCode::Char('q') | KeyCode::Esc => {
+                if self.dirty {
+                    self.open_prompt(PromptKind::ConfirmSave, String::new());
+                } else {
+                    self.quit = true;
+                }
+            }
+            KeyCode::Up | KeyCode::Char('k') => self.list.select_previous(),
+            KeyCode::Down | KeyCode::Char('j') => self.list.select_next(),
+            KeyCode::Home | KeyCode::Char('g') => self.list.select_first(),
+            KeyCode::End | KeyCode::Char('G') => self.list.select_last(),
+            KeyCode::PageUp => self.list.scroll_up_by(10),
+            KeyCode::PageDown => self.list.scroll_down_by(10),
+            KeyCode::Enter | KeyCode::Right | KeyCode::Char('l') => {
+                if let Some((entry, path)) = self.selected() {
+                    if entry.is_dir {
+                        self.cwd = path;
+                        self.list.select(Some(0));
+                        self.refresh(None);
+                    }
+                }
+            }
+            KeyCode::Backspace | KeyCode::Left | KeyCode::Char('h') => {
+                if let Some((parent, name)) = self.cwd.rsplit_once('/') {
+                    if !name.is_empty() {
+                        let name = name.to_string();
+                        self.cwd = ArhPath::normalize(parent)?;
+                        self.refresh(Some(&name));
+                    }
+                }
+            }
+            KeyCode::Char('x') => {
+                if let Some((entry, _)) = self.selected() {
+                    let name = entry.name.clone();
+                    self.open_prompt(PromptKind::Extract, name);
+                }
+            }
+            KeyCode::Char('r') => {
+                if let Some((_, path)) = self.selected() {
+                    self.open_prompt(PromptKind::Rename, path.to_string());
+                }
+            }
+            KeyCode::Char('d') if self.selected().is_some() => {
+                self.open_prompt(PromptKind::ConfirmDelete, String::new());
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn open_prompt(&mut self, kind: PromptKind, buffer: String) {
+        self.prompt = Some(Prompt { kind, buffer });
+    }
+
+    fn submit(&mut self, prompt: Prompt) -> Result<String> {
+        if prompt.kind == PromptKind::ConfirmSave {
+            self.input.write_fs(&mut self.fs)?;
+            self.quit = true;
+            return Ok(String::new());
+        }
+        let (entry, path) = self.selected().ok_or_else(|| anyhow!("nothing selected"))?;
+        let is_dir = entry.is_dir;
+        match prompt.kind {
+            PromptKind::Extract => self.extract(&path, PathBuf::from(prompt.buffer)),
+            PromptKind::Rename => {
+                let new_path = RelativeArhPath::new(prompt.buffer).resolve(&self.cwd)?;
+                if is_dir {
+                    self.fs.rename_dir(&path, &new_path)?;
+                } else {
+                    self.fs.rename_file(&path, &new_path)?;
+                }
+                self.dirty = true;
+                let name = new_path.rsplit_once('/').map(|(_, name)| name.to_string());
+                self.refresh(name.as_deref());
+                Ok(format!("Renamed {path} to {new_path}"))
+            }
+            PromptKind::ConfirmDelete => {
+                if is_dir {
+                    self.fs.delete_empty_dir(&path)?;
+                } else {
+                    self.fs.delete_file(&path)?;
+                }
+                self.dirty = true;
+                self.refresh(None);
+                Ok(format!("Deleted {path}"))
+            }
+            PromptKind::ConfirmSave => unreachable!(),
+        }
+    }
+
+    fn extract(&mut self, path: &ArhPath, out: PathBuf) -> Result<String> {
+        let ard = self
+            .ard
+            .as_mut()
+            .ok_or_else(|| anyhow!("input .ard must be passed in as --ard"))?;
+        let files = crate::extract::extract_to(&self.fs, ard, path, &out)?;
+        Ok(format!("Extracted {files} files to {}", out.display()))
+    }
+
+    fn draw(&mut self, frame: &mut Frame) {
+        let [main, status] =
+            Layout::vertical([Constraint::Fill(1), Constraint::Length(1)]).areas(frame.area());
+        let [left, right] =
+            Layout::horizontal([Constraint::Percentage(40), Constraint::Fill(1)]).areas(main);
+        let [meta, preview] =
+            Layout::vertical([Constraint::Length(9), Constraint::Fill(1)]).areas(right);
+
+        let items: Vec<_> = self
+            .entries
+            .iter()
+            .map(|e| {
+                let item = ListItem::new(if e.is_dir {
+                    format!("{}/", e.name)
+                } else {
+                    e.name.clone()
+                });
+                match (e.is_dir, e.hidden) {
+                    (true, _) => item.bold(),
+                    (_, true) => item.dim(),
+                    _ => item,
+                }
+            })
+            .collect();
+        let title = format!(
+            " {}{} ",
+            self.cwd,
+            if self.dirty { " [modified]" } else { "" }
+        );
+        let list = List::new(items)
+            .block(Block::bordered().title(title))
+            .highlight_style(Style::new().reversed());
+        frame.render_stateful_widget(list, left, &mut self.list);
+
+        self.draw_meta(frame, meta);
+        self.draw_preview(frame, preview);
+
+        let status_line = match &self.prompt {
+            Some(Prompt { kind, buffer }) => match kind {
+                PromptKind::Extract => format!("Extract to: {buffer}"),
+                PromptKind::Rename => format!("Rename to: {buffer}"),
+                PromptKind::ConfirmDelete => "Delete the selected entry? (y/n)".to_string(),
+                PromptKind::ConfirmSave => "Save changes before quitting? (y/n)".to_string(),
+            },
+            None => self.status.clone(),
+        };
+        frame.render_widget(Paragraph::new(status_line), status);
+    }
+
+    fn draw_meta(&self, frame: &mut Frame, area: Rect) {
+        let block = Block::bordered().title(" Info ");
+        let Some((entry, path)) = self.selected() else {
+            frame.render_widget(block, area);
+            return;
+        };
+        let mut lines = vec![Line::from(format!("Path: {path}"))];
+        if entry.is_dir {
+            let node = self.fs.get_dir(&path).unwrap();
+            lines.extend([
+                format!("Files: {}", node.stats.file_count).into(),
+                format!("Directories: {}", node.stats.dir_count).into(),
+                format!(
+                    "Size: {} ({} in the archive)",
+                    format_size(node.stats.uncompressed_size, true),
+                    format_size(node.stats.compressed_size, true)
+                )
+                .into(),
+            ]);
+        } else {
+            let meta = self.fs.get_file_info(&path).unwrap();
+            let mut flags = Vec::new();
+            if meta.is_flag(FileFlag::Hidden) {
+                flags.push("hidden");
+            }
+            if meta.uncompressed_size != 0 {
+                flags.push("compressed");
+            }
+            lines.extend([
+                format!("ID: {}", meta.id).into(),
+                format!("Size: {}", format_size(meta.actual_size().into(), true)).into(),
+                format!(
+                    "Size in the archive: {}",
+                    format_size(meta.compressed_size.into(), true)
+                )
+                .into(),
+                format!("ARD offset: {:#x}", meta.offset).into(),
+                format!("Flags: {}", flags.join(", ")).into(),
+            ]);
+        }
+        frame.render_widget(Paragraph::new(lines).block(block), area);
+    }
+
+    fn draw_preview(&mut self, frame: &mut Frame, area: Rect) {
+        let block = Block::bordered().title(" Preview ");
+        let path = match self.selected() {
+            Some((entry, path)) if !entry.is_dir => path,
+            _ => {
+                frame.render_widget(block, area);
+                return;
+            }
+        };
+        if self.preview.as_ref().is_none_or(|(p, _)| *p != path) {
+            let text = self.load_preview(&path);
+            self.preview = Some((path, text));
+        }
+        let text = self.preview.as_ref().unwrap().1.as_str();
+        frame.render_widget(
+            Paragraph::new(text).block(block).wrap(Wrap { trim: false }),
+            area,
+        );
+    }
+
+    fn load_preview(&mut self, path: &ArhPath) -> String {
+        let Some(ard) = &mut self.ard else {
+            return "(no .ard file, pass one with --ard)".to_string();
+        };
+        let meta = self.fs.get_file_info(path).unwrap();
+        let data = match ard.entry(meta).skip_take(0, PREVIEW_SIZE).read() {
+            Ok(data) => data,
+            Err(e) => return format!("Error: {e}"),
+        };
+        if identify(&data) == "ASCII text" {
+            return String::from_utf8_lossy(&data).into_owned();
+        }
+        let mut text = format!("{}\n\n", identify(&data));
+        for line in hex_lines(&data, 0) {
+            text.push_str(&line);
+            text.push('\n');
+        }
+        text
+    }
+}
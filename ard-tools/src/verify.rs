@@ -0,0 +1,180 @@
+use std::{collections::BTreeMap, path::PathBuf};
+
+use anyhow::{anyhow, Result};
+use ardain::{
+    path::{ArhPath, RelativeArhPath},
+    ArdReader, FileMeta,
+};
+use clap::Args;
+use log::{info, warn};
+
+use crate::{
+    errors::ErrorKind,
+    hash::Digest,
+    manifest::read_manifest,
+    output::{self, Cell, Style, Table},
+    progress::ParallelArgs,
+    InputData,
+};
+
+#[derive(Args)]
+pub struct VerifyArgs {
+    /// The files or directories to check (default: /)
+    #[arg(add = crate::completions::arh_path_completer())]
+    paths: Vec<RelativeArhPath>,
+    /// Only check the metadata against the XBC1 headers stored in the ARD file, without
+    /// reading or decompressing file contents
+    #[arg(long)]
+    fast: bool,
+    /// Also compare file contents against the hashes of a manifest written by
+    /// `manifest export`, with the algorithm it was written with
+    #[arg(long, value_name = "FILE", conflicts_with = "fast")]
+    manifest: Option<PathBuf>,
+    /// Also check hidden (soft-deleted) files
+    #[arg(short, long)]
+    all: bool,
+    #[command(flatten)]
+    parallel: ParallelArgs,
+}
+
+pub fn run(input: &InputData, args: VerifyArgs) -> Result<()> {
+    let fs = input.load_fs()?;
+    let paths = if args.paths.is_empty() {
+        vec![ArhPath::default()]
+    } else {
+        input.resolve_all(&args.paths)?
+    };
+    let files = crate::expand_paths(&fs, &paths, args.all)?;
+    input.require_ard(&files)?;
+    let mut expected = BTreeMap::new();
+    if let Some(manifest) = &args.manifest {
+        for entry in read_manifest(manifest, None)? {
+            if let Some(hash) = entry.hash {
+                expected.insert(ArhPath::normalize(&entry.path)?, hash);
+            }
+        }
+    }
+    let ard_len = input.open_ard()?.metadata()?.len();
+    info!(
+        "Verifying {} files ({} mode)",
+        files.len(),
+        if args.fast { "fast" } else { "full" }
+    );
+
+    let total_bytes = files
+        .iter()
+        .map(|path| u64::from(fs.get_file_info(path).unwrap().compressed_size))
+        .sum();
+    let progress = args.parallel.progress(files.len(), total_bytes);
+    let mut problems = args
+        .parallel
+        .visitor()
+        .try_visit(
+            &fs,
+            &files,
+            // Full reads also check the hash of compressed files
+            || input.load_ard().map(|ard| ard.verify_on_read(true)),
+            |ard, path, meta| -> Result<Vec<(&ArhPath, String)>> {
+                let ard = ard.as_mut().map_err(|e| anyhow!("{e}"))?;
+                let problems = check_file(ard, meta, ard_len, args.fast, expected.get(path))?;
+                progress.file_done(path, meta.compressed_size.into());
+                Ok(problems
+                    .into_iter()
+                    .map(|problem| (path, problem))
+                    .collect())
+            },
+        )?
+        .into_iter()
+        .flatten()
+        .collect::<Vec<_>>();
+    progress.finish();
+
+    // Files may share data (e.g. after deduplication), but must not partially overlap
+    let mut extents: Vec<_> = files
+        .iter()
+        .map(|path| (fs.get_file_info(path).unwrap(), path))
+        .filter(|(meta, _)| meta.compressed_size != 0)
+        .collect();
+    extents.sort_by_key(|(meta, _)| meta.offset);
+    let mut prev: Option<(&FileMeta, &ArhPath)> = None;
+    for &(meta, path) in &extents {
+        if let Some((prev_meta, prev_path)) = prev {
+            let prev_end = prev_meta.offset + u64::from(prev_meta.compressed_size);
+            if meta.offset != prev_meta.offset && meta.offset < prev_end {
+                problems.push((path, format!("data overlaps with {prev_path}")));
+            }
+            if meta.offset + u64::from(meta.compressed_size) <= prev_end {
+                // Keep comparing against the file that ends last
+                continue;
+            }
+        }
+        prev = Some((meta, path));
+    }
+
+    problems.sort();
+    if progress.is_enabled() {
+        // Keep stdout for progress events
+        for (path, problem) in &problems {
+            warn!("{path}: {problem}");
+        }
+    } else if !problems.is_empty() {
+        let mut table = Table::new(&["File", "Problem"]);
+        for (path, problem) in &problems {
+            table.push_row([
+                Cell::new(path.as_str(), Style::Bad),
+                problem.as_str().into(),
+            ]);
+        }
+        table.print();
+    }
+    if !problems.is_empty() {
+        return Err(ErrorKind::Corrupted.error(format!(
+            "found {} problems in {} files",
+            problems.len(),
+            files.len()
+        )));
+    }
+    if !progress.is_enabled() {
+        let message = format!("Verified {} files, no problems found", files.len());
+        println!("{}", output::paint(&message, Style::Good));
+    }
+    Ok(())
+}
+
+/// Returns a description of each problem found with the file.
+fn check_file(
+    ard: &mut ArdReader<impl std::io::Read + std::io::Seek>,
+    meta: &FileMeta,
+    ard_len: u64,
+    fast: bool,
+    expected: Option<&Digest>,
+) -> Result<Vec<String>> {
+    let mut problems: Vec<String> = ard
+        .check_entry(meta, ard_len)?
+        .iter()
+        .map(ToString::to_string)
+        .collect();
+    if fast || meta.compressed_size == 0 || !problems.is_empty() {
+        return Ok(problems);
+    }
+
+    match ard.entry(meta).read() {
+        Ok(data) if data.len() as u64 != u64::from(meta.actual_size()) => problems.push(format!(
+            "read {} bytes, metadata has {}",
+            data.len(),
+            meta.actual_size()
+        )),
+        Ok(data) => {
+            if let Some(expected) = expected {
+                let actual = expected.hasher().digest(&data);
+                if actual != *expected {
+                    problems.push(format!(
+                        "contents changed: {actual}, manifest has {expected}"
+                    ));
+                }
+            }
+        }
+        Err(e) => problems.push(format!("could not read contents: {e}")),
+    }
+    Ok(problems)
+}
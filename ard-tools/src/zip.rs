@@ -0,0 +1,125 @@
+//! A minimal ZIP writer, storing files without compression.
+//!
+//! Mod folders are mostly made of files that are already compressed by the game's own
+//! formats, so deflate wouldn't gain much. ZIP64 is not supported.
+
+use std::io::Write;
+
+use anyhow::{bail, Result};
+
+use crate::hash::crc32;
+
+const LOCAL_HEADER_SIG: u32 = 0x04034b50;
+const CENTRAL_HEADER_SIG: u32 = 0x02014b50;
+const END_OF_CENTRAL_DIR_SIG: u32 = 0x06054b50;
+/// 2.0, the minimum for directories and stored files
+const VERSION: u16 = 20;
+/// Bit 11: file names are UTF-8
+const FLAGS: u16 = 1 << 11;
+/// 1980-01-01, the earliest date a ZIP file can store
+const DOS_DATE: u16 = (1 << 5) | 1;
+
+pub struct ZipWriter<W> {
+    writer: W,
+    offset: u64,
+    entries: Vec<CentralEntry>,
+}
+
+struct CentralEntry {
+    name: String,
+    crc: u32,
+    size: u32,
+    offset: u32,
+}
+
+impl<W: Write> ZipWriter<W> {
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer,
+            offset: 0,
+            entries: Vec::new(),
+        }
+    }
+
+    pub fn add_file(&mut self, name: &str, data: &[u8]) -> Result<()> {
+        let (Ok(size), Ok(offset), Ok(name_len)) = (
+            u32::try_from(data.len()),
+            u32::try_from(self.offset),
+            u16::try_from(name.len()),
+        ) else {
+            bail!("{name}: too large for a ZIP file");
+        };
+        if self.entries.len() == usize::from(u16::MAX) {
+            bail!("too many files for a ZIP file");
+        }
+        let crc = crc32(data);
+
+        let mut header = Vec::with_capacity(30 + name.len());
+        header.extend(LOCAL_HEADER_SIG.to_le_bytes());
+        header.extend(VERSION.to_le_bytes());
+        header.extend(FLAGS.to_le_bytes());
+        header.extend(0u16.to_le_bytes()); // method: stored
+        header.extend(0u16.to_le_bytes()); // time
+        header.extend(DOS_DATE.to_le_bytes());
+        header.extend(crc.to_le_bytes());
+        header.extend(size.to_le_bytes()); // compressed size
+        header.extend(size.to_le_bytes());
+        header.extend(name_len.to_le_bytes());
+        header.extend(0u16.to_le_bytes()); // extra field length
+        header.extend(name.as_bytes());
+        self.writer.write_all(&header)?;
+        self.writer.write_all(data)?;
+
+        self.offset += (header.len() + data.len()) as u64;
+        self.entries.push(CentralEntry {
+            name: name.to_string(),
+            crc,
+            size,
+            offset,
+        });
+        Ok(())
+    }
+
+    /// Writes the central directory, and returns the inner writer.
+    pub fn finish(mut self) -> Result<W> {
+        let Ok(dir_offset) = u32::try_from(self.offset) else {
+            bail!("too large for a ZIP file");
+        };
+        let mut dir = Vec::new();
+        for entry in &self.entries {
+            dir.extend(CENTRAL_HEADER_SIG.to_le_bytes());
+            dir.extend(VERSION.to_le_bytes()); // made by
+            dir.extend(VERSION.to_le_bytes()); // needed to extract
+            dir.extend(FLAGS.to_le_bytes());
+            dir.extend(0u16.to_le_bytes()); // method: stored
+            dir.extend(0u16.to_le_bytes()); // time
+            dir.extend(DOS_DATE.to_le_bytes());
+            dir.extend(entry.crc.to_le_bytes());
+            dir.extend(entry.size.to_le_bytes()); // compressed size
+            dir.extend(entry.size.to_le_bytes());
+            dir.extend((entry.name.len() as u16).to_le_bytes());
+            dir.extend(0u16.to_le_bytes()); // extra field length
+            dir.extend(0u16.to_le_bytes()); // comment length
+            dir.extend(0u16.to_le_bytes()); // disk number
+            dir.extend(0u16.to_le_bytes()); // internal attributes
+            dir.extend(0u32.to_le_bytes()); // external attributes
+            dir.extend(entry.offset.to_le_bytes());
+            dir.extend(entry.name.as_bytes());
+        }
+        let Ok(dir_size) = u32::try_from(dir.len()) else {
+            bail!("too many files for a ZIP file");
+        };
+        let count = self.entries.len() as u16;
+        dir.extend(END_OF_CENTRAL_DIR_SIG.to_le_bytes());
+        dir.extend(0u16.to_le_bytes()); // disk number
+        dir.extend(0u16.to_le_bytes()); // disk with the central directory
+        dir.extend(count.to_le_bytes()); // entries on this disk
+        dir.extend(count.to_le_bytes());
+        dir.extend(dir_size.to_le_bytes());
+        dir.extend(dir_offset.to_le_bytes());
+        dir.extend(0u16.to_le_bytes()); // comment length
+        self.writer.write_all(&dir)?;
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
@@ -1,53 +1,376 @@
-use std::io::{Read, Seek, SeekFrom, Write};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{self, BufWriter, Cursor, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
 
-use xc3_lib::xbc1::Xbc1;
+use binrw::{BinRead, NullString};
+use xc3_lib::{
+    hash::hash_crc,
+    xbc1::{CompressionType, Xbc1},
+};
 
-use crate::error::Result;
-use crate::FileMeta;
+use crate::error::{Error, Result};
+use crate::opts::ArhOptions;
+use crate::{FileFlag, FileMeta};
+
+/// The size of a XBC1 header, before the compressed stream
+const XBC1_HEADER_SIZE: u32 = 0x30;
+
+/// Raw entries up to this size are read and cached whole, even if only part of them is
+/// requested. Larger ones are read in parts, bypassing the cache.
+const MAX_CACHED_RAW_ENTRY: u64 = 16 * 1024 * 1024;
 
 /// Provides easy access to entries in an ARD file.
 pub struct ArdReader<R> {
     reader: R,
+    verify_on_read: bool,
+    cache: Option<ReadCache>,
 }
 
 pub struct ArdWriter<W> {
     writer: W,
+    grow_ahead: Option<GrowAhead<W>>,
+    /// Set by [`ArdWriter::limit_len`]
+    max_len: Option<u64>,
+    /// Set by [`ArdWriter::verify_writes`]
+    read_back: Option<ReadBackFn<W>>,
+}
+
+/// An output whose length can be changed, e.g. a file. Used to preallocate space in the ARD
+/// file, see [`ArdWriter::grow_ahead`].
+pub trait SetLen {
+    /// Truncates or extends the output to `len` bytes. Added bytes are zeros.
+    fn set_len(&mut self, len: u64) -> io::Result<()>;
+
+    /// Extends the output to `len` bytes, like [`Self::set_len`], but also reserves the
+    /// added space on disk where supported, instead of leaving a hole in a sparse file.
+    fn preallocate(&mut self, len: u64) -> io::Result<()> {
+        self.set_len(len)
+    }
+}
+
+/// An output that written data can be read back from, see [`ArdWriter::verify_writes`].
+pub trait ReadBack {
+    /// Reads `buf.len()` bytes starting at `offset`, including data that is still buffered
+    /// for writing.
+    fn read_back(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()>;
+}
+
+/// See [`ArdWriter::grow_ahead`].
+/// [`ReadBack::read_back`] for the writer's output, kept as a function pointer so
+/// [`ArdWriter`] doesn't require the trait.
+type ReadBackFn<W> = fn(&mut W, u64, &mut [u8]) -> io::Result<()>;
+
+struct GrowAhead<W> {
+    chunk: u64,
+    /// The length of the ARD file without the preallocated space, and its actual length.
+    /// Both are read from the file the first time it grows.
+    lengths: Option<(u64, u64)>,
+    set_len: fn(&mut W, u64) -> io::Result<()>,
+    preallocate: fn(&mut W, u64) -> io::Result<()>,
 }
 
-pub struct EntryReader<R> {
+pub struct EntryReader<'a, R> {
     reader: R,
     offset: u64,
     entry_size: u64,
     compressed: bool,
+    verify: bool,
+    cache: Option<&'a mut ReadCache>,
 }
 
-pub struct OffsetReader<R> {
-    entry: EntryReader<R>,
+pub struct OffsetReader<'a, R> {
+    entry: EntryReader<'a, R>,
     offset: u64,
     max_size: Option<u64>,
 }
 
+/// A reader over a shared file handle that uses positional reads (`pread`) instead of
+/// moving the handle's cursor.
+///
+/// Any number of positional readers can read from the same file concurrently, e.g.
+/// `ArdReader::new(PositionalReader::new(&file))` on each thread.
+pub struct PositionalReader<'a> {
+    file: &'a File,
+    position: u64,
+}
+
+/// Keeps the data of recently read entries in memory, see [`ArdReader::cache`].
+///
+/// Entries are stored whole, keyed by the range of the ARD file they were read from, and
+/// after decompression, so reading any part of a cached entry skips both the disk and the
+/// decompression. Once the cache holds more than its capacity, the least recently used
+/// entries are dropped.
+struct ReadCache {
+    capacity: usize,
+    size: usize,
+    entries: HashMap<CacheKey, CachedData>,
+    /// Keys by last use, oldest first
+    lru: BTreeMap<u64, CacheKey>,
+    clock: u64,
+    stats: CacheStats,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct CacheKey {
+    offset: u64,
+    len: u64,
+    /// Whether the data was decompressed. The same range may also be read as is, e.g. to
+    /// look at the XBC1 header.
+    decompressed: bool,
+}
+
+struct CachedData {
+    data: Vec<u8>,
+    last_used: u64,
+}
+
+/// Counters for an [`ArdReader`]'s cache, see [`ArdReader::cache_stats`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    /// Reads that were served from the cache
+    pub hits: u64,
+    /// Reads that went to the ARD file
+    pub misses: u64,
+    /// How many times data was dropped to make room for newer data
+    pub evictions: u64,
+    /// The number of cached ranges
+    pub entries: usize,
+    /// The size of the cached data, in bytes
+    pub size: usize,
+    /// The maximum size of the cached data, in bytes
+    pub capacity: usize,
+}
+
+/// A XBC1 stream embedded in another file, see [`find_nested_streams`].
+pub struct NestedStream {
+    /// The offset of the XBC1 header, relative to the start of the outer file
+    pub offset: u64,
+    /// The name stored in the XBC1 header (often empty)
+    pub name: String,
+    /// The decompressed contents of the stream
+    pub data: Vec<u8>,
+}
+
+/// An inconsistency between a file's metadata and its data in the ARD file, see
+/// [`ArdReader::check_entry`].
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum EntryMismatch {
+    #[error("data ends at 0x{end:X}, past the end of the ARD file (0x{ard_len:X})")]
+    OutOfBounds { end: u64, ard_len: u64 },
+    #[error("compressed, but has no XBC1 header")]
+    MissingHeader,
+    #[error("flagged as having a XBC1 header, but has none")]
+    MissingFlaggedHeader,
+    #[error("XBC1 header has decompressed size {header}, metadata has {meta}")]
+    DecompressedSize { header: u32, meta: u32 },
+    #[error("XBC1 stream needs {stream} bytes, but the entry has {entry}")]
+    StreamSize { stream: u64, entry: u32 },
+}
+
+/// The contents of an entry's XBC1 header, see [`ArdReader::entry_header`].
+#[derive(Debug, Clone, BinRead)]
+#[br(little, magic = b"xbc1")]
+pub struct Xbc1Info {
+    pub compression_type: CompressionType,
+    /// The size of the data after decompression
+    pub decompressed_size: u32,
+    /// The size of the (compressed) stream that follows the header
+    pub compressed_size: u32,
+    /// The hash of the decompressed data, as stored in the header
+    pub decompressed_hash: u32,
+    #[br(pad_size_to = 28, map = |s: NullString| s.to_string())]
+    pub name: String,
+}
+
 impl<R: Read + Seek> ArdReader<R> {
     pub fn new(reader: R) -> Self {
-        Self { reader }
+        Self {
+            reader,
+            verify_on_read: false,
+            cache: None,
+        }
+    }
+
+    /// Creates a reader that follows the archive's options, e.g.
+    /// [`ArhOptions::verify_on_read`].
+    pub fn with_options(reader: R, opts: &ArhOptions) -> Self {
+        Self::new(reader).verify_on_read(opts.verify_on_read)
+    }
+
+    /// Sets whether compressed entries are checked against the hash in their XBC1 header
+    /// when they are read, see [`ArhOptions::verify_on_read`].
+    pub fn verify_on_read(mut self, verify: bool) -> Self {
+        self.verify_on_read = verify;
+        self
+    }
+
+    pub fn verifies_on_read(&self) -> bool {
+        self.verify_on_read
+    }
+
+    /// Keeps up to `capacity` bytes of recently read data in memory, so hot entries (e.g.
+    /// fonts, or tables read by every tool) are only read and decompressed once. A capacity
+    /// of 0 disables the cache.
+    ///
+    /// Entries are cached whole, even when only part of one is read. Raw entries over
+    /// 16 MiB are the exception: they are read in parts and never cached.
+    ///
+    /// The cache doesn't know about writes to the ARD file: call [`Self::invalidate_cache`]
+    /// or [`Self::clear_cache`] after modifying it through another handle.
+    pub fn cache(mut self, capacity: usize) -> Self {
+        self.cache = (capacity != 0).then(|| ReadCache::new(capacity));
+        self
+    }
+
+    /// Returns the counters of the cache, or `None` if there is no cache.
+    pub fn cache_stats(&self) -> Option<CacheStats> {
+        self.cache.as_ref().map(ReadCache::stats)
+    }
+
+    /// Drops cached data that was read from the given range of the ARD file.
+    pub fn invalidate_cache(&mut self, range: Range<u64>) {
+        if let Some(cache) = &mut self.cache {
+            cache.invalidate(range);
+        }
+    }
+
+    /// Drops all cached data. The counters are kept.
+    pub fn clear_cache(&mut self) {
+        if let Some(cache) = &mut self.cache {
+            cache.invalidate(0..u64::MAX);
+        }
     }
 
     /// Returns a handle that can read a file entry.
     ///
     /// The file will be transparently decompressed if needed.
-    pub fn entry(&mut self, file: &FileMeta) -> EntryReader<&mut R> {
+    pub fn entry(&mut self, file: &FileMeta) -> EntryReader<'_, &mut R> {
         EntryReader {
             reader: &mut self.reader,
             offset: file.offset,
             compressed: file.uncompressed_size != 0,
             entry_size: file.compressed_size.into(),
+            verify: self.verify_on_read,
+            cache: self.cache.as_mut(),
         }
     }
+
+    /// Reads the XBC1 header of a file entry, without reading or decompressing the rest
+    /// of its data.
+    ///
+    /// This looks at the data in the ARD file rather than the file's metadata. Returns
+    /// `None` if the data doesn't start with a XBC1 header, e.g. for files stored raw.
+    pub fn entry_header(&mut self, file: &FileMeta) -> Result<Option<Xbc1Info>> {
+        if file.compressed_size < XBC1_HEADER_SIZE {
+            return Ok(None);
+        }
+        let mut magic = [0u8; 4];
+        self.reader.seek(SeekFrom::Start(file.offset))?;
+        self.reader.read_exact(&mut magic)?;
+        if &magic != b"xbc1" {
+            return Ok(None);
+        }
+        self.reader.seek(SeekFrom::Start(file.offset))?;
+        Ok(Some(Xbc1Info::read(&mut self.reader)?))
+    }
+
+    /// Checks a file's metadata against the ARD file, which is `ard_len` bytes long, without
+    /// reading or decompressing its contents.
+    ///
+    /// The data must lie within the ARD file, and its XBC1 header (if any) must declare the
+    /// same sizes as the metadata. Streams may be padded to 16 bytes.
+    pub fn check_entry(&mut self, file: &FileMeta, ard_len: u64) -> Result<Vec<EntryMismatch>> {
+        const STREAM_ALIGN: u64 = 16;
+        let mut problems = Vec::new();
+        if file.compressed_size == 0 {
+            return Ok(problems);
+        }
+        let end = file.offset + u64::from(file.compressed_size);
+        if end > ard_len {
+            problems.push(EntryMismatch::OutOfBounds { end, ard_len });
+            return Ok(problems);
+        }
+
+        let compressed = file.uncompressed_size != 0;
+        match self.entry_header(file)? {
+            None if compressed => problems.push(EntryMismatch::MissingHeader),
+            None if file.is_flag(FileFlag::HasXbc1Header) => {
+                problems.push(EntryMismatch::MissingFlaggedHeader)
+            }
+            None => {}
+            Some(header) => {
+                if compressed && header.decompressed_size != file.uncompressed_size {
+                    problems.push(EntryMismatch::DecompressedSize {
+                        header: header.decompressed_size,
+                        meta: file.uncompressed_size,
+                    });
+                }
+                let stream = u64::from(XBC1_HEADER_SIZE) + u64::from(header.compressed_size);
+                let entry = u64::from(file.compressed_size);
+                if stream > entry || entry > stream.next_multiple_of(STREAM_ALIGN) {
+                    problems.push(EntryMismatch::StreamSize {
+                        stream,
+                        entry: file.compressed_size,
+                    });
+                }
+            }
+        }
+        Ok(problems)
+    }
+
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.reader
+    }
+
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
 }
 
 impl<W: Write + Seek> ArdWriter<W> {
     pub fn new(writer: W) -> Self {
-        Self { writer }
+        Self {
+            writer,
+            grow_ahead: None,
+            max_len: None,
+            read_back: None,
+        }
+    }
+
+    /// Limits the ARD file to `max_len` bytes, e.g. to cap how much it can grow on a nearly
+    /// full disk. Writes that would extend it further fail with [`Error::ArdSizeLimit`]
+    /// before anything is written, and space preallocated by [`Self::grow_ahead`] stops at
+    /// the limit. The file is not truncated if it is already longer.
+    pub fn limit_len(mut self, max_len: u64) -> Self {
+        self.max_len = Some(max_len);
+        self
+    }
+
+    /// Returns the size limit set by [`Self::limit_len`], if any.
+    pub fn len_limit(&self) -> Option<u64> {
+        self.max_len
+    }
+
+    /// Returns whether written entries are read back, see [`Self::verify_writes`].
+    pub fn verifies_writes(&self) -> bool {
+        self.read_back.is_some()
+    }
+
+    /// Reads back `expected.len()` bytes at `offset`, and checks that they match. Fails with
+    /// [`Error::WriteMismatch`] if they don't.
+    ///
+    /// This does nothing unless write verification is enabled (see [`Self::verify_writes`]).
+    pub fn verify(&mut self, offset: u64, expected: &[u8]) -> Result<()> {
+        let Some(read_back) = self.read_back else {
+            return Ok(());
+        };
+        let mut actual = vec![0; expected.len()];
+        read_back(&mut self.writer, offset, &mut actual)?;
+        match actual.iter().zip(expected).position(|(a, b)| a != b) {
+            Some(pos) => Err(Error::WriteMismatch(offset + pos as u64)),
+            None => Ok(()),
+        }
     }
 
     pub fn entry(&mut self, offset: u64) -> Result<impl Write + Seek + '_> {
@@ -55,6 +378,61 @@ impl<W: Write + Seek> ArdWriter<W> {
         Ok(&mut self.writer)
     }
 
+    /// Prepares the ARD file for data written up to `end`, growing it ahead of need if
+    /// enabled (see [`Self::grow_ahead`]). Otherwise, this does nothing: the file grows when
+    /// the data is written.
+    ///
+    /// [`ArdFileAllocator`](crate::file_alloc::ArdFileAllocator) calls this before every
+    /// write that may append to the file. Fails with [`Error::ArdSizeLimit`] if `end` is past
+    /// the limit set by [`Self::limit_len`].
+    pub fn extend_to(&mut self, end: u64) -> Result<()> {
+        if let Some(max_len) = self.max_len.filter(|&max_len| end > max_len) {
+            return Err(Error::ArdSizeLimit(max_len));
+        }
+        let Some(grow) = &mut self.grow_ahead else {
+            return Ok(());
+        };
+        let (len, allocated) = match grow.lengths {
+            Some(lengths) => lengths,
+            None => {
+                let len = self.writer.seek(SeekFrom::End(0))?;
+                (len, len)
+            }
+        };
+        let allocated = if end > allocated {
+            let new_len = end
+                .next_multiple_of(grow.chunk)
+                .min(self.max_len.unwrap_or(u64::MAX));
+            (grow.preallocate)(&mut self.writer, new_len)?;
+            new_len
+        } else {
+            allocated
+        };
+        grow.lengths = Some((len.max(end), allocated));
+        Ok(())
+    }
+
+    /// Cuts off the space that was preallocated by [`Self::grow_ahead`], but not used.
+    ///
+    /// This should be called before the archive is synced or closed. Without it, the ARD
+    /// file keeps zeros at the end, which wastes space but is otherwise harmless.
+    pub fn trim(&mut self) -> Result<()> {
+        let Some(grow) = &mut self.grow_ahead else {
+            return Ok(());
+        };
+        if let Some((len, allocated)) = grow.lengths {
+            if allocated > len {
+                (grow.set_len)(&mut self.writer, len)?;
+                grow.lengths = Some((len, len));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.writer
+    }
+
     pub fn get_mut(&mut self) -> &mut W {
         &mut self.writer
     }
@@ -64,14 +442,177 @@ impl<W: Write + Seek> ArdWriter<W> {
     }
 }
 
-impl<R: Read + Seek> EntryReader<R> {
+impl<W: Write + Seek + SetLen> ArdWriter<W> {
+    /// Extends the ARD file `chunk` bytes at a time when new data is appended to it,
+    /// instead of write by write. When many files are added, this keeps the host file
+    /// system from fragmenting the ARD file. 0 disables it (the default).
+    ///
+    /// The space is reserved on disk through [`SetLen::preallocate`]. For files, that only
+    /// happens on Linux: elsewhere, or if the host file system doesn't support it, the file
+    /// is extended with a hole, which may not prevent fragmentation.
+    ///
+    /// The unused part of the last chunk is removed by [`Self::trim`].
+    pub fn grow_ahead(mut self, chunk: u64) -> Self {
+        self.grow_ahead = (chunk != 0).then_some(GrowAhead {
+            chunk,
+            lengths: None,
+            set_len: W::set_len,
+            preallocate: W::preallocate,
+        });
+        self
+    }
+}
+
+impl<W: Write + Seek + ReadBack> ArdWriter<W> {
+    /// Reads back every entry written by
+    /// [`ArdFileAllocator`](crate::file_alloc::ArdFileAllocator) and checks it against the
+    /// data that was written, e.g. for archives on unreliable external drives.
+    ///
+    /// Entries are read back through the OS, which may answer from its cache, so this
+    /// catches errors on the way to the OS (and on drives that bypass the cache) rather than
+    /// media errors. Writes get slower, as every entry is read again. Disabled by default.
+    pub fn verify_writes(mut self, verify: bool) -> Self {
+        self.read_back = verify.then_some(W::read_back as _);
+        self
+    }
+}
+
+impl ReadBack for File {
+    fn read_back(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        self.seek(SeekFrom::Start(offset))?;
+        self.read_exact(buf)
+    }
+}
+
+impl ReadBack for Cursor<Vec<u8>> {
+    fn read_back(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        self.set_position(offset);
+        self.read_exact(buf)
+    }
+}
+
+impl<W: Write + ReadBack> ReadBack for BufWriter<W> {
+    fn read_back(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        self.flush()?;
+        self.get_mut().read_back(offset, buf)
+    }
+}
+
+impl<T: ReadBack + ?Sized> ReadBack for &mut T {
+    fn read_back(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        (**self).read_back(offset, buf)
+    }
+}
+
+impl SetLen for File {
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        File::set_len(self, len)
+    }
+
+    fn preallocate(&mut self, len: u64) -> io::Result<()> {
+        #[cfg(target_os = "linux")]
+        {
+            use std::os::fd::AsRawFd;
+
+            let old_len = self.metadata()?.len();
+            if len > old_len {
+                let (offset, added) = (old_len as libc::off_t, (len - old_len) as libc::off_t);
+                // Mode 0 extends the file, with the new blocks reserved and reading as zeros
+                if unsafe { libc::fallocate(self.as_raw_fd(), 0, offset, added) } == 0 {
+                    return Ok(());
+                }
+                let err = io::Error::last_os_error();
+                // The host file system can't reserve space, so the file stays sparse
+                if err.raw_os_error() != Some(libc::EOPNOTSUPP) {
+                    return Err(err);
+                }
+            }
+        }
+        File::set_len(self, len)
+    }
+}
+
+impl SetLen for Cursor<Vec<u8>> {
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        let len = usize::try_from(len).map_err(io::Error::other)?;
+        self.get_mut().resize(len, 0);
+        Ok(())
+    }
+}
+
+impl<W: Write + SetLen> SetLen for BufWriter<W> {
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        // Buffered data must not be written after the file is shrunk
+        self.flush()?;
+        self.get_mut().set_len(len)
+    }
+
+    fn preallocate(&mut self, len: u64) -> io::Result<()> {
+        self.flush()?;
+        self.get_mut().preallocate(len)
+    }
+}
+
+impl<T: SetLen + ?Sized> SetLen for &mut T {
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        (**self).set_len(len)
+    }
+
+    fn preallocate(&mut self, len: u64) -> io::Result<()> {
+        (**self).preallocate(len)
+    }
+}
+
+impl<'a> PositionalReader<'a> {
+    pub fn new(file: &'a File) -> Self {
+        Self { file, position: 0 }
+    }
+}
+
+impl<'a> Read for PositionalReader<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        #[cfg(unix)]
+        let read = std::os::unix::fs::FileExt::read_at(self.file, buf, self.position)?;
+        #[cfg(windows)]
+        let read = std::os::windows::fs::FileExt::seek_read(self.file, buf, self.position)?;
+        self.position += read as u64;
+        Ok(read)
+    }
+}
+
+impl<'a> Seek for PositionalReader<'a> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let (base, offset) = match pos {
+            SeekFrom::Start(pos) => {
+                self.position = pos;
+                return Ok(pos);
+            }
+            SeekFrom::End(offset) => (self.file.metadata()?.len(), offset),
+            SeekFrom::Current(offset) => (self.position, offset),
+        };
+        self.position = base.checked_add_signed(offset).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative position",
+            )
+        })?;
+        Ok(self.position)
+    }
+
+    fn stream_position(&mut self) -> io::Result<u64> {
+        Ok(self.position)
+    }
+}
+
+impl<'a, R: Read + Seek> EntryReader<'a, R> {
     /// Reads the entry in full.
     pub fn read(&mut self) -> Result<Vec<u8>> {
-        self.read_at(0, self.entry_size)
+        // The entry size is the size of the compressed stream, not of the decompressed data
+        self.read_at(0, u64::MAX)
     }
 
     /// Wraps the reader to apply an offset and stop reading before the end of the file.
-    pub fn skip_take(self, skip: u64, take: u64) -> OffsetReader<R> {
+    pub fn skip_take(self, skip: u64, take: u64) -> OffsetReader<'a, R> {
         OffsetReader {
             entry: self,
             offset: skip,
@@ -80,31 +621,179 @@ impl<R: Read + Seek> EntryReader<R> {
     }
 
     fn read_at(&mut self, offset_in_entry: u64, max_size: u64) -> Result<Vec<u8>> {
-        self.reader.seek(SeekFrom::Start(self.offset))?;
-        if self.compressed {
-            let xbc1 = Xbc1::read(&mut self.reader)?;
-            let buf = xbc1.decompress()?;
+        let key = CacheKey {
+            offset: self.offset,
+            len: self.entry_size,
+            decompressed: self.compressed,
+        };
+        let slice = |buf: &[u8]| -> Result<Vec<u8>> {
             let end = offset_in_entry
                 .saturating_add(max_size)
-                .min(xbc1.decompressed_size.into());
-            Ok(buf[offset_in_entry.try_into()?..end.try_into()?].to_vec())
-        } else {
-            let size = self
-                .entry_size
-                .saturating_sub(offset_in_entry)
-                .min(max_size);
+                .min(buf.len() as u64);
+            let start = offset_in_entry.min(end);
+            Ok(buf[start.try_into()?..end.try_into()?].to_vec())
+        };
+        if let Some(data) = self.cache.as_deref_mut().and_then(|c| c.get(&key)) {
+            return slice(data);
+        }
+
+        if !self.compressed {
+            let whole = self.cache.is_some() && self.entry_size <= MAX_CACHED_RAW_ENTRY;
+            let (start, size) = if whole {
+                (0, self.entry_size)
+            } else {
+                let start = offset_in_entry.min(self.entry_size);
+                (start, (self.entry_size - start).min(max_size))
+            };
             let mut buf = vec![0u8; size.try_into()?];
-            let reader = &mut self.reader;
-            reader.seek(SeekFrom::Current(offset_in_entry.try_into()?))?;
-            reader.take(size).read_exact(&mut buf)?;
-            Ok(buf)
+            self.reader.seek(SeekFrom::Start(self.offset + start))?;
+            self.reader.read_exact(&mut buf)?;
+            if !whole {
+                return Ok(buf);
+            }
+            let res = slice(&buf)?;
+            if let Some(cache) = self.cache.as_deref_mut() {
+                cache.insert(key, buf);
+            }
+            return Ok(res);
+        }
+
+        self.reader.seek(SeekFrom::Start(self.offset))?;
+        let xbc1 = Xbc1::read(&mut self.reader)?;
+        let buf = xbc1.decompress()?;
+        if self.verify {
+            let actual = hash_crc(&buf);
+            if actual != xbc1.decompressed_hash {
+                return Err(Error::ChecksumMismatch {
+                    expected: xbc1.decompressed_hash,
+                    actual,
+                });
+            }
+        }
+        let res = slice(&buf)?;
+        if let Some(cache) = self.cache.as_deref_mut() {
+            cache.insert(key, buf);
         }
+        Ok(res)
     }
 }
 
-impl<R: Read + Seek> OffsetReader<R> {
+impl<R: Read + Seek> OffsetReader<'_, R> {
     pub fn read(&mut self) -> Result<Vec<u8>> {
         self.entry
-            .read_at(self.offset, self.max_size.unwrap_or(self.entry.entry_size))
+            .read_at(self.offset, self.max_size.unwrap_or(u64::MAX))
+    }
+}
+
+impl ReadCache {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            size: 0,
+            entries: HashMap::new(),
+            lru: BTreeMap::new(),
+            clock: 0,
+            stats: CacheStats {
+                capacity,
+                ..Default::default()
+            },
+        }
+    }
+
+    fn get(&mut self, key: &CacheKey) -> Option<&[u8]> {
+        let Some(entry) = self.entries.get_mut(key) else {
+            self.stats.misses += 1;
+            return None;
+        };
+        self.stats.hits += 1;
+        self.clock += 1;
+        self.lru.remove(&entry.last_used);
+        self.lru.insert(self.clock, *key);
+        entry.last_used = self.clock;
+        Some(&entry.data)
+    }
+
+    fn insert(&mut self, key: CacheKey, data: Vec<u8>) {
+        if data.len() > self.capacity {
+            return;
+        }
+        self.remove(&key);
+        while self.size + data.len() > self.capacity {
+            let Some((_, oldest)) = self.lru.pop_first() else {
+                break;
+            };
+            self.size -= self.entries.remove(&oldest).unwrap().data.len();
+            self.stats.evictions += 1;
+        }
+        self.clock += 1;
+        self.size += data.len();
+        self.lru.insert(self.clock, key);
+        self.entries.insert(
+            key,
+            CachedData {
+                data,
+                last_used: self.clock,
+            },
+        );
+    }
+
+    fn remove(&mut self, key: &CacheKey) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.lru.remove(&entry.last_used);
+            self.size -= entry.data.len();
+        }
+    }
+
+    fn invalidate(&mut self, range: Range<u64>) {
+        let stale: Vec<_> = self
+            .entries
+            .keys()
+            .filter(|key| key.offset < range.end && range.start < key.offset + key.len)
+            .copied()
+            .collect();
+        for key in stale {
+            self.remove(&key);
+        }
+    }
+
+    fn stats(&self) -> CacheStats {
+        CacheStats {
+            entries: self.entries.len(),
+            size: self.size,
+            ..self.stats
+        }
+    }
+}
+
+/// Scans `data` for embedded XBC1 streams and decompresses them.
+///
+/// Streams are expected to be aligned to 16 bytes, like they are in containers used by the
+/// game (e.g. `.wismt` files). Candidates that fail to parse or decompress are skipped.
+pub fn find_nested_streams(data: &[u8]) -> Vec<NestedStream> {
+    const ALIGN: usize = 16;
+    let mut streams = Vec::new();
+    let mut offset = 0;
+    while offset + 4 <= data.len() {
+        if &data[offset..offset + 4] != b"xbc1" {
+            offset += ALIGN;
+            continue;
+        }
+        let mut reader = Cursor::new(&data[offset..]);
+        let Some(stream) = Xbc1::read(&mut reader)
+            .ok()
+            .and_then(|xbc1| Some((xbc1.decompress().ok()?, xbc1.name)))
+        else {
+            offset += ALIGN;
+            continue;
+        };
+        streams.push(NestedStream {
+            offset: offset.try_into().unwrap(),
+            name: stream.1,
+            data: stream.0,
+        });
+        // Skip the stream's contents
+        let len = usize::try_from(reader.position()).unwrap().max(ALIGN);
+        offset = (offset + len).next_multiple_of(ALIGN);
     }
+    streams
 }
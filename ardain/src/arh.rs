@@ -1,13 +1,14 @@
 use std::{
     ffi::CStr,
-    io::{self, Cursor, Read, Seek, SeekFrom},
+    io::{self, Cursor, Read, Seek, SeekFrom, Write},
     mem::size_of,
 };
 
-use binrw::{BinRead, BinWrite};
+use binrw::{BinRead, BinResult, BinWrite};
 
 use crate::{
     arh_ext::{ArhExtOffsets, ArhExtSection, FileRecycleBin},
+    error::{Error, Result},
     opts::ArhOptions,
 };
 
@@ -32,17 +33,25 @@ pub struct Arh {
 
     #[brw(if (arh_ext_offset.is_some()), seek_before = SeekFrom::Start(arh_ext_offset.unwrap().section_offset.into()))]
     pub(crate) arh_ext_section: Option<ArhExtSection>,
+
+    /// Bumped whenever the path dictionary or the string table may have changed
+    #[brw(ignore)]
+    dict_generation: u64,
 }
 
-#[derive(Debug, PartialEq, Clone, Copy, BinRead, BinWrite)]
-struct ArhOffsets {
-    path_dict_node_count: u32,
-    str_table_offset: u32,
-    str_table_len: u32,
-    path_dict_offset: u32,
-    path_dict_len: u32,
-    file_table_offset: u32,
-    file_table_len: u32,
+/// The location of each table in the ARH file, as stored in the header.
+#[derive(Debug, Default, PartialEq, Clone, Copy, BinRead, BinWrite)]
+pub struct ArhOffsets {
+    pub path_dict_node_count: u32,
+    pub str_table_offset: u32,
+    /// In bytes
+    pub str_table_len: u32,
+    pub path_dict_offset: u32,
+    /// In bytes
+    pub path_dict_len: u32,
+    pub file_table_offset: u32,
+    /// The number of files
+    pub file_table_len: u32,
 }
 
 #[derive(Debug, PartialEq, Clone, BinRead, BinWrite)]
@@ -62,7 +71,7 @@ struct EncryptedSection {
     path_dict: PathDictionary,
 }
 
-#[derive(Debug, PartialEq, Clone, BinRead, BinWrite)]
+#[derive(Debug, Default, PartialEq, Clone, BinRead, BinWrite)]
 #[br(import { len: u32, key: u32 })]
 pub struct StringTable {
     #[br(args { count: len.try_into().unwrap() }, map_stream = |reader| EncryptedSection::decrypt(reader, len, key).expect("TODO"))]
@@ -76,7 +85,7 @@ pub struct PathDictionary {
     pub nodes: Vec<DictNode>,
 }
 
-#[derive(Debug, PartialEq, Clone, BinRead, BinWrite)]
+#[derive(Debug, Default, PartialEq, Clone, BinRead, BinWrite)]
 #[br(import { len: u32 })]
 pub struct FileTable {
     #[br(args { count: usize::try_from(len).unwrap() })]
@@ -112,6 +121,20 @@ pub struct FileMeta {
     pub id: u32,
 }
 
+/// The tables of an ARH file as they are stored, for debugging tools.
+///
+/// Unlike [`ArhFileSystem`](crate::ArhFileSystem), reading them doesn't build the directory
+/// tree, so it also works if the path dictionary is corrupted.
+#[derive(Debug, PartialEq, Clone)]
+pub struct RawTables {
+    /// The decrypted string table. Each entry is the last part of a path, followed by a null
+    /// byte and the file ID (little-endian).
+    pub strings: Vec<u8>,
+    /// The path dictionary. Free nodes are normalized, like when an archive is written.
+    pub dict_nodes: Vec<RawDictNode>,
+    pub files: Vec<FileMeta>,
+}
+
 #[derive(Debug, PartialEq, Clone, Copy)]
 pub enum FileFlag {
     /// The game treats these files as absent
@@ -124,11 +147,38 @@ pub enum FileFlag {
 }
 
 impl Arh {
+    /// Returns an ARH file made of the given tables, without an extension section.
+    pub(crate) fn from_tables(
+        string_table: StringTable,
+        path_dict: PathDictionary,
+        file_table: FileTable,
+    ) -> Self {
+        Self {
+            _str_table_len_dup: 0,
+            offsets: ArhOffsets::default(),
+            key: KEY_XOR,
+            arh_ext_offset: None,
+            encrypted: EncryptedSection {
+                string_table,
+                path_dict,
+            },
+            file_table,
+            arh_ext_section: None,
+            dict_generation: 0,
+        }
+    }
+
+    #[cfg(feature = "raw")]
+    pub(crate) fn offsets(&self) -> ArhOffsets {
+        self.offsets
+    }
+
     pub fn strings(&self) -> &StringTable {
         &self.encrypted.string_table
     }
 
     pub fn strings_mut(&mut self) -> &mut StringTable {
+        self.dict_generation += 1;
         &mut self.encrypted.string_table
     }
 
@@ -137,9 +187,33 @@ impl Arh {
     }
 
     pub fn path_dictionary_mut(&mut self) -> &mut PathDictionary {
+        self.dict_generation += 1;
         &mut self.encrypted.path_dict
     }
 
+    /// Returns a counter that changes whenever the path dictionary or the string table
+    /// are borrowed mutably, to tell whether data derived from them is stale.
+    pub(crate) fn dict_generation(&self) -> u64 {
+        self.dict_generation
+    }
+
+    /// Writes the ARH file with another path dictionary and string table. The tables in
+    /// memory are swapped back afterwards, so they are left unchanged.
+    pub(crate) fn write_with_dictionary<W: Write + Seek>(
+        &mut self,
+        writer: &mut W,
+        dict: &mut PathDictionary,
+        strings: &mut StringTable,
+    ) -> BinResult<()> {
+        std::mem::swap(&mut self.encrypted.path_dict, dict);
+        std::mem::swap(&mut self.encrypted.string_table, strings);
+        self.prepare_for_write();
+        let res = self.write(writer);
+        std::mem::swap(&mut self.encrypted.path_dict, dict);
+        std::mem::swap(&mut self.encrypted.string_table, strings);
+        res
+    }
+
     pub(crate) fn prepare_for_write(&mut self) {
         // We don't re-encrypt
         self.key = KEY_XOR;
@@ -194,15 +268,42 @@ impl Arh {
     }
 
     pub(crate) fn get_or_init_ext(&mut self, opts: &ArhOptions) -> &mut ArhExtSection {
-        if self.arh_ext_section.as_ref().is_some_and(|ext| {
-            !opts.ext_force_block_size
-                && ext.allocated_blocks.block_size_pow == opts.ext_block_size_pow
-        }) {
-            return self.arh_ext_section.as_mut().unwrap();
-        }
-        let section = ArhExtSection::new(self, opts.ext_block_size_pow);
-        self.arh_ext_section = Some(section);
-        self.arh_ext_section.as_mut().unwrap()
+        ArhExtSection::get_or_init(&mut self.arh_ext_section, &self.file_table, opts)
+    }
+}
+
+impl RawTables {
+    pub fn read(mut reader: impl Read + Seek) -> Result<Self> {
+        let arh = Arh::read(&mut reader)?;
+        Ok(Self {
+            strings: arh.encrypted.string_table.strings,
+            dict_nodes: arh
+                .encrypted
+                .path_dict
+                .nodes
+                .into_iter()
+                .map(RawDictNode::from)
+                .collect(),
+            files: arh.file_table.files,
+        })
+    }
+
+    /// Writes an ARH file made of these tables, without checking that they are consistent.
+    ///
+    /// The extension section is not included, it is rebuilt from the file table the next
+    /// time the archive is modified.
+    pub fn write(self, mut writer: impl Write + Seek) -> Result<()> {
+        let mut arh = Arh::from_tables(
+            StringTable {
+                strings: self.strings,
+            },
+            PathDictionary {
+                nodes: self.dict_nodes.into_iter().map(DictNode::from).collect(),
+            },
+            FileTable { files: self.files },
+        );
+        arh.prepare_for_write();
+        Ok(arh.write(&mut writer)?)
     }
 }
 
@@ -230,6 +331,14 @@ impl EncryptedSection {
 }
 
 impl StringTable {
+    /// Returns a table with no strings.
+    ///
+    /// Leaf nodes store their string offset negated, so a string at offset 0 would make
+    /// its leaf read back as an occupied node. The first byte is reserved for that reason.
+    pub fn new_empty() -> Self {
+        Self { strings: vec![0] }
+    }
+
     pub fn get_str_part_id(&self, mut offset: usize) -> (&str, u32) {
         let st = CStr::from_bytes_until_nul(&self.strings[offset..])
             .unwrap()
@@ -242,22 +351,69 @@ impl StringTable {
         )
     }
 
-    pub fn push(&mut self, text: &str, id: u32) -> i32 {
-        let offset = self
-            .strings
-            .len()
-            .try_into()
-            .expect("max string table offset reached");
+    /// Appends a string, followed by the file ID, and returns its offset.
+    ///
+    /// Fails if the string would end past the maximum offset (`i32::MAX`).
+    pub fn push(&mut self, text: &str, id: u32) -> Result<i32> {
+        let offset = self.strings.len();
+        if Self::entry_end(offset, text).is_none() {
+            return Err(Error::LimitExceeded("string table is full"));
+        }
         self.strings.extend_from_slice(text.as_bytes());
         self.strings.push(0);
         self.strings.extend_from_slice(&id.to_le_bytes());
-        offset
+        Ok(offset as i32)
+    }
+
+    /// Returns where the entry for `text` ends if it is appended at `offset`, or `None` if
+    /// that is past the maximum offset.
+    fn entry_end(offset: usize, text: &str) -> Option<i32> {
+        i32::try_from(offset + text.len() + 1 + size_of::<u32>()).ok()
+    }
+
+    /// Replaces the file ID stored after the string at `offset`.
+    ///
+    /// ## Panics
+    /// Panics if there is no string at `offset`.
+    pub fn set_id(&mut self, offset: usize, id: u32) {
+        let len = self.strings[offset..]
+            .iter()
+            .position(|&b| b == 0)
+            .expect("unterminated string");
+        let start = offset + len + 1;
+        self.strings[start..start + size_of::<u32>()].copy_from_slice(&id.to_le_bytes());
+    }
+
+    /// Returns the size of the table, in bytes.
+    pub fn byte_len(&self) -> usize {
+        self.strings.len()
+    }
+
+    /// Drops the strings appended after the table was `len` bytes long, e.g. to undo a
+    /// failed operation.
+    pub(crate) fn truncate(&mut self, len: usize) {
+        self.strings.truncate(len);
+    }
+
+    #[cfg(feature = "raw")]
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.strings
     }
 }
 
 impl PathDictionary {
     const BLOCK_SIZE: usize = 0x80;
 
+    /// Returns a dictionary with no files.
+    ///
+    /// The root's children share its block (`next = 0`), which is fine because no path
+    /// contains a null character.
+    pub fn new_empty() -> Self {
+        let mut nodes = vec![DictNode::Free; Self::BLOCK_SIZE];
+        nodes[0] = DictNode::Root { next: 0 };
+        Self { nodes }
+    }
+
     pub fn get_full_path(&self, mut node_idx: usize, strings: &StringTable) -> String {
         let mut node = &self.nodes[node_idx];
 
@@ -301,15 +457,18 @@ impl PathDictionary {
     ///
     /// * `previous_node`: the parent node. A new block is allocated and all its children are
     /// moved to that block. At the end, the `next` value in `previous_node` is updated accordingly.
-    pub fn allocate_new_block(&mut self, previous_node: i32) -> i32 {
+    ///
+    /// Fails without modifying the dictionary if node indices would no longer fit in an `i32`.
+    pub fn allocate_new_block(&mut self, previous_node: i32) -> Result<i32> {
         // Offset should be the center point wrt XOR with a value in [0, BLOCK_SIZE-1]. In other words,
         // `offset ^ x` should be in [nodes.len(), nodes.len()+BLOCK_SIZE-1] for all x in [0, BLOCK_SIZE-1].
         // We choose to align the block at BLOCK_SIZE so that this is always the case.
         let mut offset = self.nodes.len();
+        let aligned = offset.next_multiple_of(Self::BLOCK_SIZE);
+        if i32::try_from(aligned + Self::BLOCK_SIZE).is_err() {
+            return Err(Error::LimitExceeded("path dictionary is full"));
+        }
         if offset % Self::BLOCK_SIZE != 0 {
-            let aligned = offset
-                .checked_next_multiple_of(Self::BLOCK_SIZE)
-                .expect("path dict overflow");
             while offset != aligned {
                 self.nodes.push(DictNode::Free);
                 offset += 1;
@@ -368,7 +527,19 @@ impl PathDictionary {
         }
         // At the end, fix back links for source node (see function docs)
         self.nodes[previous_node as usize].attach_next(offset as i32);
-        offset as i32
+        Ok(offset as i32)
+    }
+
+    /// Makes the children of node `from` children of node `to`, e.g. to move a directory.
+    /// The children stay in their block, `to` must not have children of its own.
+    pub fn move_children(&mut self, from: i32, to: i32) {
+        let block = self.node(from).next();
+        for c in 0..Self::BLOCK_SIZE as i32 {
+            if self.get_node(block ^ c).is_some_and(|n| n.is_child(from)) {
+                self.node_mut(block ^ c).attach_previous(to);
+            }
+        }
+        self.node_mut(to).attach_next(block);
     }
 
     /// Marks a node as free, recursively marking parent nodes as free if they have no children.
@@ -418,6 +589,7 @@ impl FileTable {
     ) -> u32 {
         if let Some(id) = recycle_bin.and_then(FileRecycleBin::pop) {
             // Attempt to recycle deleted entries
+            meta.id = id;
             self.files[id as usize] = meta;
             return id;
         }
@@ -434,6 +606,11 @@ impl FileTable {
     pub fn files(&self) -> &[FileMeta] {
         &self.files
     }
+
+    /// Replaces all entries. Their `id` fields must match their new index.
+    pub fn set_files(&mut self, files: Vec<FileMeta>) {
+        self.files = files;
+    }
 }
 
 impl DictNode {
@@ -507,7 +684,9 @@ impl DictNode {
 }
 
 impl FileMeta {
-    pub(crate) fn new_invalid() -> Self {
+    /// Returns metadata for a file with no data, like the ones returned by
+    /// [`ArhFileSystem::create_file`](crate::ArhFileSystem::create_file).
+    pub fn new_invalid() -> Self {
         Self {
             offset: 0,
             compressed_size: 0,
@@ -517,6 +696,59 @@ impl FileMeta {
         }
     }
 
+    /// Returns metadata for a file whose data is already in the ARD file, e.g. to pass to
+    /// [`ArhFileSystem::set_file_extent`](crate::ArhFileSystem::set_file_extent).
+    ///
+    /// `compressed_size` is the size of the data in the ARD file. For compressed files,
+    /// `uncompressed_size` is the size after decompression. For files stored uncompressed,
+    /// it must be 0.
+    ///
+    /// The returned metadata has an ID of 0, the file system assigns IDs when files are
+    /// created.
+    pub fn with_extent(
+        offset: u64,
+        compressed_size: u32,
+        uncompressed_size: u32,
+        flags: impl IntoIterator<Item = FileFlag>,
+    ) -> Result<Self> {
+        if offset.checked_add(compressed_size.into()).is_none() {
+            return Err(Error::FileMetaInvalid(
+                "extent ends past the maximum offset",
+            ));
+        }
+        if compressed_size == 0 && uncompressed_size != 0 {
+            return Err(Error::FileMetaInvalid("compressed file with no data"));
+        }
+        let mut meta = Self {
+            offset,
+            compressed_size,
+            uncompressed_size,
+            ..Self::new_invalid()
+        };
+        for flag in flags {
+            meta.set_flag(flag, true);
+        }
+        Ok(meta)
+    }
+
+    /// Returns metadata with the given fields as they are stored in the file table, without
+    /// any validation. Only meant for debugging tools, see [`RawTables`].
+    pub fn from_raw(
+        id: u32,
+        offset: u64,
+        compressed_size: u32,
+        uncompressed_size: u32,
+        flags: u32,
+    ) -> Self {
+        Self {
+            offset,
+            compressed_size,
+            uncompressed_size,
+            flags,
+            id,
+        }
+    }
+
     #[cfg(test)]
     pub fn new_for_test(offset: u64, size: u32) -> Self {
         Self {
@@ -541,10 +773,20 @@ impl FileMeta {
         }
     }
 
+    /// Returns all flags as stored in the file table, including unknown ones.
+    pub fn raw_flags(&self) -> u32 {
+        self.flags
+    }
+
+    /// Returns whether `flag` is set for this file.
     pub fn is_flag(&self, flag: FileFlag) -> bool {
         self.flags & (1 << flag as u32) != 0
     }
 
+    /// Sets or clears `flag` for this file.
+    ///
+    /// This only changes the metadata. For instance, setting [`FileFlag::HasXbc1Header`]
+    /// does not add an XBC1 header to the data in the ARD file.
     pub fn set_flag(&mut self, flag: FileFlag, value: bool) {
         if value {
             self.flags |= 1 << flag as u32;
@@ -590,3 +832,22 @@ impl From<DictNode> for RawDictNode {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::StringTable;
+
+    #[test]
+    fn string_table_limit() {
+        let mut table = StringTable {
+            strings: b"a\0\0\0\0\0".to_vec(),
+        };
+        assert_eq!(table.push("b", 1).unwrap(), 6);
+        assert_eq!(table.get_str_part_id(6), ("b", 1));
+
+        // "b", the terminator and the ID take 6 bytes
+        let last = i32::MAX as usize - 6;
+        assert_eq!(StringTable::entry_end(last, "b"), Some(i32::MAX));
+        assert_eq!(StringTable::entry_end(last + 1, "b"), None);
+    }
+}
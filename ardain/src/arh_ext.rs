@@ -1,18 +1,30 @@
 //! Persistent data that makes working with ARD/ARH files easier
 
-use std::mem::size_of;
+use std::{mem::size_of, ops::Range};
 
-use binrw::{BinRead, BinWrite};
+use binrw::{BinRead, BinWrite, NullString};
 
-use crate::{arh::Arh, FileMeta};
+use crate::{arh::FileTable, opts::ArhOptions, FileMeta};
 
 pub const BLOCK_SIZE_POW_DEFAULT: u16 = 9; // 512-byte blocks
+/// The most entries kept in the [`OpLog`], older ones are dropped
+pub const OP_LOG_MAX_ENTRIES: usize = 4096;
 
 #[derive(Debug, Clone, BinRead, BinWrite)]
 #[brw(magic = b"arhx")]
 pub struct ArhExtSection {
     pub allocated_blocks: BlockAllocTable,
     pub file_meta_recycle_bin: FileRecycleBin,
+    /// Absent in files written by older versions, in which case it is rebuilt from the file
+    /// table when the archive is loaded
+    #[br(try)]
+    pub shared_extents: SharedExtents,
+    /// Absent in files written by older versions
+    #[br(try)]
+    pub orphans: OrphanList,
+    /// Absent in files written by older versions
+    #[br(try)]
+    pub op_log: OpLog,
 }
 
 #[derive(Debug, Clone, Copy, BinRead, BinWrite)]
@@ -41,11 +53,145 @@ pub struct FileRecycleBin {
     file_ids: Vec<u32>,
 }
 
+/// Reference counts for regions of the ARD file that are used by more than one file, e.g.
+/// after deduplication.
+///
+/// Extents are identified by their offset. Extents that are not in the table are used by
+/// (at most) one file.
+#[derive(Debug, Clone, BinRead, BinWrite)]
+#[brw(magic = b"xref")]
+pub struct SharedExtents {
+    len: u32,
+    #[br(args { count: len.try_into().unwrap() })]
+    extents: Vec<SharedExtent>,
+    /// Whether the table was absent from the file, see [`ArhExtSection::fill_missing`]
+    #[brw(ignore)]
+    missing: bool,
+}
+
+#[derive(Debug, Clone, Copy, BinRead, BinWrite)]
+struct SharedExtent {
+    offset: u64,
+    refs: u32,
+}
+
+/// Files that were deleted without freeing their data, so they can be recovered later.
+///
+/// Their data stays marked as occupied in the block allocation table.
+#[derive(Debug, Clone, BinRead, BinWrite, Default)]
+#[brw(magic = b"xorp")]
+pub struct OrphanList {
+    len: u32,
+    #[br(args { count: len.try_into().unwrap() })]
+    files: Vec<OrphanedFile>,
+}
+
+/// A file in the [`OrphanList`], see
+/// [`ArhFileSystem::delete_file_keep_data`](crate::ArhFileSystem::delete_file_keep_data).
+#[derive(Debug, Clone, PartialEq, BinRead, BinWrite)]
+pub struct OrphanedFile {
+    /// The path of the file when it was deleted
+    #[br(map = |s: NullString| s.to_string())]
+    #[bw(map = |s: &String| NullString::from(s.as_str()))]
+    pub path: String,
+    pub meta: FileMeta,
+}
+
+/// The operations that modified the archive while recording was enabled, see
+/// [`ArhFileSystem::history`](crate::ArhFileSystem::history).
+#[derive(Debug, Clone, BinRead, BinWrite, Default)]
+#[brw(magic = b"xlog")]
+pub struct OpLog {
+    #[br(map = |x: u32| x != 0)]
+    #[bw(map = |x: &bool| u32::from(*x))]
+    enabled: bool,
+    len: u32,
+    #[br(args { count: len.try_into().unwrap() })]
+    entries: Vec<OpLogEntry>,
+}
+
+/// An operation in the [`OpLog`].
+#[derive(Debug, Clone, PartialEq, BinRead, BinWrite)]
+pub struct OpLogEntry {
+    /// When the operation happened, in seconds since the Unix epoch
+    pub timestamp: u64,
+    pub kind: OpKind,
+    /// The file or directory the operation applied to
+    #[br(map = |s: NullString| s.to_string())]
+    #[bw(map = |s: &String| NullString::from(s.as_str()))]
+    pub path: String,
+    /// The new path, for [`OpKind::Rename`]
+    #[br(map = |s: NullString| Some(s.to_string()).filter(|s| !s.is_empty()))]
+    #[bw(map = |s: &Option<String>| NullString::from(s.as_deref().unwrap_or_default()))]
+    pub new_path: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, BinRead, BinWrite)]
+#[brw(repr = u8)]
+pub enum OpKind {
+    /// A file or directory was created
+    Create = 0,
+    /// A file or directory was deleted
+    Delete = 1,
+    /// A file or directory was moved to [`OpLogEntry::new_path`]
+    Rename = 2,
+    /// The data of a file was changed
+    Replace = 3,
+}
+
 impl ArhExtSection {
-    pub fn new(arh: &Arh, block_size: u16) -> Self {
+    pub fn new(file_table: &FileTable, block_size: u16) -> Self {
         Self {
-            allocated_blocks: BlockAllocTable::new(arh, block_size),
+            allocated_blocks: BlockAllocTable::new(file_table, block_size),
             file_meta_recycle_bin: FileRecycleBin::default(),
+            shared_extents: SharedExtents::new(file_table, &[]),
+            orphans: OrphanList::default(),
+            op_log: OpLog::default(),
+        }
+    }
+
+    /// Returns the section in `ext`, creating it from the file table if there is none. If
+    /// [`ArhOptions::ext_force_block_size`] is set, a section with a different block size is
+    /// rebuilt with the one from `opts`.
+    pub(crate) fn get_or_init<'a>(
+        ext: &'a mut Option<Self>,
+        file_table: &FileTable,
+        opts: &ArhOptions,
+    ) -> &'a mut Self {
+        let ext = ext.get_or_insert_with(|| Self::new(file_table, opts.ext_block_size_pow));
+        if opts.ext_force_block_size
+            && ext.allocated_blocks.block_size_pow != opts.ext_block_size_pow
+        {
+            ext.rebuild_with_block_size(file_table, opts.ext_block_size_pow);
+        }
+        ext
+    }
+
+    /// Regenerates the block allocation table and the shared extent reference counts from
+    /// the file table, keeping the current block size.
+    ///
+    /// This is needed if the archive was modified by tools that don't update the section.
+    pub fn rebuild_from(&mut self, file_table: &FileTable) {
+        self.rebuild_with_block_size(file_table, self.allocated_blocks.block_size_pow);
+    }
+
+    /// Like [`Self::rebuild_from`], but with a new block size. The recycle bin, orphaned
+    /// files and history are kept.
+    pub fn rebuild_with_block_size(&mut self, file_table: &FileTable, block_size_pow: u16) {
+        self.allocated_blocks = BlockAllocTable::new(file_table, block_size_pow);
+        // Orphaned files are not in the file table, but their data must be kept
+        for file in &self.orphans.files {
+            self.allocated_blocks.mark(&file.meta, true);
+        }
+        self.shared_extents = SharedExtents::new(file_table, &self.orphans.files);
+    }
+
+    /// Builds the tables that were absent from the file, because it was written by an older
+    /// version. Without this, data that files share would be freed when one of them is
+    /// deleted.
+    pub(crate) fn fill_missing(&mut self, file_table: &FileTable) {
+        if self.shared_extents.missing {
+            self.shared_extents = SharedExtents::new(file_table, &self.orphans.files);
         }
     }
 
@@ -61,6 +207,9 @@ impl ArhExtSection {
         self.allocated_blocks
             .size_on_wire()
             .checked_add(self.file_meta_recycle_bin.size_on_wire())
+            .and_then(|sz| sz.checked_add(self.shared_extents.size_on_wire()))
+            .and_then(|sz| sz.checked_add(self.orphans.size_on_wire()))
+            .and_then(|sz| sz.checked_add(self.op_log.size_on_wire()))
             .and_then(|sz| sz.checked_add(size_of::<u32>()))
             .and_then(|sz| sz.try_into().ok())
             .expect("arhext size overflow")
@@ -68,24 +217,26 @@ impl ArhExtSection {
 }
 
 impl BlockAllocTable {
-    fn new(arh: &Arh, block_size_pow: u16) -> Self {
+    fn new(file_table: &FileTable, block_size_pow: u16) -> Self {
         let mut res = Self {
             block_size_pow,
             block_arr_count: 0,
             blocks: Vec::new(),
         };
-        for file in arh.file_table.files() {
+        for file in file_table.files() {
             res.mark(file, true);
         }
         res
     }
 
-    /// Returns the starting offset for an area with at least `desired_size` free bytes.
+    /// Returns the starting offset of the first area with at least `desired_size` free bytes.
     ///
-    /// The returned area is not guaranteed to be the one that comes first, nor must it be
-    /// the one with the minimum size.
+    /// Files written one after the other are placed one after the other, which keeps the
+    /// order chosen when building an archive. If there is no such area, the returned offset
+    /// is the start of the free space after the last occupied block, so the data is appended
+    /// to the ARD file.
     pub fn find_free_space(&self, desired_size: u64) -> u64 {
-        self.find_free_space_inner(desired_size, |_, i| i)
+        self.find_free_space_inner(desired_size, 0..0)
     }
 
     /// Treats the area occupied by `old_file` as empty, and returns the starting offset for an
@@ -98,111 +249,104 @@ impl BlockAllocTable {
             // Nothing to do, can reuse old space
             return old_file.offset;
         }
-        let file_start_block = old_file.offset.div_ceil(1 << self.block_size_pow);
-        let file_end_block = (old_file.offset + u64::from(old_file.compressed_size))
-            .div_ceil(1 << self.block_size_pow)
-            .saturating_sub(1);
-        const BITS: u64 = u64::BITS as u64;
-        let patch = |i, slot| {
-            let first_block = u64::try_from(i).unwrap() * BITS;
-            let last_block = first_block + BITS - 1;
-            if first_block >= file_start_block && last_block <= file_end_block {
-                // Completely free
-                return 0;
-            }
-            if file_start_block >= first_block && file_start_block <= last_block {
-                // [.....FFFFF] 0-9
-                // First       Last
-                return slot & !((1 << (file_start_block - first_block)) - 1);
-            }
-            if file_end_block >= first_block && file_end_block <= last_block {
-                // [FFFF------]
-                // First       Last
-                return slot & ((1 << (last_block - file_end_block)) - 1);
-            }
-            slot
-        };
-        self.find_free_space_inner(desired_size, patch)
+        let block_size: u64 = 1 << self.block_size_pow;
+        let file_blocks = old_file.offset.div_ceil(block_size)
+            ..(old_file.offset + u64::from(old_file.compressed_size)).div_ceil(block_size);
+        self.find_free_space_inner(desired_size, file_blocks)
     }
 
     /// Used for the previous two functions
     ///
-    /// The patch function can patch specific block slots, for example to make some blocks
-    /// temporarily available.
-    fn find_free_space_inner(
-        &self,
-        desired_size: u64,
-        patch_fn: impl Fn(usize, u64) -> u64,
-    ) -> u64 {
+    /// Blocks in `also_free` are treated as free, e.g. to make the space of a file that is
+    /// being replaced available.
+    fn find_free_space_inner(&self, desired_size: u64, also_free: Range<u64>) -> u64 {
         const BITS: u64 = u64::BITS as u64;
         let desired_blocks = desired_size.div_ceil(1 << self.block_size_pow);
+        if desired_blocks == 0 {
+            // Empty entries don't occupy any blocks
+            return 0;
+        }
 
-        let mut carry: u64 = 0;
-        let mut start_block = 0;
+        // The run of free blocks found so far, which may continue in the next slot
+        let mut run_start = 0;
+        let mut run_len = 0;
         for (i, slot) in self.blocks.iter().copied().enumerate() {
-            let slot = patch_fn(i, slot);
             let first_block = u64::try_from(i).unwrap() * BITS;
-            let mut trailing = 0;
-            // All occupied
-            if slot != u64::MAX {
-                if slot == 0 {
-                    // All 0 => all 64 blocks are free
-                    carry += BITS;
-                    if carry >= desired_blocks {
-                        return start_block * (1 << self.block_size_pow);
-                    }
+            let slot = slot & !Self::slot_mask(first_block, &also_free);
+            if slot == u64::MAX {
+                run_len = 0;
+                continue;
+            }
+            // Alternate between runs of free and occupied blocks, starting from the first
+            // block of the slot (the most significant bit)
+            let mut bit = 0;
+            while bit < BITS {
+                let rest = slot << bit;
+                // The zeros shifted in from the right are not blocks of this slot
+                let free = u64::from(rest.leading_zeros()).min(BITS - bit);
+                if free == 0 {
+                    // Here the shifted-in zeros become ones, which ends the count at the
+                    // last block of the slot
+                    run_len = 0;
+                    bit += u64::from((!rest).leading_zeros());
                     continue;
                 }
-                let leading = u64::from(slot.leading_zeros());
-                trailing = u64::from(slot.trailing_zeros());
-                if carry + leading >= desired_blocks {
-                    // Case 1: carried over + leading free blocks
-                    return start_block * (1 << self.block_size_pow);
+                if run_len == 0 {
+                    run_start = first_block + bit;
                 }
-                if desired_blocks <= BITS - leading - trailing {
-                    // Case 2: free blocks in the middle of a slot
-                    let n_slot = !slot;
-                    let mut mask = (1 << desired_blocks) - 1;
-                    while mask & (1 << 63) == 0 {
-                        if n_slot & mask == mask {
-                            return (first_block + u64::from(mask.leading_zeros()))
-                                * (1 << self.block_size_pow);
-                        }
-                        mask <<= 1;
-                    }
+                run_len += free;
+                if run_len >= desired_blocks {
+                    return run_start << self.block_size_pow;
                 }
+                bit += free;
             }
-            // Carry over trailing free blocks for case 1
-            carry = trailing;
-            start_block = first_block + BITS - carry;
         }
-        // No free space
-        let last = self.blocks.last().copied().unwrap_or_default();
-        let first_free_block =
-            u64::try_from(self.blocks.len()).unwrap() * BITS - u64::from(last.trailing_zeros());
-        first_free_block * (1 << self.block_size_pow)
+        // No free space: append after the last occupied block
+        if run_len == 0 {
+            run_start = u64::try_from(self.blocks.len()).unwrap() * BITS;
+        }
+        run_start << self.block_size_pow
+    }
+
+    /// Returns the bits of the slot starting at `first_block` that belong to `blocks`.
+    fn slot_mask(first_block: u64, blocks: &Range<u64>) -> u64 {
+        const BITS: u64 = u64::BITS as u64;
+        let start = blocks.start.max(first_block);
+        let end = blocks.end.min(first_block + BITS);
+        if start >= end {
+            return 0;
+        }
+        let len = end - start;
+        (u64::MAX >> (BITS - len)) << (BITS - len - (start - first_block))
     }
 
     pub fn mark(&mut self, file: &FileMeta, occupied: bool) {
         if file.compressed_size == 0 {
             return;
         }
+        self.mark_range(
+            file.offset..file.offset + u64::from(file.compressed_size),
+            occupied,
+        );
+    }
 
-        let (file_start, file_end) = (file.offset, file.offset + u64::from(file.compressed_size));
-        let mut start = file_start >> self.block_size_pow;
-        let mut end = file_end.div_ceil(1 << self.block_size_pow);
-        if !occupied {
+    /// Marks the blocks covering a region of the ARD file as occupied or free.
+    ///
+    /// When freeing, blocks that are only partially covered by the region are left untouched.
+    pub fn mark_range(&mut self, region: Range<u64>, occupied: bool) {
+        if region.is_empty() {
+            return;
+        }
+        let block_size = 1 << self.block_size_pow;
+        let (start, end) = if occupied {
+            (region.start / block_size, region.end.div_ceil(block_size))
+        } else {
             // We write files with sizes that are a multiple of the block size. If we are freeing
             // a file that only covers the start (or end) block partially, we must not mark the block
             // as freed because another file might also be there.
-            if file_start % (1 << self.block_size_pow) != 0 {
-                start += 1;
-            }
-            if file_end % (1 << self.block_size_pow) != 0 {
-                end -= 1;
-            }
-        }
-        for block in start..=end {
+            (region.start.div_ceil(block_size), region.end / block_size)
+        };
+        for block in start..end {
             let item = (block / 64) as usize;
             let in_item = block % 64;
             while item >= self.blocks.len() {
@@ -218,8 +362,92 @@ impl BlockAllocTable {
         self.block_arr_count = self.blocks.len().try_into().unwrap();
     }
 
+    /// Builds an equivalent table with a different block size.
+    ///
+    /// Blocks that are only partially occupied at the new granularity are marked as occupied.
+    /// Returns the new table and the number of bytes that were free, but are now occupied.
+    pub(crate) fn with_block_size(&self, block_size_pow: u16) -> (Self, u64) {
+        let mut res = Self {
+            block_size_pow,
+            block_arr_count: 0,
+            blocks: Vec::new(),
+        };
+        let old_block_size: u64 = 1 << self.block_size_pow;
+        for (i, slot) in self.blocks.iter().enumerate() {
+            for bit in (0..64).filter(|bit| slot & (1 << (63 - bit)) != 0) {
+                let start = (i as u64 * 64 + bit) * old_block_size;
+                res.mark_range(start..start + old_block_size, true);
+            }
+        }
+        let lost = res.occupied_bytes() - self.occupied_bytes();
+        (res, lost)
+    }
+
+    /// Compares this table to `other`, which must have the same block size.
+    ///
+    /// Returns the regions that are only occupied in `other`, and the regions that are only
+    /// occupied in this table, in that order.
+    pub(crate) fn diff(&self, other: &Self) -> (Vec<Range<u64>>, Vec<Range<u64>>) {
+        assert_eq!(self.block_size_pow, other.block_size_pow);
+        let (mut missing, mut extra) = (Vec::new(), Vec::new());
+        let block_size: u64 = 1 << self.block_size_pow;
+        let len = self.blocks.len().max(other.blocks.len());
+        for i in 0..len {
+            let ours = self.blocks.get(i).copied().unwrap_or_default();
+            let theirs = other.blocks.get(i).copied().unwrap_or_default();
+            for bit in 0..64 {
+                let mask = 1 << (63 - bit);
+                let regions = match (ours & mask != 0, theirs & mask != 0) {
+                    (false, true) => &mut missing,
+                    (true, false) => &mut extra,
+                    _ => continue,
+                };
+                let start = (i as u64 * 64 + bit) * block_size;
+                match regions.last_mut() {
+                    Some(Range { end, .. }) if *end == start => *end += block_size,
+                    _ => regions.push(start..start + block_size),
+                }
+            }
+        }
+        (missing, extra)
+    }
+
+    pub(crate) fn occupied_bytes(&self) -> u64 {
+        let blocks: u64 = self.blocks.iter().map(|s| u64::from(s.count_ones())).sum();
+        blocks << self.block_size_pow
+    }
+
+    /// The end of the last occupied block, in bytes. Everything after it is free.
+    pub(crate) fn end(&self) -> u64 {
+        let Some(i) = self.blocks.iter().rposition(|&slot| slot != 0) else {
+            return 0;
+        };
+        let blocks =
+            u64::try_from(i).unwrap() * 64 + 64 - u64::from(self.blocks[i].trailing_zeros());
+        blocks << self.block_size_pow
+    }
+
+    /// Returns the runs of free blocks before [`Self::end`], sorted by offset.
+    pub(crate) fn free_runs(&self) -> Vec<Range<u64>> {
+        let block_size: u64 = 1 << self.block_size_pow;
+        let end_block = self.end() / block_size;
+        let mut runs: Vec<Range<u64>> = Vec::new();
+        for block in 0..end_block {
+            if self.blocks[(block / 64) as usize] & (1 << (63 - block % 64)) != 0 {
+                continue;
+            }
+            let start = block * block_size;
+            match runs.last_mut() {
+                Some(Range { end, .. }) if *end == start => *end += block_size,
+                _ => runs.push(start..start + block_size),
+            }
+        }
+        runs
+    }
+
     fn size_on_wire(&self) -> usize {
-        self.blocks.len() * size_of::<u64>() + size_of::<u32>() + size_of::<u16>()
+        // block size + count + blocks
+        self.blocks.len() * size_of::<u64>() + size_of::<u64>() + size_of::<u16>()
     }
 }
 
@@ -236,16 +464,221 @@ impl FileRecycleBin {
         self.file_ids.pop()
     }
 
+    /// The recycled IDs, in ascending order. [`Self::pop`] takes the last one.
+    pub fn ids(&self) -> &[u32] {
+        &self.file_ids
+    }
+
     fn size_on_wire(&self) -> usize {
         self.file_ids.len() * size_of::<u32>() + size_of::<u32>()
     }
 }
 
+/// Used when the table is absent from the file, so it is marked as missing
+impl Default for SharedExtents {
+    fn default() -> Self {
+        Self {
+            len: 0,
+            extents: Vec::new(),
+            missing: true,
+        }
+    }
+}
+
+impl SharedExtents {
+    /// Counts the files that use each extent. Orphaned files hold a reference, so their data
+    /// isn't freed when the last file that uses it is deleted.
+    fn new(file_table: &FileTable, orphans: &[OrphanedFile]) -> Self {
+        let mut offsets: Vec<_> = file_table
+            .files()
+            .iter()
+            .chain(orphans.iter().map(|o| &o.meta))
+            .filter(|f| f.compressed_size != 0)
+            .map(|f| f.offset)
+            .collect();
+        offsets.sort_unstable();
+        let mut extents: Vec<SharedExtent> = Vec::new();
+        for pair in offsets.windows(2) {
+            if pair[0] != pair[1] {
+                continue;
+            }
+            match extents.last_mut() {
+                Some(last) if last.offset == pair[0] => last.refs += 1,
+                _ => extents.push(SharedExtent {
+                    offset: pair[0],
+                    refs: 2,
+                }),
+            }
+        }
+        Self {
+            len: extents.len().try_into().unwrap(),
+            extents,
+            missing: false,
+        }
+    }
+
+    /// Registers another file that uses the data of `file`.
+    pub fn acquire(&mut self, file: &FileMeta) {
+        if file.compressed_size == 0 {
+            return;
+        }
+        match self.find(file.offset) {
+            Ok(i) => self.extents[i].refs += 1,
+            Err(i) => {
+                self.extents.insert(
+                    i,
+                    SharedExtent {
+                        offset: file.offset,
+                        refs: 2,
+                    },
+                );
+                self.len += 1;
+            }
+        }
+    }
+
+    /// Returns whether other files use the data of `file`.
+    pub fn is_shared(&self, file: &FileMeta) -> bool {
+        file.compressed_size != 0 && self.find(file.offset).is_ok()
+    }
+
+    /// Unregisters a file that no longer uses the data of `file`.
+    ///
+    /// Returns whether other files still use the data, in which case it must not be freed.
+    pub fn release(&mut self, file: &FileMeta) -> bool {
+        if file.compressed_size == 0 {
+            return false;
+        }
+        let Ok(i) = self.find(file.offset) else {
+            return false;
+        };
+        self.extents[i].refs -= 1;
+        if self.extents[i].refs < 2 {
+            self.extents.remove(i);
+            self.len -= 1;
+        }
+        true
+    }
+
+    fn find(&self, offset: u64) -> Result<usize, usize> {
+        self.extents.binary_search_by_key(&offset, |e| e.offset)
+    }
+
+    fn size_on_wire(&self) -> usize {
+        // magic + len + (offset + refs) for each extent
+        self.extents.len() * (size_of::<u64>() + size_of::<u32>()) + 2 * size_of::<u32>()
+    }
+}
+
+impl OrphanList {
+    pub fn files(&self) -> &[OrphanedFile] {
+        &self.files
+    }
+
+    pub fn push(&mut self, file: OrphanedFile) {
+        self.files.push(file);
+        self.len += 1;
+    }
+
+    pub fn remove(&mut self, index: usize) -> OrphanedFile {
+        self.len -= 1;
+        self.files.remove(index)
+    }
+
+    pub fn retain(&mut self, f: impl FnMut(&OrphanedFile) -> bool) {
+        self.files.retain(f);
+        self.len = self.files.len().try_into().unwrap();
+    }
+
+    fn size_on_wire(&self) -> usize {
+        // magic + len + (path + null + meta) for each file
+        self.files
+            .iter()
+            .map(|f| f.path.len() + 1 + size_of::<FileMeta>())
+            .sum::<usize>()
+            + 2 * size_of::<u32>()
+    }
+}
+
+impl OpLog {
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+    }
+
+    pub fn entries(&self) -> &[OpLogEntry] {
+        &self.entries
+    }
+
+    /// Adds an entry. Consecutive changes to the data of the same file (e.g. many small
+    /// writes) are merged into one entry, with the time of the latest change.
+    ///
+    /// Past [`OP_LOG_MAX_ENTRIES`], the oldest entry is dropped.
+    pub fn push(&mut self, entry: OpLogEntry) {
+        if let Some(last) = self.entries.last_mut().filter(|last| {
+            entry.kind == OpKind::Replace && last.kind == OpKind::Replace && last.path == entry.path
+        }) {
+            last.timestamp = entry.timestamp;
+            return;
+        }
+        if self.entries.len() >= OP_LOG_MAX_ENTRIES {
+            self.entries.remove(0);
+            self.len -= 1;
+        }
+        self.entries.push(entry);
+        self.len += 1;
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.len = 0;
+    }
+
+    fn size_on_wire(&self) -> usize {
+        // magic + enabled + len + (timestamp + kind + path + null + new path + null) for
+        // each entry
+        self.entries
+            .iter()
+            .map(|e| {
+                size_of::<u64>()
+                    + 1
+                    + e.path.len()
+                    + 1
+                    + e.new_path.as_ref().map_or(0, String::len)
+                    + 1
+            })
+            .sum::<usize>()
+            + 3 * size_of::<u32>()
+    }
+}
+
+impl OpKind {
+    /// Returns the name of the operation, e.g. for listings.
+    pub fn name(self) -> &'static str {
+        match self {
+            OpKind::Create => "create",
+            OpKind::Delete => "delete",
+            OpKind::Rename => "rename",
+            OpKind::Replace => "replace",
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::FileMeta;
+    use std::{
+        io::{Cursor, Write},
+        ops::Range,
+    };
+
+    use binrw::{BinRead, BinWrite};
 
-    use super::BlockAllocTable;
+    use crate::{arh::FileTable, consistency::Rng, FileMeta};
+
+    use super::{ArhExtSection, BlockAllocTable, FileRecycleBin};
 
     const BLOCK_POW: u16 = 9;
     const BLOCK_SIZE: u64 = 1 << BLOCK_POW;
@@ -284,10 +717,241 @@ mod tests {
             block_arr_count: 0,
             blocks: vec![0b1110000110001100111111110111111111111111111111111111111111111111],
         };
-        assert_eq!(table.find_free_space(1 * BLOCK_SIZE), 24 * BLOCK_SIZE);
-        assert_eq!(table.find_free_space(2 * BLOCK_SIZE), 14 * BLOCK_SIZE);
-        assert_eq!(table.find_free_space(3 * BLOCK_SIZE), 9 * BLOCK_SIZE);
+        // The first run that fits is used, even if a smaller one would also fit. This used to
+        // be the last one in the slot (24, 14, 9 and 3), which put a new archive's second
+        // file at the end of the first slot instead of after the first file
+        assert_eq!(table.find_free_space(1 * BLOCK_SIZE), 3 * BLOCK_SIZE);
         assert_eq!(table.find_free_space(4 * BLOCK_SIZE), 3 * BLOCK_SIZE);
+        assert_eq!(table.find_free_space(5 * BLOCK_SIZE), 64 * BLOCK_SIZE);
+
+        // Case 3: free blocks after the last occupied one
+        let table = BlockAllocTable {
+            block_size_pow: BLOCK_POW,
+            block_arr_count: 0,
+            // 124 occupied blocks, 4+64 free blocks
+            blocks: vec![u64::MAX, !0b1111, 0],
+        };
+        assert_eq!(table.find_free_space(100 * BLOCK_SIZE), 124 * BLOCK_SIZE);
+        assert_eq!(table.find_free_space(0), 0);
+    }
+
+    /// Returns the first block of the first run of `desired_blocks` free blocks, one block
+    /// at a time.
+    fn find_reference(table: &BlockAllocTable, desired_blocks: u64, also_free: &Range<u64>) -> u64 {
+        let total = table.blocks.len() as u64 * 64;
+        let is_free = |block: u64| {
+            also_free.contains(&block)
+                || block >= total
+                || table.blocks[(block / 64) as usize] & (1 << (63 - block % 64)) == 0
+        };
+        let mut run = 0;
+        for block in 0.. {
+            run = if is_free(block) { run + 1 } else { 0 };
+            if run == desired_blocks {
+                return block + 1 - desired_blocks;
+            }
+        }
+        unreachable!()
+    }
+
+    fn table_with_free(runs: &[Range<u64>]) -> BlockAllocTable {
+        let mut table = BlockAllocTable {
+            block_size_pow: BLOCK_POW,
+            block_arr_count: 3,
+            blocks: vec![u64::MAX; 3],
+        };
+        for run in runs {
+            table.mark_range(run.start * BLOCK_SIZE..run.end * BLOCK_SIZE, false);
+        }
+        table
+    }
+
+    #[test]
+    fn block_table_find_single_run() {
+        // Every free run in a table of 3 slots, with sizes around the run and slot sizes
+        for start in 0..192 {
+            for end in start + 1..=192 {
+                let table = table_with_free(std::slice::from_ref(&(start..end)));
+                let len = end - start;
+                for desired in [1, len - 1, len, len + 1, 63, 64, 65, 128, 129, 192] {
+                    let desired = desired.max(1);
+                    assert_eq!(
+                        table.find_free_space(desired * BLOCK_SIZE),
+                        find_reference(&table, desired, &(0..0)) * BLOCK_SIZE,
+                        "free {start}..{end}, {desired} blocks"
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn block_table_find_two_runs() {
+        // Runs that start and end at or next to slot boundaries
+        const EDGES: [u64; 14] = [0, 1, 2, 31, 62, 63, 64, 65, 66, 127, 128, 129, 190, 191];
+        for (i, &a) in EDGES.iter().enumerate() {
+            for (j, &b) in EDGES.iter().enumerate().skip(i + 1) {
+                for (k, &c) in EDGES.iter().enumerate().skip(j + 1) {
+                    for &d in &EDGES[k + 1..] {
+                        let table = table_with_free(&[a..b, c..d]);
+                        for desired in 1..=192 {
+                            assert_eq!(
+                                table.find_free_space(desired * BLOCK_SIZE),
+                                find_reference(&table, desired, &(0..0)) * BLOCK_SIZE,
+                                "free {a}..{b} and {c}..{d}, {desired} blocks"
+                            );
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn block_table_find_replace_boundaries() {
+        // One free block in each slot, so runs only form with the replaced file's blocks
+        let table = table_with_free(&[10..11, 100..101, 150..151]);
+        for start in 0..192 {
+            for end in start + 1..=192 {
+                let file =
+                    FileMeta::new_for_test(start * BLOCK_SIZE, ((end - start) * BLOCK_SIZE) as u32);
+                for desired in [end - start + 1, end - start + 2, 64, 65] {
+                    if desired <= end - start {
+                        continue;
+                    }
+                    assert_eq!(
+                        table.find_space_replace(&file, desired * BLOCK_SIZE),
+                        find_reference(&table, desired, &(start..end)) * BLOCK_SIZE,
+                        "file {start}..{end}, {desired} blocks"
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn block_table_slot_mask() {
+        for start in 0..=192 {
+            for end in start..=192 {
+                for slot in 0..3 {
+                    let first = slot * 64;
+                    let expected = (first..first + 64)
+                        .filter(|block| (start..end).contains(block))
+                        .fold(0, |mask, block| mask | 1 << (63 - (block - first)));
+                    assert_eq!(
+                        BlockAllocTable::slot_mask(first, &(start..end)),
+                        expected,
+                        "{start}..{end} in slot {slot}"
+                    );
+                }
+            }
+        }
+    }
+
+    /// The search before it worked on whole words, shifting a mask one block at a time.
+    fn find_free_space_old(table: &BlockAllocTable, desired_size: u64) -> u64 {
+        const BITS: u64 = u64::BITS as u64;
+        let desired_blocks = desired_size.div_ceil(1 << table.block_size_pow);
+
+        let mut carry: u64 = 0;
+        let mut start_block = 0;
+        for (i, slot) in table.blocks.iter().copied().enumerate() {
+            let first_block = u64::try_from(i).unwrap() * BITS;
+            let mut trailing = 0;
+            if slot != u64::MAX {
+                if slot == 0 {
+                    carry += BITS;
+                    if carry >= desired_blocks {
+                        return start_block * (1 << table.block_size_pow);
+                    }
+                    continue;
+                }
+                let leading = u64::from(slot.leading_zeros());
+                trailing = u64::from(slot.trailing_zeros());
+                if carry + leading >= desired_blocks {
+                    return start_block * (1 << table.block_size_pow);
+                }
+                if desired_blocks <= BITS - leading - trailing {
+                    let n_slot = !slot;
+                    let mut mask = (1 << desired_blocks) - 1;
+                    while mask & (1 << 63) == 0 {
+                        if n_slot & mask == mask {
+                            return (first_block + u64::from(mask.leading_zeros()))
+                                * (1 << table.block_size_pow);
+                        }
+                        mask <<= 1;
+                    }
+                }
+            }
+            carry = trailing;
+            start_block = first_block + BITS - carry;
+        }
+        let last = table.blocks.last().copied().unwrap_or_default();
+        let first_free_block =
+            u64::try_from(table.blocks.len()).unwrap() * BITS - u64::from(last.trailing_zeros());
+        first_free_block * (1 << table.block_size_pow)
+    }
+
+    /// Compares the search to the old one on a large, fragmented table.
+    ///
+    /// Run with `cargo test --release -p ardain block_table_bench -- --ignored --nocapture`.
+    #[test]
+    #[ignore]
+    fn block_table_bench() {
+        // 64 Mi blocks (32 GiB with 512-byte blocks), mostly occupied, with short free runs
+        // Seeded, so the table is the same every time
+        let mut rng = Rng::new(0);
+        let blocks = (0..1 << 20)
+            .map(|_| {
+                let state = rng.next_u64();
+                state | state >> 1 | state >> 2
+            })
+            .collect();
+        let table = BlockAllocTable {
+            block_size_pow: BLOCK_POW,
+            block_arr_count: 1 << 20,
+            blocks,
+        };
+        for desired in [1, 4, 8, 200] {
+            let size = desired * BLOCK_SIZE;
+            let time = |find: &dyn Fn() -> u64| {
+                let start = std::time::Instant::now();
+                let mut offset = 0;
+                for _ in 0..10 {
+                    offset = std::hint::black_box(find());
+                }
+                (offset, start.elapsed() / 10)
+            };
+            let (old_offset, old) = time(&|| find_free_space_old(&table, size));
+            let (new_offset, new) = time(&|| table.find_free_space(size));
+            println!(
+                "{desired:>4} blocks: old {old:?} (at {old_offset:#x}), \
+                 new {new:?} (at {new_offset:#x})"
+            );
+        }
+    }
+
+    #[test]
+    fn block_table_resize() {
+        let table = BlockAllocTable {
+            block_size_pow: BLOCK_POW,
+            block_arr_count: 2,
+            // 2 occupied blocks, 1 free block, 1 occupied block, 124 free blocks, 1 occupied block
+            blocks: vec![0b1101 << 60, 1],
+        };
+        // Finer blocks are always exact
+        let (finer, lost) = table.with_block_size(BLOCK_POW - 1);
+        assert_eq!(lost, 0);
+        assert_eq!(finer.blocks, [0b11110011 << 56, 0, 0, 0b11]);
+        assert_eq!(finer.occupied_bytes(), table.occupied_bytes());
+
+        // Free blocks 2 and 126 share a coarser block with an occupied one
+        let (coarser, lost) = table.with_block_size(BLOCK_POW + 1);
+        assert_eq!(lost, 2 * BLOCK_SIZE);
+        assert_eq!(coarser.blocks, [0b11 << 62 | 1]);
+        let (back, lost) = coarser.with_block_size(BLOCK_POW);
+        assert_eq!(lost, 0);
+        assert_eq!(back.blocks, [0b1111 << 60, 0b11]);
     }
 
     #[test]
@@ -310,4 +974,44 @@ mod tests {
             320 * BLOCK_SIZE
         );
     }
+
+    #[test]
+    fn shared_extents_missing() {
+        let mut file_table = FileTable::default();
+        file_table.set_files(vec![
+            FileMeta::new_for_test(0, 16),
+            FileMeta::new_for_test(0, 16),
+            FileMeta::new_for_test(16, 16),
+        ]);
+        // Written by a version without the shared extent table
+        let mut bytes = Cursor::new(Vec::new());
+        bytes.write_all(b"arhx").unwrap();
+        BlockAllocTable::new(&file_table, BLOCK_POW)
+            .write_le(&mut bytes)
+            .unwrap();
+        FileRecycleBin::default().write_le(&mut bytes).unwrap();
+        bytes.set_position(0);
+
+        let mut ext = ArhExtSection::read_le(&mut bytes).unwrap();
+        ext.fill_missing(&file_table);
+        assert!(ext.shared_extents.is_shared(&file_table.files()[0]));
+        assert!(!ext.shared_extents.is_shared(&file_table.files()[2]));
+    }
+
+    #[test]
+    fn calc_size() {
+        let mut file_table = FileTable::default();
+        file_table.set_files(vec![
+            FileMeta::new_for_test(0, 16),
+            FileMeta::new_for_test(0, 16),
+            FileMeta::new_for_test(BLOCK_SIZE * 100, 16),
+        ]);
+        let mut ext = ArhExtSection::new(&file_table, BLOCK_POW);
+        ext.file_meta_recycle_bin.push(3);
+        // The section is written after the tables that follow it, so a size that is too
+        // small overwrites them
+        let mut bytes = Cursor::new(Vec::new());
+        ext.write_le(&mut bytes).unwrap();
+        assert_eq!(ext.calc_size() as usize, bytes.into_inner().len());
+    }
 }
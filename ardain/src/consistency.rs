@@ -0,0 +1,468 @@
+//! Randomized sequences of file system operations, checking the file system's invariants
+//! after each step.
+//!
+//! This can be used to stress an existing archive, or driven by a fuzzer through
+//! [`ConsistencyCheck::replay`]. Failures carry the seed and the operations that led to
+//! them, so they can be reproduced.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt,
+    io::{Cursor, Read, Seek, Write},
+};
+
+use crate::{
+    error::Error,
+    file_alloc::{ArdFileAllocator, CompressionStrategy},
+    path::{ArhPath, ARH_PATH_ROOT},
+    ArdReader, ArdWriter, ArhFileSystem, DirEntry, DirNode, DirStats,
+};
+
+/// A single operation, as generated by [`ConsistencyCheck::run`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Op {
+    Create(ArhPath),
+    Delete(ArhPath),
+    Rename {
+        from: ArhPath,
+        to: ArhPath,
+    },
+    /// Renames a directory, moving the files inside it
+    RenameDir {
+        from: ArhPath,
+        to: ArhPath,
+    },
+    /// Writes `len` bytes to an existing file. The data is derived from the file's path and
+    /// the step number, so replays write the same bytes.
+    Write {
+        path: ArhPath,
+        len: usize,
+    },
+}
+
+/// A broken invariant, see [`ConsistencyCheck::run`].
+#[derive(Debug, Clone)]
+pub struct Failure {
+    /// The seed of the run, if the operations were generated
+    pub seed: Option<u64>,
+    /// The operations that were applied, the last one being the one that broke the invariant.
+    /// Empty if the file system was inconsistent to begin with.
+    pub ops: Vec<Op>,
+    pub reason: String,
+}
+
+/// Generates and applies random operations, see the [module docs](self).
+///
+/// Operations that fail with an error (e.g. creating a file that already exists) are not
+/// failures by themselves, but they must leave the file system unchanged.
+#[derive(Debug, Clone)]
+pub struct ConsistencyCheck {
+    seed: u64,
+    steps: usize,
+    max_write_len: usize,
+    read_back: bool,
+}
+
+/// What the file system should contain, based on the operations that succeeded.
+struct Model {
+    /// All files, with the last data written to them, if it was written by an operation
+    files: BTreeMap<ArhPath, Option<Vec<u8>>>,
+    check_blocks: bool,
+}
+
+/// A small xorshift generator, so runs are reproducible without extra dependencies.
+///
+/// Benchmarks use it too, to get the same "random" order on every run.
+pub struct Rng(u64);
+
+impl ConsistencyCheck {
+    pub fn new(seed: u64) -> Self {
+        Self {
+            seed,
+            steps: 100,
+            max_write_len: 4096,
+            read_back: true,
+        }
+    }
+
+    /// The number of operations to apply (default: 100).
+    pub fn steps(mut self, steps: usize) -> Self {
+        self.steps = steps;
+        self
+    }
+
+    /// The largest amount of data written to a file at once (default: 4096).
+    pub fn max_write_len(mut self, len: usize) -> Self {
+        self.max_write_len = len;
+        self
+    }
+
+    /// Whether the archive is also written and loaded again after the last step, checking
+    /// that the result matches (default: `true`).
+    pub fn read_back(mut self, read_back: bool) -> Self {
+        self.read_back = read_back;
+        self
+    }
+
+    /// Applies random operations to the file system. File data is written to `ard`, and
+    /// read back from it to check the result.
+    ///
+    /// Returns the operations that were applied, or the first failure.
+    pub fn run(
+        &self,
+        fs: &mut ArhFileSystem,
+        ard: &mut ArdWriter<impl Read + Write + Seek>,
+    ) -> Result<Vec<Op>, Failure> {
+        let mut rng = Rng::new(self.seed);
+        let mut model = Model::new(fs).map_err(|reason| self.failure(&[], reason))?;
+        let mut ops = Vec::with_capacity(self.steps);
+        for _ in 0..self.steps {
+            ops.push(self.next_op(&mut rng, &model));
+            let step = ops.len() - 1;
+            model
+                .apply(fs, ard, &ops[step], step)
+                .map_err(|reason| self.failure(&ops, reason))?;
+        }
+        if self.read_back {
+            model
+                .check_read_back(fs, ard)
+                .map_err(|reason| self.failure(&ops, reason))?;
+        }
+        Ok(ops)
+    }
+
+    /// Applies the given operations, e.g. from a [`Failure`] or from fuzzer input, checking
+    /// the invariants after each step like [`Self::run`].
+    pub fn replay(
+        fs: &mut ArhFileSystem,
+        ard: &mut ArdWriter<impl Read + Write + Seek>,
+        ops: &[Op],
+    ) -> Result<(), Failure> {
+        let failure = |ops: &[Op], reason| Failure {
+            seed: None,
+            ops: ops.to_vec(),
+            reason,
+        };
+        let mut model = Model::new(fs).map_err(|reason| failure(&[], reason))?;
+        for (step, op) in ops.iter().enumerate() {
+            model
+                .apply(fs, ard, op, step)
+                .map_err(|reason| failure(&ops[..=step], reason))?;
+        }
+        model
+            .check_read_back(fs, ard)
+            .map_err(|reason| failure(ops, reason))
+    }
+
+    fn failure(&self, ops: &[Op], reason: String) -> Failure {
+        Failure {
+            seed: Some(self.seed),
+            ops: ops.to_vec(),
+            reason,
+        }
+    }
+
+    fn next_op(&self, rng: &mut Rng, model: &Model) -> Op {
+        const DIRS: &[&str] = &["/", "/fuzz", "/fuzz/a", "/fuzz/a/b", "/fuzz/c"];
+        let existing: Vec<_> = model.files.keys().collect();
+        let pick_existing = |rng: &mut Rng| (*rng.pick(&existing)).clone();
+        // Mostly short names in a few directories, so operations often collide
+        let new_path = |rng: &mut Rng| {
+            let parent = match rng.below(8) {
+                // Below an existing file, which must fail
+                0 if !existing.is_empty() => pick_existing(rng),
+                _ => ArhPath::normalize(rng.pick(DIRS)).unwrap(),
+            };
+            let name = match rng.below(16) {
+                // Extended file names are not supported
+                0 => format!("f{}.tar.gz", rng.below(4)),
+                _ => format!("f{}.bin", rng.below(12)),
+            };
+            parent.join(&name)
+        };
+        if existing.is_empty() {
+            return Op::Create(new_path(rng));
+        }
+        match rng.below(12) {
+            0..=2 => Op::Create(new_path(rng)),
+            3..=4 => Op::Delete(pick_existing(rng)),
+            5..=6 => Op::Rename {
+                from: pick_existing(rng),
+                to: match rng.below(4) {
+                    0 => pick_existing(rng),
+                    _ => new_path(rng),
+                },
+            },
+            // The directory of an existing file, which may be the root (and fail)
+            7 => {
+                let file = pick_existing(rng);
+                let parent = &file[..file.rfind('/').unwrap()];
+                let to = ArhPath::normalize(rng.pick(DIRS)).unwrap();
+                Op::RenameDir {
+                    from: ArhPath::normalize(parent).unwrap_or_default(),
+                    to: to.join(&format!("d{}", rng.below(4))),
+                }
+            }
+            _ => Op::Write {
+                path: pick_existing(rng),
+                len: rng.below(self.max_write_len as u64 + 1) as usize,
+            },
+        }
+    }
+}
+
+impl Model {
+    fn new(fs: &ArhFileSystem) -> Result<Self, String> {
+        let mut files = BTreeSet::new();
+        list_files(
+            fs.get_dir(&ARH_PATH_ROOT).unwrap(),
+            &ARH_PATH_ROOT,
+            &mut files,
+        );
+        let model = Self {
+            files: files.into_iter().map(|path| (path, None)).collect(),
+            check_blocks: fs.check_block_table().is_empty(),
+        };
+        model.check::<Cursor<Vec<u8>>>(fs, None)?;
+        Ok(model)
+    }
+
+    /// Applies an operation to both the file system and the model, then checks that they
+    /// match.
+    fn apply(
+        &mut self,
+        fs: &mut ArhFileSystem,
+        ard: &mut ArdWriter<impl Read + Write + Seek>,
+        op: &Op,
+        step: usize,
+    ) -> Result<(), String> {
+        let res = match op {
+            Op::Create(path) => fs.create_file(path).map(|_| ()),
+            Op::Delete(path) => fs.delete_file(path),
+            Op::Rename { from, to } => fs.rename_file(from, to),
+            Op::RenameDir { from, to } => fs.rename_dir(from, to),
+            Op::Write { path, len } => match fs.get_file_info(path) {
+                Some(meta) => {
+                    let id = meta.id;
+                    let data = write_data(path, step, *len);
+                    ArdFileAllocator::new(fs, ard).replace_file(
+                        id,
+                        &data,
+                        CompressionStrategy::None,
+                    )
+                }
+                None => Err(Error::FsNoEntry),
+            },
+        };
+        if res.is_ok() {
+            match op {
+                Op::Create(path) => {
+                    self.files.insert(path.clone(), None);
+                }
+                Op::Delete(path) => {
+                    self.files.remove(path);
+                }
+                Op::Rename { from, to } => {
+                    let data = self.files.remove(from).flatten();
+                    self.files.insert(to.clone(), data);
+                }
+                Op::RenameDir { from, to } => {
+                    let moved: Vec<_> = self
+                        .files
+                        .keys()
+                        .filter(|path| {
+                            path.strip_prefix(from.as_str())
+                                .is_some_and(|rest| rest.starts_with('/'))
+                        })
+                        .cloned()
+                        .collect();
+                    for path in moved {
+                        let data = self.files.remove(&path).unwrap();
+                        self.files.insert(to.join(&path[from.len()..]), data);
+                    }
+                }
+                Op::Write { path, len } => {
+                    self.files
+                        .insert(path.clone(), Some(write_data(path, step, *len)));
+                }
+            }
+        }
+        self.check(fs, Some(ard)).map_err(|reason| match res {
+            Err(e) => format!("{reason} (after the operation failed with: {e})"),
+            Ok(()) => reason,
+        })
+    }
+
+    /// Checks the file system against the model, and its own invariants.
+    fn check<W: Read + Write + Seek>(
+        &self,
+        fs: &ArhFileSystem,
+        ard: Option<&mut ArdWriter<W>>,
+    ) -> Result<(), String> {
+        let mut listed = BTreeSet::new();
+        check_stats(fs, fs.get_dir(&ARH_PATH_ROOT).unwrap(), &ARH_PATH_ROOT)?;
+        list_files(
+            fs.get_dir(&ARH_PATH_ROOT).unwrap(),
+            &ARH_PATH_ROOT,
+            &mut listed,
+        );
+        if let Some(path) = listed.iter().find(|p| !self.files.contains_key(*p)) {
+            return Err(format!("{path}: listed, but should not exist"));
+        }
+        let mut ids = BTreeSet::new();
+        for path in self.files.keys() {
+            if !listed.contains(path) {
+                return Err(format!("{path}: missing from the directory tree"));
+            }
+            let Some(meta) = fs.get_file_info(path) else {
+                return Err(format!("{path}: listed, but has no metadata"));
+            };
+            // The game looks files up by their slot in the file table
+            let (slot, _) = fs.get_file_id(path).unwrap();
+            if meta.id != slot {
+                return Err(format!(
+                    "{path}: file ID is {}, but its file table entry is {slot}",
+                    meta.id
+                ));
+            }
+            if !ids.insert(meta.id) {
+                return Err(format!(
+                    "{path}: file ID {} is used by another file",
+                    meta.id
+                ));
+            }
+        }
+        if self.check_blocks {
+            let drift = fs.check_block_table();
+            if !drift.is_empty() {
+                return Err(format!("block allocation table out of sync: {drift:?}"));
+            }
+        }
+        let Some(ard) = ard else {
+            return Ok(());
+        };
+        let mut reader = ArdReader::new(ard.get_mut());
+        for (path, data) in &self.files {
+            let Some(data) = data.as_ref().filter(|d| !d.is_empty()) else {
+                continue;
+            };
+            let meta = fs.get_file_info(path).unwrap();
+            match reader.entry(meta).read() {
+                Ok(read) if read == *data => {}
+                Ok(_) => return Err(format!("{path}: data doesn't match what was written")),
+                Err(e) => return Err(format!("{path}: failed to read data: {e}")),
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes the archive and loads it again, then checks the loaded copy.
+    fn check_read_back(
+        &self,
+        fs: &mut ArhFileSystem,
+        ard: &mut ArdWriter<impl Read + Write + Seek>,
+    ) -> Result<(), String> {
+        let mut out = Cursor::new(Vec::new());
+        fs.sync(&mut out)
+            .map_err(|e| format!("failed to write the archive: {e}"))?;
+        out.set_position(0);
+        let loaded = ArhFileSystem::load(out)
+            .map_err(|e| format!("failed to load the written archive: {e}"))?;
+        self.check(&loaded, Some(ard))
+            .map_err(|reason| format!("{reason} (after writing and loading the archive)"))
+    }
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.seed {
+            Some(seed) => writeln!(f, "consistency check failed (seed {seed}): {}", self.reason)?,
+            None => writeln!(f, "consistency check failed: {}", self.reason)?,
+        }
+        for (step, op) in self.ops.iter().enumerate() {
+            writeln!(f, "  {step}: {op:?}")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for Failure {}
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        // Zero is the only state xorshift can't leave
+        Self(seed ^ 0x9E37_79B9_7F4A_7C15)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    /// Returns a number in `0..n`.
+    pub fn below(&mut self, n: u64) -> u64 {
+        self.next_u64() % n
+    }
+
+    pub fn pick<'a, T>(&mut self, items: &'a [T]) -> &'a T {
+        &items[self.below(items.len() as u64) as usize]
+    }
+
+    /// Shuffles the items in place (Fisher-Yates).
+    pub fn shuffle<T>(&mut self, items: &mut [T]) {
+        for i in (1..items.len()).rev() {
+            items.swap(i, self.below(i as u64 + 1) as usize);
+        }
+    }
+}
+
+/// Returns the data for [`Op::Write`].
+fn write_data(path: &ArhPath, step: usize, len: usize) -> Vec<u8> {
+    let seed = path.bytes().fold(step as u64, |h, b| {
+        h.wrapping_mul(31).wrapping_add(u64::from(b))
+    });
+    let mut rng = Rng::new(seed);
+    (0..len).map(|_| rng.next_u64() as u8).collect()
+}
+
+fn list_files(node: &DirNode, path: &ArhPath, files: &mut BTreeSet<ArhPath>) {
+    match &node.entry {
+        DirEntry::File => {
+            files.insert(path.clone());
+        }
+        DirEntry::Directory { children } => {
+            for (name, child) in children {
+                list_files(child, &path.join(name), files);
+            }
+        }
+    }
+}
+
+fn check_stats(fs: &ArhFileSystem, node: &DirNode, path: &ArhPath) -> Result<DirStats, String> {
+    let DirEntry::Directory { children } = &node.entry else {
+        let meta = fs
+            .get_file_info(path)
+            .ok_or_else(|| format!("{path}: listed, but has no metadata"))?;
+        return Ok(DirStats::from_file(meta));
+    };
+    let mut stats = DirStats::default();
+    for (name, child) in children {
+        let child_stats = check_stats(fs, child, &path.join(name))?;
+        if matches!(child.entry, DirEntry::Directory { .. }) {
+            stats.dir_count += 1;
+        }
+        stats.file_count += child_stats.file_count;
+        stats.dir_count += child_stats.dir_count;
+        stats.compressed_size += child_stats.compressed_size;
+        stats.uncompressed_size += child_stats.uncompressed_size;
+    }
+    if stats != node.stats {
+        return Err(format!(
+            "{path}: directory stats are {:?}, expected {stats:?}",
+            node.stats
+        ));
+    }
+    Ok(stats)
+}
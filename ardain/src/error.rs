@@ -1,8 +1,11 @@
 use std::{io, num::TryFromIntError};
 
-use xc3_lib::{error::DecompressStreamError, xbc1::CreateXbc1Error};
+use xc3_lib::{
+    error::DecompressStreamError,
+    xbc1::{CompressionType, CreateXbc1Error},
+};
 
-use crate::path::InvalidPathError;
+use crate::{game::Game, path::InvalidPathError};
 
 pub type Result<T> = std::result::Result<T, Error>;
 
@@ -18,12 +21,42 @@ pub enum Error {
     SizeConvert(#[from] TryFromIntError),
     #[error("ARD entry decompression: {0}, corrupted ARD entry?")]
     ArdDecompress(#[from] DecompressStreamError),
+    #[error("ARD entry checksum mismatch: expected {expected:08X}, got {actual:08X}, corrupted ARD entry?")]
+    ChecksumMismatch { expected: u32, actual: u32 },
     #[error("FS: no such file or directory")]
     FsNoEntry,
     #[error("FS: an entry already exists with this name")]
     FsAlreadyExists,
     #[error("FS: extended file names are not supported (e.g. \"a.tar\", \"a.tar.gz\")")]
     FsFileNameExtended,
+    #[error("FS: cannot move a directory into itself")]
+    FsMoveIntoSelf,
+    #[error("FS: directory is not empty")]
+    FsNotEmpty,
+    #[error("FS: the archive is read-only")]
+    ReadOnly,
+    #[error("invalid file metadata: {0}")]
+    FileMetaInvalid(&'static str),
+    #[error("ARH ext: invalid block size 2^{0}")]
+    ExtBlockSize(u16),
+    #[error("ARH ext: changing the block size would mark {0} free bytes as occupied")]
+    ExtBlockSizeLossy(u64),
+    #[error("invalid ARH tables: {0}")]
+    InvalidTables(String),
+    #[error("format limit exceeded: {0}")]
+    LimitExceeded(&'static str),
+    #[error("unknown game \"{0}\"")]
+    UnknownGame(String),
+    #[error("unknown sync policy \"{0}\"")]
+    UnknownSyncPolicy(String),
+    #[error("invalid size \"{0}\", expected e.g. 512M or 2G")]
+    InvalidSize(String),
+    #[error("{0:?} compression is not supported by {1}")]
+    UnsupportedCompression(CompressionType, Game),
+    #[error("the ARD file can't grow past its size limit of {0} bytes")]
+    ArdSizeLimit(u64),
+    #[error("ARD write verification failed at offset 0x{0:X}: the data read back differs from what was written")]
+    WriteMismatch(u64),
 }
 
 impl From<CreateXbc1Error> for Error {
@@ -1,29 +1,73 @@
 //! ARD file allocator
 
-use std::io::{Seek, Write};
+use std::{
+    collections::BTreeMap,
+    io::{self, Cursor, Read, Seek, Write},
+};
 
 use xc3_lib::xbc1::{CompressionType, Xbc1};
 
 use crate::{
-    ard::ArdWriter, arh::FileTable, arh_ext::BlockAllocTable, error::Result, ArhFileSystem,
-    FileFlag, FileMeta,
+    ard::ArdWriter,
+    arh::{Arh, FileTable},
+    arh_ext::{ArhExtSection, BlockAllocTable, OpKind, SharedExtents},
+    error::{Error, Result},
+    game::Game,
+    path::ArhPath,
+    ArhFileSystem, FileFlag, FileMeta,
 };
 
 pub struct ArdFileAllocator<'a, 'w, W> {
-    block_table: &'a mut BlockAllocTable,
-    file_table: &'a mut FileTable,
+    fs: &'a mut ArhFileSystem,
     writer: &'w mut ArdWriter<W>,
 }
 
 pub enum CompressionStrategy {
     /// Never compress entries.
     None,
-    /// Use the chosen compression algorithm.
+    /// Use the chosen compression algorithm. Fails if the game doesn't support it, see
+    /// [`Game::compression_types`].
     Standard(CompressionType),
-    /// Compress using all available methods, then pick the smallest result.
+    /// Compress using the methods the game's own archives use (see
+    /// [`Game::best_compression_types`]), then pick the smallest result (possibly leaving
+    /// the data uncompressed).
     Best,
 }
 
+/// Where [`ArdFileAllocator`] would write a file's data, see [`SpacePlanner`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Placement {
+    /// Over the file's old data, which is large enough
+    InPlace,
+    /// In free space inside the ARD file
+    FreeSpace,
+    /// At the end of the ARD file, which has to grow
+    Append,
+}
+
+/// The result of [`SpacePlanner::plan_write`].
+#[derive(Debug, Clone, Copy)]
+pub struct PlannedWrite {
+    pub offset: u64,
+    /// The size of the data in the ARD file, see [`entry_size`]
+    pub size: u64,
+    pub placement: Placement,
+    /// The number of bytes the ARD file grows by
+    pub growth: u64,
+}
+
+/// Predicts where [`ArdFileAllocator`] would write files, without writing anything.
+///
+/// Planned writes are applied to a copy of the block allocation table, so a sequence of
+/// writes is laid out like the allocator would, if they are performed in the same order.
+pub struct SpacePlanner<'a> {
+    fs: &'a ArhFileSystem,
+    ext: ArhExtSection,
+    ard_len: u64,
+    /// Files whose data was moved by earlier plans
+    planned: BTreeMap<ArhPath, FileMeta>,
+}
+
 enum EntryFile<'a> {
     /// Stored verbatim
     Raw(&'a [u8]),
@@ -31,6 +75,12 @@ enum EntryFile<'a> {
     RawWrapped(&'a [u8]),
     /// Compressed and wrapped in a XBC1 structure
     Compressed(Xbc1),
+    /// Copied as it is stored in another ARD file
+    Stored {
+        data: &'a [u8],
+        has_xbc1: bool,
+        uncompressed_size: u32,
+    },
 }
 
 struct CompressionMeta {
@@ -42,11 +92,7 @@ struct CompressionMeta {
 impl<'a, 'w, W: Write + Seek> ArdFileAllocator<'a, 'w, W> {
     pub fn new(arh: &'a mut ArhFileSystem, writer: &'w mut ArdWriter<W>) -> Self {
         arh.arh.get_or_init_ext(&arh.opts);
-        Self {
-            block_table: &mut arh.arh.arh_ext_section.as_mut().unwrap().allocated_blocks,
-            file_table: &mut arh.arh.file_table,
-            writer,
-        }
+        Self { fs: arh, writer }
     }
 
     /// Writes the file as a new entry.
@@ -60,15 +106,13 @@ impl<'a, 'w, W: Write + Seek> ArdFileAllocator<'a, 'w, W> {
         data: &[u8],
         strategy: CompressionStrategy,
     ) -> Result<()> {
-        let file = self
-            .file_table
-            .get_meta_mut(file_id)
-            .expect("file not found");
-        let data = Self::compress_data(data, strategy)?;
-        let total_len: u64 = data.size_on_disk().try_into().unwrap();
-        let offset = self.block_table.find_free_space(total_len);
-        data.write(self.writer.entry(offset)?)?;
-        Self::update_meta(self.block_table, &data, file, offset);
+        self.fs.check_writable()?;
+        let (_, file_table) = self.tables();
+        let old_meta = *file_table.get_meta(file_id).expect("file not found");
+        let data = EntryFile::compress(data, strategy, self.fs.opts.game)?;
+        self.write_new_data(file_id, &data, &old_meta)?;
+        // Only once the data is written, so the file still shares its old data on failure
+        self.shared_extents().release(&old_meta);
         Ok(())
     }
 
@@ -76,57 +120,284 @@ impl<'a, 'w, W: Write + Seek> ArdFileAllocator<'a, 'w, W> {
     ///
     /// This works like [`Self::write_new_file`], except it treats the file as
     /// empty, and frees the space occupied by the old file.
+    ///
+    /// If other files share the old data, it is left untouched and the new data is
+    /// written elsewhere.
     pub fn replace_file(
         &mut self,
         file_id: u32,
         new_data: &[u8],
         strategy: CompressionStrategy,
     ) -> Result<()> {
-        let file = self
-            .file_table
-            .get_meta_mut(file_id)
-            .expect("file not found");
-        let data = Self::compress_data(new_data, strategy)?;
-        if data.size_on_disk() <= file.compressed_size.try_into().unwrap() {
+        self.fs.check_writable()?;
+        let data = EntryFile::compress(new_data, strategy, self.fs.opts.game)?;
+        self.replace_entry(file_id, &data)
+    }
+
+    /// Replaces the entry identified by `file_id` with one copied from another archive, e.g.
+    /// to port files between archives without decompressing and compressing them again.
+    ///
+    /// `source` is the file's metadata in the other archive, and `stored` its entry as stored
+    /// in the other ARD file. The file keeps the source's storage type (compressed, wrapped in
+    /// a XBC1 structure, or raw). The data is placed like [`Self::replace_file`] would.
+    ///
+    /// The entry is not checked: callers should make sure the game supports its compression
+    /// type, see [`Game::supports_compression`].
+    pub fn replace_file_stored(
+        &mut self,
+        file_id: u32,
+        source: &FileMeta,
+        stored: &[u8],
+    ) -> Result<()> {
+        self.fs.check_writable()?;
+        if usize::try_from(source.compressed_size) != Ok(stored.len()) {
+            return Err(Error::FileMetaInvalid(
+                "stored entry doesn't match its metadata",
+            ));
+        }
+        let data = EntryFile::Stored {
+            data: stored,
+            has_xbc1: source.is_flag(FileFlag::HasXbc1Header) || source.uncompressed_size != 0,
+            uncompressed_size: source.uncompressed_size,
+        };
+        self.replace_entry(file_id, &data)
+    }
+
+    fn replace_entry(&mut self, file_id: u32, data: &EntryFile) -> Result<()> {
+        let (_, file_table) = self.tables();
+        let old_meta = *file_table.get_meta(file_id).expect("file not found");
+        if self.shared_extents().is_shared(&old_meta) {
+            // Other files still use the old data, so it can't be overwritten or freed
+            self.write_new_data(file_id, data, &old_meta)?;
+            self.shared_extents().release(&old_meta);
+            return Ok(());
+        }
+        if data.size_on_disk() <= old_meta.compressed_size.try_into().unwrap() {
             // If it fits, just write and update size
-            data.write(self.writer.entry(file.offset)?)?;
-            Self::update_meta(self.block_table, &data, file, file.offset);
+            self.write_entry(old_meta.offset, data)?;
+            self.count_alloc(old_meta.offset, &old_meta);
+            let (block_table, file_table) = self.tables();
+            let file = file_table.get_meta_mut(file_id).unwrap();
+            // The new data may be smaller (e.g. after a reservation), free the rest
+            block_table.mark(file, false);
+            Self::update_meta(block_table, data, file, old_meta.offset);
+            self.fs.update_file_stats_by_id(file_id, &old_meta);
+            self.record_replace(file_id, &old_meta);
             return Ok(());
         }
         let total_len: u64 = data.size_on_disk().try_into().unwrap();
-        let offset = self.block_table.find_space_replace(file, total_len);
-        data.write(self.writer.entry(offset)?)?;
+        let (block_table, _) = self.tables();
+        let offset = block_table.find_space_replace(&old_meta, total_len);
+        self.write_entry(offset, data)?;
+        self.count_alloc(offset, &old_meta);
+        let (block_table, file_table) = self.tables();
+        let file = file_table.get_meta_mut(file_id).unwrap();
         // First, mark the old file as unoccupied
-        self.block_table.mark(file, false);
+        block_table.mark(file, false);
         // After updating the file entry, this will mark the new one as occupied
         // (no problem if they overlap)
-        Self::update_meta(self.block_table, &data, file, offset);
+        Self::update_meta(block_table, data, file, offset);
+        self.fs.update_file_stats_by_id(file_id, &old_meta);
+        self.record_replace(file_id, &old_meta);
+        Ok(())
+    }
+
+    /// Overwrites part of a file directly in the ARD, without reallocating it.
+    ///
+    /// This only works for files stored raw (without a XBC1 header) whose data is not shared
+    /// with other files, and only if the written range is within the file. Otherwise,
+    /// nothing is written and `false` is returned: the file must be rewritten in full with
+    /// [`Self::replace_file`].
+    pub fn write_in_place(&mut self, file_id: u32, offset: u64, data: &[u8]) -> Result<bool> {
+        self.patch_in_place(file_id, &[(offset, data)])
+    }
+
+    /// Overwrites several ranges of a file directly in the ARD, e.g. to save a few edits to a
+    /// large file without reading or rewriting the rest of it.
+    ///
+    /// The same conditions as [`Self::write_in_place`] apply to every range, so the file's
+    /// size can't change. If any range can't be written in place, nothing is written and
+    /// `false` is returned.
+    pub fn patch_in_place(&mut self, file_id: u32, patches: &[(u64, &[u8])]) -> Result<bool> {
+        self.fs.check_writable()?;
+        let (_, file_table) = self.tables();
+        let meta = *file_table.get_meta(file_id).expect("file not found");
+        let fits = |&(offset, data): &(u64, &[u8])| {
+            u64::try_from(data.len())
+                .ok()
+                .and_then(|len| offset.checked_add(len))
+                .is_some_and(|end| end <= meta.compressed_size.into())
+        };
+        if !patches.iter().all(fits)
+            || meta.uncompressed_size != 0
+            || meta.is_flag(FileFlag::HasXbc1Header)
+            || self.shared_extents().is_shared(&meta)
+        {
+            return Ok(false);
+        }
+        for &(offset, data) in patches {
+            self.writer.entry(meta.offset + offset)?.write_all(data)?;
+            self.writer.verify(meta.offset + offset, data)?;
+        }
+        self.fs.record_by_id(OpKind::Replace, file_id);
+        Ok(true)
+    }
+
+    /// Deletes a file, and overwrites its data in the ARD file with zeros, e.g. to remove
+    /// files that were added by mistake.
+    ///
+    /// Data that other files still use is left intact. Returns whether the data was
+    /// overwritten.
+    pub fn delete_file_scrub(&mut self, path: &ArhPath) -> Result<bool> {
+        let meta = *self.fs.get_file_info(path).ok_or(Error::FsNoEntry)?;
+        let shared = self.shared_extents().is_shared(&meta);
+        self.fs.delete_file(path)?;
+        if shared || meta.compressed_size == 0 {
+            return Ok(false);
+        }
+        self.write_zeros(meta.offset, meta.compressed_size.into())?;
+        Ok(true)
+    }
+
+    /// Reserves `size` bytes in the archive for the file, before its data is written.
+    ///
+    /// The file's old data is discarded (and freed, unless other files share it), and the
+    /// file is set to contain `size` zero bytes, stored uncompressed. The reserved space
+    /// is marked as occupied right away, so it can't be handed out to other files.
+    ///
+    /// Writing the file later with [`Self::replace_file`] reuses the reserved space if the
+    /// new data fits, and frees what is left over.
+    pub fn reserve(&mut self, file_id: u32, size: u64) -> Result<()> {
+        self.fs.check_writable()?;
+        let size_u32 = u32::try_from(size)
+            .map_err(|_| Error::LimitExceeded("file size does not fit in 32 bits"))?;
+        let (_, file_table) = self.tables();
+        let old_meta = *file_table.get_meta(file_id).expect("file not found");
+        let shared = self.shared_extents().is_shared(&old_meta);
+        let (block_table, _) = self.tables();
+        let offset = if shared {
+            block_table.find_free_space(size)
+        } else {
+            block_table.find_space_replace(&old_meta, size)
+        };
+        let new_meta = FileMeta::with_extent(offset, size_u32, 0, [])?;
+
+        // Don't leave stale data behind in the placeholder
+        self.writer.extend_to(offset + size)?;
+        self.write_zeros(offset, size)?;
+        self.count_alloc(offset, &old_meta);
+        if shared {
+            self.shared_extents().release(&old_meta);
+        }
+
+        let (block_table, file_table) = self.tables();
+        let file = file_table.get_meta_mut(file_id).unwrap();
+        if !shared {
+            block_table.mark(file, false);
+        }
+        file.offset = new_meta.offset;
+        file.compressed_size = new_meta.compressed_size;
+        file.uncompressed_size = 0;
+        file.set_flag(FileFlag::HasXbc1Header, false);
+        block_table.mark(file, true);
+        self.fs.update_file_stats_by_id(file_id, &old_meta);
+        self.record_replace(file_id, &old_meta);
+        Ok(())
+    }
+
+    fn write_new_data(
+        &mut self,
+        file_id: u32,
+        data: &EntryFile,
+        old_meta: &FileMeta,
+    ) -> Result<()> {
+        let (block_table, _) = self.tables();
+        let total_len: u64 = data.size_on_disk().try_into().unwrap();
+        let offset = block_table.find_free_space(total_len);
+        self.write_entry(offset, data)?;
+        self.count_alloc(offset, old_meta);
+        let (block_table, file_table) = self.tables();
+        let file = file_table.get_meta_mut(file_id).unwrap();
+        Self::update_meta(block_table, data, file, offset);
+        self.fs.update_file_stats_by_id(file_id, old_meta);
+        self.record_replace(file_id, old_meta);
         Ok(())
     }
 
-    fn compress_data(data: &[u8], strategy: CompressionStrategy) -> Result<EntryFile> {
-        if let CompressionStrategy::None = strategy {
-            return Ok(EntryFile::Raw(data));
+    /// Adds the change to the archive's history, unless the file had no data before (i.e. it
+    /// was just created).
+    fn record_replace(&mut self, file_id: u32, old_meta: &FileMeta) {
+        if old_meta.compressed_size != 0 {
+            self.fs.record_by_id(OpKind::Replace, file_id);
         }
-        let compressed = Xbc1::from_decompressed(
-            String::new(),
-            data,
-            match strategy {
-                CompressionStrategy::Standard(ty) => ty,
-                _ => CompressionType::Zlib,
-            },
+    }
+
+    /// Writes an entry at `offset`, and reads it back if enabled (see
+    /// [`ArdWriter::verify_writes`]).
+    fn write_entry(&mut self, offset: u64, data: &EntryFile) -> Result<()> {
+        let size: u64 = data.size_on_disk().try_into().unwrap();
+        self.writer.extend_to(offset + size)?;
+        if !self.writer.verifies_writes() {
+            return data.write(self.writer.entry(offset)?);
+        }
+        let mut bytes = Cursor::new(Vec::with_capacity(data.size_on_disk()));
+        data.write(&mut bytes)?;
+        let bytes = bytes.into_inner();
+        self.writer.entry(offset)?.write_all(&bytes)?;
+        self.writer.verify(offset, &bytes)
+    }
+
+    /// Overwrites `len` bytes at `offset` with zeros, and reads them back if enabled (see
+    /// [`ArdWriter::verify_writes`]).
+    fn write_zeros(&mut self, offset: u64, len: u64) -> Result<()> {
+        io::copy(
+            &mut io::repeat(0).take(len),
+            &mut self.writer.entry(offset)?,
         )?;
-        Ok(match strategy {
-            CompressionStrategy::None => EntryFile::Raw(data),
-            CompressionStrategy::Standard(_) => EntryFile::Compressed(compressed),
-            CompressionStrategy::Best => {
-                if data.len() < compressed.compressed_stream.len() + 0x30 {
-                    EntryFile::Raw(data)
-                } else {
-                    EntryFile::Compressed(compressed)
-                }
-            }
-        })
+        if !self.writer.verifies_writes() {
+            return Ok(());
+        }
+        // Compare in chunks, the placeholder can be large
+        let zeros = vec![0; len.min(1 << 20) as usize];
+        let mut done = 0;
+        while done < len {
+            let chunk = (len - done).min(zeros.len() as u64);
+            self.writer
+                .verify(offset + done, &zeros[..chunk as usize])?;
+            done += chunk;
+        }
+        Ok(())
+    }
+
+    /// Records where an entry was placed, see [`ArhFileSystem::alloc_stats`]. This must be
+    /// called before the new extent is marked as occupied.
+    fn count_alloc(&mut self, offset: u64, old_meta: &FileMeta) {
+        let (block_table, _) = self.tables();
+        let appended = offset >= block_table.end();
+        let counts = &mut self.fs.alloc_counts;
+        if old_meta.compressed_size != 0 && offset == old_meta.offset {
+            counts.in_place += 1;
+        } else if appended {
+            counts.appended += 1;
+        } else {
+            counts.reused += 1;
+        }
+    }
+
+    fn shared_extents(&mut self) -> &mut SharedExtents {
+        &mut self.fs.arh.arh_ext_section.as_mut().unwrap().shared_extents
+    }
+
+    fn tables(&mut self) -> (&mut BlockAllocTable, &mut FileTable) {
+        let Arh {
+            file_table,
+            arh_ext_section,
+            ..
+        } = &mut self.fs.arh;
+        (
+            &mut arh_ext_section.as_mut().unwrap().allocated_blocks,
+            file_table,
+        )
     }
 
     fn update_meta(
@@ -140,6 +411,11 @@ impl<'a, 'w, W: Write + Seek> ArdFileAllocator<'a, 'w, W> {
             EntryFile::Raw(_) => (false, 0),
             EntryFile::RawWrapped(_) => (true, 0),
             EntryFile::Compressed(xbc1) => (true, xbc1.decompressed_size),
+            EntryFile::Stored {
+                has_xbc1,
+                uncompressed_size,
+                ..
+            } => (*has_xbc1, *uncompressed_size),
         };
         meta.set_flag(FileFlag::HasXbc1Header, has_xbc1);
         meta.uncompressed_size = unc_size;
@@ -148,9 +424,121 @@ impl<'a, 'w, W: Write + Seek> ArdFileAllocator<'a, 'w, W> {
     }
 }
 
+impl<'a> SpacePlanner<'a> {
+    /// Starts planning for the given file system. `ard_len` is the current size of the ARD
+    /// file.
+    pub fn new(fs: &'a ArhFileSystem, ard_len: u64) -> Self {
+        // Plan on a copy of the section that ArdFileAllocator::new would initialize
+        let mut ext = fs.arh.arh_ext_section.clone();
+        ArhExtSection::get_or_init(&mut ext, &fs.arh.file_table, &fs.opts);
+        Self {
+            fs,
+            ext: ext.unwrap(),
+            ard_len,
+            planned: BTreeMap::new(),
+        }
+    }
+
+    /// Plans writing `size` bytes (as returned by [`entry_size`]) to the file at `path`,
+    /// like [`ArdFileAllocator::replace_file`] if the file exists, or
+    /// [`ArdFileAllocator::write_new_file`] for a new file.
+    pub fn plan_write(&mut self, path: &ArhPath, size: u64) -> PlannedWrite {
+        let old = self
+            .planned
+            .get(path)
+            .or_else(|| self.fs.get_file_info(path))
+            .copied()
+            .unwrap_or_else(FileMeta::new_invalid);
+        let shared = self.ext.shared_extents.release(&old);
+        let table = &mut self.ext.allocated_blocks;
+        let (offset, in_place) = if old.compressed_size == 0 || shared {
+            (table.find_free_space(size), false)
+        } else if size <= old.compressed_size.into() {
+            (old.offset, true)
+        } else {
+            (table.find_space_replace(&old, size), false)
+        };
+        if !shared {
+            table.mark(&old, false);
+        }
+        table.mark_range(offset..offset + size, true);
+
+        let end = offset + size;
+        let growth = end.saturating_sub(self.ard_len);
+        self.ard_len = self.ard_len.max(end);
+        let placement = match (in_place, growth) {
+            (true, _) => Placement::InPlace,
+            (false, 0) => Placement::FreeSpace,
+            (false, _) => Placement::Append,
+        };
+        let mut new = old;
+        new.offset = offset;
+        new.compressed_size = size.try_into().unwrap_or(u32::MAX);
+        self.planned.insert(path.clone(), new);
+        PlannedWrite {
+            offset,
+            size,
+            placement,
+            growth,
+        }
+    }
+
+    /// Returns the size of the ARD file after all planned writes.
+    pub fn ard_len(&self) -> u64 {
+        self.ard_len
+    }
+}
+
+/// Returns the number of bytes `data` would take in the ARD file, if written with the given
+/// compression strategy.
+///
+/// This compresses the data, so it takes as long as writing it.
+pub fn entry_size(data: &[u8], strategy: CompressionStrategy, game: Game) -> Result<u64> {
+    Ok(EntryFile::compress(data, strategy, game)?
+        .size_on_disk()
+        .try_into()?)
+}
+
 impl<'a> EntryFile<'a> {
+    /// Compresses the data, making sure the sizes can be stored in the file's metadata.
+    fn compress(data: &'a [u8], strategy: CompressionStrategy, game: Game) -> Result<Self> {
+        let too_large = || Error::LimitExceeded("file size does not fit in 32 bits");
+        u32::try_from(data.len()).map_err(|_| too_large())?;
+        let entry = Self::compress_unchecked(data, strategy, game)?;
+        // The XBC1 header may still push it over the limit
+        u32::try_from(entry.size_on_disk()).map_err(|_| too_large())?;
+        Ok(entry)
+    }
+
+    fn compress_unchecked(
+        data: &'a [u8],
+        strategy: CompressionStrategy,
+        game: Game,
+    ) -> Result<Self> {
+        let types = match strategy {
+            CompressionStrategy::None => return Ok(EntryFile::Raw(data)),
+            CompressionStrategy::Standard(ty) if !game.supports_compression(ty) => {
+                return Err(Error::UnsupportedCompression(ty, game))
+            }
+            CompressionStrategy::Standard(ty) => {
+                let compressed = Xbc1::from_decompressed(String::new(), data, ty)?;
+                return Ok(EntryFile::Compressed(compressed));
+            }
+            CompressionStrategy::Best => game.best_compression_types(),
+        };
+        let mut best = EntryFile::Raw(data);
+        for &ty in types {
+            let compressed =
+                EntryFile::Compressed(Xbc1::from_decompressed(String::new(), data, ty)?);
+            if compressed.size_on_disk() < best.size_on_disk() {
+                best = compressed;
+            }
+        }
+        Ok(best)
+    }
+
     pub fn write(&self, mut writer: impl Write + Seek) -> Result<()> {
-        if let Self::Raw(data) = self {
+        if let Self::Raw(data) | Self::Stored { data, .. } = self {
             writer.write_all(data)?;
             return Ok(());
         }
@@ -160,7 +548,7 @@ impl<'a> EntryFile<'a> {
                     .expect("TODO")
             }
             EntryFile::Compressed(xbc1) => xbc1.clone(),
-            EntryFile::Raw(_) => unreachable!(),
+            EntryFile::Raw(_) | EntryFile::Stored { .. } => unreachable!(),
         };
         xbc1.write(&mut writer)?;
         Ok(())
@@ -168,7 +556,7 @@ impl<'a> EntryFile<'a> {
 
     pub fn size_on_disk(&self) -> usize {
         match self {
-            EntryFile::Raw(data) => data.len(),
+            EntryFile::Raw(data) | EntryFile::Stored { data, .. } => data.len(),
             EntryFile::RawWrapped(data) => data.len() + 0x30,
             EntryFile::Compressed(xbc1) => xbc1.compressed_stream.len() + 0x30,
         }
@@ -176,7 +564,7 @@ impl<'a> EntryFile<'a> {
 
     pub fn as_slice(&self) -> &[u8] {
         match self {
-            EntryFile::Raw(buf) => buf,
+            EntryFile::Raw(buf) | EntryFile::Stored { data: buf, .. } => buf,
             EntryFile::RawWrapped(buf) => buf,
             EntryFile::Compressed(xbc1) => &xbc1.compressed_stream,
         }
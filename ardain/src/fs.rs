@@ -1,36 +1,227 @@
 use std::{
-    collections::VecDeque,
-    io::{Read, Seek, Write},
+    cmp::Reverse,
+    collections::{btree_map::Entry, BTreeMap, HashMap, VecDeque},
+    io::{Read, Seek, SeekFrom, Write},
+    ops::Range,
+    time::{SystemTime, UNIX_EPOCH},
 };
 
 use binrw::{BinRead, BinResult, BinWrite};
 
 use crate::{
-    arh::{Arh, DictNode, FileMeta},
-    arh_ext::ArhExtSection,
+    ard::{ArdReader, EntryMismatch},
+    arh::{Arh, DictNode, FileFlag, FileMeta, FileTable, PathDictionary, StringTable},
+    arh_ext::{ArhExtSection, FileRecycleBin, OpKind, OpLogEntry, OrphanedFile},
     error::{Error, Result},
     opts::ArhOptions,
-    path::ArhPath,
+    path::{ArhPath, PathCase, PathFilter, ARH_PATH_ROOT},
 };
 
+/// The name of the hidden file that keeps an empty directory in the archive.
+///
+/// The ARH format has no concept of directories, so a directory only exists as long as it
+/// contains at least one file. Placeholders are never listed by the frontends.
+pub const DIR_PLACEHOLDER: &str = ".fuse_ard_dir";
+
 pub struct ArhFileSystem {
     pub(crate) arh: Arh,
     pub(crate) opts: ArhOptions,
     // Not part of the ARH format, but we keep one to make enumerating and traversing directories
     // easier.
     dir_tree: DirNode,
+    /// The spelling of files whose names have uppercase characters, with
+    /// [`PathCase::Preserve`]. The dictionary and the directory tree only have lowercase
+    /// names, these are written back by [`Self::sync`].
+    case_names: BTreeMap<ArhPath, String>,
+    /// Bumped whenever `case_names` changes, see [`CasedDictionary::source`]
+    case_generation: u64,
+    /// The last dictionary written by [`Self::sync`] with the spelling from `case_names`
+    cased_dict: Option<CasedDictionary>,
+    /// File paths by ID, for the allocator, which only has IDs. Entries are checked before
+    /// they are used, so they don't need to be removed when files are.
+    paths_by_id: HashMap<u32, ArhPath>,
+    /// Not persisted, see [`Self::alloc_stats`]
+    pub(crate) alloc_counts: AllocCounts,
+}
+
+/// A dictionary and string table with the original spelling of names, see
+/// [`PathCase::Preserve`]. Building one means adding every file again, so it is reused
+/// until the names change.
+#[derive(Debug)]
+struct CasedDictionary {
+    /// The generations of the lowercase tables and of the spellings it was built from,
+    /// see [`Arh::dict_generation`]
+    source: (u64, u64),
+    dict: PathDictionary,
+    strings: StringTable,
 }
 
 #[derive(Debug)]
 pub struct DirNode {
     pub name: String,
     pub entry: DirEntry,
+    /// Aggregate data for the subtree starting at this node.
+    ///
+    /// For files, this only includes the file itself.
+    pub stats: DirStats,
+}
+
+/// A region of the ARD file, see [`ArhFileSystem::iter_extents`].
+#[derive(Debug, Clone)]
+pub struct Extent {
+    pub range: Range<u64>,
+    /// The files whose data is stored in the region, sorted by path. Empty if the region
+    /// is not referenced by any file.
+    pub files: Vec<ArhPath>,
+}
+
+/// The order in which files (or extents) are read, e.g. when extracting them.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ReadOrder {
+    /// By offset in the ARD file, so data is read sequentially. This is much faster on HDDs.
+    #[default]
+    Offset,
+    /// By path, so files are always processed in the same order
+    Path,
+    /// Largest first, so the work is spread evenly when reading in parallel
+    Size,
+}
+
+/// The result of [`ArhFileSystem::check_block_table`].
+///
+/// Regions are sorted by offset, and aligned to the block size.
+#[derive(Debug, Clone, Default)]
+pub struct BlockTableDrift {
+    /// Regions that are used by files, but marked as free. New files could overwrite them.
+    pub unmarked: Vec<Range<u64>>,
+    /// Regions that are marked as occupied, but not used by any file
+    pub leaked: Vec<Range<u64>>,
+}
+
+impl BlockTableDrift {
+    pub fn is_empty(&self) -> bool {
+        self.unmarked.is_empty() && self.leaked.is_empty()
+    }
+}
+
+/// The result of [`ArhFileSystem::set_block_size`].
+#[derive(Debug, Clone, Copy)]
+pub struct BlockSizeChange {
+    pub old_block_size_pow: u16,
+    pub new_block_size_pow: u16,
+    /// Free space that is now marked as occupied, in bytes
+    pub lost_bytes: u64,
+    /// The size of the extension section in the ARH file before the change, or 0 if the
+    /// file didn't have one
+    pub old_ext_size: u32,
+    pub new_ext_size: u32,
+}
+
+/// The result of [`ArhFileSystem::alloc_stats`].
+#[derive(Debug, Clone, Copy, Default)]
+pub struct AllocStats {
+    pub block_size: u32,
+    /// Where the allocations made since the archive was loaded were placed
+    pub counts: AllocCounts,
+    /// Space marked as occupied, in bytes
+    pub occupied_bytes: u64,
+    /// Space in occupied blocks that is not used by the data in them, because entry sizes
+    /// are rounded up to whole blocks
+    pub rounding_waste: u64,
+    /// Free space between occupied blocks, in bytes. Space after the last occupied block is
+    /// not counted.
+    pub free_bytes: u64,
+    /// The number of separate runs of free blocks that make up [`Self::free_bytes`]
+    pub free_runs: usize,
+    /// The size of the largest run of free blocks, i.e. the largest entry that can be
+    /// written without growing the ARD file
+    pub largest_free_run: u64,
+}
+
+/// The result of [`ArhFileSystem::file_table_usage`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FileTableUsage {
+    /// The number of entries in the file table
+    pub entries: usize,
+    /// Entries that a path points to. Hidden files and directory placeholders count too.
+    pub live: usize,
+    /// Entries freed by deleting files, which new files reuse before the table grows, see
+    /// [`ArhFileSystem::recycled_file_ids`]
+    pub recycled: Vec<u32>,
+    /// Entries that no path points to, but that are not in the recycle bin either (e.g.
+    /// left behind by other tools). They are never reused.
+    pub unreferenced: Vec<u32>,
+}
+
+/// Counts of allocations by where they were placed in the ARD file, see [`AllocStats`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct AllocCounts {
+    /// Entries written over the data they replaced
+    pub in_place: u64,
+    /// Entries written in free space between occupied blocks
+    pub reused: u64,
+    /// Entries written after the last occupied block, growing the ARD file
+    pub appended: u64,
+}
+
+impl AllocStats {
+    /// How scattered the free space is, from 0 (all of it in one run, or no free space) to
+    /// 1 (spread over many small runs).
+    pub fn fragmentation(&self) -> f64 {
+        if self.free_bytes == 0 {
+            return 0.0;
+        }
+        1.0 - self.largest_free_run as f64 / self.free_bytes as f64
+    }
+}
+
+/// The result of [`ArhFileSystem::rebuild_dictionary`].
+#[derive(Debug, Clone, Copy)]
+pub struct DictRebuild {
+    /// The number of files in the new dictionary
+    pub file_count: usize,
+    pub old_node_count: usize,
+    pub new_node_count: usize,
+    /// The size of the string table before the rebuild, in bytes
+    pub old_string_table_len: usize,
+    pub new_string_table_len: usize,
+}
+
+/// A leaf added to the path dictionary whose string is not added yet: its node, the rest of
+/// the file's name and the file's ID.
+type PendingLeaf = (i32, String, u32);
+
+/// The result of [`ArhFileSystem::compact_file_table`].
+#[derive(Debug, Clone, Copy)]
+pub struct FileTableCompaction {
+    /// The number of entries before compacting
+    pub old_len: usize,
+    pub new_len: usize,
+    /// The number of files whose ID changed
+    pub renumbered: usize,
+}
+
+/// Aggregate data for a directory tree, updated incrementally whenever the file system
+/// is modified.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct DirStats {
+    /// The number of files in the subtree (recursive)
+    pub file_count: u64,
+    /// The number of subdirectories in the subtree (recursive)
+    pub dir_count: u64,
+    /// The sum of all file sizes in the ARD file, see [`FileMeta::compressed_size`]
+    pub compressed_size: u64,
+    /// The sum of all extracted file sizes, see [`FileMeta::actual_size`]
+    pub uncompressed_size: u64,
 }
 
 #[derive(Debug)]
 pub enum DirEntry {
     File,
-    Directory { children: Vec<DirNode> },
+    /// Children are indexed by name, see [`DirNode::name`]
+    Directory {
+        children: BTreeMap<String, DirNode>,
+    },
 }
 
 impl ArhFileSystem {
@@ -38,22 +229,98 @@ impl ArhFileSystem {
         Self::load_with_options(reader, ArhOptions::default())
     }
 
-    pub fn load_with_options(mut reader: impl Read + Seek, options: ArhOptions) -> BinResult<Self> {
-        let arh = Arh::read(&mut reader)?;
-        Ok(Self {
+    pub fn load_with_options(
+        mut reader: impl Read + Seek,
+        mut options: ArhOptions,
+    ) -> BinResult<Self> {
+        let mut arh = Arh::read(&mut reader)?;
+        if let Some(ext) = arh.arh_ext_section.as_mut() {
+            ext.fill_missing(&arh.file_table);
+            // Keep the existing table, see ArhOptions::ext_force_block_size
+            if !options.ext_force_block_size {
+                options.ext_block_size_pow = ext.allocated_blocks.block_size_pow;
+            }
+        }
+        let mut fs = Self {
+            dir_tree: DirNode::empty_root(),
+            opts: options,
+            arh,
+            case_names: BTreeMap::new(),
+            case_generation: 0,
+            cased_dict: None,
+            paths_by_id: HashMap::new(),
+            alloc_counts: AllocCounts::default(),
+        };
+        if fs.opts.path_case == PathCase::Preserve {
+            fs.lowercase_dictionary()
+                .map_err(|e| binrw::Error::AssertFail {
+                    pos: 0,
+                    message: e.to_string(),
+                })?;
+        }
+        fs.reload_dir_tree();
+        Ok(fs)
+    }
+
+    /// Returns an archive with no files, e.g. to build one from scratch.
+    ///
+    /// Files are allocated in the order they are written, starting from the beginning of the
+    /// (empty) ARD file.
+    pub fn new(options: ArhOptions) -> Self {
+        let arh = Arh::from_tables(
+            StringTable::new_empty(),
+            PathDictionary::new_empty(),
+            FileTable::default(),
+        );
+        Self {
             dir_tree: DirNode::build(&arh),
             opts: options,
             arh,
-        })
+            case_names: BTreeMap::new(),
+            case_generation: 0,
+            cased_dict: None,
+            paths_by_id: HashMap::new(),
+            alloc_counts: AllocCounts::default(),
+        }
     }
 
     /// Returns the size of a single block, in bytes.
     ///
-    /// This can be changed by loading the file system using [`Self::load_with_options`].
+    /// This can be changed by loading the file system using [`Self::load_with_options`], or
+    /// with [`Self::set_block_size`].
     pub fn block_size(&self) -> u32 {
         1 << self.opts.ext_block_size_pow
     }
 
+    /// Returns whether the file system was loaded with [`ArhOptions::read_only`].
+    pub fn is_read_only(&self) -> bool {
+        self.opts.read_only
+    }
+
+    /// Checks a path for a new or renamed file, see [`ArhPath::check_components`]. Also
+    /// checks that the game can look it up, if strict paths are enabled (see
+    /// [`ArhOptions::strict_paths`]).
+    fn check_new_path(&self, path: &ArhPath) -> Result<()> {
+        path.check_components()?;
+        if self.opts.strict_paths {
+            path.check_strict()?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn check_writable(&self) -> Result<()> {
+        if self.opts.read_only {
+            return Err(Error::ReadOnly);
+        }
+        Ok(())
+    }
+
+    /// Rebuilds the directory tree from the path dictionary, after the tables were modified
+    /// directly.
+    pub(crate) fn reload_dir_tree(&mut self) {
+        self.dir_tree = DirNode::build(&self.arh);
+    }
+
     // Node queries
 
     pub fn is_file(&self, path: &ArhPath) -> bool {
@@ -68,14 +335,34 @@ impl ArhFileSystem {
         self.is_dir(path) || self.is_file(path)
     }
 
+    /// Returns whether `path` is a directory placeholder (see [`DIR_PLACEHOLDER`]).
+    pub fn is_placeholder(path: &ArhPath) -> bool {
+        path.rsplit_once('/')
+            .is_some_and(|(_, name)| name == DIR_PLACEHOLDER)
+    }
+
+    /// Returns whether the directory has no children, other than its placeholder.
+    ///
+    /// Returns `false` if `path` is not a directory.
+    pub fn is_dir_empty(&self, path: &ArhPath) -> bool {
+        let Some(DirEntry::Directory { children }) = self.get_dir(path).map(|d| &d.entry) else {
+            return false;
+        };
+        children
+            .iter()
+            .all(|(name, node)| name == DIR_PLACEHOLDER && matches!(node.entry, DirEntry::File))
+    }
+
     pub fn get_file_info(&self, path: &ArhPath) -> Option<&FileMeta> {
         self.get_file_id(path)
             .and_then(|(id, _)| self.arh.file_table.get_meta(id))
     }
 
-    pub fn get_file_info_mut(&mut self, path: &ArhPath) -> Option<&mut FileMeta> {
+    pub fn get_file_info_mut(&mut self, path: &ArhPath) -> Result<&mut FileMeta> {
+        self.check_writable()?;
         self.get_file_id(path)
             .and_then(|(id, _)| self.arh.file_table.get_meta_mut(id))
+            .ok_or(Error::FsNoEntry)
     }
 
     pub fn get_dir(&self, path: &ArhPath) -> Option<&DirNode> {
@@ -93,19 +380,57 @@ impl ArhFileSystem {
                 return None;
             };
 
-            let child = children
-                .binary_search_by_key(part, |c| c.name.as_str())
-                .ok()?;
-            node = &children[child]
+            node = children.get(*part)?;
         }
         matches!(node.entry, DirEntry::Directory { .. }).then_some(node)
     }
 
+    /// Returns the files at or below `root` that match `filter`, in tree order.
+    ///
+    /// Directory placeholders are not included.
+    pub fn matching_files(&self, root: &ArhPath, filter: &PathFilter) -> Result<Vec<ArhPath>> {
+        let mut files = Vec::new();
+        let mut visit = |path: &ArhPath| {
+            if !Self::is_placeholder(path) && filter.matches(path) {
+                files.push(path.clone());
+            }
+        };
+        if self.is_file(root) {
+            visit(root);
+        } else {
+            self.get_dir(root)
+                .ok_or(Error::FsNoEntry)?
+                .visit_files(root, &mut visit);
+        }
+        Ok(files)
+    }
+
+    /// Sets or clears `flag` on the files returned by [`Self::matching_files`].
+    ///
+    /// Returns the number of files whose flag changed.
+    pub fn set_flag_matching(
+        &mut self,
+        root: &ArhPath,
+        filter: &PathFilter,
+        flag: FileFlag,
+        value: bool,
+    ) -> Result<usize> {
+        self.check_writable()?;
+        let mut changed = 0;
+        for path in self.matching_files(root, filter)? {
+            let meta = self.get_file_info_mut(&path)?;
+            if meta.is_flag(flag) != value {
+                meta.set_flag(flag, value);
+                changed += 1;
+            }
+        }
+        Ok(changed)
+    }
+
     /// Returns the file ID and leaf node ID for the given path.
-    fn get_file_id(&self, path: &ArhPath) -> Option<(u32, i32)> {
+    pub(crate) fn get_file_id(&self, mut path: &str) -> Option<(u32, i32)> {
         let nodes = &self.arh.path_dictionary();
         let mut cur = (0, nodes.node(0));
-        let mut path = path.as_str();
 
         while !cur.1.is_leaf() {
             if path.is_empty() {
@@ -135,7 +460,75 @@ impl ArhFileSystem {
     // Structural modifications
 
     pub fn create_file(&mut self, full_path: &ArhPath) -> Result<&mut FileMeta> {
-        if self.get_file_info(full_path).is_some() {
+        self.check_writable()?;
+        self.check_new_path(full_path)?;
+        let id = self.create_file_unchecked(full_path)?.id;
+        self.record(OpKind::Create, full_path, None);
+        Ok(self.arh.file_table.get_meta_mut(id).unwrap())
+    }
+
+    /// Like [`Self::create_file`], but allows paths rejected by strict path validation, e.g.
+    /// to restore a file under its old name.
+    fn create_file_unchecked(&mut self, full_path: &ArhPath) -> Result<&mut FileMeta> {
+        let id = self.add_dict_entry(full_path, None)?;
+        self.record_case(full_path);
+        let meta = self.arh.file_table.get_meta_mut(id).unwrap();
+        self.dir_tree
+            .insert_file_entry(full_path.to_string(), DirStats::from_file(meta));
+        Ok(meta)
+    }
+
+    /// Returns the spelling of a file, which only differs from `path` with
+    /// [`PathCase::Preserve`].
+    pub fn original_path<'a>(&'a self, path: &'a ArhPath) -> &'a str {
+        self.case_names
+            .get(path)
+            .map_or(path.as_str(), String::as_str)
+    }
+
+    /// Remembers the spelling of a new file, with [`PathCase::Preserve`].
+    fn record_case(&mut self, path: &ArhPath) {
+        if self.opts.path_case == PathCase::Preserve && path.original() != path.as_str() {
+            self.case_generation += 1;
+            self.case_names
+                .insert(path.clone(), path.original().to_string());
+        } else {
+            self.forget_case(path);
+        }
+    }
+
+    /// Drops the spelling of a file, returning it if it had one.
+    fn forget_case(&mut self, path: &ArhPath) -> Option<String> {
+        let name = self.case_names.remove(path);
+        if name.is_some() {
+            self.case_generation += 1;
+        }
+        name
+    }
+
+    /// Creates an empty directory, along with its parents.
+    ///
+    /// The directory is kept in the archive by a hidden placeholder file.
+    pub fn create_dir(&mut self, path: &ArhPath) -> Result<()> {
+        self.check_writable()?;
+        if self.exists(path) {
+            return Err(Error::FsAlreadyExists);
+        }
+        self.create_file(&path.join(DIR_PLACEHOLDER))?
+            .set_flag(FileFlag::Hidden, true);
+        self.record(OpKind::Create, path, None);
+        Ok(())
+    }
+
+    /// Adds a file to the path dictionary and the file table, without updating the directory
+    /// tree. Returns the new file's ID.
+    ///
+    /// If `file_id` is set, the path points to that (existing) file table entry instead.
+    fn add_dict_entry(&mut self, full_path: &str, file_id: Option<u32>) -> Result<u32> {
+        let exists = self
+            .get_file_id(full_path)
+            .and_then(|(id, _)| self.arh.file_table.get_meta(id));
+        if exists.is_some() {
             return Err(Error::FsAlreadyExists);
         }
 
@@ -143,7 +536,7 @@ impl ArhFileSystem {
         let (last, mut last_parent, mut path) = {
             let nodes = &self.arh.path_dictionary().nodes;
             let mut cur = (0, &nodes[0]);
-            let mut path = full_path.as_str();
+            let mut path = full_path;
             let mut last_parent = 0;
 
             while !cur.1.is_leaf() {
@@ -207,7 +600,7 @@ impl ArhFileSystem {
                     path_dict.node_mut(last).attach_next(node_block);
                 } else {
                     // Otherwise, allocate a block
-                    node_block = path_dict.allocate_new_block(last);
+                    node_block = path_dict.allocate_new_block(last)?;
                     next = node_block ^ path.as_bytes()[0] as i32;
                     *path_dict.node_mut(next) = DictNode::Occupied {
                         previous: last,
@@ -225,10 +618,10 @@ impl ArhFileSystem {
 
             // Found a level where the two strings differ. Make a block for them, copy the leaf node
             // to it and pass it on.
-            let next_block = path_dict.allocate_new_block(last);
+            let next_block = path_dict.allocate_new_block(last)?;
             path_dict.node_mut(last).attach_next(next_block);
 
-            let id = self.arh.strings_mut().push(&old_str[1..], old_file);
+            let id = self.arh.strings_mut().push(&old_str[1..], old_file)?;
             let idx = next_block ^ old_str.as_bytes()[0] as i32;
             *path_dict.node_mut(idx) = DictNode::Leaf {
                 previous: last,
@@ -250,7 +643,7 @@ impl ArhFileSystem {
             let idx = self
                 .arh
                 .path_dictionary_mut()
-                .allocate_new_block(final_node.0)
+                .allocate_new_block(final_node.0)?
                 ^ path.as_bytes()[0] as i32;
             last_parent = final_node.0;
             final_node = (idx, *self.arh.path_dictionary().node(idx));
@@ -264,28 +657,112 @@ impl ArhFileSystem {
             arh_ext_section,
             ..
         } = &mut self.arh;
-        let id = file_table.push_entry(
-            FileMeta::new_invalid(),
-            arh_ext_section.as_mut().map(ArhExtSection::recycle_bin_mut),
-        );
-        let str_offset = self.arh.strings_mut().push(path, id);
+        let id = file_id.unwrap_or_else(|| {
+            file_table.push_entry(
+                FileMeta::new_invalid(),
+                arh_ext_section.as_mut().map(ArhExtSection::recycle_bin_mut),
+            )
+        });
+        let str_offset = match self.arh.strings_mut().push(path, id) {
+            Ok(offset) => offset,
+            Err(e) if file_id.is_some() => return Err(e),
+            Err(e) => {
+                // Give the file entry back
+                self.arh.file_table.delete_entry(id);
+                self.arh
+                    .get_or_init_ext(&self.opts)
+                    .file_meta_recycle_bin
+                    .push(id);
+                return Err(e);
+            }
+        };
         *self.arh.path_dictionary_mut().node_mut(final_node.0) = DictNode::Leaf {
             previous: last_parent,
             string_offset: str_offset,
         };
-
-        // Update directory tree
-        self.dir_tree.insert_file_entry(full_path.to_string());
-        Ok(self.arh.file_table.get_meta_mut(id).unwrap())
+        if let Ok(path) = ArhPath::normalize(full_path) {
+            self.paths_by_id.insert(id, path);
+        }
+        Ok(id)
     }
 
     pub fn delete_file(&mut self, path: &ArhPath) -> Result<()> {
-        let (file_id, leaf_id) = self.get_file_id(path).ok_or(Error::FsNoEntry)?;
+        self.check_writable()?;
+        let file = self.remove_file_entry(path)?;
+        let ext = self.arh.get_or_init_ext(&self.opts);
+        // Other files may still point to the same data
+        if !ext.shared_extents.release(&file) {
+            ext.allocated_blocks.mark(&file, false);
+        }
+        self.record(OpKind::Delete, path, None);
+        Ok(())
+    }
 
-        // We must recursively free nodes. Consider this scenario:
-        // Files "ab", "ac", "ad" are created, then removed. If nodes are not freed
-        // recursively, then file "a" cannot be created because the common node was not freed
-        self.arh.path_dictionary_mut().free_node_recursive(leaf_id);
+    /// Deletes a file, but keeps its data in the ARD file so it can be recovered with
+    /// [`Self::recover_file`]. The file is recorded in the archive's orphan list, see
+    /// [`Self::orphaned_files`].
+    ///
+    /// If other files use the same data, it is only kept as long as they do.
+    pub fn delete_file_keep_data(&mut self, path: &ArhPath) -> Result<()> {
+        self.check_writable()?;
+        let file = self.remove_file_entry(path)?;
+        let ext = self.arh.get_or_init_ext(&self.opts);
+        // The blocks stay marked as occupied, and the orphan keeps its reference to shared
+        // data, so it isn't freed when the other files are deleted
+        ext.orphans.push(OrphanedFile {
+            path: path.to_string(),
+            meta: file,
+        });
+        self.record(OpKind::Delete, path, None);
+        Ok(())
+    }
+
+    /// Returns the files that were deleted with [`Self::delete_file_keep_data`], in the
+    /// order they were deleted.
+    pub fn orphaned_files(&self) -> &[OrphanedFile] {
+        self.arh
+            .arh_ext_section
+            .as_ref()
+            .map_or(&[], |ext| ext.orphans.files())
+    }
+
+    /// Recreates a file that was deleted with [`Self::delete_file_keep_data`], pointing to
+    /// its data. If the same path was deleted more than once, the latest file is recovered.
+    pub fn recover_file(&mut self, path: &ArhPath) -> Result<()> {
+        self.check_writable()?;
+        let index = self
+            .orphaned_files()
+            .iter()
+            .rposition(|o| o.path == path.as_str())
+            .ok_or(Error::FsNoEntry)?;
+        if self.exists(path) {
+            return Err(Error::FsAlreadyExists);
+        }
+        self.create_file_unchecked(path)?;
+        let ext = self.arh.get_or_init_ext(&self.opts);
+        let meta = ext.orphans.remove(index).meta;
+        // The recovered file takes over the orphan's reference to shared data
+        ext.shared_extents.release(&meta);
+        self.set_file_extent(path, &meta)?;
+        self.get_file_info_mut(path)?
+            .set_flag(FileFlag::Hidden, meta.is_flag(FileFlag::Hidden));
+        self.record(OpKind::Create, path, None);
+        Ok(())
+    }
+
+    /// Removes a file from the file system, without freeing its data.
+    fn remove_file_entry(&mut self, path: &ArhPath) -> Result<FileMeta> {
+        let file = self.remove_dict_entry(path)?;
+        self.forget_case(path);
+        self.dir_tree
+            .remove_file_entry(path, &DirStats::from_file(&file));
+        Ok(file)
+    }
+
+    /// Removes a file from the path dictionary and the file table, without updating the
+    /// directory tree.
+    fn remove_dict_entry(&mut self, path: &ArhPath) -> Result<FileMeta> {
+        let file_id = self.remove_dict_leaf(path)?;
 
         // For the file entry, it's not as simple as it looks. While FileMeta has an ID field,
         // the game actually indexes into the file table instead of filtering by that field.
@@ -293,20 +770,384 @@ impl ArhFileSystem {
         // contents, and recycle it later.
         let file = self.arh.file_table.delete_entry(file_id).unwrap();
         let ext = self.arh.get_or_init_ext(&self.opts);
-        ext.allocated_blocks.mark(&file, false);
         ext.file_meta_recycle_bin.push(file_id);
+        Ok(file)
+    }
+
+    /// Removes a file from the path dictionary, but keeps its file table entry, e.g. to add
+    /// it back under another path with [`Self::add_dict_entry`]. Returns the file's ID.
+    fn remove_dict_leaf(&mut self, path: &ArhPath) -> Result<u32> {
+        let (file_id, leaf_id) = self.get_file_id(path).ok_or(Error::FsNoEntry)?;
+
+        // We must recursively free nodes. Consider this scenario:
+        // Files "ab", "ac", "ad" are created, then removed. If nodes are not freed
+        // recursively, then file "a" cannot be created because the common node was not freed
+        self.arh.path_dictionary_mut().free_node_recursive(leaf_id);
+        Ok(file_id)
+    }
 
-        // Update directory tree
-        self.dir_tree.remove_file_entry(path);
+    /// Moves a file to another path in the path dictionary, keeping its file table entry.
+    /// Returns the file's ID.
+    ///
+    /// If the file can't be added at `new_path`, the dictionary is left unchanged.
+    fn move_dict_leaf(&mut self, path: &ArhPath, new_path: &ArhPath) -> Result<u32> {
+        let (id, _) = self.get_file_id(path).ok_or(Error::FsNoEntry)?;
+        if !path.starts_with(new_path.as_str()) && !new_path.starts_with(path.as_str()) {
+            // Neither name extends the other, so both can be in the dictionary at once
+            self.add_dict_entry(new_path, Some(id))?;
+            self.remove_dict_leaf(path)?;
+            return Ok(id);
+        }
+        // The old name must be removed first. For instance, some file managers first create a
+        // ".part" file which they then rename to the regular file name without ".part".
+        // Adding the new name can still fail (e.g. if the dictionary is full), so keep the
+        // tables to restore them.
+        let dict = self.arh.path_dictionary().clone();
+        let strings_len = self.arh.strings().byte_len();
+        self.remove_dict_leaf(path)?;
+        if let Err(e) = self.add_dict_entry(new_path, Some(id)) {
+            *self.arh.path_dictionary_mut() = dict;
+            self.arh.strings_mut().truncate(strings_len);
+            return Err(e);
+        }
+        Ok(id)
+    }
+
+    /// Returns the node reached after `prefix` in the path dictionary, if it is not a leaf.
+    fn dict_prefix_node(&self, prefix: &str) -> Option<i32> {
+        let dict = self.arh.path_dictionary();
+        let mut cur = 0;
+        for &chr in prefix.as_bytes() {
+            let next = dict.node(cur).get_next()? ^ i32::from(chr);
+            dict.get_node(next)
+                .filter(|n| n.is_child(cur) && !n.is_leaf())?;
+            cur = next;
+        }
+        Some(cur)
+    }
+
+    /// Moves every path that starts with `prefix` to `new_prefix`, by moving the nodes after
+    /// `prefix` in the path dictionary. Only the nodes for `new_prefix` are added, the
+    /// leaves and their strings are kept.
+    ///
+    /// `prefix` must end at a node that is not a leaf, see [`Self::dict_prefix_node`]. If this
+    /// fails, the dictionary is left unchanged.
+    fn move_dict_subtree(&mut self, prefix: &str, new_prefix: &str) -> Result<()> {
+        // Adding nodes can fail halfway through, e.g. if the dictionary is full
+        let mut dict = self.arh.path_dictionary().clone();
+        let (new_node, leaf) = self.add_dict_prefix(&mut dict, new_prefix)?;
+        if let Some((idx, name, id)) = leaf {
+            let string_offset = self.arh.strings_mut().push(&name, id)?;
+            let previous = dict.node(idx).previous();
+            *dict.node_mut(idx) = DictNode::Leaf {
+                previous,
+                string_offset,
+            };
+        }
+        *self.arh.path_dictionary_mut() = dict;
+        // Adding blocks may have moved the old nodes
+        let old_node = self
+            .dict_prefix_node(prefix)
+            .expect("prefix not in dictionary");
+        let dict = self.arh.path_dictionary_mut();
+        dict.move_children(old_node, new_node);
+        dict.free_node_recursive(old_node);
         Ok(())
     }
 
-    /// Deletes an empty directory.
+    /// Adds the nodes for `prefix` to `dict` and returns the last one, which has no children
+    /// yet. No path may start with `prefix`.
     ///
-    /// This only updates the in-memory directory tree, it has no effect on the underlying
-    /// file system, as the ARH format has no concept of directories.
+    /// A file whose leaf held part of `prefix` in its string is moved further down. Its new
+    /// leaf is returned along with the rest of its name and its ID: the leaf's string still
+    /// has to be added.
+    fn add_dict_prefix(
+        &self,
+        dict: &mut PathDictionary,
+        prefix: &str,
+    ) -> Result<(i32, Option<PendingLeaf>)> {
+        let prefix = prefix.as_bytes();
+        // Follow existing paths
+        let mut cur = 0;
+        let mut depth = 0;
+        // Where the file's leaf goes: the depth of its sibling, its character, the rest of
+        // its name and its ID
+        let mut split = None;
+        while depth < prefix.len() {
+            let next = dict.node(cur).next_after_chr(prefix[depth]);
+            let Some(node) = dict.get_node(next).filter(|n| n.is_child(cur)).copied() else {
+                break;
+            };
+            if let DictNode::Leaf { string_offset, .. } = node {
+                let (name, id) = self.arh.strings().get_str_part_id(string_offset as usize);
+                let rest = &prefix[depth + 1..];
+                if name.as_bytes().starts_with(rest) {
+                    return Err(Error::FsAlreadyExists);
+                }
+                if rest.starts_with(name.as_bytes()) {
+                    return Err(Error::FsFileNameExtended);
+                }
+                let common = rest
+                    .iter()
+                    .zip(name.bytes())
+                    .take_while(|(a, b)| **a == *b)
+                    .count();
+                split = Some((
+                    depth + 1 + common,
+                    name.as_bytes()[common],
+                    name[common + 1..].to_string(),
+                    id,
+                ));
+                // The prefix takes over the leaf's node
+                *dict.node_mut(next) = DictNode::Free;
+                break;
+            }
+            cur = next;
+            depth += 1;
+        }
+        if depth == prefix.len() {
+            return Err(Error::FsAlreadyExists);
+        }
+
+        // New nodes share a block with their parent where possible
+        let mut block = dict.node(cur).next();
+        let mut leaf = None;
+        let free = |dict: &PathDictionary, block: i32, chr: u8| {
+            dict.get_node(block ^ i32::from(chr))
+                .is_some_and(DictNode::is_free)
+        };
+        for (i, &chr) in prefix.iter().enumerate().skip(depth) {
+            let leaf_chr = split.as_ref().filter(|s| s.0 == i).map(|s| s.1);
+            if free(dict, block, chr) && leaf_chr.is_none_or(|c| free(dict, block, c)) {
+                dict.node_mut(cur).attach_next(block);
+            } else {
+                block = dict.allocate_new_block(cur)?;
+            }
+            if let Some(c) = leaf_chr {
+                let idx = block ^ i32::from(c);
+                // The string offset is set by the caller
+                *dict.node_mut(idx) = DictNode::Leaf {
+                    previous: cur,
+                    string_offset: 0,
+                };
+                leaf = split.take().map(|(_, _, name, id)| (idx, name, id));
+            }
+            let next = block ^ i32::from(chr);
+            *dict.node_mut(next) = DictNode::Occupied {
+                previous: cur,
+                next: block,
+            };
+            cur = next;
+        }
+        Ok((cur, leaf))
+    }
+
+    /// Deletes an empty directory, along with its placeholder if it has one.
     pub fn delete_empty_dir(&mut self, path: &ArhPath) -> Result<()> {
+        self.check_writable()?;
+        if !self.is_dir(path) {
+            return Err(Error::FsNoEntry);
+        }
+        if !self.is_dir_empty(path) {
+            return Err(Error::FsNotEmpty);
+        }
+        let placeholder = path.join(DIR_PLACEHOLDER);
+        if self.is_file(&placeholder) {
+            self.delete_file(&placeholder)?;
+        }
         self.dir_tree.remove_empty_dir(path);
+        self.record(OpKind::Delete, path, None);
+        Ok(())
+    }
+
+    /// Returns the regions of the ARD file that are not referenced by any file entry, given
+    /// the total size of the ARD file.
+    ///
+    /// Regions are sorted by offset. Data in these regions is unreachable, for example
+    /// leftovers from files that were replaced or deleted.
+    pub fn unreferenced_regions(&self, ard_len: u64) -> Vec<Range<u64>> {
+        let mut extents: Vec<_> = self
+            .arh
+            .file_table
+            .files()
+            .iter()
+            .filter(|f| f.compressed_size != 0)
+            .map(|f| (f.offset, f.offset + u64::from(f.compressed_size)))
+            .collect();
+        extents.sort_unstable();
+        let mut regions = Vec::new();
+        let mut pos = 0;
+        for (start, end) in extents {
+            if start > pos {
+                regions.push(pos..start.min(ard_len));
+            }
+            pos = pos.max(end);
+        }
+        regions.push(pos..ard_len);
+        regions.retain(|r| !r.is_empty());
+        regions
+    }
+
+    /// Returns the physical layout of the ARD file, given its total size: the regions used by
+    /// each file, and the unreferenced regions between them.
+    ///
+    /// Extents are sorted by offset. Files that point to the same data share a single extent,
+    /// and hidden files are included. Extents of different files only overlap if the archive
+    /// is inconsistent.
+    pub fn iter_extents(&self, ard_len: u64) -> impl Iterator<Item = Extent> {
+        let mut used: BTreeMap<(u64, u32), Vec<ArhPath>> = BTreeMap::new();
+        let dict = self.arh.path_dictionary();
+        for (idx, node) in dict.nodes.iter().enumerate() {
+            let DictNode::Leaf { string_offset, .. } = *node else {
+                continue;
+            };
+            let (_, file_id) = self.arh.strings().get_str_part_id(string_offset as usize);
+            let Some(meta) = self.arh.file_table.get_meta(file_id) else {
+                continue;
+            };
+            let Ok(path) = ArhPath::normalize(dict.get_full_path(idx, self.arh.strings())) else {
+                continue;
+            };
+            if meta.compressed_size != 0 {
+                used.entry((meta.offset, meta.compressed_size))
+                    .or_default()
+                    .push(path);
+            }
+        }
+        let free = self
+            .unreferenced_regions(ard_len)
+            .into_iter()
+            .map(|range| Extent {
+                range,
+                files: Vec::new(),
+            });
+        let mut extents: Vec<_> = used
+            .into_iter()
+            .map(|((offset, size), mut files)| {
+                files.sort();
+                Extent {
+                    range: offset..offset + u64::from(size),
+                    files,
+                }
+            })
+            .chain(free)
+            .collect();
+        extents.sort_by_key(|e| (e.range.start, e.range.end));
+        extents.into_iter()
+    }
+
+    /// Checks the metadata of every file (including hidden files) against the ARD file,
+    /// without reading or decompressing file contents. See [`ArdReader::check_entry`].
+    ///
+    /// This catches .arh and .ard files that don't belong together, or a truncated .ard
+    /// file, before anything is written to them. Problems are sorted by path.
+    pub fn validate(
+        &self,
+        ard: &mut ArdReader<impl Read + Seek>,
+    ) -> Result<Vec<(ArhPath, EntryMismatch)>> {
+        let ard_len = ard.get_mut().seek(SeekFrom::End(0))?;
+        let dict = self.arh.path_dictionary();
+        let mut problems = Vec::new();
+        for (idx, node) in dict.nodes.iter().enumerate() {
+            let DictNode::Leaf { string_offset, .. } = *node else {
+                continue;
+            };
+            let (_, file_id) = self.arh.strings().get_str_part_id(string_offset as usize);
+            let Some(meta) = self.arh.file_table.get_meta(file_id) else {
+                continue;
+            };
+            let path = ArhPath::normalize(dict.get_full_path(idx, self.arh.strings()))?;
+            for problem in ard.check_entry(meta, ard_len)? {
+                problems.push((path.clone(), problem));
+            }
+        }
+        problems.sort_by(|(a, _), (b, _)| a.cmp(b));
+        Ok(problems)
+    }
+
+    /// Marks a region of the ARD file as free, so it can be reused for new files.
+    ///
+    /// Blocks that are only partially covered by the region are not freed. No file should
+    /// reference data in the region, see [`Self::unreferenced_regions`]. Orphaned files
+    /// whose data overlaps the region are dropped from the orphan list.
+    pub fn free_region(&mut self, region: Range<u64>) -> Result<()> {
+        self.check_writable()?;
+        let ext = self.arh.get_or_init_ext(&self.opts);
+        let shared_extents = &mut ext.shared_extents;
+        // Orphaned files in the region can't be recovered anymore
+        ext.orphans.retain(|o| {
+            let end = o.meta.offset + u64::from(o.meta.compressed_size);
+            let keep =
+                o.meta.compressed_size == 0 || end <= region.start || o.meta.offset >= region.end;
+            if !keep {
+                shared_extents.release(&o.meta);
+            }
+            keep
+        });
+        ext.allocated_blocks.mark_range(region, false);
+        Ok(())
+    }
+
+    /// Makes the file at `path` point to the same data as the file at `source`, e.g. to
+    /// deduplicate identical files.
+    ///
+    /// The space previously used by the file is not freed, see [`Self::free_region`].
+    /// Shared data is reference-counted, so later writes to either file through
+    /// [`ArdFileAllocator`](crate::file_alloc::ArdFileAllocator) leave the other one intact.
+    pub fn share_extent(&mut self, path: &ArhPath, source: &ArhPath) -> Result<()> {
+        let src = *self.get_file_info(source).ok_or(Error::FsNoEntry)?;
+        self.set_file_extent(path, &src)
+    }
+
+    /// Makes the file at `path` point to data that is already in the ARD file, e.g. when
+    /// importing entries with known offsets from another tool.
+    ///
+    /// Only the location, sizes and [`FileFlag::HasXbc1Header`] are taken from `extent`,
+    /// see [`FileMeta::with_extent`]. The region is marked as occupied, and if other files
+    /// already point to the same data, it is shared with them like in [`Self::share_extent`].
+    ///
+    /// The space previously used by the file is not freed, see [`Self::free_region`].
+    pub fn set_file_extent(&mut self, path: &ArhPath, extent: &FileMeta) -> Result<()> {
+        self.check_writable()?;
+        let (file_id, _) = self.get_file_id(path).ok_or(Error::FsNoEntry)?;
+        // Initialize the section before changing the file table, so the new extent isn't
+        // counted twice
+        let ext = self.arh.get_or_init_ext(&self.opts);
+        let same_data = |f: &FileMeta| f.compressed_size != 0 && f.offset == extent.offset;
+        // Orphaned files also hold a reference to their data
+        let orphaned = ext.orphans.files().iter().any(|o| same_data(&o.meta));
+        let shared = orphaned
+            || self
+                .arh
+                .file_table
+                .files()
+                .iter()
+                .enumerate()
+                .any(|(id, f)| id != file_id as usize && same_data(f));
+
+        let meta = self.arh.file_table.get_meta_mut(file_id).unwrap();
+        let old = *meta;
+        meta.offset = extent.offset;
+        meta.compressed_size = extent.compressed_size;
+        meta.uncompressed_size = extent.uncompressed_size;
+        meta.set_flag(
+            FileFlag::HasXbc1Header,
+            extent.is_flag(FileFlag::HasXbc1Header),
+        );
+        let new = *meta;
+        self.update_file_stats(path, &old, &new);
+        // Nothing to do if the file already points to the same data
+        if (old.offset, old.compressed_size) != (new.offset, new.compressed_size) {
+            let ext = self.arh.get_or_init_ext(&self.opts);
+            ext.shared_extents.release(&old);
+            if shared {
+                ext.shared_extents.acquire(&new);
+            }
+            ext.allocated_blocks.mark(&new, true);
+        }
+        if old.compressed_size != 0 {
+            self.record(OpKind::Replace, path, None);
+        }
         Ok(())
     }
 
@@ -318,70 +1159,679 @@ impl ArhFileSystem {
     /// This operation is atomic. If it fails, the file system will be in the same (visible)
     /// state as before it was attempted.
     pub fn rename_file(&mut self, path: &ArhPath, new_path: &ArhPath) -> Result<()> {
+        self.check_writable()?;
         let meta = self.get_file_info(path).copied().ok_or(Error::FsNoEntry)?;
-        // We need to delete the file first, because the new name might be in conflict with the old
-        // file's name. For instance, some file managers first create a ".part" file which they then
-        // rename to the regular file name without ".part". This type of file names is not supported
-        // by the file system.
-        self.delete_file(path)?;
-        let new_file = match self.create_file(new_path) {
-            Ok(f) => f,
-            Err(e) => {
-                // Re-create the old file if creating the new one fails.
-                // This shouldn't fail as we just deleted it.
-                self.create_file(path).unwrap().clone_from(&meta);
-                return Err(e);
-            }
-        };
-        new_file.clone_from(&meta);
+        self.check_new_path(new_path)?;
+        // The file keeps its file table entry (and ID), so its data stays where it is.
+        self.move_dict_leaf(path, new_path)?;
+        self.dir_tree
+            .remove_file_entry(path, &DirStats::from_file(&meta));
+        self.dir_tree
+            .insert_file_entry(new_path.to_string(), DirStats::from_file(&meta));
+        self.forget_case(path);
+        self.record_case(new_path);
+        self.record(OpKind::Rename, path, Some(new_path));
+        Ok(())
+    }
+
+    /// Like [`Self::rename_file`], but if a file already exists at `new_path`, it is
+    /// replaced. Its data is freed unless other files share it.
+    ///
+    /// Directories are never replaced.
+    pub fn rename_file_overwrite(&mut self, path: &ArhPath, new_path: &ArhPath) -> Result<()> {
+        self.check_writable()?;
+        if !self.is_file(path) {
+            return Err(Error::FsNoEntry);
+        }
+        if path == new_path {
+            // Only the spelling changes
+            self.record_case(new_path);
+            return Ok(());
+        }
+        if self.is_file(new_path) {
+            // The new path was valid for the old file, so renaming can't fail after this
+            self.delete_file(new_path)?;
+        }
+        self.rename_file(path, new_path)
+    }
+
+    /// Atomically swaps the contents of two files.
+    ///
+    /// Only the files' data and flags are exchanged, both paths keep their file IDs.
+    pub fn exchange_files(&mut self, a: &ArhPath, b: &ArhPath) -> Result<()> {
+        self.check_writable()?;
+        let (id_a, _) = self.get_file_id(a).ok_or(Error::FsNoEntry)?;
+        let (id_b, _) = self.get_file_id(b).ok_or(Error::FsNoEntry)?;
+        let table = &mut self.arh.file_table;
+        let meta_a = *table.get_meta(id_a).unwrap();
+        let meta_b = *table.get_meta(id_b).unwrap();
+        let (mut new_a, mut new_b) = (meta_b, meta_a);
+        new_a.id = meta_a.id;
+        new_b.id = meta_b.id;
+        *table.get_meta_mut(id_a).unwrap() = new_a;
+        *table.get_meta_mut(id_b).unwrap() = new_b;
+        self.update_file_stats(a, &meta_a, &new_a);
+        self.update_file_stats(b, &meta_b, &new_b);
+        self.record(OpKind::Replace, a, None);
+        self.record(OpKind::Replace, b, None);
         Ok(())
     }
 
     /// Renames a directory, recursively moving its children.
     ///
     /// No data in the ARD file has to actually be moved, this operation only affects the file
-    /// system.
+    /// system. Empty subdirectories are moved as well.
+    ///
+    /// This operation is atomic. If it fails, the file system will be in the same (visible)
+    /// state as before it was attempted.
     pub fn rename_dir(&mut self, path: &ArhPath, new_path: &ArhPath) -> Result<()> {
+        self.check_writable()?;
         let dir = self.get_dir(path).ok_or(Error::FsNoEntry)?;
+        if path == new_path {
+            return Ok(());
+        }
+        if new_path.starts_with(path.as_str())
+            && (path == &ARH_PATH_ROOT || new_path.as_bytes()[path.len()] == b'/')
+        {
+            return Err(Error::FsMoveIntoSelf);
+        }
+        if self.exists(new_path) {
+            return Err(Error::FsAlreadyExists);
+        }
         let relative_paths = dir.children_paths();
-        for (i, child) in relative_paths.iter().enumerate() {
-            let child = &child[1..];
-            if let Err(e) = self.rename_file(&path.join(child), &new_path.join(child)) {
-                // Attempt rollback and panic if any operation fails.
-                // This is currently implemented by renaming back the files for which the operation
-                // succeeded. Another possibility is to save the state of the file system before
-                // the operation.
-                for child in &relative_paths[..i] {
-                    self.rename_file(&new_path.join(child), &path.join(child))
-                        .unwrap();
+        for child in &relative_paths {
+            self.check_new_path(&new_path.try_join(child)?)?;
+        }
+
+        // Files keep their file table entries, so their IDs don't change
+        match &relative_paths[..] {
+            // Only empty directories, which are not in the path dictionary
+            [] => {}
+            [child] if self.dict_prefix_node(&format!("{path}/")).is_none() => {
+                // The file's leaf also holds the end of the directory's name
+                self.move_dict_leaf(&path.join(&child[1..]), &new_path.join(&child[1..]))?;
+            }
+            _ => self.move_dict_subtree(&format!("{path}/"), &format!("{new_path}/"))?,
+        }
+
+        for child in &relative_paths {
+            let old = path.join(child);
+            let new = new_path.join(&child[1..]);
+            if let Some((id, _)) = self.get_file_id(&new) {
+                self.paths_by_id.insert(id, new);
+            }
+            let name = self.forget_case(&old);
+            // Keep the spelling of the child, in the (possibly new) spelling of `new_path`
+            let child = name.as_deref().unwrap_or(old.as_str());
+            self.record_case(&new_path.join(&child[path.len()..]));
+        }
+
+        let subtree = self.dir_tree.take_subtree(path).unwrap();
+        self.dir_tree.insert_subtree(new_path, subtree);
+        self.record(OpKind::Rename, path, Some(new_path));
+        Ok(())
+    }
+
+    /// Updates the directory tree's aggregate data after the metadata for the file at `path`
+    /// was changed from `old` to `new`.
+    ///
+    /// This is done automatically by the file system and the allocator. Users that edit
+    /// sizes through [`Self::get_file_info_mut`] should call this afterwards.
+    pub fn update_file_stats(&mut self, path: &ArhPath, old: &FileMeta, new: &FileMeta) {
+        self.dir_tree
+            .update_file_stats(path, &DirStats::from_file(old), &DirStats::from_file(new));
+    }
+
+    /// Like [`Self::update_file_stats`], but looks up the file by its ID.
+    pub(crate) fn update_file_stats_by_id(&mut self, file_id: u32, old: &FileMeta) {
+        let Some(new) = self.arh.file_table.get_meta(file_id).copied() else {
+            return;
+        };
+        // Cached, instead of searching the path dictionary for every file that is written
+        let Some(path) = self.path_of(file_id) else {
+            return;
+        };
+        self.dir_tree.update_file_stats(
+            &path,
+            &DirStats::from_file(old),
+            &DirStats::from_file(&new),
+        );
+    }
+
+    /// Rebuilds the block allocation table with blocks of `1 << block_size_pow` bytes.
+    ///
+    /// Switching to larger blocks may turn partially free blocks into occupied ones. Unless
+    /// `allow_lossy` is set, this fails with [`Error::ExtBlockSizeLossy`] instead, leaving the
+    /// table untouched.
+    pub fn set_block_size(
+        &mut self,
+        block_size_pow: u16,
+        allow_lossy: bool,
+    ) -> Result<BlockSizeChange> {
+        self.check_writable()?;
+        if block_size_pow >= u32::BITS as u16 {
+            return Err(Error::ExtBlockSize(block_size_pow));
+        }
+        let old_ext_size = match &mut self.arh.arh_ext_section {
+            Some(ext) => ext.calc_size(),
+            None => 0,
+        };
+        // Build the new table on a copy of the section, which is only installed once the
+        // change is allowed
+        let mut new_ext = self.arh.arh_ext_section.clone();
+        let ext = ArhExtSection::get_or_init(&mut new_ext, &self.arh.file_table, &self.opts);
+        let old_block_size_pow = ext.allocated_blocks.block_size_pow;
+        let (table, lost_bytes) = ext.allocated_blocks.with_block_size(block_size_pow);
+        if lost_bytes != 0 && !allow_lossy {
+            return Err(Error::ExtBlockSizeLossy(lost_bytes));
+        }
+        ext.allocated_blocks = table;
+        let new_ext_size = ext.calc_size();
+        self.arh.arh_ext_section = new_ext;
+        self.opts.ext_block_size_pow = block_size_pow;
+        Ok(BlockSizeChange {
+            old_block_size_pow,
+            new_block_size_pow: block_size_pow,
+            lost_bytes,
+            old_ext_size,
+            new_ext_size,
+        })
+    }
+
+    /// Returns how space in the ARD file is used, e.g. to decide whether the archive
+    /// should be compacted, or its block size changed (see [`Self::set_block_size`]).
+    ///
+    /// Allocation counts start from zero when the file system is loaded. The other
+    /// statistics describe the current state of the block allocation table. If the archive
+    /// doesn't have one yet, they are computed from the file table.
+    pub fn alloc_stats(&self) -> AllocStats {
+        let built;
+        let table = match &self.arh.arh_ext_section {
+            Some(ext) => &ext.allocated_blocks,
+            None => {
+                built = ArhExtSection::new(&self.arh.file_table, self.opts.ext_block_size_pow);
+                &built.allocated_blocks
+            }
+        };
+        let block_size: u64 = 1 << table.block_size_pow;
+        let orphans = self.orphaned_files().iter().map(|file| &file.meta);
+        // Shared extents are only counted once
+        let extents: BTreeMap<u64, u32> = self
+            .arh
+            .file_table
+            .files()
+            .iter()
+            .chain(orphans)
+            .filter(|file| file.compressed_size != 0)
+            .map(|file| (file.offset, file.compressed_size))
+            .collect();
+        let rounding_waste = extents
+            .iter()
+            .map(|(&offset, &size)| {
+                let end = offset + u64::from(size);
+                (end.div_ceil(block_size) - offset / block_size) * block_size - u64::from(size)
+            })
+            .sum();
+        let free_runs = table.free_runs();
+        AllocStats {
+            block_size: block_size.try_into().unwrap(),
+            counts: self.alloc_counts,
+            occupied_bytes: table.occupied_bytes(),
+            rounding_waste,
+            free_bytes: free_runs.iter().map(|run| run.end - run.start).sum(),
+            free_runs: free_runs.len(),
+            largest_free_run: free_runs
+                .iter()
+                .map(|run| run.end - run.start)
+                .max()
+                .unwrap_or_default(),
+        }
+    }
+
+    /// Returns the IDs of file table entries that were freed by deleting files, in ascending
+    /// order. New files reuse them, highest ID first, before the file table grows.
+    ///
+    /// Archives without an extension section have no recycle bin.
+    pub fn recycled_file_ids(&self) -> &[u32] {
+        self.arh
+            .arh_ext_section
+            .as_ref()
+            .map_or(&[], |ext| ext.recycle_bin().ids())
+    }
+
+    /// Returns the operations recorded in the archive while history was enabled (see
+    /// [`Self::set_history_enabled`]), oldest first.
+    pub fn history(&self) -> &[OpLogEntry] {
+        self.arh
+            .arh_ext_section
+            .as_ref()
+            .map_or(&[], |ext| ext.op_log.entries())
+    }
+
+    pub fn is_history_enabled(&self) -> bool {
+        self.arh
+            .arh_ext_section
+            .as_ref()
+            .is_some_and(|ext| ext.op_log.is_enabled())
+    }
+
+    /// Starts or stops recording the operations that modify the archive (see [`OpKind`]),
+    /// with their time. The setting and the history are saved in the archive's extension
+    /// section, so recording continues when the archive is loaded again.
+    ///
+    /// Only changes made through this library are recorded. The latest
+    /// [`OP_LOG_MAX_ENTRIES`](crate::OP_LOG_MAX_ENTRIES) operations are kept.
+    pub fn set_history_enabled(&mut self, enabled: bool) -> Result<()> {
+        self.check_writable()?;
+        self.arh
+            .get_or_init_ext(&self.opts)
+            .op_log
+            .set_enabled(enabled);
+        Ok(())
+    }
+
+    /// Removes all recorded operations, without changing whether recording is enabled.
+    pub fn clear_history(&mut self) -> Result<()> {
+        self.check_writable()?;
+        if let Some(ext) = &mut self.arh.arh_ext_section {
+            ext.op_log.clear();
+        }
+        Ok(())
+    }
+
+    /// Adds an operation to the history, if recording is enabled. Directory placeholders
+    /// are left out.
+    fn record(&mut self, kind: OpKind, path: &ArhPath, new_path: Option<&ArhPath>) {
+        if Self::is_placeholder(path) {
+            return;
+        }
+        let Some(ext) = self
+            .arh
+            .arh_ext_section
+            .as_mut()
+            .filter(|ext| ext.op_log.is_enabled())
+        else {
+            return;
+        };
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |t| t.as_secs());
+        ext.op_log.push(OpLogEntry {
+            timestamp,
+            kind,
+            path: path.to_string(),
+            new_path: new_path.map(ArhPath::to_string),
+        });
+    }
+
+    /// Like [`Self::record`], but looks up the file by its ID.
+    pub(crate) fn record_by_id(&mut self, kind: OpKind, file_id: u32) {
+        if !self.is_history_enabled() {
+            return;
+        }
+        if let Some(path) = self.path_of(file_id) {
+            self.record(kind, &path, None);
+        }
+    }
+
+    /// Returns the path of the file with the given ID.
+    fn path_of(&mut self, file_id: u32) -> Option<ArhPath> {
+        if let Some(path) = self.paths_by_id.get(&file_id) {
+            if self.get_file_id(path).is_some_and(|(id, _)| id == file_id) {
+                return Some(path.clone());
+            }
+        }
+        // Missing or out of date: index every file again
+        let dict = self.arh.path_dictionary();
+        let strings = self.arh.strings();
+        self.paths_by_id = dict
+            .nodes
+            .iter()
+            .enumerate()
+            .filter_map(|(i, node)| {
+                let DictNode::Leaf { string_offset, .. } = *node else {
+                    return None;
+                };
+                let (_, id) = strings.get_str_part_id(string_offset as usize);
+                let path = ArhPath::normalize(dict.get_full_path(i, strings)).ok()?;
+                Some((id, path))
+            })
+            .collect();
+        self.paths_by_id.get(&file_id).cloned()
+    }
+
+    /// Returns how the entries of the file table are used, e.g. to explain why it has more
+    /// entries than the archive has files.
+    pub fn file_table_usage(&self) -> FileTableUsage {
+        let strings = self.arh.strings();
+        let mut live = vec![false; self.arh.file_table.files().len()];
+        for node in &self.arh.path_dictionary().nodes {
+            if let DictNode::Leaf { string_offset, .. } = *node {
+                let (_, id) = strings.get_str_part_id(string_offset as usize);
+                if let Some(live) = live.get_mut(id as usize) {
+                    *live = true;
                 }
-                return Err(e);
             }
         }
-        self.dir_tree.remove_empty_dir(path);
+        let recycled = self.recycled_file_ids().to_vec();
+        let unreferenced = (0..live.len())
+            .filter(|&id| !live[id])
+            .map(|id| id as u32)
+            .filter(|id| recycled.binary_search(id).is_err())
+            .collect();
+        FileTableUsage {
+            entries: live.len(),
+            live: live.iter().filter(|&&l| l).count(),
+            recycled,
+            unreferenced,
+        }
+    }
+
+    /// Compares the block allocation table to the file table.
+    ///
+    /// The two can go out of sync if the archive was modified by tools that don't update the
+    /// extension section. Use [`Self::repair_block_table`] to fix any differences.
+    pub fn check_block_table(&self) -> BlockTableDrift {
+        let Some(ext) = &self.arh.arh_ext_section else {
+            return BlockTableDrift::default();
+        };
+        let mut expected = ext.clone();
+        expected.rebuild_from(&self.arh.file_table);
+        let (unmarked, leaked) = ext.allocated_blocks.diff(&expected.allocated_blocks);
+        BlockTableDrift { unmarked, leaked }
+    }
+
+    /// Regenerates the block allocation table from the file table, see
+    /// [`Self::check_block_table`].
+    pub fn repair_block_table(&mut self) -> Result<()> {
+        self.check_writable()?;
+        self.arh.get_or_init_ext(&self.opts);
+        let Arh {
+            file_table,
+            arh_ext_section,
+            ..
+        } = &mut self.arh;
+        arh_ext_section.as_mut().unwrap().rebuild_from(file_table);
         Ok(())
     }
 
+    /// Regenerates the path dictionary and the string table from scratch, keeping the path
+    /// and ID of every file.
+    ///
+    /// Files are inserted in path order into empty tables, so nodes and strings left behind
+    /// by deleted or renamed files are dropped. This is also the last resort
+    /// for a damaged dictionary, as only files that can be reached from their leaf node are
+    /// kept. The file table is not changed.
+    ///
+    /// This operation is atomic. If it fails, the file system will be in the same state as
+    /// before it was attempted.
+    pub fn rebuild_dictionary(&mut self) -> Result<DictRebuild> {
+        self.check_writable()?;
+        let old_node_count = self.arh.path_dictionary().nodes.len();
+        let old_string_table_len = self.arh.strings().byte_len();
+        // Sorted and without duplicates, in case the dictionary had more than one leaf for
+        // the same path
+        let mut files = BTreeMap::new();
+        for (path, file_id) in self.dict_files() {
+            let Ok(path) = ArhPath::normalize(path) else {
+                continue;
+            };
+            files.entry(path).or_insert(file_id);
+        }
+
+        self.replace_dictionary(files.iter().map(|(path, &id)| (path.as_str(), id)))?;
+        self.reload_dir_tree();
+        Ok(DictRebuild {
+            file_count: files.len(),
+            old_node_count,
+            new_node_count: self.arh.path_dictionary().nodes.len(),
+            old_string_table_len,
+            new_string_table_len: self.arh.strings().byte_len(),
+        })
+    }
+
+    /// Removes the file table entries that no path points to (see
+    /// [`Self::file_table_usage`]), and renumbers the remaining files with consecutive IDs,
+    /// in their current order.
+    ///
+    /// The ID stored after each leaf's string is updated in place, so the path dictionary
+    /// keeps its layout. Leaves that pointed past the end of the file table are removed,
+    /// rather than pointing to another file after compaction. The recycle bin is emptied,
+    /// and the block allocation table is regenerated, freeing any data that only removed
+    /// entries pointed to.
+    ///
+    /// File IDs change, so manifests and snapshots that refer to files by ID no longer
+    /// match the archive.
+    pub fn compact_file_table(&mut self) -> Result<FileTableCompaction> {
+        self.check_writable()?;
+        let old_len = self.arh.file_table.files().len();
+        // (leaf node, string offset, file ID)
+        let mut leaves = Vec::new();
+        let mut dangling = Vec::new();
+        for (idx, node) in self.arh.path_dictionary().nodes.iter().enumerate() {
+            let DictNode::Leaf { string_offset, .. } = *node else {
+                continue;
+            };
+            let (_, file_id) = self.arh.strings().get_str_part_id(string_offset as usize);
+            if self.arh.file_table.get_meta(file_id).is_some() {
+                leaves.push((string_offset as usize, file_id));
+            } else {
+                dangling.push(idx as i32);
+            }
+        }
+
+        let mut new_ids: BTreeMap<u32, u32> = leaves.iter().map(|&(_, id)| (id, 0)).collect();
+        let mut files = Vec::with_capacity(new_ids.len());
+        for (new_id, (&old_id, slot)) in new_ids.iter_mut().enumerate() {
+            *slot = new_id.try_into().unwrap();
+            let mut meta = *self.arh.file_table.get_meta(old_id).unwrap();
+            meta.id = *slot;
+            files.push(meta);
+        }
+        let renumbered = new_ids.iter().filter(|(old, new)| old != new).count();
+
+        for leaf in dangling {
+            self.arh.path_dictionary_mut().free_node_recursive(leaf);
+        }
+        for (string_offset, old_id) in leaves {
+            self.arh
+                .strings_mut()
+                .set_id(string_offset, new_ids[&old_id]);
+        }
+        self.arh.file_table.set_files(files);
+        let Arh {
+            file_table,
+            arh_ext_section,
+            ..
+        } = &mut self.arh;
+        if let Some(ext) = arh_ext_section {
+            ext.file_meta_recycle_bin = FileRecycleBin::default();
+            ext.rebuild_from(file_table);
+        }
+        self.reload_dir_tree();
+        Ok(FileTableCompaction {
+            old_len,
+            new_len: self.arh.file_table.files().len(),
+            renumbered,
+        })
+    }
+
     /// Writes the updated version of the ARH file system to the given writer.
+    ///
+    /// The output is reproducible: applying the same sequence of operations to the same
+    /// input always produces byte-identical files. In particular, dictionary nodes and ARD
+    /// blocks are allocated in a fixed order, nothing depends on hash map iteration order,
+    /// and encrypted sections are always written with the same (null) key.
     pub fn sync(&mut self, mut writer: impl Write + Seek) -> Result<()> {
-        self.arh.prepare_for_write();
-        Ok(self.arh.write(&mut writer)?)
+        self.check_writable()?;
+        if self.case_names.is_empty() {
+            self.arh.prepare_for_write();
+            return Ok(self.arh.write(&mut writer)?);
+        }
+        // Write a dictionary with the original spelling of names, see PathCase::Preserve.
+        // The one in memory stays lowercase, so lookups keep working.
+        let source = self.cased_source();
+        let cased = match self.cased_dict.take() {
+            Some(cased) if cased.source == source => cased,
+            _ => self.build_cased_dictionary()?,
+        };
+        let CasedDictionary { dict, strings, .. } = self.cased_dict.insert(cased);
+        Ok(self.arh.write_with_dictionary(&mut writer, dict, strings)?)
+    }
+
+    /// Builds the dictionary written by [`Self::sync`], with the spelling from `case_names`.
+    fn build_cased_dictionary(&mut self) -> Result<CasedDictionary> {
+        let files: BTreeMap<_, _> = self
+            .dict_files()
+            .into_iter()
+            .map(|(path, id)| {
+                let name = ArhPath::normalize(&path)
+                    .ok()
+                    .and_then(|p| self.case_names.get(&p).cloned());
+                (name.unwrap_or(path), id)
+            })
+            .collect();
+        // The file table is only read, to look up existing entries, so it is lent to the
+        // new tables instead of being cloned.
+        let mut cased = Self {
+            arh: Arh::from_tables(
+                StringTable::new_empty(),
+                PathDictionary::new_empty(),
+                std::mem::take(&mut self.arh.file_table),
+            ),
+            opts: self.opts.clone(),
+            dir_tree: DirNode::empty_root(),
+            case_names: BTreeMap::new(),
+            case_generation: 0,
+            cased_dict: None,
+            paths_by_id: HashMap::new(),
+            alloc_counts: AllocCounts::default(),
+        };
+        let res = files
+            .iter()
+            .try_for_each(|(path, &id)| cased.add_dict_entry(path, Some(id)).map(|_| ()));
+        self.arh.file_table = std::mem::take(&mut cased.arh.file_table);
+        res?;
+        Ok(CasedDictionary {
+            source: self.cased_source(),
+            dict: std::mem::replace(cased.arh.path_dictionary_mut(), PathDictionary::new_empty()),
+            strings: std::mem::take(cased.arh.strings_mut()),
+        })
+    }
+
+    fn cased_source(&self) -> (u64, u64) {
+        (self.arh.dict_generation(), self.case_generation)
+    }
+
+    /// Returns the full path and file ID of every leaf in the dictionary that points to
+    /// an existing file entry.
+    fn dict_files(&self) -> Vec<(String, u32)> {
+        let dict = self.arh.path_dictionary();
+        let mut files = Vec::new();
+        for (idx, node) in dict.nodes.iter().enumerate() {
+            let DictNode::Leaf { string_offset, .. } = *node else {
+                continue;
+            };
+            let (_, file_id) = self.arh.strings().get_str_part_id(string_offset as usize);
+            if self.arh.file_table.get_meta(file_id).is_some() {
+                files.push((dict.get_full_path(idx, self.arh.strings()), file_id));
+            }
+        }
+        files
+    }
+
+    /// Replaces the path dictionary and the string table with new ones that only have the
+    /// given files, without updating the directory tree.
+    ///
+    /// If this fails, the tables are left unchanged.
+    fn replace_dictionary<'a>(
+        &mut self,
+        files: impl IntoIterator<Item = (&'a str, u32)>,
+    ) -> Result<()> {
+        let backup = self.arh.clone();
+        *self.arh.path_dictionary_mut() = PathDictionary::new_empty();
+        *self.arh.strings_mut() = StringTable::new_empty();
+        for (path, file_id) in files {
+            if let Err(e) = self.add_dict_entry(path, Some(file_id)) {
+                self.arh = backup;
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Moves the spelling of names with uppercase characters from the dictionary to
+    /// [`Self::case_names`], after loading an archive with [`PathCase::Preserve`].
+    ///
+    /// Fails with [`Error::InvalidTables`] if a name can't be normalized, or if two names
+    /// only differ in case: they would be merged, and one of the files lost on the next sync.
+    fn lowercase_dictionary(&mut self) -> Result<()> {
+        let files = self.dict_files();
+        if !files
+            .iter()
+            .any(|(path, _)| path.bytes().any(|b| b.is_ascii_uppercase()))
+        {
+            return Ok(());
+        }
+        let mut lowercase = BTreeMap::new();
+        for (name, file_id) in files {
+            let path = ArhPath::normalize_with(&name, PathCase::Preserve)
+                .map_err(|e| Error::InvalidTables(format!("{name}: {e}")))?;
+            match lowercase.entry(path.clone()) {
+                Entry::Vacant(entry) => {
+                    entry.insert(file_id);
+                    self.record_case(&path);
+                }
+                Entry::Occupied(entry) => {
+                    return Err(Error::InvalidTables(format!(
+                        "{name} and {} only differ in case",
+                        self.original_path(entry.key())
+                    )));
+                }
+            }
+        }
+        let original = (
+            self.arh.path_dictionary().clone(),
+            self.arh.strings().clone(),
+        );
+        self.replace_dictionary(lowercase.iter().map(|(path, &id)| (path.as_str(), id)))?;
+        // The loaded tables already have the original spelling
+        self.cased_dict = Some(CasedDictionary {
+            source: self.cased_source(),
+            dict: original.0,
+            strings: original.1,
+        });
+        Ok(())
     }
 }
 
 impl DirNode {
-    fn build(arh: &Arh) -> Self {
-        let mut start = DirNode {
+    fn empty_root() -> Self {
+        DirNode {
             name: "/".to_string(),
             entry: DirEntry::Directory {
-                children: Vec::new(),
+                children: BTreeMap::new(),
             },
-        };
+            stats: DirStats::default(),
+        }
+    }
+
+    fn build(arh: &Arh) -> Self {
+        let mut start = Self::empty_root();
         for (idx, node) in arh.path_dictionary().nodes.iter().enumerate() {
-            if !node.is_leaf() {
+            let DictNode::Leaf { string_offset, .. } = *node else {
                 continue;
-            }
-            start.insert_file_entry(arh.path_dictionary().get_full_path(idx, arh.strings()));
+            };
+            let (_, file_id) = arh.strings().get_str_part_id(string_offset as usize);
+            let stats = arh
+                .file_table
+                .get_meta(file_id)
+                .map(DirStats::from_file)
+                .unwrap_or_default();
+            start.insert_file_entry(
+                arh.path_dictionary().get_full_path(idx, arh.strings()),
+                stats,
+            );
         }
 
         start
@@ -398,7 +1848,7 @@ impl DirNode {
         };
         let mut paths = Vec::new();
         let mut stack = VecDeque::new();
-        for child in children {
+        for child in children.values() {
             stack.push_back((child, "".to_string()));
         }
 
@@ -408,7 +1858,7 @@ impl DirNode {
                     paths.push(format!("{path}/{}", node.name));
                 }
                 DirEntry::Directory { children } => {
-                    for child in children {
+                    for child in children.values() {
                         stack.push_back((child, format!("{path}/{}", node.name)));
                     }
                 }
@@ -418,43 +1868,73 @@ impl DirNode {
         paths
     }
 
-    fn insert_file_entry(&mut self, path: String) {
+    /// Calls `visit` with the full path of each file in this subtree, visiting the children
+    /// of each directory by name.
+    ///
+    /// `path` is the full path of this node.
+    pub fn visit_files(&self, path: &ArhPath, visit: &mut impl FnMut(&ArhPath)) {
+        match &self.entry {
+            DirEntry::File => visit(path),
+            DirEntry::Directory { children } => {
+                for child in children.values() {
+                    child.visit_files(&path.join(&child.name), visit);
+                }
+            }
+        }
+    }
+
+    fn insert_file_entry(&mut self, path: String, stats: DirStats) {
         assert!(path.starts_with('/'), "path must start at the root");
-        let mut node = self;
+        let mut node = &mut *self;
         let parts = path.split('/').collect::<Vec<_>>();
+        // Depths of the directories that had to be created, for the parents' stats
+        let mut new_dir_depths = Vec::new();
         for (comp_idx, comp) in parts[1..].iter().enumerate() {
             let next_node = {
                 let DirEntry::Directory { ref mut children } = node.entry else {
                     continue;
                 };
-                match children.binary_search_by_key(comp, |c| &c.name) {
-                    Ok(i) => {
+                match children.entry(comp.to_string()) {
+                    Entry::Occupied(e) => {
                         // File/Subdirectory already present, proceed from there
-                        &mut children[i]
+                        e.into_mut()
                     }
-                    Err(i) => {
+                    Entry::Vacant(e) => {
                         // Need to create file or subdirectory
+                        let is_dir = comp_idx != parts.len() - 2;
+                        if is_dir {
+                            new_dir_depths.push(comp_idx + 1);
+                        }
                         let dir_node = DirNode {
                             name: comp.to_string(),
-                            entry: if comp_idx != parts.len() - 2 {
+                            entry: if is_dir {
                                 DirEntry::Directory {
-                                    children: Vec::new(),
+                                    children: BTreeMap::new(),
                                 }
                             } else {
                                 DirEntry::File
                             },
+                            stats: DirStats::default(),
                         };
-                        children.insert(i, dir_node);
-                        &mut children[i]
+                        e.insert(dir_node)
                     }
                 }
             };
             node = next_node;
         }
+        self.update_stats_on_path(&path, |depth, node_stats| {
+            let new_dirs = new_dir_depths.iter().filter(|&&d| d > depth).count();
+            node_stats.add(&DirStats {
+                dir_count: new_dirs as u64,
+                ..stats
+            });
+        });
     }
 
-    fn remove_file_entry(&mut self, path: &str) {
+    fn remove_file_entry(&mut self, path: &str, stats: &DirStats) {
         assert!(path.starts_with('/'), "path must start at the root");
+        // Parent stats need to be updated first, as the file node won't be reachable afterwards
+        self.update_stats_on_path(path, |_, node_stats| node_stats.sub(stats));
         let mut node = self;
         let parts = path.split('/').collect::<Vec<_>>();
         for comp in &parts[1..] {
@@ -462,16 +1942,13 @@ impl DirNode {
                 let DirEntry::Directory { ref mut children } = node.entry else {
                     continue;
                 };
-                if let Ok(i) = children.binary_search_by_key(comp, |c| &c.name) {
-                    let child = &mut children[i];
-                    if matches!(child.entry, DirEntry::File) {
-                        children.remove(i);
+                match children.get(*comp) {
+                    Some(child) if matches!(child.entry, DirEntry::File) => {
+                        children.remove(*comp);
                         break;
-                    } else {
-                        &mut children[i]
                     }
-                } else {
-                    break;
+                    Some(_) => children.get_mut(*comp).unwrap(),
+                    None => break,
                 }
             };
             node = next_node;
@@ -479,26 +1956,181 @@ impl DirNode {
     }
 
     fn remove_empty_dir(&mut self, path: &str) {
+        self.take_subtree(path);
+    }
+
+    /// Detaches the node at `path` (and its children) from the tree, updating the stats of
+    /// its parents.
+    fn take_subtree(&mut self, path: &str) -> Option<DirNode> {
         assert!(path.starts_with('/'), "path must start at the root");
         let parts = path.split('/').collect::<Vec<_>>();
-        let mut node = self;
+        let mut node = &mut *self;
+        let mut removed = None;
 
         for (comp_idx, comp) in parts[1..].iter().enumerate() {
             let next_node = {
                 let DirEntry::Directory { ref mut children } = node.entry else {
                     continue;
                 };
-                if let Ok(i) = children.binary_search_by_key(comp, |c| &c.name) {
-                    if comp_idx == parts.len() - 2 {
-                        children.remove(i);
-                        return;
-                    }
-                    &mut children[i]
-                } else {
-                    return;
+                if comp_idx == parts.len() - 2 {
+                    removed = children.remove(*comp);
+                    break;
                 }
+                children.get_mut(*comp)?
             };
             node = next_node;
         }
+
+        let removed = removed?;
+        let mut stats = removed.stats;
+        if matches!(removed.entry, DirEntry::Directory { .. }) {
+            stats.dir_count += 1;
+        }
+        self.update_stats_on_path(path, |_, node_stats| node_stats.sub(&stats));
+        Some(removed)
+    }
+
+    /// Attaches a node (and its children) at `path`, creating missing parent directories.
+    ///
+    /// There must not be any node at `path` already.
+    fn insert_subtree(&mut self, path: &str, mut subtree: DirNode) {
+        assert!(path.starts_with('/'), "path must start at the root");
+        let parts = path.split('/').collect::<Vec<_>>();
+        let mut node = &mut *self;
+        let mut new_dir_depths = Vec::new();
+        for (comp_idx, comp) in parts[1..parts.len() - 1].iter().enumerate() {
+            let DirEntry::Directory { ref mut children } = node.entry else {
+                unreachable!("parent of a subtree must be a directory");
+            };
+            node = children.entry(comp.to_string()).or_insert_with(|| {
+                new_dir_depths.push(comp_idx + 1);
+                DirNode {
+                    name: comp.to_string(),
+                    entry: DirEntry::Directory {
+                        children: BTreeMap::new(),
+                    },
+                    stats: DirStats::default(),
+                }
+            });
+        }
+        let DirEntry::Directory { ref mut children } = node.entry else {
+            unreachable!("parent of a subtree must be a directory");
+        };
+        let name = parts[parts.len() - 1].to_string();
+        subtree.name.clone_from(&name);
+        let mut stats = subtree.stats;
+        if matches!(subtree.entry, DirEntry::Directory { .. }) {
+            stats.dir_count += 1;
+        }
+        children.insert(name, subtree);
+
+        let depth = parts.len() - 1;
+        self.update_stats_on_path(path, |node_depth, node_stats| {
+            if node_depth == depth {
+                return;
+            }
+            let new_dirs = new_dir_depths.iter().filter(|&&d| d > node_depth).count();
+            node_stats.add(&DirStats {
+                dir_count: stats.dir_count + new_dirs as u64,
+                ..stats
+            });
+        });
+    }
+
+    /// Updates the stats of the file at `path`, also updating its parents.
+    fn update_file_stats(&mut self, path: &str, old: &DirStats, new: &DirStats) {
+        self.update_stats_on_path(path, |_, node_stats| {
+            node_stats.sub(old);
+            node_stats.add(new);
+        });
+    }
+
+    /// Calls `update_fn` with the stats of every node that is on the given path, including this
+    /// node and the last node (if it exists).
+    ///
+    /// The first argument to `update_fn` is the depth of the node, relative to this node.
+    fn update_stats_on_path(
+        &mut self,
+        path: &str,
+        mut update_fn: impl FnMut(usize, &mut DirStats),
+    ) {
+        let mut node = self;
+        update_fn(0, &mut node.stats);
+        for (depth, comp) in path.split('/').filter(|c| !c.is_empty()).enumerate() {
+            let DirEntry::Directory { ref mut children } = node.entry else {
+                return;
+            };
+            let Some(child) = children.get_mut(comp) else {
+                return;
+            };
+            node = child;
+            update_fn(depth + 1, &mut node.stats);
+        }
+    }
+}
+
+impl DirStats {
+    /// Returns the stats for a single file.
+    pub fn from_file(meta: &FileMeta) -> Self {
+        Self {
+            file_count: 1,
+            dir_count: 0,
+            compressed_size: meta.compressed_size.into(),
+            uncompressed_size: meta.actual_size().into(),
+        }
+    }
+
+    fn add(&mut self, other: &DirStats) {
+        self.file_count += other.file_count;
+        self.dir_count += other.dir_count;
+        self.compressed_size += other.compressed_size;
+        self.uncompressed_size += other.uncompressed_size;
+    }
+
+    fn sub(&mut self, other: &DirStats) {
+        self.file_count = self.file_count.saturating_sub(other.file_count);
+        self.dir_count = self.dir_count.saturating_sub(other.dir_count);
+        self.compressed_size = self.compressed_size.saturating_sub(other.compressed_size);
+        self.uncompressed_size = self
+            .uncompressed_size
+            .saturating_sub(other.uncompressed_size);
+    }
+}
+
+impl ReadOrder {
+    pub const ALL: [ReadOrder; 3] = [ReadOrder::Offset, ReadOrder::Path, ReadOrder::Size];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            ReadOrder::Offset => "offset",
+            ReadOrder::Path => "path",
+            ReadOrder::Size => "size",
+        }
+    }
+
+    /// Sorts files in this order. Files with the same offset or size keep their relative
+    /// order.
+    pub fn sort_files(self, fs: &ArhFileSystem, files: &mut [ArhPath]) {
+        match self {
+            ReadOrder::Offset => {
+                files.sort_by_cached_key(|p| fs.get_file_info(p).map(|m| m.offset))
+            }
+            ReadOrder::Path => files.sort(),
+            ReadOrder::Size => {
+                files.sort_by_cached_key(|p| Reverse(fs.get_file_info(p).map(|m| m.actual_size())))
+            }
+        }
+    }
+
+    /// Sorts extents from [`ArhFileSystem::iter_extents`] in this order. Extents are
+    /// ordered by their first file, and unreferenced regions come last in path order.
+    pub fn sort_extents(self, extents: &mut [Extent]) {
+        match self {
+            ReadOrder::Offset => extents.sort_by_key(|e| (e.range.start, e.range.end)),
+            ReadOrder::Path => extents.sort_by(|a, b| {
+                (a.files.is_empty(), a.files.first()).cmp(&(b.files.is_empty(), b.files.first()))
+            }),
+            ReadOrder::Size => extents.sort_by_key(|e| Reverse(e.range.end - e.range.start)),
+        }
     }
 }
@@ -0,0 +1,93 @@
+//! Differences between the games that use ARH/ARD archives
+//!
+//! For now, only the compression of new entries depends on the game. Other differences
+//! are not handled yet:
+//!
+//! * Format version: all games use the same "arh1" format, and no differences in the
+//!   tables are known.
+//! * Path conventions: the same rules apply to all games, see
+//!   [`ArhPath::check_strict`](crate::path::ArhPath::check_strict).
+//! * Limits: the format limits (e.g. [`ARH_PATH_MAX_LEN`](crate::path::ARH_PATH_MAX_LEN))
+//!   are the same for all games. Limits of the games themselves aren't known.
+//! * Detection: archives don't record the game they are for, so it must be chosen by the
+//!   user. It defaults to XC3.
+
+use std::{fmt::Display, str::FromStr};
+
+use xc3_lib::xbc1::CompressionType;
+
+use crate::error::Error;
+
+/// A game that uses ARH/ARD archives.
+///
+/// All games share the same archive format ("arh1"), but older games can't read every
+/// compression type. The game is used to pick compression for new entries, see
+/// [`ArhOptions::game`](crate::opts::ArhOptions::game).
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Game {
+    /// Xenoblade Chronicles 2
+    Xc2,
+    /// Xenoblade Chronicles: Definitive Edition
+    Xcde,
+    /// Xenoblade Chronicles 3
+    #[default]
+    Xc3,
+    /// Xenoblade Chronicles X: Definitive Edition
+    XcxDe,
+}
+
+impl Game {
+    pub const ALL: [Game; 4] = [Game::Xc2, Game::Xcde, Game::Xc3, Game::XcxDe];
+
+    /// Returns the short name of the game, as accepted by [`Game::from_str`].
+    pub fn name(self) -> &'static str {
+        match self {
+            Game::Xc2 => "xc2",
+            Game::Xcde => "xcde",
+            Game::Xc3 => "xc3",
+            Game::XcxDe => "xcx-de",
+        }
+    }
+
+    /// Returns the compression algorithms the game can decompress.
+    ///
+    /// Entries can also always be stored uncompressed.
+    pub fn compression_types(self) -> &'static [CompressionType] {
+        match self {
+            Game::Xc2 | Game::Xcde => &[CompressionType::Zlib],
+            Game::Xc3 | Game::XcxDe => &[CompressionType::Zlib, CompressionType::Zstd],
+        }
+    }
+
+    /// Returns the compression algorithms that
+    /// [`CompressionStrategy::Best`](crate::file_alloc::CompressionStrategy::Best) tries
+    /// for new entries: the ones used by the game's own archives.
+    pub fn best_compression_types(self) -> &'static [CompressionType] {
+        match self {
+            Game::Xc2 | Game::Xcde | Game::Xc3 => &[CompressionType::Zlib],
+            Game::XcxDe => &[CompressionType::Zlib, CompressionType::Zstd],
+        }
+    }
+
+    pub fn supports_compression(self, compression: CompressionType) -> bool {
+        compression == CompressionType::Uncompressed
+            || self.compression_types().contains(&compression)
+    }
+}
+
+impl Display for Game {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Game {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|game| game.name() == s)
+            .ok_or_else(|| Error::UnknownGame(s.to_string()))
+    }
+}
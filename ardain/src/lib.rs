@@ -1,12 +1,24 @@
 mod ard;
 mod arh;
 mod arh_ext;
+pub mod consistency;
 pub mod error;
 pub mod file_alloc;
 mod fs;
-mod opts;
+pub mod game;
+pub mod mem;
+pub mod opts;
 pub mod path;
+#[cfg(feature = "raw")]
+pub mod raw;
+pub mod snapshot;
+pub mod visit;
 
-pub use ard::{ArdReader, ArdWriter};
-pub use arh::{FileFlag, FileMeta};
+pub use ard::{
+    find_nested_streams, ArdReader, ArdWriter, CacheStats, EntryMismatch, NestedStream,
+    PositionalReader, ReadBack, SetLen, Xbc1Info,
+};
+pub use arh::{FileFlag, FileMeta, RawDictNode, RawTables};
+pub use arh_ext::{OpKind, OpLogEntry, OrphanedFile, OP_LOG_MAX_ENTRIES};
 pub use fs::*;
+pub use xc3_lib::xbc1::CompressionType;
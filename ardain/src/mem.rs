@@ -0,0 +1,107 @@
+//! Archives held entirely in memory, e.g. to test tools that modify archives without the
+//! game files.
+
+use std::io::Cursor;
+
+use crate::{
+    error::{Error, Result},
+    file_alloc::{ArdFileAllocator, CompressionStrategy},
+    opts::ArhOptions,
+    path::ArhPath,
+    ArdReader, ArdWriter, ArhFileSystem,
+};
+
+/// A file system together with the contents of its .ard file.
+///
+/// The bytes of both files can be taken at any point with [`Self::arh_bytes`] and
+/// [`Self::ard_bytes`], and loaded again with [`Self::load`].
+pub struct MemArchive {
+    fs: ArhFileSystem,
+    ard: ArdWriter<Cursor<Vec<u8>>>,
+}
+
+impl MemArchive {
+    /// Returns an archive with no files, see [`ArhFileSystem::new`].
+    pub fn new(options: ArhOptions) -> Self {
+        Self {
+            fs: ArhFileSystem::new(options),
+            ard: ArdWriter::new(Cursor::new(Vec::new())),
+        }
+    }
+
+    /// Loads an archive from the contents of its .arh and .ard files.
+    pub fn load(arh: &[u8], ard: Vec<u8>) -> Result<Self> {
+        Self::load_with_options(arh, ard, ArhOptions::default())
+    }
+
+    pub fn load_with_options(arh: &[u8], ard: Vec<u8>, options: ArhOptions) -> Result<Self> {
+        Ok(Self {
+            fs: ArhFileSystem::load_with_options(Cursor::new(arh), options)?,
+            ard: ArdWriter::new(Cursor::new(ard)),
+        })
+    }
+
+    pub fn fs(&self) -> &ArhFileSystem {
+        &self.fs
+    }
+
+    pub fn fs_mut(&mut self) -> &mut ArhFileSystem {
+        &mut self.fs
+    }
+
+    /// Returns an allocator that writes to the in-memory .ard file.
+    pub fn allocator(&mut self) -> ArdFileAllocator<'_, '_, Cursor<Vec<u8>>> {
+        ArdFileAllocator::new(&mut self.fs, &mut self.ard)
+    }
+
+    /// Returns a reader over the in-memory .ard file, following the archive's options.
+    pub fn reader(&self) -> ArdReader<Cursor<&[u8]>> {
+        ArdReader::with_options(Cursor::new(self.ard_bytes()), &self.fs.opts)
+    }
+
+    /// Creates the file if it doesn't exist, then writes its data. Returns the file's ID.
+    pub fn write_file(
+        &mut self,
+        path: &ArhPath,
+        data: &[u8],
+        strategy: CompressionStrategy,
+    ) -> Result<u32> {
+        match self.fs.get_file_info(path) {
+            Some(meta) => {
+                let id = meta.id;
+                self.allocator().replace_file(id, data, strategy)?;
+                Ok(id)
+            }
+            None => {
+                let id = self.fs.create_file(path)?.id;
+                self.allocator().write_new_file(id, data, strategy)?;
+                Ok(id)
+            }
+        }
+    }
+
+    /// Reads the (decompressed) data of a file.
+    pub fn read_file(&self, path: &ArhPath) -> Result<Vec<u8>> {
+        let meta = self.fs.get_file_info(path).ok_or(Error::FsNoEntry)?;
+        self.reader().entry(meta).read()
+    }
+
+    /// Returns the contents of the .arh file, as written by [`ArhFileSystem::sync`].
+    ///
+    /// Fails if the file system is read-only.
+    pub fn arh_bytes(&mut self) -> Result<Vec<u8>> {
+        let mut arh = Cursor::new(Vec::new());
+        self.fs.sync(&mut arh)?;
+        Ok(arh.into_inner())
+    }
+
+    pub fn ard_bytes(&self) -> &[u8] {
+        self.ard.get_ref().get_ref()
+    }
+
+    /// Returns the contents of the .arh and .ard files, see [`Self::arh_bytes`].
+    pub fn into_bytes(mut self) -> Result<(Vec<u8>, Vec<u8>)> {
+        let arh = self.arh_bytes()?;
+        Ok((arh, self.ard.into_inner().into_inner()))
+    }
+}
@@ -1,4 +1,6 @@
-use crate::arh_ext;
+use std::{fmt::Display, str::FromStr};
+
+use crate::{arh_ext, error::Error, game::Game, path::PathCase};
 
 #[derive(Clone)]
 pub struct ArhOptions {
@@ -12,8 +14,41 @@ pub struct ArhOptions {
     /// If `true`, when loading a file with an existing block table, the table will be
     /// regenerated if its block size is different than `ext_block_size_pow`.
     ///
+    /// To convert an existing table instead, see
+    /// [`ArhFileSystem::set_block_size`](crate::ArhFileSystem::set_block_size).
+    ///
     /// Defaults to `false`
     pub ext_force_block_size: bool,
+    /// The game the archive is for. This restricts the compression types used for new
+    /// entries to the ones the game supports.
+    ///
+    /// Defaults to [`Game::Xc3`]
+    pub game: Game,
+    /// If `true`, all methods that modify the file system fail with
+    /// [`Error::ReadOnly`](crate::error::Error::ReadOnly).
+    ///
+    /// Defaults to `false`
+    pub read_only: bool,
+    /// If `true`, readers created with
+    /// [`ArdReader::with_options`](crate::ArdReader::with_options) check the decompressed
+    /// data of compressed entries against the hash in their XBC1 header, and fail with
+    /// [`Error::ChecksumMismatch`](crate::error::Error::ChecksumMismatch) if it is different.
+    ///
+    /// Defaults to `false`
+    pub verify_on_read: bool,
+    /// If `true`, creating or renaming files fails if the new path is not accepted by
+    /// [`ArhPath::check_strict`](crate::path::ArhPath::check_strict), i.e. the game might
+    /// not be able to look it up.
+    ///
+    /// Defaults to `false`
+    pub strict_paths: bool,
+    /// How the case of file names is handled. With [`PathCase::Preserve`], names of new
+    /// files keep the spelling of their [`ArhPath`](crate::path::ArhPath), which is written to
+    /// the archive, and the spelling of existing names is kept. Lookups are always
+    /// case-insensitive.
+    ///
+    /// Defaults to [`PathCase::Lowercase`]
+    pub path_case: PathCase,
 }
 
 impl Default for ArhOptions {
@@ -21,6 +56,93 @@ impl Default for ArhOptions {
         Self {
             ext_block_size_pow: arh_ext::BLOCK_SIZE_POW_DEFAULT,
             ext_force_block_size: false,
+            game: Game::default(),
+            read_only: false,
+            verify_on_read: false,
+            strict_paths: false,
+            path_case: PathCase::default(),
+        }
+    }
+}
+
+/// When changes are made durable, for tools that keep an archive open across many
+/// operations, like a FUSE mount or a batch script.
+///
+/// At each sync point, the ARD file is flushed and synced to disk first, then the ARH file
+/// is rewritten, so the ARH file never points to data that isn't on disk yet. Changes made
+/// since the last sync point are lost if the process is killed.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum SyncPolicy {
+    /// Sync after every operation that modifies the archive. This is the safest, but the
+    /// whole ARH file is rewritten each time, which is slow for large imports.
+    Always,
+    /// Sync when a file that was written to is closed
+    #[default]
+    OnClose,
+    /// Only sync when asked to (e.g. `fsync`), and when the archive is closed
+    Manual,
+}
+
+impl SyncPolicy {
+    pub const ALL: [SyncPolicy; 3] = [SyncPolicy::Always, SyncPolicy::OnClose, SyncPolicy::Manual];
+
+    /// Returns the name of the policy, as accepted by [`SyncPolicy::from_str`].
+    pub fn name(self) -> &'static str {
+        match self {
+            SyncPolicy::Always => "always",
+            SyncPolicy::OnClose => "on-close",
+            SyncPolicy::Manual => "manual",
+        }
+    }
+
+    /// Returns a clap value parser that accepts the policy [names](SyncPolicy::name), and
+    /// lists them in the help text.
+    #[cfg(feature = "clap")]
+    pub fn value_parser() -> impl clap::builder::TypedValueParser<Value = Self> {
+        use clap::builder::{PossibleValuesParser, TypedValueParser};
+
+        PossibleValuesParser::new(Self::ALL.map(Self::name)).map(|s| s.parse::<Self>().unwrap())
+    }
+
+    /// Returns whether to sync after an operation that modified the archive. `closed` is
+    /// whether the operation finished writing file data, e.g. by closing a file.
+    pub fn syncs_after(self, closed: bool) -> bool {
+        match self {
+            SyncPolicy::Always => true,
+            SyncPolicy::OnClose => closed,
+            SyncPolicy::Manual => false,
         }
     }
 }
+
+impl Display for SyncPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for SyncPolicy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|policy| policy.name() == s)
+            .ok_or_else(|| Error::UnknownSyncPolicy(s.to_string()))
+    }
+}
+
+/// Parses a size in bytes, with an optional K, M or G suffix (powers of 1024), e.g. for
+/// command-line options.
+pub fn parse_size(s: &str) -> Result<u64, Error> {
+    let (num, shift) = match s.as_bytes().last() {
+        Some(b'k' | b'K') => (&s[..s.len() - 1], 10),
+        Some(b'm' | b'M') => (&s[..s.len() - 1], 20),
+        Some(b'g' | b'G') => (&s[..s.len() - 1], 30),
+        _ => (s, 0),
+    };
+    num.parse::<u64>()
+        .ok()
+        .and_then(|num| num.checked_mul(1 << shift))
+        .ok_or_else(|| Error::InvalidSize(s.to_string()))
+}
@@ -6,11 +6,36 @@ use thiserror::Error;
 ///
 /// Includes the leading slash.
 pub const ARH_PATH_MAX_LEN: usize = 256;
-pub const ARH_PATH_ROOT: ArhPath = ArhPath(Cow::Borrowed("/"));
+/// The maximum length of a path accepted by [`ArhPath::check_strict`], leaving room for the
+/// game's null terminator.
+pub const ARH_PATH_STRICT_MAX_LEN: usize = ARH_PATH_MAX_LEN - 1;
+pub const ARH_PATH_ROOT: ArhPath = ArhPath {
+    path: Cow::Borrowed("/"),
+    original: None,
+};
 
 /// A valid (absolute) path in an ARH file system.
-#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
-pub struct ArhPath(Cow<'static, str>);
+///
+/// Paths are always compared and looked up in lowercase. Paths created with
+/// [`PathCase::Preserve`] also remember how they were spelled, see [`ArhPath::original`].
+#[derive(Clone, Debug)]
+pub struct ArhPath {
+    path: Cow<'static, str>,
+    /// The spelling of the path, if it had uppercase characters
+    original: Option<Box<str>>,
+}
+
+/// How the case of file names is handled.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PathCase {
+    /// Uppercase characters are changed to lowercase, like the game does.
+    #[default]
+    Lowercase,
+    /// Names keep their spelling, but are still compared case-insensitively. With
+    /// [`ArhOptions::path_case`](crate::opts::ArhOptions::path_case), the spelling is also
+    /// written to the archive, where the game can't look it up.
+    Preserve,
+}
 
 #[derive(Debug, Error)]
 #[error("invalid path {path}: {desc}")]
@@ -26,10 +51,32 @@ pub enum PathErrorDesc {
     NoLeadingSlash,
     #[error("consecutive slashes are not allowedin ARH paths")]
     ConsecutiveSlashes,
-    #[error("ARH paths can be up to {ARH_PATH_MAX_LEN} characters in length")]
-    TooLong,
-    #[error("illegal character for an ARH path: {0}")]
+    #[error("the path is {len} characters long, ARH paths can be up to {ARH_PATH_MAX_LEN}")]
+    TooLong { len: usize },
+    #[error("illegal character for an ARH path: {0:?}")]
     IllegalCharacter(char),
+    #[error("empty, \".\" and \"..\" names are not allowed for new files and directories")]
+    InvalidComponent,
+    #[error("character not allowed with strict paths: {0:?}")]
+    StrictCharacter(char),
+    #[error("empty, \".\" and \"..\" path components are not allowed with strict paths")]
+    StrictComponent,
+    #[error("paths can be up to {ARH_PATH_STRICT_MAX_LEN} characters in length with strict paths")]
+    StrictTooLong,
+}
+
+impl InvalidPathError {
+    pub fn desc(&self) -> &PathErrorDesc {
+        &self.desc
+    }
+
+    /// Whether the path was rejected only because of its length.
+    pub fn is_too_long(&self) -> bool {
+        matches!(
+            self.desc,
+            PathErrorDesc::TooLong { .. } | PathErrorDesc::StrictTooLong
+        )
+    }
 }
 
 impl ArhPath {
@@ -43,16 +90,24 @@ impl ArhPath {
     /// * Uppercase characters are changed to lowercase
     ///
     /// An error is returned if:
-    /// * The string contains illegal (non-ASCII) characters
+    /// * The string contains illegal (non-ASCII or null) characters
     /// * The string is longer than the maximum size ([`ARH_PATH_MAX_LEN`])
     pub fn normalize(value: impl AsRef<str>) -> Result<Self, InvalidPathError> {
+        Self::normalize_with(value, PathCase::Lowercase)
+    }
+
+    /// Like [`ArhPath::normalize`], but with [`PathCase::Preserve`], the path remembers
+    /// its original spelling.
+    pub fn normalize_with(
+        value: impl AsRef<str>,
+        case: PathCase,
+    ) -> Result<Self, InvalidPathError> {
         let mut new = String::with_capacity(value.as_ref().len() + 1);
         if !value.as_ref().chars().next().is_some_and(|c| c == '/') {
             new.push('/');
         }
         let mut last = '\0';
         for mut ch in value.as_ref().chars() {
-            ch = ch.to_ascii_lowercase();
             if ch == '\\' {
                 ch = '/';
             }
@@ -63,15 +118,23 @@ impl ArhPath {
             new.push(ch);
             last = ch;
         }
-        Self::from_str(&new)
+        let mut path = Self::from_str(&new)?;
+        if case == PathCase::Preserve && new != path.as_str() {
+            path.original = Some(new.into());
+        }
+        Ok(path)
     }
 
     pub fn join(&self, child: &str) -> Self {
         self.try_join(child).unwrap()
     }
 
+    /// Joins a child path, keeping the original spelling of both.
+    ///
+    /// Fails with [`PathErrorDesc::TooLong`] if the joined path doesn't fit in
+    /// [`ARH_PATH_MAX_LEN`], e.g. when moving a deeply nested directory.
     pub fn try_join(&self, child: &str) -> Result<Self, InvalidPathError> {
-        let mut new_str = self.0.to_string();
+        let mut new_str = self.original().to_string();
         if new_str.as_bytes().last() != Some(&b'/') {
             new_str.push('/');
         }
@@ -80,11 +143,85 @@ impl ArhPath {
         } else {
             new_str.push_str(child);
         }
-        Self::normalize(&new_str)
+        // Normalizing only makes the path shorter if `child` has consecutive slashes
+        let shrinks = child.contains("//") || child.contains('\\');
+        if new_str.len() > ARH_PATH_MAX_LEN && !shrinks {
+            return Err(InvalidPathError {
+                desc: PathErrorDesc::TooLong { len: new_str.len() },
+                path: new_str,
+            });
+        }
+        Self::normalize_with(&new_str, PathCase::Preserve)
+    }
+
+    /// The number of characters that can still be added to this path (including
+    /// separators) before it reaches [`ARH_PATH_MAX_LEN`].
+    pub fn remaining_len(&self) -> usize {
+        ARH_PATH_MAX_LEN.saturating_sub(self.len())
     }
 
     pub fn as_str(&self) -> &str {
-        self.0.as_ref()
+        self.path.as_ref()
+    }
+
+    /// Returns the path as it was spelled when it was created with
+    /// [`PathCase::Preserve`]. Otherwise, this is the same as [`ArhPath::as_str`].
+    pub fn original(&self) -> &str {
+        self.original.as_deref().unwrap_or(self.as_str())
+    }
+
+    /// Checks that the path can be added to the archive as a new file or directory.
+    ///
+    /// [`ArhPath::normalize`] accepts paths with empty (e.g. trailing slash), `.` and `..`
+    /// components, so existing files with such names can still be looked up. New files
+    /// can't have them: they would show up as a nameless file, or clash with the entries
+    /// file managers add to every directory.
+    pub fn check_components(&self) -> Result<(), InvalidPathError> {
+        if self
+            .split('/')
+            .skip(1)
+            .any(|c| matches!(c, "" | "." | ".."))
+        {
+            return Err(InvalidPathError {
+                path: self.to_string(),
+                desc: PathErrorDesc::InvalidComponent,
+            });
+        }
+        Ok(())
+    }
+
+    /// Checks whether the game can look up this path, for new file names.
+    ///
+    /// Paths that [`ArhPath::normalize`] accepts may still not resolve in the game (cf.
+    /// ml::DevFileArchiveNx::normalizeFileName). In addition to the regular rules, strict
+    /// paths:
+    ///
+    /// * only contain lowercase letters, digits, `_`, `-` and `.`, separated by slashes
+    /// * have no empty (e.g. trailing slash), `.` or `..` components
+    /// * are at most [`ARH_PATH_STRICT_MAX_LEN`] characters long
+    pub fn check_strict(&self) -> Result<(), InvalidPathError> {
+        let error = |desc| {
+            Err(InvalidPathError {
+                path: self.to_string(),
+                desc,
+            })
+        };
+        if self.len() > ARH_PATH_STRICT_MAX_LEN {
+            return error(PathErrorDesc::StrictTooLong);
+        }
+        if let Some(chr) = self.chars().find(|&c| {
+            !(c.is_ascii_lowercase() || c.is_ascii_digit() || matches!(c, '_' | '-' | '.' | '/'))
+        }) {
+            return error(PathErrorDesc::StrictCharacter(chr));
+        }
+        if self
+            .split('/')
+            .skip(1)
+            .any(|c| matches!(c, "" | "." | ".."))
+        {
+            return error(PathErrorDesc::StrictComponent);
+        }
+        Ok(())
     }
 
     /// Checks whether a character is legal for an ARH path.
@@ -93,9 +230,11 @@ impl ArhPath {
     /// for them. Uppercase characters either return another error type, or they are transparently
     /// converted to lowercase.
     ///  
+    /// Null characters are rejected because names are stored as C strings.
+    ///
     /// cf. ml::DevFileArchiveNx::normalizeFileName
     fn is_character_legal(chr: char) -> bool {
-        chr.is_ascii() && chr != '\\'
+        chr.is_ascii() && !matches!(chr, '\\' | '\0')
     }
 }
 
@@ -112,8 +251,8 @@ impl FromStr for ArhPath {
         let mut path = s.to_string();
         if path.len() > ARH_PATH_MAX_LEN {
             return Err(InvalidPathError {
+                desc: PathErrorDesc::TooLong { len: path.len() },
                 path,
-                desc: PathErrorDesc::TooLong,
             });
         }
         if !path.chars().next().is_some_and(|c| c == '/') {
@@ -140,13 +279,36 @@ impl FromStr for ArhPath {
         }
         // Still normalize uppercase characters
         path.make_ascii_lowercase();
-        Ok(ArhPath(path.into()))
+        Ok(ArhPath {
+            path: path.into(),
+            original: None,
+        })
+    }
+}
+
+impl PartialEq for ArhPath {
+    fn eq(&self, other: &Self) -> bool {
+        self.path == other.path
+    }
+}
+
+impl Eq for ArhPath {}
+
+impl PartialOrd for ArhPath {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for ArhPath {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.path.cmp(&other.path)
     }
 }
 
 impl Display for ArhPath {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        self.0.fmt(f)
+        self.path.fmt(f)
     }
 }
 
@@ -157,3 +319,158 @@ impl Deref for ArhPath {
         self.as_str()
     }
 }
+
+/// A path that may be relative to a working directory, e.g. `../textures/foo.dds`.
+///
+/// Paths that start with a slash are absolute. `.` and `..` components are resolved by
+/// [`RelativeArhPath::resolve`], with `..` staying at the root like in a shell.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RelativeArhPath(String);
+
+impl RelativeArhPath {
+    pub fn new(path: impl Into<String>) -> Self {
+        Self(path.into().replace('\\', "/"))
+    }
+
+    pub fn is_absolute(&self) -> bool {
+        self.0.starts_with('/')
+    }
+
+    /// Returns the absolute path, resolving relative paths against `cwd`.
+    ///
+    /// The spelling of both paths is kept, see [`PathCase::Preserve`].
+    pub fn resolve(&self, cwd: &ArhPath) -> Result<ArhPath, InvalidPathError> {
+        let mut components: Vec<&str> = Vec::new();
+        let base = if self.is_absolute() {
+            ""
+        } else {
+            cwd.original()
+        };
+        for component in base.split('/').chain(self.0.split('/')) {
+            match component {
+                "" | "." => {}
+                ".." => {
+                    components.pop();
+                }
+                name => components.push(name),
+            }
+        }
+        ArhPath::normalize_with(format!("/{}", components.join("/")), PathCase::Preserve)
+    }
+}
+
+impl FromStr for RelativeArhPath {
+    type Err = std::convert::Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Self::new(s))
+    }
+}
+
+impl Display for RelativeArhPath {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+/// A glob pattern for paths in an ARH file system, see [`PathFilter`].
+///
+/// `*` matches any number of characters in a path component, `?` matches a single
+/// character, and a `**` component matches any number of directories. Patterns are
+/// normalized like paths, so `*.BDAT` and `/*.bdat` are the same pattern, except that a
+/// pattern without slashes matches file and directory names at any depth.
+///
+/// A pattern also matches everything inside the directories it matches.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PathPattern {
+    components: Vec<String>,
+    /// Whether the pattern had no slashes, and only matches a single name
+    name_only: bool,
+}
+
+/// Include and exclude patterns, to select files in a directory tree.
+///
+/// A path matches the filter if it matches any of the include patterns (or there are
+/// none), and none of the exclude patterns.
+#[derive(Clone, Debug, Default)]
+pub struct PathFilter {
+    pub include: Vec<PathPattern>,
+    pub exclude: Vec<PathPattern>,
+}
+
+impl PathPattern {
+    pub fn new(pattern: &str) -> Result<Self, InvalidPathError> {
+        let name_only = !pattern.contains(['/', '\\']);
+        let pattern = ArhPath::normalize(pattern)?;
+        Ok(Self {
+            components: pattern
+                .split('/')
+                .filter(|c| !c.is_empty())
+                .map(String::from)
+                .collect(),
+            name_only,
+        })
+    }
+
+    /// Returns whether the pattern matches `path`, or one of its parent directories.
+    pub fn matches(&self, path: &ArhPath) -> bool {
+        let path: Vec<_> = path.split('/').filter(|c| !c.is_empty()).collect();
+        match self.components.as_slice() {
+            [name] if self.name_only => path
+                .iter()
+                .any(|c| match_name(name.as_bytes(), c.as_bytes())),
+            pattern => (0..=path.len()).any(|len| match_components(pattern, &path[..len])),
+        }
+    }
+}
+
+impl PathFilter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn include(mut self, pattern: PathPattern) -> Self {
+        self.include.push(pattern);
+        self
+    }
+
+    pub fn exclude(mut self, pattern: PathPattern) -> Self {
+        self.exclude.push(pattern);
+        self
+    }
+
+    pub fn matches(&self, path: &ArhPath) -> bool {
+        (self.include.is_empty() || self.include.iter().any(|p| p.matches(path)))
+            && !self.exclude.iter().any(|p| p.matches(path))
+    }
+}
+
+impl FromStr for PathPattern {
+    type Err = InvalidPathError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::new(s)
+    }
+}
+
+fn match_components(pattern: &[String], path: &[&str]) -> bool {
+    match pattern.split_first() {
+        None => path.is_empty(),
+        Some((first, rest)) if first == "**" => {
+            (0..=path.len()).any(|skip| match_components(rest, &path[skip..]))
+        }
+        Some((first, rest)) => path.split_first().is_some_and(|(name, path)| {
+            match_name(first.as_bytes(), name.as_bytes()) && match_components(rest, path)
+        }),
+    }
+}
+
+/// Matches a single path component, with `*` and `?` wildcards.
+fn match_name(pattern: &[u8], name: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => name.is_empty(),
+        Some((b'*', rest)) => (0..=name.len()).any(|skip| match_name(rest, &name[skip..])),
+        Some((b'?', rest)) => !name.is_empty() && match_name(rest, &name[1..]),
+        Some((c, rest)) => name.first() == Some(c) && match_name(rest, &name[1..]),
+    }
+}
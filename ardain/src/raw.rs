@@ -0,0 +1,220 @@
+//! Direct access to the tables of an ARH file, for reverse engineering and external tools.
+//!
+//! Requires the `raw` feature. Views are read-only, changes go through [`RawArhMut`], which
+//! checks that the tables are still valid before applying them.
+
+use std::ffi::CStr;
+
+use crate::{
+    arh::{Arh, DictNode},
+    error::{Error, Result},
+    path::ARH_PATH_MAX_LEN,
+    ArhFileSystem, FileMeta,
+};
+
+pub use crate::arh::{ArhOffsets, RawDictNode};
+
+/// A read-only view of the tables of an ARH file, see [`ArhFileSystem::raw`].
+#[derive(Clone, Copy)]
+pub struct RawArh<'a> {
+    arh: &'a Arh,
+}
+
+/// Checked write access to the tables of an ARH file, see [`ArhFileSystem::raw_mut`].
+///
+/// Each change is applied to a copy of the tables, which replaces the original only if it
+/// passes [`RawArh::check`]. The directory tree is then rebuilt from the new tables.
+pub struct RawArhMut<'a> {
+    fs: &'a mut ArhFileSystem,
+}
+
+impl ArhFileSystem {
+    pub fn raw(&self) -> RawArh<'_> {
+        RawArh { arh: &self.arh }
+    }
+
+    /// Fails if the file system is read-only.
+    pub fn raw_mut(&mut self) -> Result<RawArhMut<'_>> {
+        self.check_writable()?;
+        Ok(RawArhMut { fs: self })
+    }
+}
+
+impl<'a> RawArh<'a> {
+    /// Returns the header offsets as they were when the file was loaded, or last written.
+    pub fn offsets(&self) -> ArhOffsets {
+        self.arh.offsets()
+    }
+
+    pub fn dict_len(&self) -> usize {
+        self.arh.path_dictionary().nodes.len()
+    }
+
+    /// Returns the path dictionary node at `index`. Free nodes are normalized, like when an
+    /// archive is written.
+    pub fn dict_node(&self, index: usize) -> Option<RawDictNode> {
+        self.arh
+            .path_dictionary()
+            .nodes
+            .get(index)
+            .map(|&n| n.into())
+    }
+
+    pub fn dict_nodes(&self) -> impl Iterator<Item = RawDictNode> + 'a {
+        self.arh.path_dictionary().nodes.iter().map(|&n| n.into())
+    }
+
+    /// Returns the decrypted string table.
+    pub fn string_table(&self) -> &'a [u8] {
+        self.arh.strings().as_bytes()
+    }
+
+    /// Returns the string and file ID of the string table entry at `offset`, or `None` if
+    /// there is no complete entry there.
+    ///
+    /// As with leaf nodes, `offset` doesn't need to point to the start of an entry.
+    pub fn string_at(&self, offset: usize) -> Option<(&'a str, u32)> {
+        let rest = self.string_table().get(offset..)?;
+        let text = CStr::from_bytes_until_nul(rest).ok()?.to_str().ok()?;
+        let id = rest.get(text.len() + 1..text.len() + 5)?;
+        Some((text, u32::from_le_bytes(id.try_into().unwrap())))
+    }
+
+    /// Returns the file table, indexed by file ID.
+    pub fn files(&self) -> &'a [FileMeta] {
+        self.arh.file_table.files()
+    }
+
+    /// Returns the full path of the file whose leaf node is at `index`, or `None` if it is
+    /// not a leaf.
+    ///
+    /// May panic if the tables don't pass [`Self::check`].
+    pub fn leaf_path(&self, index: usize) -> Option<String> {
+        let dict = self.arh.path_dictionary();
+        dict.nodes
+            .get(index)
+            .is_some_and(DictNode::is_leaf)
+            .then(|| dict.get_full_path(index, self.arh.strings()))
+    }
+
+    /// Checks the invariants that the rest of the crate relies on:
+    ///
+    /// * The first node is the root
+    /// * The parent of each node has children, and the node is in the parent's block at
+    ///   the position of an ASCII character
+    /// * Each leaf is reachable from the root, and points to a complete string table entry
+    ///   with a valid file ID
+    ///
+    /// Fails with [`Error::InvalidTables`] at the first violation.
+    pub fn check(&self) -> Result<()> {
+        let nodes = &self.arh.path_dictionary().nodes;
+        let fail = |msg: String| Err(Error::InvalidTables(msg));
+        if !matches!(nodes.first(), Some(DictNode::Root { .. })) {
+            return fail("node 0 is not the root".to_string());
+        }
+        for (i, node) in nodes.iter().enumerate() {
+            if let Some(next) = node.get_next() {
+                if !usize::try_from(next).is_ok_and(|n| n < nodes.len()) {
+                    return fail(format!("node {i}: next node {next} is out of range"));
+                }
+            }
+            let Some(prev) = node.get_previous() else {
+                continue;
+            };
+            let Some(parent) = usize::try_from(prev).ok().and_then(|p| nodes.get(p)) else {
+                return fail(format!("node {i}: previous node {prev} is out of range"));
+            };
+            let Some(parent_next) = parent.get_next() else {
+                return fail(format!("node {i}: previous node {prev} has no children"));
+            };
+            if !(1..0x80).contains(&(i as i32 ^ parent_next)) {
+                return fail(format!("node {i}: not in the block of node {prev}"));
+            }
+            if let DictNode::Leaf { string_offset, .. } = *node {
+                let Some((_, id)) = self.string_at(string_offset as usize) else {
+                    return fail(format!("node {i}: no string at offset {string_offset}"));
+                };
+                if self.arh.file_table.get_meta(id).is_none() {
+                    return fail(format!("node {i}: file ID {id} is out of range"));
+                }
+            }
+        }
+        // All links are in range now, look for leaves that can't be reached
+        for (i, node) in nodes.iter().enumerate().filter(|(_, n)| n.is_leaf()) {
+            let mut cur = *node;
+            let mut cur_idx = i;
+            for _ in 0..=ARH_PATH_MAX_LEN {
+                let Some(prev) = cur.get_previous() else {
+                    break;
+                };
+                cur_idx = prev as usize;
+                cur = nodes[cur_idx];
+            }
+            if cur_idx != 0 || cur.get_previous().is_some() {
+                return fail(format!("node {i}: not reachable from the root"));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<'a> RawArhMut<'a> {
+    pub fn as_raw(&self) -> RawArh<'_> {
+        self.fs.raw()
+    }
+
+    /// Replaces path dictionary nodes, given as `(index, node)` pairs. Indices past the end
+    /// of the dictionary extend it with free nodes.
+    ///
+    /// All changes are checked together, so nodes can be moved without going through an
+    /// invalid state.
+    pub fn set_dict_nodes(
+        &mut self,
+        changes: impl IntoIterator<Item = (usize, RawDictNode)>,
+    ) -> Result<()> {
+        self.modify(|arh| {
+            let dict = arh.path_dictionary_mut();
+            for (index, node) in changes {
+                if i32::try_from(index).is_err() {
+                    return Err(Error::LimitExceeded("path dictionary is full"));
+                }
+                if index >= dict.nodes.len() {
+                    dict.nodes.resize(index + 1, DictNode::Free);
+                }
+                dict.nodes[index] = node.into();
+            }
+            Ok(())
+        })
+    }
+
+    /// Appends an entry to the string table and returns its offset, e.g. for a new leaf
+    /// node.
+    pub fn push_string(&mut self, text: &str, file_id: u32) -> Result<i32> {
+        self.modify(|arh| arh.strings_mut().push(text, file_id))
+    }
+
+    /// Replaces the file table entry for `file_id`. The ID stored in the entry is set to
+    /// `file_id`.
+    ///
+    /// The block allocation table is not updated, see
+    /// [`ArhFileSystem::repair_block_table`].
+    pub fn set_file_meta(&mut self, file_id: u32, meta: FileMeta) -> Result<()> {
+        self.modify(|arh| {
+            let entry = arh.file_table.get_meta_mut(file_id).ok_or_else(|| {
+                Error::InvalidTables(format!("file ID {file_id} is out of range"))
+            })?;
+            *entry = meta;
+            entry.id = file_id;
+            Ok(())
+        })
+    }
+
+    fn modify<T>(&mut self, f: impl FnOnce(&mut Arh) -> Result<T>) -> Result<T> {
+        let mut arh = self.fs.arh.clone();
+        let res = f(&mut arh)?;
+        RawArh { arh: &arh }.check()?;
+        self.fs.arh = arh;
+        self.fs.reload_dir_tree();
+        Ok(res)
+    }
+}
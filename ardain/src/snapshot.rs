@@ -0,0 +1,308 @@
+//! Lightweight snapshots of an archive, to undo modifications.
+//!
+//! Instead of copying the whole ARD file, a snapshot keeps a copy of the ARH file, and
+//! records the original contents of every ARD range right before it is overwritten
+//! (see [`SnapshotWriter`]). Data appended past the original end of the ARD file doesn't
+//! need to be recorded, reverting simply truncates the file.
+//!
+//! The snapshot for `<name>.ard` is stored in the `<name>.ard.snapshot` directory, so
+//! every tool that writes to the same ARD file can find it.
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, BufReader, Read, Seek, SeekFrom, Write},
+    ops::Range,
+    path::{Path, PathBuf},
+};
+
+use binrw::{BinRead, BinWrite};
+
+use crate::{error::Result, ReadBack, SetLen};
+
+const ARH_FILE: &str = "snapshot.arh";
+const JOURNAL_FILE: &str = "ranges.bin";
+const JOURNAL_HEADER_SIZE: u64 = 12;
+const RANGE_HEADER_SIZE: u64 = 12;
+
+pub struct Snapshot {
+    dir: PathBuf,
+    header: JournalHeader,
+    journal: File,
+    /// Sorted, non-overlapping ranges whose original contents are already recorded
+    saved: Vec<Range<u64>>,
+}
+
+/// Wraps the writer for an ARD file, recording the original contents of the ranges it
+/// overwrites in the active snapshot (if any).
+pub struct SnapshotWriter<F> {
+    inner: F,
+    snapshot: Option<Snapshot>,
+}
+
+#[derive(BinRead, BinWrite)]
+#[brw(little, magic(b"ards"))]
+struct JournalHeader {
+    /// The size of the ARD file when the snapshot was taken
+    ard_len: u64,
+}
+
+/// Followed by `len` bytes of data
+#[derive(BinRead, BinWrite)]
+#[brw(little)]
+struct RangeHeader {
+    offset: u64,
+    len: u32,
+}
+
+impl Snapshot {
+    /// Returns the directory where the snapshot for the given ARD file is stored.
+    pub fn dir_for(ard_path: impl AsRef<Path>) -> PathBuf {
+        let mut dir = ard_path.as_ref().as_os_str().to_owned();
+        dir.push(".snapshot");
+        PathBuf::from(dir)
+    }
+
+    /// Takes a snapshot of the archive, replacing any existing snapshot.
+    pub fn create(ard_path: impl AsRef<Path>, arh: &[u8]) -> Result<Self> {
+        let dir = Self::dir_for(&ard_path);
+        if dir.exists() {
+            fs::remove_dir_all(&dir)?;
+        }
+        fs::create_dir(&dir)?;
+        fs::write(dir.join(ARH_FILE), arh)?;
+        let header = JournalHeader {
+            ard_len: fs::metadata(ard_path)?.len(),
+        };
+        let mut journal = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(dir.join(JOURNAL_FILE))?;
+        header.write(&mut journal)?;
+        journal.sync_all()?;
+        Ok(Self {
+            dir,
+            header,
+            journal,
+            saved: Vec::new(),
+        })
+    }
+
+    /// Opens the snapshot for the given ARD file, if there is one.
+    pub fn open(ard_path: impl AsRef<Path>) -> Result<Option<Self>> {
+        let dir = Self::dir_for(ard_path);
+        if !dir.is_dir() {
+            return Ok(None);
+        }
+        let mut journal = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(dir.join(JOURNAL_FILE))?;
+        let len = journal.metadata()?.len();
+        let mut reader = BufReader::new(&mut journal);
+        let header = JournalHeader::read(&mut reader)?;
+        let mut saved = Vec::new();
+        let mut pos = reader.stream_position()?;
+        // A range may have been cut off by a crash, its data was never overwritten
+        while pos + RANGE_HEADER_SIZE <= len {
+            let range = RangeHeader::read(&mut reader)?;
+            let end = pos + RANGE_HEADER_SIZE + u64::from(range.len);
+            if end > len {
+                break;
+            }
+            reader.seek_relative(range.len.into())?;
+            insert_range(
+                &mut saved,
+                range.offset..range.offset + u64::from(range.len),
+            );
+            pos = end;
+        }
+        journal.set_len(pos)?;
+        journal.seek(SeekFrom::Start(pos))?;
+        Ok(Some(Self {
+            dir,
+            header,
+            journal,
+            saved,
+        }))
+    }
+
+    /// The size of the ARD file when the snapshot was taken.
+    pub fn ard_len(&self) -> u64 {
+        self.header.ard_len
+    }
+
+    /// Returns the number of bytes of original data that are recorded.
+    pub fn saved_bytes(&self) -> u64 {
+        self.saved.iter().map(|r| r.end - r.start).sum()
+    }
+
+    /// Returns the contents of the ARH file when the snapshot was taken.
+    pub fn arh(&self) -> Result<Vec<u8>> {
+        Ok(fs::read(self.dir.join(ARH_FILE))?)
+    }
+
+    /// Records the current contents of `range` in `ard`, unless they were already recorded.
+    ///
+    /// Only data that was in the ARD file when the snapshot was taken is recorded.
+    pub fn preserve(&mut self, ard: &mut (impl Read + Seek), range: Range<u64>) -> Result<()> {
+        let range = range.start..range.end.min(self.header.ard_len);
+        let missing = missing_ranges(&self.saved, range);
+        if missing.is_empty() {
+            return Ok(());
+        }
+        let old_pos = ard.stream_position()?;
+        for range in missing {
+            let mut start = range.start;
+            while start < range.end {
+                let len = (range.end - start).min(u32::MAX.into());
+                let mut data = vec![0; len.try_into()?];
+                ard.seek(SeekFrom::Start(start))?;
+                ard.read_exact(&mut data)?;
+                RangeHeader {
+                    offset: start,
+                    len: len.try_into()?,
+                }
+                .write(&mut self.journal)?;
+                self.journal.write_all(&data)?;
+                start += len;
+            }
+            insert_range(&mut self.saved, range);
+        }
+        // The original data must be on disk before it gets overwritten
+        self.journal.sync_data()?;
+        ard.seek(SeekFrom::Start(old_pos))?;
+        Ok(())
+    }
+
+    /// Restores the ARD file to its state when the snapshot was taken, and returns the
+    /// contents of the ARH file, which must be written by the caller.
+    ///
+    /// The snapshot stays active afterwards, as if it was just taken.
+    pub fn revert(&mut self, ard: &mut File) -> Result<Vec<u8>> {
+        self.journal.seek(SeekFrom::Start(0))?;
+        let mut reader = BufReader::new(&mut self.journal);
+        JournalHeader::read(&mut reader)?;
+        // Each byte is recorded at most once, so the order doesn't matter
+        loop {
+            let range = match RangeHeader::read(&mut reader) {
+                Ok(range) => range,
+                Err(e) if e.is_eof() => break,
+                Err(e) => return Err(e.into()),
+            };
+            let mut data = vec![0; range.len.try_into()?];
+            reader.read_exact(&mut data)?;
+            ard.seek(SeekFrom::Start(range.offset))?;
+            ard.write_all(&data)?;
+        }
+        ard.set_len(self.header.ard_len)?;
+        ard.sync_all()?;
+
+        self.saved.clear();
+        self.journal.set_len(JOURNAL_HEADER_SIZE)?;
+        self.journal.seek(SeekFrom::Start(JOURNAL_HEADER_SIZE))?;
+        self.arh()
+    }
+
+    /// Deletes the snapshot, keeping all modifications.
+    pub fn remove(self) -> Result<()> {
+        drop(self.journal);
+        Ok(fs::remove_dir_all(self.dir)?)
+    }
+}
+
+impl<F: Read + Write + Seek> SnapshotWriter<F> {
+    pub fn new(inner: F, snapshot: Option<Snapshot>) -> Self {
+        Self { inner, snapshot }
+    }
+
+    pub fn snapshot(&self) -> Option<&Snapshot> {
+        self.snapshot.as_ref()
+    }
+
+    pub fn get_mut(&mut self) -> &mut F {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> F {
+        self.inner
+    }
+}
+
+impl<F: Read + Write + Seek> Write for SnapshotWriter<F> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if let Some(snapshot) = &mut self.snapshot {
+            let pos = self.inner.stream_position()?;
+            snapshot
+                .preserve(&mut self.inner, pos..pos + buf.len() as u64)
+                .map_err(io::Error::other)?;
+        }
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<F: ReadBack> ReadBack for SnapshotWriter<F> {
+    fn read_back(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        self.inner.read_back(offset, buf)
+    }
+}
+
+impl<F: Read + Write + Seek + SetLen> SetLen for SnapshotWriter<F> {
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        if let Some(snapshot) = &mut self.snapshot {
+            // Data that is cut off is lost, just like overwritten data
+            let old_len = self.inner.seek(SeekFrom::End(0))?;
+            snapshot
+                .preserve(&mut self.inner, len..old_len)
+                .map_err(io::Error::other)?;
+        }
+        self.inner.set_len(len)
+    }
+
+    fn preallocate(&mut self, len: u64) -> io::Result<()> {
+        // Only ever grows the file, so there is nothing to preserve
+        self.inner.preallocate(len)
+    }
+}
+
+impl<F: Seek> Seek for SnapshotWriter<F> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.inner.seek(pos)
+    }
+}
+
+/// Returns the parts of `range` that are not covered by `saved`.
+fn missing_ranges(saved: &[Range<u64>], range: Range<u64>) -> Vec<Range<u64>> {
+    let mut missing = Vec::new();
+    let mut start = range.start;
+    for r in saved
+        .iter()
+        .filter(|r| r.end > range.start && r.start < range.end)
+    {
+        if r.start > start {
+            missing.push(start..r.start);
+        }
+        start = start.max(r.end);
+    }
+    if start < range.end {
+        missing.push(start..range.end);
+    }
+    missing
+}
+
+fn insert_range(saved: &mut Vec<Range<u64>>, range: Range<u64>) {
+    if range.is_empty() {
+        return;
+    }
+    let idx = saved.partition_point(|r| r.end < range.start);
+    let mut merged = range;
+    while idx < saved.len() && saved[idx].start <= merged.end {
+        let r = saved.remove(idx);
+        merged = merged.start.min(r.start)..merged.end.max(r.end);
+    }
+    saved.insert(idx, merged);
+}
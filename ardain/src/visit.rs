@@ -0,0 +1,116 @@
+//! Reading many file entries in parallel, e.g. to verify or hash them.
+
+use std::{convert::Infallible, num::NonZeroUsize};
+
+use rayon::{prelude::*, ThreadPoolBuilder};
+
+use crate::{path::ArhPath, ArhFileSystem, FileMeta, ReadOrder};
+
+/// Visits files from several threads, each with its own state (typically an
+/// [`ArdReader`](crate::ArdReader) over its own handle, or a
+/// [`PositionalReader`](crate::PositionalReader)).
+///
+/// Threads take files from a shared queue in the chosen [`ReadOrder`]. With the default
+/// order (by offset), the ARD file is read mostly sequentially even with many threads.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ParallelVisitor {
+    threads: Option<NonZeroUsize>,
+    order: ReadOrder,
+}
+
+impl ParallelVisitor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the number of threads. By default, one thread is used per CPU.
+    pub fn threads(mut self, threads: NonZeroUsize) -> Self {
+        self.threads = Some(threads);
+        self
+    }
+
+    /// Sets the order in which files are visited.
+    pub fn order(mut self, order: ReadOrder) -> Self {
+        self.order = order;
+        self
+    }
+
+    /// Calls `visit` for each file, and returns the results in the same order as `files`.
+    ///
+    /// `init` creates the state that is passed to `visit`. It is called at least once per
+    /// thread, and the state is reused for the files the thread takes from the queue.
+    /// Every file is visited, see [`Self::try_visit`] to stop on the first error.
+    ///
+    /// ## Panics
+    /// Panics if one of the paths is not a file in `fs`, or if the threads can't be
+    /// started.
+    pub fn visit<'a, S, T>(
+        &self,
+        fs: &'a ArhFileSystem,
+        files: &'a [ArhPath],
+        init: impl Fn() -> S + Sync,
+        visit: impl Fn(&mut S, &'a ArhPath, &'a FileMeta) -> T + Sync,
+    ) -> Vec<T>
+    where
+        T: Send,
+    {
+        let Ok(results) = self.try_visit(fs, files, init, |state, path, meta| {
+            Ok::<_, Infallible>(visit(state, path, meta))
+        });
+        results
+    }
+
+    /// Like [`Self::visit`], but stops on the first error: files that weren't taken from
+    /// the queue yet are skipped, and the error is returned. If several threads fail, one
+    /// of their errors is returned.
+    ///
+    /// ## Panics
+    /// Panics if one of the paths is not a file in `fs`, or if the threads can't be
+    /// started.
+    pub fn try_visit<'a, S, T, E>(
+        &self,
+        fs: &'a ArhFileSystem,
+        files: &'a [ArhPath],
+        init: impl Fn() -> S + Sync,
+        visit: impl Fn(&mut S, &'a ArhPath, &'a FileMeta) -> Result<T, E> + Sync,
+    ) -> Result<Vec<T>, E>
+    where
+        T: Send,
+        E: Send,
+    {
+        let mut queue: Vec<_> = files
+            .iter()
+            .enumerate()
+            .map(|(i, path)| {
+                let meta = fs
+                    .get_file_info(path)
+                    .unwrap_or_else(|| panic!("{path}: not a file"));
+                (i, path, meta)
+            })
+            .collect();
+        match self.order {
+            ReadOrder::Offset => queue.sort_by_key(|(_, _, meta)| meta.offset),
+            ReadOrder::Path => queue.sort_by_key(|(_, path, _)| *path),
+            ReadOrder::Size => {
+                queue.sort_by_key(|(_, _, meta)| std::cmp::Reverse(meta.actual_size()))
+            }
+        }
+
+        let pool = ThreadPoolBuilder::new()
+            .num_threads(self.threads.map_or(0, NonZeroUsize::get))
+            .build()
+            .expect("failed to start threads");
+        let mut results = pool.install(|| {
+            // Threads take files from a shared queue, so they are started in the chosen order
+            queue
+                .into_iter()
+                .par_bridge()
+                .map_init(&init, |state, (i, path, meta)| {
+                    visit(state, path, meta).map(|res| (i, res))
+                })
+                .collect::<Result<Vec<_>, E>>()
+        })?;
+        results.sort_unstable_by_key(|(i, _)| *i);
+        Ok(results.into_iter().map(|(_, res)| res).collect())
+    }
+}
@@ -1,8 +1,21 @@
-use std::{collections::VecDeque, fs::File, io::Cursor};
+use std::{
+    collections::VecDeque,
+    fs::File,
+    io::Cursor,
+    num::NonZeroUsize,
+    sync::atomic::{AtomicUsize, Ordering},
+};
 
 use ardain::{
-    path::{ArhPath, ARH_PATH_ROOT},
-    ArhFileSystem, DirEntry,
+    error::Error,
+    opts::ArhOptions,
+    path::{
+        ArhPath, PathCase, PathFilter, PathPattern, RelativeArhPath, ARH_PATH_MAX_LEN,
+        ARH_PATH_ROOT,
+    },
+    visit::ParallelVisitor,
+    ArhFileSystem, DirEntry, DirNode, DirStats, FileFlag, FileMeta, OpKind, RawDictNode, RawTables,
+    ReadOrder, DIR_PLACEHOLDER, OP_LOG_MAX_ENTRIES,
 };
 
 #[test]
@@ -59,6 +72,62 @@ fn create_error_into_extended() {
     check_and_read_back(&mut arh, |arh| check_reachable(&arh));
 }
 
+#[test]
+fn special_names() {
+    let mut arh = load_arh();
+    let long_name = format!("/{}", "n".repeat(ARH_PATH_MAX_LEN - 1));
+    let files = [
+        "/special/.keep",
+        "/special/.directory",
+        "/special/..data",
+        "/special/._btl.bdat",
+        "/special/dir/.keep",
+        "/special/x",
+        "/special/y.",
+        "/special/q/z",
+        "/special/-",
+        "/special/~$tmp",
+        long_name.as_str(),
+    ]
+    .map(|s| ArhPath::normalize(s).unwrap());
+    for f in &files {
+        arh.create_file(f).unwrap();
+        check_and_read_back(&mut arh, |arh| {
+            println!("Checking after adding {f}");
+            check_reachable(&arh);
+        });
+    }
+    // Names that extend each other (e.g. only differ by a trailing dot) can't both be stored
+    for extended in ["/special/x.", "/special/y", "/special/.kee"] {
+        let res = arh.create_file(&ArhPath::normalize(extended).unwrap());
+        assert!(
+            matches!(res, Err(Error::FsFileNameExtended)),
+            "{extended} was accepted"
+        );
+    }
+    for bad in [
+        "/",
+        "/special/",
+        "/special/./x",
+        "/special/../x",
+        "/special/..",
+    ] {
+        let bad = ArhPath::normalize(bad).unwrap();
+        assert!(
+            matches!(arh.create_file(&bad), Err(Error::Path(_))),
+            "{bad} was accepted"
+        );
+    }
+    assert!(ArhPath::normalize("/special/a\0b").is_err());
+    assert!(ArhPath::normalize(format!("{long_name}x")).is_err());
+    check_and_read_back(&mut arh, |arh| {
+        check_reachable(&arh);
+        for f in &files {
+            assert!(arh.is_file(f), "{f} is missing");
+        }
+    });
+}
+
 #[test]
 fn delete_files() {
     let mut arh = load_arh();
@@ -109,6 +178,246 @@ fn delete_files() {
     }
 }
 
+#[test]
+fn file_table_usage() {
+    let mut arh = load_arh();
+    let before = arh.file_table_usage();
+    assert_eq!(
+        before.entries,
+        before.live + before.recycled.len() + before.unreferenced.len()
+    );
+
+    let btl = ArhPath::normalize("/bdat/btl.bdat").unwrap();
+    let id = arh.get_file_info(&btl).unwrap().id;
+    arh.delete_file(&btl).unwrap();
+    let usage = arh.file_table_usage();
+    assert_eq!(usage.entries, before.entries);
+    assert_eq!(usage.live, before.live - 1);
+    assert!(usage.recycled.contains(&id));
+    assert_eq!(arh.recycled_file_ids(), usage.recycled);
+
+    // New files take the highest recycled ID
+    let new = ArhPath::normalize("/bdat/new.bdat").unwrap();
+    let new_id = arh.create_file(&new).unwrap().id;
+    assert_eq!(Some(&new_id), usage.recycled.last());
+    assert_eq!(arh.file_table_usage().live, before.live);
+}
+
+#[test]
+fn compact_file_table() {
+    let mut arh = load_arh();
+    for path in ["/bdat/btl.bdat", "/bdat/fld.bdat"] {
+        arh.delete_file(&ArhPath::normalize(path).unwrap()).unwrap();
+    }
+    let mut files: Vec<_> = arh
+        .get_dir(&ARH_PATH_ROOT)
+        .unwrap()
+        .children_paths()
+        .into_iter()
+        .map(|path| {
+            let path = ArhPath::normalize(path).unwrap();
+            let meta = *arh.get_file_info(&path).unwrap();
+            (path, meta)
+        })
+        .collect();
+    files.sort_by_key(|(_, meta)| meta.id);
+    let usage = arh.file_table_usage();
+
+    let compaction = arh.compact_file_table().unwrap();
+    assert_eq!(compaction.old_len, usage.entries);
+    assert_eq!(compaction.new_len, usage.live);
+    let after = arh.file_table_usage();
+    assert!(after.recycled.is_empty());
+    assert!(after.unreferenced.is_empty());
+    check_and_read_back(&mut arh, |arh| {
+        check_reachable(&arh);
+        // Files keep their data and their order, with consecutive IDs
+        for (expected_id, (path, old)) in files.iter().enumerate() {
+            let new = arh.get_file_info(path).unwrap();
+            assert_eq!(new.id as usize, expected_id, "{path}");
+            assert_eq!(
+                (new.offset, new.compressed_size, new.uncompressed_size),
+                (old.offset, old.compressed_size, old.uncompressed_size)
+            );
+        }
+    });
+}
+
+#[test]
+fn repair_block_table() {
+    let mut arh = load_arh();
+    arh.repair_block_table().unwrap();
+    check_and_read_back(&mut arh, |arh| assert!(arh.check_block_table().is_empty()));
+
+    // Operations on the file system must never mark used data as free. (Deleting a file may
+    // leak blocks that it only partially covers, however.)
+    let btl = ArhPath::normalize("/bdat/btl.bdat").unwrap();
+    let copy = ArhPath::normalize("/bdat/btl_copy.bdat").unwrap();
+    arh.create_file(&copy).unwrap();
+    arh.share_extent(&copy, &btl).unwrap();
+    arh.rename_file(&btl, &ArhPath::normalize("/btl.bdat").unwrap())
+        .unwrap();
+    arh.delete_file(&copy).unwrap();
+    arh.delete_file(&ArhPath::normalize("/bdat/fld.bdat").unwrap())
+        .unwrap();
+    check_and_read_back(&mut arh, |arh| {
+        assert!(arh.check_block_table().unmarked.is_empty())
+    });
+}
+
+#[test]
+fn set_block_size_lossy() {
+    // The block table is only rebuilt with the forced size when it is next used
+    let opts = ArhOptions {
+        ext_block_size_pow: 8,
+        ext_force_block_size: true,
+        ..Default::default()
+    };
+    let mut arh =
+        ArhFileSystem::load_with_options(File::open("tests/res/bf3.arh").unwrap(), opts).unwrap();
+    let sync = |arh: &mut ArhFileSystem| {
+        let mut out = Cursor::new(Vec::new());
+        arh.sync(&mut out).unwrap();
+        out.into_inner()
+    };
+    let before = sync(&mut arh);
+    let block_size = arh.block_size();
+
+    // A refused change leaves the archive as it was
+    assert!(matches!(
+        arh.set_block_size(20, false),
+        Err(Error::ExtBlockSizeLossy(_))
+    ));
+    assert_eq!(arh.block_size(), block_size);
+    assert_eq!(sync(&mut arh), before);
+
+    let change = arh.set_block_size(20, true).unwrap();
+    assert_ne!(change.lost_bytes, 0);
+    assert_eq!(arh.block_size(), 1 << 20);
+}
+
+#[test]
+fn set_file_extent() {
+    let mut arh = load_arh();
+    let btl = *arh
+        .get_file_info(&ArhPath::normalize("/bdat/btl.bdat").unwrap())
+        .unwrap();
+    let extent = FileMeta::with_extent(
+        btl.offset,
+        btl.compressed_size,
+        btl.uncompressed_size,
+        [FileFlag::HasXbc1Header]
+            .into_iter()
+            .filter(|&f| btl.is_flag(f)),
+    )
+    .unwrap();
+    let path = ArhPath::normalize("/imported.bdat").unwrap();
+    arh.create_file(&path).unwrap();
+    arh.set_file_extent(&path, &extent).unwrap();
+    check_and_read_back(&mut arh, |arh| {
+        let meta = arh.get_file_info(&path).unwrap();
+        assert_eq!(meta.offset, btl.offset);
+        assert_eq!(meta.actual_size(), btl.actual_size());
+        assert!(arh.check_block_table().unmarked.is_empty());
+    });
+
+    assert!(FileMeta::with_extent(u64::MAX, 1, 0, []).is_err());
+    assert!(FileMeta::with_extent(0, 0, 1, []).is_err());
+}
+
+#[test]
+fn iter_extents() {
+    let mut arh = load_arh();
+    let btl = ArhPath::normalize("/bdat/btl.bdat").unwrap();
+    let copy = ArhPath::normalize("/bdat/btl_copy.bdat").unwrap();
+    arh.create_file(&copy).unwrap();
+    arh.share_extent(&copy, &btl).unwrap();
+    arh.delete_file(&ArhPath::normalize("/bdat/fld.bdat").unwrap())
+        .unwrap();
+
+    let ard_len = arh.iter_extents(0).map(|e| e.range.end).max().unwrap() + 100;
+    let extents: Vec<_> = arh.iter_extents(ard_len).collect();
+    // Free and used extents cover the whole file
+    assert_eq!(extents[0].range.start, 0);
+    assert_eq!(extents.last().unwrap().range.end, ard_len);
+    assert!(extents.last().unwrap().files.is_empty());
+    for pair in extents.windows(2) {
+        assert!(pair[0].range.end <= pair[1].range.start);
+    }
+    let shared = extents.iter().find(|e| e.files.contains(&btl)).unwrap();
+    assert_eq!(shared.files, [btl, copy]);
+}
+
+#[test]
+fn read_order() {
+    let arh = load_arh();
+    let mut files: Vec<_> = arh
+        .get_dir(&ARH_PATH_ROOT)
+        .unwrap()
+        .children_paths()
+        .into_iter()
+        .map(|p| ArhPath::normalize(p).unwrap())
+        .collect();
+    let meta = |p: &ArhPath| *arh.get_file_info(p).unwrap();
+
+    ReadOrder::Offset.sort_files(&arh, &mut files);
+    assert!(files
+        .windows(2)
+        .all(|w| meta(&w[0]).offset <= meta(&w[1]).offset));
+    ReadOrder::Size.sort_files(&arh, &mut files);
+    assert!(files
+        .windows(2)
+        .all(|w| meta(&w[0]).actual_size() >= meta(&w[1]).actual_size()));
+    ReadOrder::Path.sort_files(&arh, &mut files);
+    assert!(files.windows(2).all(|w| w[0] < w[1]));
+
+    let ard_len = arh.iter_extents(0).map(|e| e.range.end).max().unwrap() + 100;
+    let mut extents: Vec<_> = arh.iter_extents(ard_len).collect();
+    ReadOrder::Size.sort_extents(&mut extents);
+    assert!(extents
+        .windows(2)
+        .all(|w| w[0].range.end - w[0].range.start >= w[1].range.end - w[1].range.start));
+    ReadOrder::Path.sort_extents(&mut extents);
+    assert!(extents.last().unwrap().files.is_empty());
+    assert_eq!(extents[0].files.first(), files.first());
+    ReadOrder::Offset.sort_extents(&mut extents);
+    assert!(extents
+        .windows(2)
+        .all(|w| w[0].range.start <= w[1].range.start));
+}
+
+#[test]
+fn rename_overwrite_exchange() {
+    let mut arh = load_arh();
+    let btl = ArhPath::normalize("/bdat/btl.bdat").unwrap();
+    let fld = ArhPath::normalize("/bdat/fld.bdat").unwrap();
+    let btl_meta = *arh.get_file_info(&btl).unwrap();
+    let fld_meta = *arh.get_file_info(&fld).unwrap();
+
+    arh.exchange_files(&btl, &fld).unwrap();
+    check_and_read_back(&mut arh, |arh| {
+        let (new_btl, new_fld) = (
+            arh.get_file_info(&btl).unwrap(),
+            arh.get_file_info(&fld).unwrap(),
+        );
+        assert_eq!((new_btl.id, new_btl.offset), (btl_meta.id, fld_meta.offset));
+        assert_eq!((new_fld.id, new_fld.offset), (fld_meta.id, btl_meta.offset));
+        check_reachable(arh);
+    });
+
+    assert!(matches!(
+        arh.rename_file(&btl, &fld),
+        Err(Error::FsAlreadyExists)
+    ));
+    arh.rename_file_overwrite(&btl, &fld).unwrap();
+    check_and_read_back(&mut arh, |arh| {
+        assert!(!arh.is_file(&btl));
+        assert_eq!(arh.get_file_info(&fld).unwrap().offset, fld_meta.offset);
+        assert!(arh.check_block_table().unmarked.is_empty());
+        check_reachable(arh);
+    });
+}
+
 #[test]
 fn create_all_delete_recursive() {
     let mut arh = load_arh();
@@ -122,6 +431,221 @@ fn create_all_delete_recursive() {
     arh.create_file(&ArhPath::normalize("a").unwrap()).unwrap();
 }
 
+#[test]
+fn create_delete_dir() {
+    let mut arh = load_arh();
+    let dir = ArhPath::normalize("/empty/sub").unwrap();
+    let placeholder = dir.join(DIR_PLACEHOLDER);
+    arh.create_dir(&dir).unwrap();
+    assert!(matches!(arh.create_dir(&dir), Err(Error::FsAlreadyExists)));
+    check_and_read_back(&mut arh, |arh| {
+        assert!(arh.is_dir(&dir));
+        assert!(arh.is_dir_empty(&dir));
+        assert!(!arh.is_dir_empty(&ArhPath::normalize("/empty").unwrap()));
+        assert!(ArhFileSystem::is_placeholder(&placeholder));
+        assert!(arh
+            .get_file_info(&placeholder)
+            .unwrap()
+            .is_flag(FileFlag::Hidden));
+        check_stats(arh);
+    });
+
+    let parent = ArhPath::normalize("/empty").unwrap();
+    assert!(matches!(
+        arh.delete_empty_dir(&parent),
+        Err(Error::FsNotEmpty)
+    ));
+    arh.delete_empty_dir(&dir).unwrap();
+    arh.delete_empty_dir(&parent).unwrap();
+    check_and_read_back(&mut arh, |arh| {
+        assert!(!arh.exists(&parent));
+        assert!(!arh.is_file(&placeholder));
+        check_reachable(arh);
+        check_stats(arh);
+    });
+}
+
+#[test]
+fn read_only() {
+    let mut arh = ArhFileSystem::load_with_options(
+        File::open("tests/res/bf3.arh").unwrap(),
+        ArhOptions {
+            read_only: true,
+            ..Default::default()
+        },
+    )
+    .unwrap();
+    let btl = ArhPath::normalize("/bdat/btl.bdat").unwrap();
+    let new = ArhPath::normalize("/new_file").unwrap();
+    assert!(arh.is_read_only());
+    assert!(matches!(arh.create_file(&new), Err(Error::ReadOnly)));
+    assert!(matches!(arh.delete_file(&btl), Err(Error::ReadOnly)));
+    assert!(matches!(arh.rename_file(&btl, &new), Err(Error::ReadOnly)));
+    assert!(matches!(arh.get_file_info_mut(&btl), Err(Error::ReadOnly)));
+    assert!(matches!(
+        arh.sync(Cursor::new(Vec::new())),
+        Err(Error::ReadOnly)
+    ));
+    assert!(arh.is_file(&btl));
+    assert!(!arh.exists(&new));
+}
+
+#[test]
+fn strict_paths() {
+    let mut arh = ArhFileSystem::load_with_options(
+        File::open("tests/res/bf3.arh").unwrap(),
+        ArhOptions {
+            strict_paths: true,
+            ..Default::default()
+        },
+    )
+    .unwrap();
+    let path = |s: &str| ArhPath::normalize(s).unwrap();
+    for bad in [
+        "/bdat/a b.bdat",
+        "/bdat/a*.bdat",
+        "/bdat/../x.bdat",
+        "/bdat/x/",
+    ] {
+        assert!(
+            matches!(arh.create_file(&path(bad)), Err(Error::Path(_))),
+            "{bad} was accepted"
+        );
+        assert!(!arh.exists(&path(bad)));
+    }
+    let long = format!("/{}", "a".repeat(ARH_PATH_MAX_LEN - 1));
+    assert!(matches!(arh.create_file(&path(&long)), Err(Error::Path(_))));
+
+    let btl = path("/bdat/btl.bdat");
+    assert!(matches!(
+        arh.rename_file(&btl, &path("/bdat/btl?.bdat")),
+        Err(Error::Path(_))
+    ));
+    assert!(arh.is_file(&btl));
+    assert!(matches!(
+        arh.rename_dir(&path("/bdat"), &path("/bdat (old)")),
+        Err(Error::Path(_))
+    ));
+    assert!(arh.is_file(&btl));
+
+    arh.create_file(&path("/bdat/new_file-2.bdat")).unwrap();
+    arh.rename_file(&btl, &path("/bdat/btl_old.bdat")).unwrap();
+    check_reachable(&arh);
+}
+
+#[test]
+fn preserve_case() {
+    let options = || ArhOptions {
+        path_case: PathCase::Preserve,
+        ..Default::default()
+    };
+    let mut arh =
+        ArhFileSystem::load_with_options(File::open("tests/res/bf3.arh").unwrap(), options())
+            .unwrap();
+    let path = |s: &str| ArhPath::normalize_with(s, PathCase::Preserve).unwrap();
+    arh.create_file(&path("/Textures/Sky.DDS")).unwrap();
+    arh.create_file(&path("/textures/ground.dds")).unwrap();
+    assert!(matches!(
+        arh.create_file(&path("/TEXTURES/SKY.dds")),
+        Err(Error::FsAlreadyExists)
+    ));
+    // Lookups ignore case
+    let sky = ArhPath::normalize("/textures/sky.dds").unwrap();
+    assert!(arh.is_file(&sky));
+    assert!(arh.is_dir(&path("/TEXTURES")));
+    assert_eq!(arh.original_path(&sky), "/Textures/Sky.DDS");
+
+    arh.rename_dir(&path("/textures"), &path("/Tex")).unwrap();
+    arh.rename_file(&path("/bdat/btl.bdat"), &path("/bdat/BTL.bdat"))
+        .unwrap();
+
+    let mut out = Cursor::new(Vec::new());
+    arh.sync(&mut out).unwrap();
+    let data = out.into_inner();
+    let mut arh = ArhFileSystem::load_with_options(Cursor::new(&data), options()).unwrap();
+    let names = [
+        ("/tex/sky.dds", "/Tex/Sky.DDS"),
+        ("/tex/ground.dds", "/Tex/ground.dds"),
+        ("/bdat/btl.bdat", "/bdat/BTL.bdat"),
+        ("/bdat/fld.bdat", "/bdat/fld.bdat"),
+    ];
+    for (lower, original) in names {
+        let lower = ArhPath::normalize(lower).unwrap();
+        assert!(arh.is_file(&lower), "{lower} not found");
+        assert_eq!(arh.original_path(&lower), original);
+    }
+    check_reachable(&arh);
+    check_stats(&arh);
+    // Unchanged names are written with the dictionary they were loaded with
+    let mut again = Cursor::new(Vec::new());
+    arh.sync(&mut again).unwrap();
+    assert_eq!(again.into_inner(), data);
+
+    // Renaming to the same name only changes the spelling
+    arh.rename_file_overwrite(&path("/bdat/btl.bdat"), &path("/bdat/btl.bdat"))
+        .unwrap();
+    assert_eq!(
+        arh.original_path(&ArhPath::normalize("/bdat/btl.bdat").unwrap()),
+        "/bdat/btl.bdat"
+    );
+    let mut out = Cursor::new(Vec::new());
+    arh.sync(&mut out).unwrap();
+    out.set_position(0);
+    let reloaded = ArhFileSystem::load_with_options(out, options()).unwrap();
+    assert_eq!(
+        reloaded.original_path(&ArhPath::normalize("/bdat/btl.bdat").unwrap()),
+        "/bdat/btl.bdat"
+    );
+    // Without the option, names are looked up exactly like the game does
+    let mut arh = ArhFileSystem::load(Cursor::new(&data)).unwrap();
+    let sky = ArhPath::normalize("/tex/sky.dds").unwrap();
+    assert!(!arh.is_file(&sky));
+
+    // Names that only differ in case can't be told apart, instead of losing one of the files
+    arh.create_file(&sky).unwrap();
+    let mut out = Cursor::new(Vec::new());
+    arh.sync(&mut out).unwrap();
+    out.set_position(0);
+    assert!(ArhFileSystem::load_with_options(out, options()).is_err());
+}
+
+#[test]
+fn relative_paths() {
+    let cwd = ArhPath::normalize("/map/textures").unwrap();
+    let resolve = |s: &str| RelativeArhPath::new(s).resolve(&cwd).unwrap();
+    assert_eq!(
+        resolve("foo.dds"),
+        ArhPath::normalize("/map/textures/foo.dds").unwrap()
+    );
+    assert_eq!(
+        resolve("../a/./b.bin"),
+        ArhPath::normalize("/map/a/b.bin").unwrap()
+    );
+    assert_eq!(resolve("..\\..\\..\\c"), ArhPath::normalize("/c").unwrap());
+    assert_eq!(
+        resolve("/bdat/../bdat/"),
+        ArhPath::normalize("/bdat").unwrap()
+    );
+    assert_eq!(resolve("."), cwd);
+    assert_eq!(
+        resolve("Dir/File.TXT").original(),
+        "/map/textures/Dir/File.TXT"
+    );
+}
+
+#[test]
+fn path_length_limit() {
+    let dir = ArhPath::normalize(format!("/{}", "a".repeat(200))).unwrap();
+    assert_eq!(dir.remaining_len(), ARH_PATH_MAX_LEN - 201);
+    let name = "b".repeat(dir.remaining_len() - 1);
+    assert_eq!(dir.try_join(&name).unwrap().remaining_len(), 0);
+    let err = dir.try_join(&format!("{name}c")).unwrap_err();
+    assert!(err.is_too_long());
+    assert!(err.to_string().contains("257 characters long"));
+    // Consecutive slashes are removed before the length is checked
+    assert!(dir.try_join(&format!("x//{}", &name[2..])).is_ok());
+}
+
 #[test]
 fn rename_files() {
     let mut arh = load_arh();
@@ -165,6 +689,443 @@ fn rename_files() {
     }
 }
 
+#[test]
+fn rename_dir() {
+    let mut arh = load_arh();
+    let path = |s: &str| ArhPath::normalize(s).unwrap();
+    for f in ["/mv/a/x.txt", "/mv/b.txt", "/mv/e/y.txt", "/out/ne"] {
+        arh.create_file(&path(f)).unwrap();
+    }
+    // Leaves "/mv/e" as an empty directory
+    arh.delete_file(&path("/mv/e/y.txt")).unwrap();
+    let meta = *arh.get_file_info(&path("/mv/a/x.txt")).unwrap();
+
+    arh.rename_dir(&path("/mv"), &path("/moved/here")).unwrap();
+    assert!(!arh.exists(&path("/mv")));
+    assert!(arh.is_dir(&path("/moved/here/e")));
+    check_ids(&mut arh);
+    check_and_read_back(&mut arh, |arh| {
+        assert!(arh.is_file(&path("/moved/here/b.txt")));
+        assert_eq!(
+            *arh.get_file_info(&path("/moved/here/a/x.txt")).unwrap(),
+            meta
+        );
+        check_stats(arh);
+        check_reachable(arh);
+    });
+
+    let moved = path("/moved/here");
+    assert!(matches!(
+        arh.rename_dir(&moved, &path("/moved/here/sub")),
+        Err(Error::FsMoveIntoSelf)
+    ));
+    assert!(matches!(
+        arh.rename_dir(&moved, &path("/out/ne")),
+        Err(Error::FsAlreadyExists)
+    ));
+    // "/out/ne" is a prefix of the new paths, so the first file can't be moved
+    assert!(matches!(
+        arh.rename_dir(&moved, &path("/out/new")),
+        Err(Error::FsFileNameExtended)
+    ));
+    assert!(!arh.exists(&path("/out/new")));
+    assert!(arh.is_dir(&path("/moved/here/e")));
+    check_and_read_back(&mut arh, |arh| {
+        assert!(arh.is_file(&path("/moved/here/b.txt")));
+        assert!(arh.is_file(&path("/moved/here/a/x.txt")));
+        check_stats(arh);
+        check_reachable(arh);
+    });
+}
+
+#[test]
+fn rename_dir_nodes() {
+    let mut arh = load_arh();
+    let path = |s: &str| ArhPath::normalize(s).unwrap();
+    let tables = |arh: &mut ArhFileSystem| {
+        let mut out = Cursor::new(Vec::new());
+        arh.sync(&mut out).unwrap();
+        out.set_position(0);
+        RawTables::read(out).unwrap()
+    };
+    let used_nodes = |tables: &RawTables| {
+        let free = |n: &&RawDictNode| n.prev < 0 && n.next < 0;
+        tables.dict_nodes.iter().filter(|n| !free(n)).count()
+    };
+    let children = arh.get_dir(&path("/bdat")).unwrap().children_paths();
+    assert!(children.len() > 1);
+    let metas: Vec<_> = children
+        .iter()
+        .map(|c| *arh.get_file_info(&path(&format!("/bdat{c}"))).unwrap())
+        .collect();
+
+    let before = tables(&mut arh);
+    arh.rename_dir(&path("/bdat"), &path("/bdat_old")).unwrap();
+    let after = tables(&mut arh);
+    // The files keep their leaves and strings, only the nodes for "_old/" are added
+    assert_eq!(after.strings, before.strings);
+    assert!(used_nodes(&after) <= used_nodes(&before) + "_old/".len());
+    for (child, meta) in children.iter().zip(&metas) {
+        assert!(!arh.exists(&path(&format!("/bdat{child}"))));
+        assert_eq!(
+            arh.get_file_info(&path(&format!("/bdat_old{child}"))),
+            Some(meta)
+        );
+    }
+
+    // The new name goes through the leaf of "/newdir_file.txt", which moves down
+    arh.create_file(&path("/newdir_file.txt")).unwrap();
+    let file = *arh.get_file_info(&path("/newdir_file.txt")).unwrap();
+    arh.rename_dir(&path("/bdat_old"), &path("/newdir"))
+        .unwrap();
+    check_ids(&mut arh);
+    check_and_read_back(&mut arh, |arh| {
+        assert_eq!(arh.get_file_info(&path("/newdir_file.txt")), Some(&file));
+        for (child, meta) in children.iter().zip(&metas) {
+            assert_eq!(
+                arh.get_file_info(&path(&format!("/newdir{child}"))),
+                Some(meta)
+            );
+        }
+        check_stats(arh);
+        check_reachable(arh);
+    });
+
+    // A single file, so the directory name ends in the file's string
+    arh.create_file(&path("/lonely_dir/file.txt")).unwrap();
+    arh.rename_dir(&path("/lonely_dir"), &path("/lonely"))
+        .unwrap();
+    check_and_read_back(&mut arh, |arh| {
+        assert!(arh.is_file(&path("/lonely/file.txt")));
+        assert!(!arh.exists(&path("/lonely_dir")));
+        check_reachable(arh);
+    });
+
+    // The new name is a prefix of the old one
+    arh.delete_file(&path("/lonely/file.txt")).unwrap();
+    arh.create_file(&path("/lonely/file.txt.part")).unwrap();
+    arh.rename_file(&path("/lonely/file.txt.part"), &path("/lonely/file.txt"))
+        .unwrap();
+    check_and_read_back(&mut arh, |arh| {
+        assert!(arh.is_file(&path("/lonely/file.txt")));
+        assert!(!arh.exists(&path("/lonely/file.txt.part")));
+    });
+}
+
+#[test]
+fn history() {
+    let mut arh = load_arh();
+    let path = |p: &str| ArhPath::normalize(p).unwrap();
+    // Nothing is recorded until history is enabled
+    arh.create_file(&path("/before.txt")).unwrap();
+    assert!(!arh.is_history_enabled());
+    arh.set_history_enabled(true).unwrap();
+    arh.create_file(&path("/a.txt")).unwrap();
+    arh.rename_file(&path("/a.txt"), &path("/b.txt")).unwrap();
+    arh.create_dir(&path("/dir")).unwrap();
+    arh.delete_file(&path("/b.txt")).unwrap();
+    arh.delete_empty_dir(&path("/dir")).unwrap();
+
+    check_and_read_back(&mut arh, |arh| {
+        assert!(arh.is_history_enabled());
+        let ops: Vec<_> = arh
+            .history()
+            .iter()
+            .map(|e| (e.kind, e.path.as_str(), e.new_path.as_deref()))
+            .collect();
+        // Directory placeholders are not recorded
+        assert_eq!(
+            ops,
+            [
+                (OpKind::Create, "/a.txt", None),
+                (OpKind::Rename, "/a.txt", Some("/b.txt")),
+                (OpKind::Create, "/dir", None),
+                (OpKind::Delete, "/b.txt", None),
+                (OpKind::Delete, "/dir", None),
+            ]
+        );
+    });
+
+    // Only the latest operations are kept
+    arh.create_file(&path("/c0.txt")).unwrap();
+    for i in 0..OP_LOG_MAX_ENTRIES {
+        let (from, to) = (format!("/c{i}.txt"), format!("/c{}.txt", i + 1));
+        arh.rename_file(&path(&from), &path(&to)).unwrap();
+    }
+    assert_eq!(arh.history().len(), OP_LOG_MAX_ENTRIES);
+    assert_eq!(arh.history()[0].path, "/c0.txt");
+
+    arh.clear_history().unwrap();
+    arh.set_history_enabled(false).unwrap();
+    arh.create_file(&path("/c.txt")).unwrap();
+    assert!(arh.history().is_empty());
+}
+
+#[test]
+fn dir_stats() {
+    let mut arh = load_arh();
+    check_stats(&arh);
+    let files = [
+        "/bdat/test.bdat2",
+        "/new_dir/a/b/file.txt",
+        "/new_dir/a/other.txt",
+    ]
+    .map(|s| ArhPath::normalize(s).unwrap());
+    for f in &files {
+        arh.create_file(f).unwrap();
+        check_and_read_back(&mut arh, |arh| check_stats(&arh));
+    }
+    arh.rename_file(&files[1], &ArhPath::normalize("/new_dir/c.txt").unwrap())
+        .unwrap();
+    check_and_read_back(&mut arh, |arh| check_stats(&arh));
+    arh.rename_dir(
+        &ArhPath::normalize("/bdat").unwrap(),
+        &ArhPath::normalize("/bdat2").unwrap(),
+    )
+    .unwrap();
+    check_and_read_back(&mut arh, |arh| check_stats(&arh));
+    arh.delete_file(&files[2]).unwrap();
+    check_and_read_back(&mut arh, |arh| check_stats(&arh));
+}
+
+#[test]
+fn rebuild_dictionary() {
+    let mut arh = load_arh();
+    for i in 0..50 {
+        let f = ArhPath::normalize(format!("/tmp/file{i}.bin")).unwrap();
+        arh.create_file(&f).unwrap();
+        arh.delete_file(&f).unwrap();
+    }
+    let new = ArhPath::normalize("/new/file.txt").unwrap();
+    arh.create_file(&new).unwrap();
+    let files: Vec<_> = arh
+        .get_dir(&ARH_PATH_ROOT)
+        .unwrap()
+        .children_paths()
+        .into_iter()
+        .map(|p| {
+            let p = ArhPath::normalize(p).unwrap();
+            let meta = *arh.get_file_info(&p).unwrap();
+            (p, meta)
+        })
+        .collect();
+
+    let rebuild = arh.rebuild_dictionary().unwrap();
+    assert_eq!(rebuild.file_count, files.len());
+    // The new tables only depend on the set of files
+    let again = arh.rebuild_dictionary().unwrap();
+    assert_eq!(again.old_node_count, rebuild.new_node_count);
+    assert_eq!(again.new_node_count, rebuild.new_node_count);
+    assert_eq!(again.new_string_table_len, rebuild.new_string_table_len);
+    check_and_read_back(&mut arh, |arh| {
+        for (path, meta) in &files {
+            assert_eq!(arh.get_file_info(path), Some(meta), "{path}");
+        }
+        check_stats(arh);
+        check_reachable(arh);
+    });
+}
+
+#[test]
+fn rebuild_dictionary_single_file() {
+    let mut arh = load_arh();
+    let mut files = arh.get_dir(&ARH_PATH_ROOT).unwrap().children_paths();
+    let kept = ArhPath::normalize(files.pop().unwrap()).unwrap();
+    for path in files {
+        arh.delete_file(&ArhPath::normalize(path).unwrap()).unwrap();
+    }
+    let meta = *arh.get_file_info(&kept).unwrap();
+
+    // The only leaf must not point at the start of the string table
+    arh.rebuild_dictionary().unwrap();
+    check_and_read_back(&mut arh, |arh| {
+        assert_eq!(arh.get_file_info(&kept), Some(&meta));
+        check_reachable(arh);
+    });
+}
+
+#[test]
+fn sync_reproducible() {
+    fn modify_and_sync() -> Vec<u8> {
+        let mut arh = load_arh();
+        for f in ["/bdat/new.bdat", "/a/b/c.txt", "/root.txt"] {
+            arh.create_file(&ArhPath::normalize(f).unwrap()).unwrap();
+        }
+        arh.delete_file(&ArhPath::normalize("/bdat/fld.bdat").unwrap())
+            .unwrap();
+        arh.rename_file(
+            &ArhPath::normalize("/map/ma66a.wismhd").unwrap(),
+            &ArhPath::normalize("/map2/ma66a.wismhd").unwrap(),
+        )
+        .unwrap();
+        let mut out = Cursor::new(Vec::new());
+        arh.sync(&mut out).unwrap();
+        out.into_inner()
+    }
+
+    let first = modify_and_sync();
+    assert_eq!(first, modify_and_sync());
+
+    // Loading and syncing again shouldn't change anything either
+    let mut arh = ArhFileSystem::load(Cursor::new(&first)).unwrap();
+    let mut out = Cursor::new(Vec::new());
+    arh.sync(&mut out).unwrap();
+    assert_eq!(first, out.into_inner());
+}
+
+#[test]
+fn flag_matching() {
+    let mut arh = load_arh();
+    let path = |s: &str| ArhPath::normalize(s).unwrap();
+    let pattern = |s: &str| PathPattern::new(s).unwrap();
+    for f in [
+        "/filt/a.bdat",
+        "/filt/b.bdat",
+        "/filt/c.bdat",
+        "/filt/readme.txt",
+        "/filt/sub/d.bdat",
+        "/filt/sub/deep/e.bdat",
+    ] {
+        arh.create_file(&path(f)).unwrap();
+    }
+    let root = path("/filt");
+
+    let filter = PathFilter::new()
+        .include(pattern("*.bdat"))
+        .exclude(pattern("/filt/a.bdat"))
+        .exclude(pattern("/FILT/sub/deep"));
+    let files = arh.matching_files(&root, &filter).unwrap();
+    assert_eq!(
+        files,
+        ["/filt/b.bdat", "/filt/c.bdat", "/filt/sub/d.bdat"].map(path)
+    );
+
+    let filter = PathFilter::new().include(pattern("/filt/**/?.bdat"));
+    assert_eq!(arh.matching_files(&root, &filter).unwrap().len(), 5);
+    let filter = PathFilter::new().include(pattern("/filt/*/*.bdat"));
+    assert_eq!(
+        arh.matching_files(&root, &filter).unwrap(),
+        [path("/filt/sub/d.bdat")]
+    );
+
+    let filter = PathFilter::new().exclude(pattern("sub"));
+    assert_eq!(
+        arh.set_flag_matching(&root, &filter, FileFlag::Hidden, true)
+            .unwrap(),
+        4
+    );
+    assert_eq!(
+        arh.set_flag_matching(&root, &filter, FileFlag::Hidden, true)
+            .unwrap(),
+        0
+    );
+    assert!(arh
+        .get_file_info(&path("/filt/readme.txt"))
+        .unwrap()
+        .is_flag(FileFlag::Hidden));
+    assert!(!arh
+        .get_file_info(&path("/filt/sub/d.bdat"))
+        .unwrap()
+        .is_flag(FileFlag::Hidden));
+    assert!(matches!(
+        arh.matching_files(&path("/nothing"), &filter),
+        Err(Error::FsNoEntry)
+    ));
+}
+
+#[test]
+fn parallel_visit() {
+    let arh = load_arh();
+    let mut files = Vec::new();
+    arh.get_dir(&ArhPath::default())
+        .unwrap()
+        .visit_files(&ArhPath::default(), &mut |path| files.push(path.clone()));
+    let files: Vec<_> = files
+        .into_iter()
+        .filter(|p| !ArhFileSystem::is_placeholder(p))
+        .collect();
+
+    for order in ReadOrder::ALL {
+        let visitor = ParallelVisitor::new()
+            .threads(NonZeroUsize::new(3).unwrap())
+            .order(order);
+        let visited = AtomicUsize::new(0);
+        let ids = visitor.visit(
+            &arh,
+            &files,
+            || 0,
+            |count, path, meta| {
+                *count += 1;
+                visited.fetch_add(1, Ordering::Relaxed);
+                assert_eq!(arh.get_file_info(path).unwrap().id, meta.id);
+                meta.id
+            },
+        );
+        assert_eq!(visited.into_inner(), files.len());
+        // Results are in the order of the input, whatever order files were read in
+        let expected: Vec<_> = files
+            .iter()
+            .map(|p| arh.get_file_info(p).unwrap().id)
+            .collect();
+        assert_eq!(ids, expected);
+    }
+    assert!(ParallelVisitor::new()
+        .visit(&arh, &[], || (), |_, _, _| ())
+        .is_empty());
+
+    // The first error stops the visit
+    let visited = AtomicUsize::new(0);
+    let res: Result<Vec<()>, u32> = ParallelVisitor::new()
+        .threads(NonZeroUsize::new(1).unwrap())
+        .try_visit(
+            &arh,
+            &files,
+            || (),
+            |_, _, meta| {
+                visited.fetch_add(1, Ordering::Relaxed);
+                Err(meta.id)
+            },
+        );
+    assert!(res.is_err());
+    assert_eq!(visited.into_inner(), 1);
+}
+
+fn check_stats(arh: &ArhFileSystem) {
+    fn walk(node: &DirNode, path: &ArhPath, arh: &ArhFileSystem) -> DirStats {
+        let DirEntry::Directory { children } = &node.entry else {
+            let meta = arh.get_file_info(path).unwrap();
+            return DirStats::from_file(meta);
+        };
+        let mut stats = DirStats::default();
+        for child in children.values() {
+            let child_stats = walk(child, &path.join(&child.name), arh);
+            if matches!(child.entry, DirEntry::Directory { .. }) {
+                stats.dir_count += 1;
+            }
+            stats.file_count += child_stats.file_count;
+            stats.dir_count += child_stats.dir_count;
+            stats.compressed_size += child_stats.compressed_size;
+            stats.uncompressed_size += child_stats.uncompressed_size;
+        }
+        assert_eq!(stats, node.stats, "stats mismatch for {path}");
+        stats
+    }
+    walk(arh.get_dir(&ARH_PATH_ROOT).unwrap(), &ARH_PATH_ROOT, arh);
+}
+
+/// Checks that every file's ID is the index of its file table entry.
+fn check_ids(arh: &mut ArhFileSystem) {
+    let mut out_arh = Cursor::new(Vec::new());
+    arh.sync(&mut out_arh).expect("arh write");
+    out_arh.set_position(0);
+    let tables = RawTables::read(out_arh).expect("arh read back");
+    for path in arh.get_dir(&ARH_PATH_ROOT).unwrap().children_paths() {
+        let path = ArhPath::normalize(path).unwrap();
+        let meta = arh.get_file_info(&path).unwrap();
+        assert_eq!(tables.files.get(meta.id as usize), Some(meta), "{path}");
+    }
+}
+
 fn check_reachable(arh: &ArhFileSystem) {
     let node = arh.get_dir(&ARH_PATH_ROOT).unwrap();
     let mut queue = VecDeque::new();
@@ -176,7 +1137,7 @@ fn check_reachable(arh: &ArhFileSystem) {
                 assert!(arh.is_file(&path), "{path} does not exist");
             }
             DirEntry::Directory { children } => {
-                for child in children {
+                for child in children.values() {
                     queue.push_back((child, path.join(&node.name)));
                 }
             }
@@ -0,0 +1,82 @@
+#![cfg(feature = "raw")]
+
+use std::{fs::File, io::Cursor};
+
+use ardain::{error::Error, path::ArhPath, raw::RawDictNode, ArhFileSystem};
+
+#[test]
+fn raw_view() {
+    let arh = load_arh();
+    let raw = arh.raw();
+    raw.check().unwrap();
+    assert_eq!(raw.offsets().path_dict_node_count as usize, raw.dict_len());
+    assert_eq!(raw.offsets().file_table_len as usize, raw.files().len());
+
+    let path = ArhPath::normalize("/bdat/btl.bdat").unwrap();
+    let id = arh.get_file_info(&path).unwrap().id;
+    let leaf = raw
+        .dict_nodes()
+        .position(|n| {
+            // Leaf nodes
+            n.prev >= 0 && n.next < 0 && raw.string_at(-n.next as usize).unwrap().1 == id
+        })
+        .unwrap();
+    assert_eq!(raw.leaf_path(leaf).unwrap(), path.as_str());
+}
+
+#[test]
+fn raw_checked_changes() {
+    let mut arh = load_arh();
+    let path = ArhPath::normalize("/bdat/btl.bdat").unwrap();
+    let id = arh.get_file_info(&path).unwrap().id;
+    let leaf = (0..arh.raw().dict_len())
+        .find(|&i| arh.raw().leaf_path(i).as_deref() == Some(path.as_str()))
+        .unwrap();
+    let node = arh.raw().dict_node(leaf).unwrap();
+
+    // Pointing the leaf to a string that doesn't exist breaks the dictionary
+    let mut raw = arh.raw_mut().unwrap();
+    let len = raw.as_raw().string_table().len() as i32;
+    assert!(matches!(
+        raw.set_dict_nodes([(leaf, RawDictNode { next: -len, ..node })]),
+        Err(Error::InvalidTables(_))
+    ));
+    // ...and so does a leaf without a parent
+    assert!(matches!(
+        raw.set_dict_nodes([(
+            leaf,
+            RawDictNode {
+                prev: i32::MAX,
+                ..node
+            }
+        )]),
+        Err(Error::InvalidTables(_))
+    ));
+
+    // Renaming the file by pointing its leaf to a new string works
+    let (old_name, _) = raw.as_raw().string_at(-node.next as usize).unwrap();
+    let new_name = format!("{old_name}2");
+    let offset = raw.push_string(&new_name, id).unwrap();
+    raw.set_dict_nodes([(
+        leaf,
+        RawDictNode {
+            next: -offset,
+            ..node
+        },
+    )])
+    .unwrap();
+    let new_path = ArhPath::normalize(format!("{path}2")).unwrap();
+    assert!(!arh.is_file(&path));
+    assert_eq!(arh.get_file_info(&new_path).unwrap().id, id);
+
+    let mut out = Cursor::new(Vec::new());
+    arh.sync(&mut out).unwrap();
+    out.set_position(0);
+    let arh = ArhFileSystem::load(out).unwrap();
+    assert!(arh.is_file(&new_path));
+    arh.raw().check().unwrap();
+}
+
+fn load_arh() -> ArhFileSystem {
+    ArhFileSystem::load(File::open("tests/res/bf3.arh").unwrap()).unwrap()
+}
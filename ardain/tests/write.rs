@@ -1,9 +1,18 @@
-use std::{fs::File, io::Cursor};
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, BufWriter, Cursor, Seek, SeekFrom, Write},
+};
 
 use ardain::{
-    file_alloc::{ArdFileAllocator, CompressionStrategy},
+    consistency::ConsistencyCheck,
+    error::Error,
+    file_alloc::{entry_size, ArdFileAllocator, CompressionStrategy, Placement, SpacePlanner},
+    game::Game,
+    mem::MemArchive,
+    opts::ArhOptions,
     path::ArhPath,
-    ArdReader, ArdWriter, ArhFileSystem,
+    snapshot::{Snapshot, SnapshotWriter},
+    ArdReader, ArdWriter, ArhFileSystem, EntryMismatch, FileMeta, OpKind, RawTables, ReadBack,
 };
 use xc3_lib::xbc1::CompressionType;
 
@@ -50,6 +59,971 @@ fn read_write() {
     assert_eq!(&bdat_read_back, &[100, 101, 102, 103, 104, 105]);
 }
 
+#[test]
+fn entry_header() {
+    let mut arh = load_arh();
+    let mut buf = Cursor::new(std::fs::read("tests/res/bf3_dlc04.ard").unwrap());
+
+    let btl_path = ArhPath::normalize("/bdat/btl.bdat").unwrap();
+    let btl = *arh.get_file_info(&btl_path).unwrap();
+    assert!(ArdReader::new(&mut buf)
+        .entry_header(&btl)
+        .unwrap()
+        .is_none());
+
+    let data = [7; 100];
+    ArdFileAllocator::new(&mut arh, &mut ArdWriter::new(&mut buf))
+        .replace_file(
+            btl.id,
+            &data,
+            CompressionStrategy::Standard(CompressionType::Zstd),
+        )
+        .unwrap();
+    let btl = *arh.get_file_info(&btl_path).unwrap();
+    let header = ArdReader::new(&mut buf)
+        .entry_header(&btl)
+        .unwrap()
+        .unwrap();
+    assert_eq!(header.compression_type, CompressionType::Zstd);
+    assert_eq!(header.decompressed_size, 100);
+    assert_eq!(header.decompressed_size, btl.uncompressed_size);
+}
+
+#[test]
+fn replace_shared() {
+    let mut arh = load_arh();
+    let original = std::fs::read("tests/res/bf3_dlc04.ard").unwrap();
+    let mut buf = Cursor::new(original.clone());
+
+    let btl_path = ArhPath::normalize("/bdat/btl.bdat").unwrap();
+    let copy_path = ArhPath::normalize("/bdat/btl_copy.bdat").unwrap();
+    arh.create_file(&copy_path).unwrap();
+    arh.share_extent(&copy_path, &btl_path).unwrap();
+    let btl_meta = *arh.get_file_info(&btl_path).unwrap();
+
+    // Small enough to fit in place, but the data is shared
+    let copy = arh.get_file_info(&copy_path).unwrap().id;
+    ArdFileAllocator::new(&mut arh, &mut ArdWriter::new(&mut buf))
+        .replace_file(copy, &[1, 2, 3], CompressionStrategy::None)
+        .unwrap();
+    assert_ne!(
+        arh.get_file_info(&copy_path).unwrap().offset,
+        btl_meta.offset
+    );
+
+    buf.set_position(0);
+    let btl_read_back = ArdReader::new(&mut buf).entry(&btl_meta).read().unwrap();
+    let btl_original = ArdReader::new(Cursor::new(original))
+        .entry(&btl_meta)
+        .read()
+        .unwrap();
+    assert_eq!(btl_read_back, btl_original);
+
+    // Now the last reference, so it can be replaced in place
+    let btl = btl_meta.id;
+    ArdFileAllocator::new(&mut arh, &mut ArdWriter::new(&mut buf))
+        .replace_file(btl, &[4, 5, 6], CompressionStrategy::None)
+        .unwrap();
+    assert_eq!(
+        arh.get_file_info(&btl_path).unwrap().offset,
+        btl_meta.offset
+    );
+}
+
+#[test]
+fn build_from_scratch() {
+    let mut arh = ArhFileSystem::new(ArhOptions::default());
+    let mut buf = Cursor::new(Vec::new());
+    let mut writer = ArdWriter::new(&mut buf);
+    let files = ["/b/z.bin", "/a/y.bin", "/a/x.bin"].map(|s| ArhPath::normalize(s).unwrap());
+    for (i, path) in files.iter().enumerate() {
+        let id = arh.create_file(path).unwrap().id;
+        ArdFileAllocator::new(&mut arh, &mut writer)
+            .write_new_file(id, &vec![i as u8; 1000], CompressionStrategy::None)
+            .unwrap();
+    }
+    // Files are written in order, one after the other
+    let offsets: Vec<_> = files
+        .iter()
+        .map(|p| arh.get_file_info(p).unwrap().offset)
+        .collect();
+    assert!(offsets.windows(2).all(|w| w[0] < w[1]));
+
+    let mut out = Cursor::new(Vec::new());
+    arh.sync(&mut out).unwrap();
+    out.set_position(0);
+    let arh = ArhFileSystem::load(out).unwrap();
+    for (i, path) in files.iter().enumerate() {
+        buf.set_position(0);
+        let data = ArdReader::new(&mut buf)
+            .entry(arh.get_file_info(path).unwrap())
+            .read()
+            .unwrap();
+        assert_eq!(data, vec![i as u8; 1000]);
+    }
+}
+
+#[test]
+fn build_single_file() {
+    // Small tables are easily overwritten if the extension section is bigger than planned
+    let mut archive = MemArchive::new(ArhOptions::default());
+    let path = ArhPath::normalize("/a.bin").unwrap();
+    archive
+        .write_file(&path, &[1; 100], CompressionStrategy::None)
+        .unwrap();
+    let (arh, ard) = archive.into_bytes().unwrap();
+    let archive = MemArchive::load(&arh, ard).unwrap();
+    assert_eq!(archive.read_file(&path).unwrap(), [1; 100]);
+}
+
+#[test]
+fn mem_archive() {
+    let arh = std::fs::read("tests/res/bf3_dlc04.arh").unwrap();
+    let ard = std::fs::read("tests/res/bf3_dlc04.ard").unwrap();
+    let mut archive = MemArchive::load(&arh, ard).unwrap();
+
+    let btl_path = ArhPath::normalize("/bdat/btl.bdat").unwrap();
+    let new_path = ArhPath::normalize("/mod/new.bin").unwrap();
+    let zstd = CompressionStrategy::Standard(CompressionType::Zstd);
+    let btl_id = archive.fs().get_file_info(&btl_path).unwrap().id;
+    assert_eq!(
+        archive.write_file(&btl_path, &[1; 500], zstd).unwrap(),
+        btl_id
+    );
+    archive
+        .write_file(&new_path, &[2; 100], CompressionStrategy::None)
+        .unwrap();
+    assert_eq!(archive.read_file(&new_path).unwrap(), [2; 100]);
+
+    let (arh, ard) = archive.into_bytes().unwrap();
+    let archive = MemArchive::load(&arh, ard).unwrap();
+    assert_eq!(archive.read_file(&btl_path).unwrap(), [1; 500]);
+    assert_eq!(archive.read_file(&new_path).unwrap(), [2; 100]);
+    assert!(matches!(
+        archive.read_file(&ArhPath::normalize("/missing").unwrap()),
+        Err(Error::FsNoEntry)
+    ));
+}
+
+#[test]
+fn replace_stored() {
+    let arh = std::fs::read("tests/res/bf3_dlc04.arh").unwrap();
+    let ard = std::fs::read("tests/res/bf3_dlc04.ard").unwrap();
+    let mut source = MemArchive::load(&arh, ard.clone()).unwrap();
+    let mut dest = MemArchive::load(&arh, ard).unwrap();
+
+    let btl_path = ArhPath::normalize("/bdat/btl.bdat").unwrap();
+    let new_path = ArhPath::normalize("/mod/copied.bin").unwrap();
+    let data: Vec<u8> = (0..2000).map(|i| (i % 7) as u8).collect();
+    let zstd = CompressionStrategy::Standard(CompressionType::Zstd);
+    source.write_file(&btl_path, &data, zstd).unwrap();
+    let meta = *source.fs().get_file_info(&btl_path).unwrap();
+    let extent = FileMeta::with_extent(meta.offset, meta.compressed_size, 0, []).unwrap();
+    let stored = source.reader().entry(&extent).read().unwrap();
+
+    for path in [&btl_path, &new_path] {
+        let id = match dest.fs().get_file_info(path) {
+            Some(meta) => meta.id,
+            None => dest.fs_mut().create_file(path).unwrap().id,
+        };
+        dest.allocator()
+            .replace_file_stored(id, &meta, &stored)
+            .unwrap();
+        let copy = *dest.fs().get_file_info(path).unwrap();
+        assert_eq!(copy.compressed_size, meta.compressed_size);
+        assert_eq!(copy.uncompressed_size, meta.uncompressed_size);
+        let header = dest.reader().entry_header(&copy).unwrap().unwrap();
+        assert_eq!(header.compression_type, CompressionType::Zstd);
+        assert_eq!(dest.read_file(path).unwrap(), data);
+    }
+
+    // The stored data must match the metadata
+    let id = dest.fs().get_file_info(&new_path).unwrap().id;
+    assert!(matches!(
+        dest.allocator()
+            .replace_file_stored(id, &meta, &stored[1..]),
+        Err(Error::FileMetaInvalid(_))
+    ));
+}
+
+#[test]
+fn consistency_check() {
+    let ard = std::fs::read("tests/res/bf3_dlc04.ard").unwrap();
+    for seed in 0..4 {
+        let mut arh = load_arh();
+        let mut writer = ArdWriter::new(Cursor::new(ard.clone()));
+        let ops = ConsistencyCheck::new(seed)
+            .steps(200)
+            .run(&mut arh, &mut writer)
+            .unwrap_or_else(|failure| panic!("{failure}"));
+        assert_eq!(ops.len(), 200);
+
+        let mut arh = load_arh();
+        let mut writer = ArdWriter::new(Cursor::new(ard.clone()));
+        ConsistencyCheck::replay(&mut arh, &mut writer, &ops)
+            .unwrap_or_else(|failure| panic!("{failure}"));
+    }
+}
+
+#[test]
+fn plan_matches_allocator() {
+    let mut arh = load_arh();
+    let mut buf = Cursor::new(std::fs::read("tests/res/bf3_dlc04.ard").unwrap());
+    let ard_len = buf.get_ref().len() as u64;
+    let btl_path = ArhPath::normalize("/bdat/btl.bdat").unwrap();
+    let new_path = ArhPath::normalize("/new_file").unwrap();
+    let btl_size = arh.get_file_info(&btl_path).unwrap().compressed_size as usize;
+    let writes = [
+        (btl_path.clone(), vec![1; btl_size / 2]),
+        (new_path.clone(), vec![2; 5000]),
+        (btl_path.clone(), vec![3; btl_size * 2]),
+    ];
+
+    let mut planner = SpacePlanner::new(&arh, ard_len);
+    let plans: Vec<_> = writes
+        .iter()
+        .map(|(path, data)| {
+            let size = entry_size(data, CompressionStrategy::None, Game::Xc3).unwrap();
+            assert_eq!(size, data.len() as u64);
+            planner.plan_write(path, size)
+        })
+        .collect();
+    assert_eq!(plans[0].placement, Placement::InPlace);
+    let planned_len = planner.ard_len();
+
+    let mut writer = ArdWriter::new(&mut buf);
+    for ((path, data), plan) in writes.iter().zip(&plans) {
+        let id = match arh.get_file_info(path) {
+            Some(meta) => meta.id,
+            None => arh.create_file(path).unwrap().id,
+        };
+        let mut allocator = ArdFileAllocator::new(&mut arh, &mut writer);
+        if path == &btl_path {
+            allocator
+                .replace_file(id, data, CompressionStrategy::None)
+                .unwrap();
+        } else {
+            allocator
+                .write_new_file(id, data, CompressionStrategy::None)
+                .unwrap();
+        }
+        assert_eq!(
+            arh.get_file_info(path).unwrap().offset,
+            plan.offset,
+            "{path}"
+        );
+    }
+    assert_eq!(buf.get_ref().len() as u64, planned_len);
+}
+
+#[test]
+fn write_in_place() {
+    let mut arh = load_arh();
+    let mut buf = Cursor::new(std::fs::read("tests/res/bf3_dlc04.ard").unwrap());
+
+    let path = ArhPath::normalize("/raw_file").unwrap();
+    let id = arh.create_file(&path).unwrap().id;
+    let mut writer = ArdWriter::new(&mut buf);
+    let mut allocator = ArdFileAllocator::new(&mut arh, &mut writer);
+    allocator
+        .write_new_file(id, &[0, 1, 2, 3, 4, 5], CompressionStrategy::None)
+        .unwrap();
+    assert!(allocator.write_in_place(id, 2, &[12, 13]).unwrap());
+    // Past the end of the file
+    assert!(!allocator.write_in_place(id, 5, &[15, 16]).unwrap());
+    let meta = *arh.get_file_info(&path).unwrap();
+    assert_eq!(meta.compressed_size, 6);
+
+    buf.set_position(0);
+    let read_back = ArdReader::new(&mut buf).entry(&meta).read().unwrap();
+    assert_eq!(read_back, [0, 1, 12, 13, 4, 5]);
+
+    // Compressed files and shared data must be rewritten in full
+    let compressed = arh
+        .create_file(&ArhPath::normalize("/compressed_file").unwrap())
+        .unwrap()
+        .id;
+    let copy_path = ArhPath::normalize("/raw_copy").unwrap();
+    arh.create_file(&copy_path).unwrap();
+    arh.share_extent(&copy_path, &path).unwrap();
+    let mut writer = ArdWriter::new(&mut buf);
+    let mut allocator = ArdFileAllocator::new(&mut arh, &mut writer);
+    allocator
+        .write_new_file(
+            compressed,
+            &[0; 64],
+            CompressionStrategy::Standard(CompressionType::Zlib),
+        )
+        .unwrap();
+    assert!(!allocator.write_in_place(compressed, 0, &[1]).unwrap());
+    assert!(!allocator.write_in_place(id, 0, &[1]).unwrap());
+}
+
+#[test]
+fn patch_in_place() {
+    let mut arh = load_arh();
+    let mut buf = Cursor::new(std::fs::read("tests/res/bf3_dlc04.ard").unwrap());
+
+    let path = ArhPath::normalize("/raw_file").unwrap();
+    let id = arh.create_file(&path).unwrap().id;
+    let mut writer = ArdWriter::new(&mut buf);
+    let mut allocator = ArdFileAllocator::new(&mut arh, &mut writer);
+    allocator
+        .write_new_file(id, &[0; 1024], CompressionStrategy::None)
+        .unwrap();
+    let patches: [(u64, &[u8]); 3] = [(0, &[1, 2]), (500, &[3; 10]), (1022, &[4, 5])];
+    assert!(allocator.patch_in_place(id, &patches).unwrap());
+    // One range is past the end, so none are written
+    assert!(!allocator
+        .patch_in_place(id, &[(10, &[9]), (1023, &[9, 9])])
+        .unwrap());
+    let meta = *arh.get_file_info(&path).unwrap();
+
+    buf.set_position(0);
+    let read_back = ArdReader::new(&mut buf).entry(&meta).read().unwrap();
+    let mut expected = vec![0; 1024];
+    for (offset, data) in patches {
+        expected[offset as usize..][..data.len()].copy_from_slice(data);
+    }
+    assert_eq!(read_back, expected);
+}
+
+#[test]
+fn alloc_stats() {
+    let mut arh = load_arh();
+    let mut buf = Cursor::new(std::fs::read("tests/res/bf3_dlc04.ard").unwrap());
+    let before = arh.alloc_stats();
+    assert_eq!(before.counts, Default::default());
+    assert!(before.largest_free_run <= before.free_bytes);
+    assert!((0.0..=1.0).contains(&before.fragmentation()));
+
+    let path = ArhPath::normalize("/stats.bin").unwrap();
+    let id = arh.create_file(&path).unwrap().id;
+    let mut writer = ArdWriter::new(&mut buf);
+    let mut allocator = ArdFileAllocator::new(&mut arh, &mut writer);
+    allocator
+        .write_new_file(id, &[1; 100], CompressionStrategy::None)
+        .unwrap();
+    let stats = arh.alloc_stats();
+    assert_eq!(stats.counts.in_place, 0);
+    assert_eq!(stats.counts.reused + stats.counts.appended, 1);
+    assert_eq!(
+        stats.rounding_waste - before.rounding_waste,
+        u64::from(stats.block_size) - 100
+    );
+
+    let mut allocator = ArdFileAllocator::new(&mut arh, &mut writer);
+    allocator
+        .replace_file(id, &[2; 50], CompressionStrategy::None)
+        .unwrap();
+    let stats = arh.alloc_stats();
+    assert_eq!(stats.counts.in_place, 1);
+    assert_eq!(
+        stats.rounding_waste - before.rounding_waste,
+        u64::from(stats.block_size) - 50
+    );
+}
+
+#[test]
+fn grow_ahead() {
+    const CHUNK: u64 = 1 << 20;
+    let mut arh = load_arh();
+    let mut buf = Cursor::new(std::fs::read("tests/res/bf3_dlc04.ard").unwrap());
+    let original_len = buf.get_ref().len() as u64;
+
+    let mut writer = ArdWriter::new(&mut buf).grow_ahead(CHUNK);
+    // Larger than the whole ARD file, so the files are appended
+    let size = 100_000;
+    let mut end = original_len;
+    for i in 0..3 {
+        let path = ArhPath::normalize(format!("/grow/{i}.bin")).unwrap();
+        let id = arh.create_file(&path).unwrap().id;
+        ArdFileAllocator::new(&mut arh, &mut writer)
+            .write_new_file(id, &vec![i; size], CompressionStrategy::None)
+            .unwrap();
+        let meta = arh.get_file_info(&path).unwrap();
+        end = end.max(meta.offset + u64::from(meta.compressed_size));
+    }
+    let len = writer.get_ref().get_ref().len() as u64;
+    assert!(len >= end);
+    assert_eq!(len % CHUNK, 0);
+
+    writer.trim().unwrap();
+    assert_eq!(writer.get_ref().get_ref().len() as u64, end);
+    for i in 0..3 {
+        let path = ArhPath::normalize(format!("/grow/{i}.bin")).unwrap();
+        let data = ArdReader::new(writer.get_mut())
+            .entry(arh.get_file_info(&path).unwrap())
+            .read()
+            .unwrap();
+        assert_eq!(data, vec![i; size]);
+    }
+}
+
+#[test]
+fn len_limit() {
+    let mut arh = load_arh();
+    let mut buf = Cursor::new(std::fs::read("tests/res/bf3_dlc04.ard").unwrap());
+    let max_len = buf.get_ref().len() as u64 + 150_000;
+
+    let mut writer = ArdWriter::new(&mut buf)
+        .grow_ahead(1 << 20)
+        .limit_len(max_len);
+    assert_eq!(writer.len_limit(), Some(max_len));
+    // Larger than the whole ARD file, so the files are appended
+    let first = arh
+        .create_file(&ArhPath::normalize("/first.bin").unwrap())
+        .unwrap()
+        .id;
+    ArdFileAllocator::new(&mut arh, &mut writer)
+        .write_new_file(first, &[1; 100_000], CompressionStrategy::None)
+        .unwrap();
+    // Preallocated space stops at the limit
+    assert_eq!(writer.get_ref().get_ref().len() as u64, max_len);
+
+    let path = ArhPath::normalize("/second.bin").unwrap();
+    let second = arh.create_file(&path).unwrap().id;
+    let before = *arh.get_file_info(&path).unwrap();
+    let leaked = arh.check_block_table().leaked;
+    let mut allocator = ArdFileAllocator::new(&mut arh, &mut writer);
+    assert!(matches!(
+        allocator.write_new_file(second, &[2; 100_000], CompressionStrategy::None),
+        Err(Error::ArdSizeLimit(len)) if len == max_len
+    ));
+    assert!(matches!(
+        allocator.reserve(second, 100_000),
+        Err(Error::ArdSizeLimit(_))
+    ));
+    // Nothing was allocated for the file
+    assert_eq!(*arh.get_file_info(&path).unwrap(), before);
+    let drift = arh.check_block_table();
+    assert!(drift.unmarked.is_empty());
+    assert_eq!(drift.leaked, leaked);
+    assert!(writer.get_ref().get_ref().len() as u64 <= max_len);
+
+    // Data that fits is still written
+    ArdFileAllocator::new(&mut arh, &mut writer)
+        .replace_file(second, &[2; 0x100], CompressionStrategy::None)
+        .unwrap();
+    let data = ArdReader::new(writer.get_mut())
+        .entry(arh.get_file_info(&path).unwrap())
+        .read()
+        .unwrap();
+    assert_eq!(data, [2; 0x100]);
+}
+
+/// Drops the last byte of every write after `ok_writes` writes, like a faulty drive.
+struct FlakyWriter {
+    inner: Cursor<Vec<u8>>,
+    ok_writes: usize,
+}
+
+impl Write for FlakyWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.ok_writes > 0 {
+            self.ok_writes -= 1;
+            return self.inner.write(buf);
+        }
+        let len = buf.len().saturating_sub(1);
+        self.inner.write_all(&buf[..len])?;
+        self.inner.seek(SeekFrom::Current(1))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for FlakyWriter {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.inner.seek(pos)
+    }
+}
+
+impl ReadBack for FlakyWriter {
+    fn read_back(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        self.inner.read_back(offset, buf)
+    }
+}
+
+#[test]
+fn verify_writes() {
+    let mut arh = load_arh();
+    let ard = std::fs::read("tests/res/bf3_dlc04.ard").unwrap();
+    let path = ArhPath::normalize("/verified.bin").unwrap();
+    let id = arh.create_file(&path).unwrap().id;
+
+    let mut writer = ArdWriter::new(FlakyWriter {
+        inner: Cursor::new(ard),
+        ok_writes: 1,
+    })
+    .verify_writes(true);
+    ArdFileAllocator::new(&mut arh, &mut writer)
+        .write_new_file(id, &[3; 1000], CompressionStrategy::None)
+        .unwrap();
+    let offset = arh.get_file_info(&path).unwrap().offset;
+    let err = ArdFileAllocator::new(&mut arh, &mut writer)
+        .replace_file(id, &[4; 500], CompressionStrategy::None)
+        .unwrap_err();
+    assert!(matches!(err, Error::WriteMismatch(at) if at == offset + 499));
+    // Writes in place are verified too
+    let err = ArdFileAllocator::new(&mut arh, &mut writer)
+        .write_in_place(id, 10, &[5; 10])
+        .unwrap_err();
+    assert!(matches!(err, Error::WriteMismatch(at) if at == offset + 19));
+}
+
+#[test]
+fn reserve() {
+    let mut arh = load_arh();
+    let mut buf = Cursor::new(std::fs::read("tests/res/bf3_dlc04.ard").unwrap());
+
+    let path = ArhPath::normalize("/reserved.bin").unwrap();
+    let other_path = ArhPath::normalize("/other.bin").unwrap();
+    let id = arh.create_file(&path).unwrap().id;
+    let other = arh.create_file(&other_path).unwrap().id;
+    let mut writer = ArdWriter::new(&mut buf);
+    let mut allocator = ArdFileAllocator::new(&mut arh, &mut writer);
+    allocator.reserve(id, 0x1000).unwrap();
+    // Must not be placed in the reserved space
+    allocator
+        .write_new_file(other, &[1; 0x100], CompressionStrategy::None)
+        .unwrap();
+
+    let reserved = *arh.get_file_info(&path).unwrap();
+    let other = *arh.get_file_info(&other_path).unwrap();
+    assert_eq!(reserved.actual_size(), 0x1000);
+    assert!(
+        other.offset >= reserved.offset + 0x1000
+            || other.offset + u64::from(other.compressed_size) <= reserved.offset
+    );
+    buf.set_position(0);
+    let data = ArdReader::new(&mut buf).entry(&reserved).read().unwrap();
+    assert_eq!(data, [0; 0x1000]);
+    assert!(arh.check_block_table().unmarked.is_empty());
+
+    // Writing the data later reuses the reserved space
+    ArdFileAllocator::new(&mut arh, &mut ArdWriter::new(&mut buf))
+        .replace_file(id, &[2; 0x800], CompressionStrategy::None)
+        .unwrap();
+    let written = *arh.get_file_info(&path).unwrap();
+    assert_eq!(written.offset, reserved.offset);
+    assert_eq!(written.compressed_size, 0x800);
+    assert!(arh.check_block_table().unmarked.is_empty());
+
+    // Sizes are stored as 32-bit integers
+    assert!(matches!(
+        ArdFileAllocator::new(&mut arh, &mut ArdWriter::new(&mut buf)).reserve(id, 1 << 32),
+        Err(Error::LimitExceeded(_))
+    ));
+    assert_eq!(*arh.get_file_info(&path).unwrap(), written);
+}
+
+#[test]
+fn game_compression() {
+    let mut buf = Cursor::new(std::fs::read("tests/res/bf3_dlc04.ard").unwrap());
+    let opts = ArhOptions {
+        game: Game::Xc2,
+        ..Default::default()
+    };
+    let mut arh =
+        ArhFileSystem::load_with_options(File::open("tests/res/bf3_dlc04.arh").unwrap(), opts)
+            .unwrap();
+    let btl_path = ArhPath::normalize("/bdat/btl.bdat").unwrap();
+    let btl = *arh.get_file_info(&btl_path).unwrap();
+
+    let mut writer = ArdWriter::new(&mut buf);
+    let mut allocator = ArdFileAllocator::new(&mut arh, &mut writer);
+    assert!(matches!(
+        allocator.replace_file(
+            btl.id,
+            &[0; 100],
+            CompressionStrategy::Standard(CompressionType::Zstd)
+        ),
+        Err(Error::UnsupportedCompression(
+            CompressionType::Zstd,
+            Game::Xc2
+        ))
+    ));
+    allocator
+        .replace_file(
+            btl.id,
+            &[0; 100],
+            CompressionStrategy::Standard(CompressionType::Zlib),
+        )
+        .unwrap();
+    let btl = *arh.get_file_info(&btl_path).unwrap();
+    let header = ArdReader::new(&mut buf)
+        .entry_header(&btl)
+        .unwrap()
+        .unwrap();
+    assert_eq!(header.compression_type, CompressionType::Zlib);
+
+    // XC3 supports zstd, but its archives use zlib
+    let mut arh = load_arh();
+    let mut writer = ArdWriter::new(&mut buf);
+    ArdFileAllocator::new(&mut arh, &mut writer)
+        .replace_file(btl.id, &[1; 1000], CompressionStrategy::Best)
+        .unwrap();
+    let btl = *arh.get_file_info(&btl_path).unwrap();
+    let header = ArdReader::new(&mut buf)
+        .entry_header(&btl)
+        .unwrap()
+        .unwrap();
+    assert_eq!(header.compression_type, CompressionType::Zlib);
+
+    assert_eq!("xcx-de".parse::<Game>().unwrap(), Game::XcxDe);
+    assert!("xc1".parse::<Game>().is_err());
+}
+
+#[test]
+fn verify_on_read() {
+    let mut arh = load_arh();
+    let mut buf = Cursor::new(std::fs::read("tests/res/bf3_dlc04.ard").unwrap());
+    let path = ArhPath::normalize("/compressed").unwrap();
+    let id = arh.create_file(&path).unwrap().id;
+    ArdFileAllocator::new(&mut arh, &mut ArdWriter::new(&mut buf))
+        .write_new_file(
+            id,
+            &[5; 1000],
+            CompressionStrategy::Standard(CompressionType::Zlib),
+        )
+        .unwrap();
+    let meta = *arh.get_file_info(&path).unwrap();
+    let opts = ArhOptions {
+        verify_on_read: true,
+        ..Default::default()
+    };
+    let mut reader = ArdReader::with_options(&mut buf, &opts);
+    assert_eq!(reader.entry(&meta).read().unwrap(), [5; 1000]);
+
+    // Corrupt the hash in the XBC1 header
+    let hash_offset = meta.offset as usize + 16;
+    buf.get_mut()[hash_offset] ^= 0xFF;
+    let mut reader = ArdReader::with_options(&mut buf, &opts);
+    assert!(matches!(
+        reader.entry(&meta).read(),
+        Err(Error::ChecksumMismatch { .. })
+    ));
+    assert!(matches!(
+        reader.entry(&meta).skip_take(10, 10).read(),
+        Err(Error::ChecksumMismatch { .. })
+    ));
+    // Not checked by default
+    let mut reader = ArdReader::new(&mut buf);
+    assert_eq!(reader.entry(&meta).read().unwrap(), [5; 1000]);
+}
+
+#[test]
+fn read_cache() {
+    let mut arh = load_arh();
+    let mut buf = Cursor::new(std::fs::read("tests/res/bf3_dlc04.ard").unwrap());
+    let compressed = ArhPath::normalize("/cached/compressed").unwrap();
+    let raw = ArhPath::normalize("/cached/raw").unwrap();
+    for (path, strategy) in [
+        (
+            &compressed,
+            CompressionStrategy::Standard(CompressionType::Zlib),
+        ),
+        (&raw, CompressionStrategy::None),
+    ] {
+        let id = arh.create_file(path).unwrap().id;
+        ArdFileAllocator::new(&mut arh, &mut ArdWriter::new(&mut buf))
+            .write_new_file(id, &[7; 1000], strategy)
+            .unwrap();
+    }
+    let compressed = *arh.get_file_info(&compressed).unwrap();
+    let raw = *arh.get_file_info(&raw).unwrap();
+
+    let mut reader = ArdReader::new(buf).cache(1500);
+    assert_eq!(reader.entry(&compressed).read().unwrap(), [7; 1000]);
+    assert_eq!(
+        reader.entry(&compressed).skip_take(990, 20).read().unwrap(),
+        [7; 10]
+    );
+    let stats = reader.cache_stats().unwrap();
+    assert_eq!((stats.hits, stats.misses), (1, 1));
+    assert_eq!((stats.entries, stats.size, stats.capacity), (1, 1000, 1500));
+
+    // Raw entries are cached whole, so other parts are served from the first read. The
+    // compressed entry was used least recently, so it makes room for the raw one.
+    assert_eq!(
+        reader.entry(&raw).skip_take(0, 100).read().unwrap(),
+        [7; 100]
+    );
+    assert_eq!(
+        reader.entry(&raw).skip_take(500, 100).read().unwrap(),
+        [7; 100]
+    );
+    let stats = reader.cache_stats().unwrap();
+    assert_eq!((stats.hits, stats.misses), (2, 2));
+    assert_eq!((stats.evictions, stats.entries, stats.size), (1, 1, 1000));
+
+    // Cached data is served without looking at the file
+    let start = raw.offset as usize;
+    reader.get_mut().get_mut()[start..start + 1000].fill(0);
+    assert_eq!(
+        reader.entry(&raw).skip_take(900, 200).read().unwrap(),
+        [7; 100]
+    );
+    reader.invalidate_cache(raw.offset + 50..raw.offset + 51);
+    assert_eq!(
+        reader.entry(&raw).skip_take(900, 200).read().unwrap(),
+        [0; 100]
+    );
+
+    reader.clear_cache();
+    let stats = reader.cache_stats().unwrap();
+    assert_eq!((stats.entries, stats.size), (0, 0));
+    assert!(ArdReader::new(Cursor::new(Vec::new()))
+        .cache(0)
+        .cache_stats()
+        .is_none());
+}
+
+#[test]
+fn validate() {
+    let mut arh = load_arh();
+    let data = std::fs::read("tests/res/bf3_dlc04.ard").unwrap();
+    let mut buf = Cursor::new(data.clone());
+    assert!(arh
+        .validate(&mut ArdReader::new(&mut buf))
+        .unwrap()
+        .is_empty());
+
+    let path = ArhPath::normalize("/compressed").unwrap();
+    let id = arh.create_file(&path).unwrap().id;
+    ArdFileAllocator::new(&mut arh, &mut ArdWriter::new(&mut buf))
+        .write_new_file(
+            id,
+            &[5; 1000],
+            CompressionStrategy::Standard(CompressionType::Zlib),
+        )
+        .unwrap();
+    assert!(arh
+        .validate(&mut ArdReader::new(&mut buf))
+        .unwrap()
+        .is_empty());
+
+    arh.get_file_info_mut(&path).unwrap().uncompressed_size = 999;
+    assert_eq!(
+        arh.validate(&mut ArdReader::new(&mut buf)).unwrap(),
+        [(
+            path.clone(),
+            EntryMismatch::DecompressedSize {
+                header: 1000,
+                meta: 999
+            }
+        )]
+    );
+
+    // A truncated ARD file, e.g. one that doesn't belong to the ARH
+    let btl_path = ArhPath::normalize("/bdat/btl.bdat").unwrap();
+    let btl = *arh.get_file_info(&btl_path).unwrap();
+    let ard_len = btl.offset + 1;
+    let problems = arh
+        .validate(&mut ArdReader::new(Cursor::new(&data[..ard_len as usize])))
+        .unwrap();
+    assert!(problems.contains(&(
+        btl_path,
+        EntryMismatch::OutOfBounds {
+            end: btl.offset + u64::from(btl.compressed_size),
+            ard_len
+        }
+    )));
+}
+
+#[test]
+fn snapshot_revert() {
+    let ard_path = std::env::temp_dir().join(format!("ardain-snapshot-{}.ard", std::process::id()));
+    let original = std::fs::read("tests/res/bf3_dlc04.ard").unwrap();
+    let original_arh = std::fs::read("tests/res/bf3_dlc04.arh").unwrap();
+    std::fs::write(&ard_path, &original).unwrap();
+    let mut snapshot = Snapshot::create(&ard_path, &original_arh).unwrap();
+    assert_eq!(snapshot.ard_len(), original.len() as u64);
+    drop(snapshot);
+
+    let mut arh = load_arh();
+    let btl = arh
+        .get_file_info(&ArhPath::normalize("/bdat/btl.bdat").unwrap())
+        .unwrap()
+        .id;
+    let new_file = arh
+        .create_file(&ArhPath::normalize("/new").unwrap())
+        .unwrap()
+        .id;
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(&ard_path)
+        .unwrap();
+    let mut writer = ArdWriter::new(BufWriter::new(SnapshotWriter::new(
+        file,
+        Snapshot::open(&ard_path).unwrap(),
+    )));
+    let mut allocator = ArdFileAllocator::new(&mut arh, &mut writer);
+    allocator
+        .replace_file(btl, &[1; 100], CompressionStrategy::None)
+        .unwrap();
+    allocator
+        .write_new_file(new_file, &[2; 5000], CompressionStrategy::None)
+        .unwrap();
+    writer.get_mut().flush().unwrap();
+    drop(writer);
+    assert_ne!(std::fs::read(&ard_path).unwrap(), original);
+
+    // Reopening finds the recorded ranges again
+    snapshot = Snapshot::open(&ard_path).unwrap().unwrap();
+    assert_ne!(snapshot.saved_bytes(), 0);
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(&ard_path)
+        .unwrap();
+    assert_eq!(snapshot.revert(&mut file).unwrap(), original_arh);
+    assert_eq!(snapshot.saved_bytes(), 0);
+    drop(file);
+    assert_eq!(std::fs::read(&ard_path).unwrap(), original);
+
+    snapshot.remove().unwrap();
+    assert!(Snapshot::open(&ard_path).unwrap().is_none());
+    std::fs::remove_file(&ard_path).unwrap();
+}
+
+#[test]
+fn rm_keep_data_scrub() {
+    let mut arh = load_arh();
+    let btl = ArhPath::normalize("/bdat/btl.bdat").unwrap();
+    let meta = *arh.get_file_info(&btl).unwrap();
+    let drift = arh.check_block_table().leaked;
+
+    arh.delete_file_keep_data(&btl).unwrap();
+    assert!(!arh.is_file(&btl));
+    assert_eq!(arh.orphaned_files().len(), 1);
+    assert_eq!(arh.orphaned_files()[0].path, btl.as_str());
+    // The data is still allocated
+    assert_eq!(arh.check_block_table().leaked, drift);
+
+    let mut buf = Cursor::new(Vec::new());
+    arh.sync(&mut buf).unwrap();
+    buf.set_position(0);
+    let mut arh = ArhFileSystem::load(buf).unwrap();
+    assert_eq!(arh.orphaned_files().len(), 1);
+
+    arh.recover_file(&btl).unwrap();
+    assert!(arh.orphaned_files().is_empty());
+    let recovered = arh.get_file_info(&btl).unwrap();
+    assert_eq!(recovered.offset, meta.offset);
+    assert_eq!(recovered.compressed_size, meta.compressed_size);
+    assert!(matches!(arh.recover_file(&btl), Err(Error::FsNoEntry)));
+
+    // Reclaiming the region forgets the file
+    arh.delete_file_keep_data(&btl).unwrap();
+    let end = meta.offset + u64::from(meta.compressed_size);
+    arh.free_region(meta.offset..end).unwrap();
+    assert!(arh.orphaned_files().is_empty());
+
+    let mut arh = load_arh();
+    let mut buf = Cursor::new(std::fs::read("tests/res/bf3_dlc04.ard").unwrap());
+    let mut writer = ArdWriter::new(&mut buf);
+    let mut allocator = ArdFileAllocator::new(&mut arh, &mut writer);
+    assert!(allocator.delete_file_scrub(&btl).unwrap());
+    let start = meta.offset as usize;
+    assert!(buf.get_ref()[start..end as usize].iter().all(|&b| b == 0));
+    assert!(!arh.is_file(&btl));
+}
+
+#[test]
+fn rm_keep_data_shared() {
+    let mut arh = load_arh();
+    let mut buf = Cursor::new(std::fs::read("tests/res/bf3_dlc04.ard").unwrap());
+    let btl = ArhPath::normalize("/bdat/btl.bdat").unwrap();
+    let copy = ArhPath::normalize("/bdat/btl_copy.bdat").unwrap();
+    let meta = *arh.get_file_info(&btl).unwrap();
+    let original = ArdReader::new(&mut buf).entry(&meta).read().unwrap();
+    arh.create_file(&copy).unwrap();
+    arh.share_extent(&copy, &btl).unwrap();
+    arh.set_history_enabled(true).unwrap();
+
+    // The orphan still uses the data, so the copy can't be replaced in place
+    arh.delete_file_keep_data(&btl).unwrap();
+    let copy_id = arh.get_file_info(&copy).unwrap().id;
+    ArdFileAllocator::new(&mut arh, &mut ArdWriter::new(&mut buf))
+        .replace_file(copy_id, &[1, 2, 3], CompressionStrategy::None)
+        .unwrap();
+    assert_ne!(arh.get_file_info(&copy).unwrap().offset, meta.offset);
+
+    // Changing the block size keeps the orphans, recycle bin and history
+    let mut out = Cursor::new(Vec::new());
+    arh.sync(&mut out).unwrap();
+    out.set_position(0);
+    let opts = ArhOptions {
+        ext_block_size_pow: 12,
+        ext_force_block_size: true,
+        ..Default::default()
+    };
+    let mut arh = ArhFileSystem::load_with_options(out, opts).unwrap();
+    let recycled = arh.file_table_usage().recycled;
+    let history = arh.history().len();
+    assert!(!recycled.is_empty() && history != 0);
+    arh.set_history_enabled(true).unwrap();
+    assert_eq!(arh.block_size(), 1 << 12);
+    assert_eq!(arh.orphaned_files().len(), 1);
+    assert_eq!(arh.file_table_usage().recycled, recycled);
+    assert_eq!(arh.history().len(), history);
+
+    arh.recover_file(&btl).unwrap();
+    buf.set_position(0);
+    let recovered = arh.get_file_info(&btl).unwrap();
+    assert_eq!(
+        ArdReader::new(&mut buf).entry(recovered).read().unwrap(),
+        original
+    );
+}
+
+#[test]
+fn history_replace() {
+    let mut arh = load_arh();
+    let mut buf = Cursor::new(std::fs::read("tests/res/bf3_dlc04.ard").unwrap());
+    let btl = ArhPath::normalize("/bdat/btl.bdat").unwrap();
+    let renamed = ArhPath::normalize("/bdat/renamed.bdat").unwrap();
+    let id = arh.get_file_info(&btl).unwrap().id;
+    arh.set_history_enabled(true).unwrap();
+
+    // The allocator only has the ID, the path must follow renames
+    let mut replace = |arh: &mut ArhFileSystem| {
+        ArdFileAllocator::new(arh, &mut ArdWriter::new(&mut buf))
+            .replace_file(id, &[1, 2, 3], CompressionStrategy::None)
+            .unwrap();
+        let last = arh.history().last().unwrap();
+        (last.kind, last.path.clone())
+    };
+    assert_eq!(replace(&mut arh), (OpKind::Replace, btl.to_string()));
+    arh.rename_file(&btl, &renamed).unwrap();
+    assert_eq!(replace(&mut arh), (OpKind::Replace, renamed.to_string()));
+}
+
+#[test]
+fn raw_tables_round_trip() {
+    let tables = RawTables::read(File::open("tests/res/bf3_dlc04.arh").unwrap()).unwrap();
+    let mut buf = Cursor::new(Vec::new());
+    tables.clone().write(&mut buf).unwrap();
+
+    buf.set_position(0);
+    assert_eq!(RawTables::read(&mut buf).unwrap(), tables);
+    buf.set_position(0);
+    let arh = ArhFileSystem::load(buf).unwrap();
+    let original = load_arh();
+    let root = ArhPath::default();
+    assert_eq!(
+        arh.get_dir(&root).unwrap().children_paths(),
+        original.get_dir(&root).unwrap().children_paths()
+    );
+    let btl = ArhPath::normalize("/bdat/btl.bdat").unwrap();
+    assert_eq!(arh.get_file_info(&btl), original.get_file_info(&btl));
+}
+
 fn load_arh() -> ArhFileSystem {
     ArhFileSystem::load(File::open("tests/res/bf3_dlc04.arh").unwrap()).unwrap()
 }
@@ -0,0 +1,40 @@
+//! Open directory handles.
+//!
+//! Each handle keeps the listing taken when the directory was opened, so `readdir` offsets
+//! (positions in the listing) stay valid even if entries are created or deleted while the
+//! directory is being read.
+
+use std::{collections::HashMap, sync::Arc};
+
+use ardain::path::ArhPath;
+use fuser::FileType;
+
+#[derive(Default)]
+pub struct DirHandles {
+    open_dirs: HashMap<u64, Arc<[DirListEntry]>>,
+    next_fh: u64,
+}
+
+pub struct DirListEntry {
+    pub path: ArhPath,
+    pub ino: u64,
+    pub kind: FileType,
+    pub name: String,
+}
+
+impl DirHandles {
+    pub fn open(&mut self, entries: Vec<DirListEntry>) -> u64 {
+        // 0 is never used, in case the kernel didn't call opendir
+        self.next_fh += 1;
+        self.open_dirs.insert(self.next_fh, entries.into());
+        self.next_fh
+    }
+
+    pub fn get(&self, fh: u64) -> Option<Arc<[DirListEntry]>> {
+        self.open_dirs.get(&fh).cloned()
+    }
+
+    pub fn release(&mut self, fh: u64) {
+        self.open_dirs.remove(&fh);
+    }
+}
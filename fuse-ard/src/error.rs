@@ -1,7 +1,7 @@
-//! Error -> libc errno conversion
+//! Error -> libc errno conversion, and isolating handlers from panics
 
 use ardain::error::Error;
-use libc::{c_int, EEXIST, EINVAL, EIO, ENOENT};
+use libc::{c_int, EEXIST, EFBIG, EINVAL, EIO, ENAMETOOLONG, ENOENT, ENOSPC, ENOTEMPTY, EROFS};
 use log::{error, warn};
 
 pub trait LibcError {
@@ -27,19 +27,43 @@ macro_rules! fuse_err {
     }};
 }
 
+/// Runs the body of a FUSE handler, recovering if it panics. The body owns the handler's
+/// reply, which is dropped unsent while unwinding: fuser then replies with EIO. The file
+/// system is then synced by `ArhFuseSystem::recover`.
+#[macro_export]
+macro_rules! isolate {
+    ($fs:ident, $op:literal, $body:block) => {{
+        let res = ::std::panic::catch_unwind(::std::panic::AssertUnwindSafe(|| $body));
+        if let Err(panic) = res {
+            $fs.recover($op, panic);
+        }
+    }};
+}
+
 impl LibcError for Error {
     fn errno(&self) -> c_int {
         match self {
             Error::FsNoEntry => ENOENT,
             Error::FsAlreadyExists => EEXIST,
-            Error::FsFileNameExtended | Error::Path(_) => EINVAL,
+            Error::FsNotEmpty => ENOTEMPTY,
+            Error::ReadOnly => EROFS,
+            Error::Path(e) if e.is_too_long() => ENAMETOOLONG,
+            Error::FsFileNameExtended | Error::FsMoveIntoSelf | Error::Path(_) => EINVAL,
+            Error::LimitExceeded(_) => EFBIG,
+            Error::ArdSizeLimit(_) => ENOSPC,
             _ => EIO,
         }
     }
 
     fn handle(&self) {
         match self {
-            e @ Error::FsFileNameExtended => warn!("{e}"),
+            e @ (Error::FsFileNameExtended
+            | Error::FsMoveIntoSelf
+            | Error::FsNotEmpty
+            | Error::ArdSizeLimit(_)) => {
+                warn!("{e}")
+            }
+            e @ Error::Path(path) if path.is_too_long() => warn!("{e}"),
             e => error!("{e}"),
         }
     }
@@ -47,10 +71,14 @@ impl LibcError for Error {
 
 impl LibcError for anyhow::Error {
     fn errno(&self) -> c_int {
-        EIO
+        // e.g. from write buffers, which fail with library errors
+        self.downcast_ref::<Error>().map_or(EIO, Error::errno)
     }
 
     fn handle(&self) {
-        error!("{}", self)
+        match self.downcast_ref::<Error>() {
+            Some(e) => e.handle(),
+            None => error!("{}", self),
+        }
     }
 }
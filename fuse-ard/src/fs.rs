@@ -1,41 +1,139 @@
 use std::{
-    collections::{hash_map::DefaultHasher, HashMap},
+    any::Any,
+    collections::hash_map::DefaultHasher,
     ffi::OsStr,
     fs::File,
     hash::{Hash, Hasher},
-    io::{BufWriter, Read, Seek},
+    io::{BufWriter, Read, Seek, Write},
+    panic::{self, AssertUnwindSafe},
     path::{Path, PathBuf},
+    sync::Arc,
     time::{Duration, UNIX_EPOCH},
 };
 
 use ardain::{
-    error::Result,
+    error::{Error, Result},
+    file_alloc::ArdFileAllocator,
+    game::Game,
+    opts::{ArhOptions, SyncPolicy},
     path::{ArhPath, ARH_PATH_MAX_LEN, ARH_PATH_ROOT},
-    ArhFileSystem, DirEntry, DirNode, FileMeta,
+    ArhFileSystem, DirEntry, DirNode, FileFlag, FileMeta,
 };
 use fuser::{
-    FileAttr, FileType, Filesystem, ReplyAttr, ReplyData, ReplyDirectory, ReplyEmpty, ReplyEntry,
-    ReplyOpen, ReplyStatfs, ReplyWrite, Request,
+    consts::{FOPEN_DIRECT_IO, FUSE_DO_READDIRPLUS, FUSE_READDIRPLUS_AUTO},
+    FileAttr, FileType, Filesystem, KernelConfig, ReplyAttr, ReplyData, ReplyDirectory,
+    ReplyDirectoryPlus, ReplyEmpty, ReplyEntry, ReplyOpen, ReplyStatfs, ReplyWrite, Request,
 };
-use libc::{EBADFD, EEXIST, ENOENT, ENOTDIR, ENOTEMPTY, ENOTSUP, O_RDWR, O_WRONLY};
-use log::debug;
+use libc::{
+    EACCES, EBADFD, EEXIST, EISDIR, ENOENT, ENOTDIR, ENOTSUP, O_RDWR, O_WRONLY, RENAME_EXCHANGE,
+    RENAME_NOREPLACE,
+};
+use log::{debug, error, info};
 
-use crate::{fuse_err, write::FileBuffers, StandardArdFile};
+use crate::{
+    dir::{DirHandles, DirListEntry},
+    fuse_err,
+    inode::InodeTable,
+    isolate,
+    meta::{is_meta_path, MetaHandles, MetaNode, META_DIR, META_DIR_NAME},
+    metrics::{Metrics, Op},
+    readahead::ReadAhead,
+    write::FileBuffers,
+    StandardArdFile,
+};
 
 pub struct ArhFuseSystem {
     pub arh: ArhFileSystem,
     pub ard: Option<StandardArdFile>,
-    inode_cache: HashMap<u64, (ArhPath, u64)>,
+    inodes: InodeTable,
     out_arh: PathBuf,
     write_buffers: FileBuffers,
-    /// Owner uid for files
+    dir_handles: DirHandles,
+    meta_handles: MetaHandles,
+    read_ahead: ReadAhead,
+    /// Owner uid for files, unless [`FuseOptions::owner_from_request`] is set
     uid: u32,
-    /// Owner gid for files
+    /// Owner gid for files, unless [`FuseOptions::owner_from_request`] is set
     gid: u32,
+    opts: FuseOptions,
+    /// Shared with the timers of running operations, which can't borrow the file system
+    metrics: Arc<Metrics>,
+}
+
+/// Mount options that affect how the file system is presented
+pub struct FuseOptions {
+    /// Whether to show files that have the hidden flag (e.g. soft-deleted files)
+    pub show_hidden: bool,
+    /// The game the archive is from, see [`ArhOptions::game`]
+    pub game: Game,
+    /// Whether to report the uid/gid of the requesting process as the owner of all files,
+    /// instead of a fixed owner. Useful when other users access the mount (`allow_other`).
+    /// The kernel must not cache attributes then (zero TTLs, no `kernel_cache`), or it
+    /// would check one user's access against another user's attributes.
+    pub owner_from_request: bool,
+    /// How long the kernel may cache file attributes
+    pub attr_ttl: Duration,
+    /// How long the kernel may cache name lookups (and the attributes returned with them)
+    pub entry_ttl: Duration,
+    /// How long the kernel may remember that a name doesn't exist. Zero disables negative
+    /// lookup caching.
+    pub negative_ttl: Duration,
+    /// Whether the archive is mounted read-only, see [`ArhOptions::read_only`]
+    pub read_only: bool,
+    /// Whether to check compressed files against their hash when they are read, see
+    /// [`ArhOptions::verify_on_read`]. Corrupted files fail with EIO.
+    pub verify_on_read: bool,
+    /// Whether to read back files after writing them, see
+    /// [`ArdWriter::verify_writes`](ardain::ArdWriter::verify_writes)
+    pub verify_writes: bool,
+    /// How many bytes of recently read file data to keep in memory, see
+    /// [`ArdReader::cache`](ardain::ArdReader::cache). 0 disables the cache.
+    pub read_cache_size: usize,
+    /// How many bytes to extend the ARD file by at a time when data is appended, see
+    /// [`ArdWriter::grow_ahead`](ardain::ArdWriter::grow_ahead). 0 disables it.
+    pub grow_ahead: u64,
+    /// When changes are written to disk, see [`SyncPolicy`]
+    pub sync_policy: SyncPolicy,
+    /// How many bytes the ARD file may grow by while mounted, see
+    /// [`ArdWriter::limit_len`](ardain::ArdWriter::limit_len). Writes past it fail with
+    /// ENOSPC.
+    pub max_grow: Option<u64>,
 }
 
-const TTL: Duration = Duration::from_secs(1);
 const INODE_ROOT: u64 = 1;
+pub const DEFAULT_TTL: Duration = Duration::from_secs(1);
+
+impl Default for FuseOptions {
+    fn default() -> Self {
+        Self {
+            show_hidden: false,
+            game: Game::default(),
+            owner_from_request: false,
+            attr_ttl: DEFAULT_TTL,
+            entry_ttl: DEFAULT_TTL,
+            negative_ttl: Duration::ZERO,
+            read_only: false,
+            verify_on_read: false,
+            verify_writes: false,
+            read_cache_size: 0,
+            grow_ahead: 0,
+            sync_policy: SyncPolicy::default(),
+            max_grow: None,
+        }
+    }
+}
+
+impl FuseOptions {
+    /// The options for loading the archive and creating ARD readers.
+    pub fn arh_options(&self) -> ArhOptions {
+        ArhOptions {
+            game: self.game,
+            read_only: self.read_only,
+            verify_on_read: self.verify_on_read,
+            ..Default::default()
+        }
+    }
+}
 
 impl ArhFuseSystem {
     pub fn load(
@@ -43,69 +141,260 @@ impl ArhFuseSystem {
         ard: Option<StandardArdFile>,
         out_arh: impl AsRef<Path>,
         (uid, gid): (u32, u32),
+        opts: FuseOptions,
     ) -> anyhow::Result<Self> {
-        let fs = ArhFileSystem::load(arh)?;
+        let fs = ArhFileSystem::load_with_options(arh, opts.arh_options())?;
         Ok(Self {
             arh: fs,
-            inode_cache: HashMap::default(),
+            inodes: InodeTable::default(),
             ard,
             out_arh: PathBuf::from(out_arh.as_ref()),
             write_buffers: FileBuffers::default(),
+            dir_handles: DirHandles::default(),
+            meta_handles: MetaHandles::default(),
+            read_ahead: ReadAhead::default(),
             uid,
             gid,
+            opts,
+            metrics: Arc::default(),
         })
     }
 
+    /// Returns the metadata for a file, unless the file should be hidden from the mount.
+    /// Directory placeholders are always hidden.
+    fn get_file_info(&self, path: &ArhPath) -> Option<&FileMeta> {
+        if ArhFileSystem::is_placeholder(path) {
+            return None;
+        }
+        self.arh
+            .get_file_info(path)
+            .filter(|f| self.opts.show_hidden || !f.is_flag(FileFlag::Hidden))
+    }
+
     fn get_inode_and_save(&mut self, full_path: ArhPath) -> u64 {
         let hash = Self::hash_name(&full_path);
-        self.inode_cache
-            .entry(hash)
-            .and_modify(|e| e.1 += 1)
-            .or_insert_with(|| (full_path, 1));
+        self.inodes.lookup(hash, full_path);
         hash
     }
 
-    fn get_path(&self, inode: u64) -> Option<&ArhPath> {
+    fn get_path(&self, inode: u64) -> Option<ArhPath> {
         if inode == INODE_ROOT {
-            return Some(&ARH_PATH_ROOT);
+            return Some(ARH_PATH_ROOT.clone());
+        }
+        self.inodes.get(inode).cloned()
+    }
+
+    /// Generates the contents of a file in the metadata directory.
+    fn meta_contents(&self, node: &MetaNode) -> Vec<u8> {
+        let cache = self.ard.as_ref().and_then(|ard| ard.reader.cache_stats());
+        node.contents(&self.arh, cache, &self.metrics)
+    }
+
+    /// Drops data read ahead or cached, after the ARD file was modified.
+    fn clear_read_caches(&mut self) {
+        self.read_ahead.clear();
+        if let Some(ard) = &mut self.ard {
+            ard.reader.clear_cache();
         }
-        self.inode_cache.get(&inode).map(|s| &s.0)
     }
 
     pub(crate) fn sync(&mut self, only_data: bool) -> Result<()> {
-        if !only_data {
+        if let Some(ard) = &mut self.ard {
+            ard.writer.trim()?;
+            // The ARH must not point to data that isn't on disk yet
+            ard.writer.get_mut().flush()?;
+            if !self.opts.read_only {
+                ard.shared.sync_data()?;
+            }
+        }
+        // Nothing can have changed
+        if !only_data && !self.arh.is_read_only() {
             self.arh
                 .sync(BufWriter::new(File::create(&self.out_arh)?))?;
         }
         Ok(())
     }
 
-    fn build_path(&self, parent_inode: u64, name: &OsStr) -> Option<Result<ArhPath>> {
-        let base = if parent_inode == INODE_ROOT {
-            ""
-        } else {
-            let Some(parent) = self.get_path(parent_inode) else {
-                return None;
-            };
-            parent
-        };
+    /// Syncs the file system after an operation that modified it, if the sync policy asks
+    /// for it. `closed` is whether a file's buffered data was written to the ARD file.
+    ///
+    /// Errors are only logged: the change was already made in memory, so the operation
+    /// succeeded, and the next sync (e.g. fsync or unmount) writes it again.
+    fn sync_after_change(&mut self, closed: bool) {
+        if !self.opts.sync_policy.syncs_after(closed) {
+            return;
+        }
+        if let Err(e) = self.sync(false) {
+            error!("[SYNC] could not write the changes to disk, retrying on the next sync: {e}");
+        }
+    }
+
+    /// Called after a handler panicked, see [`isolate!`](crate::isolate). Logs the panic and
+    /// syncs the file system, so the mount keeps working and changes that were made before
+    /// aren't lost if it is unmounted.
+    pub(crate) fn recover(&mut self, op: &str, payload: Box<dyn Any + Send>) {
+        let message = payload
+            .downcast_ref::<&str>()
+            .copied()
+            .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+            .unwrap_or("unknown cause");
+        error!("[{op}] handler panicked: {message}");
+        self.clear_read_caches();
+        // Syncing can panic too, if the handler left the state inconsistent
+        let synced = panic::catch_unwind(AssertUnwindSafe(|| {
+            if let Some(ard) = self.ard.as_mut() {
+                self.write_buffers.flush_all(&mut self.arh, ard)?;
+            }
+            self.sync(false)
+        }));
+        match synced {
+            Ok(Ok(())) => info!("[{op}] synced the file system after the panic"),
+            Ok(Err(e)) => error!("[{op}] could not sync after the panic: {e}"),
+            Err(_) => error!("[{op}] could not sync after the panic, data may be lost"),
+        }
+    }
+
+    fn build_path(&mut self, parent_inode: u64, name: &OsStr) -> Option<Result<ArhPath>> {
+        let parent = self.get_path(parent_inode)?;
         let name = name.to_str()?;
-        Some(ArhPath::normalize(format!("{base}/{name}")).map_err(Into::into))
+        // Joining reports names that don't fit with their length (ENAMETOOLONG). Paths
+        // created through the mount are always lowercase.
+        let path = parent
+            .try_join(name)
+            .and_then(|path| ArhPath::normalize(path.as_str()));
+        Some(path.map_err(Into::into))
     }
 
-    fn is_fuse_dir_empty(&self, path: &ArhPath) -> bool {
-        let Some(dir) = self.arh.get_dir(path) else {
-            return true;
+    /// Lists the visible entries of a directory, or returns `None` if `path` is not a
+    /// directory.
+    fn list_dir(&self, path: &ArhPath, ino: u64) -> Option<Vec<DirListEntry>> {
+        if let Some(node) = MetaNode::resolve(&self.arh, path) {
+            return self.list_meta_dir(path, ino, &node);
+        }
+        let DirEntry::Directory { children } = &self.arh.get_dir(path)?.entry else {
+            return None;
         };
-        let DirEntry::Directory { children } = &dir.entry else {
-            unreachable!()
+        let parent = match path.rsplit_once('/') {
+            Some(("", _)) | None => ARH_PATH_ROOT.clone(),
+            Some((parent, _)) => ArhPath::normalize(parent).unwrap(),
         };
-        if children.is_empty() {
-            return true;
+        let mut entries = vec![
+            DirListEntry {
+                path: path.clone(),
+                ino,
+                kind: FileType::Directory,
+                name: ".".to_string(),
+            },
+            DirListEntry {
+                ino: Self::hash_name(&parent),
+                path: parent,
+                kind: FileType::Directory,
+                name: "..".to_string(),
+            },
+        ];
+        for node in children.values() {
+            let child_path = path.join(&node.name);
+            let kind = match node.entry {
+                DirEntry::File if self.get_file_info(&child_path).is_none() => continue,
+                DirEntry::File => FileType::RegularFile,
+                DirEntry::Directory { .. } => FileType::Directory,
+            };
+            entries.push(DirListEntry {
+                ino: Self::hash_name(&child_path),
+                path: child_path,
+                kind,
+                name: node.name.clone(),
+            });
         }
-        children.len() == 1
-            && children[0].name == ".fuse_ard_dir"
-            && matches!(children[0].entry, DirEntry::File)
+        if ino == INODE_ROOT {
+            entries.push(DirListEntry {
+                path: ArhPath::normalize(META_DIR).unwrap(),
+                ino: Self::hash_name(META_DIR),
+                kind: FileType::Directory,
+                name: META_DIR_NAME.to_string(),
+            });
+        }
+        Some(entries)
+    }
+
+    fn list_meta_dir(
+        &self,
+        path: &ArhPath,
+        ino: u64,
+        node: &MetaNode,
+    ) -> Option<Vec<DirListEntry>> {
+        if !node.is_dir() {
+            return None;
+        }
+        let parent = path.rsplit_once('/').map_or("/", |(parent, _)| parent);
+        let parent = ArhPath::normalize(parent).unwrap();
+        let mut entries = vec![
+            DirListEntry {
+                path: path.clone(),
+                ino,
+                kind: FileType::Directory,
+                name: ".".to_string(),
+            },
+            DirListEntry {
+                ino: Self::hash_name(&parent),
+                path: parent,
+                kind: FileType::Directory,
+                name: "..".to_string(),
+            },
+        ];
+        for (name, kind) in node.children(&self.arh) {
+            let child_path = path.join(&name);
+            entries.push(DirListEntry {
+                ino: Self::hash_name(&child_path),
+                path: child_path,
+                kind,
+                name,
+            });
+        }
+        Some(entries)
+    }
+
+    /// Returns the attributes of a file or directory, including virtual metadata nodes.
+    fn make_attr(&self, path: &ArhPath, ino: u64, owner: (u32, u32)) -> Option<FileAttr> {
+        if let Some(node) = MetaNode::resolve(&self.arh, path) {
+            return Some(self.make_meta_attr(&node, ino, owner));
+        }
+        if let Some(dir) = self.arh.get_dir(path) {
+            return Some(self.make_dir_attr(dir, ino, owner));
+        }
+        self.get_file_info(path)
+            .map(|file| self.make_file_attr(file, ino, owner))
+    }
+
+    /// Returns the uid and gid to report as the owner of all files, for the given request.
+    fn owner(&self, req: &Request) -> (u32, u32) {
+        if self.opts.owner_from_request {
+            (req.uid(), req.gid())
+        } else {
+            (self.uid, self.gid)
+        }
+    }
+
+    /// Returns whether the requesting process may modify the archive.
+    ///
+    /// This matches the permissions in file attributes: the owner and the owner's group
+    /// can write, other users can only read. Root can always write.
+    fn can_write(&self, req: &Request) -> bool {
+        let (uid, gid) = self.owner(req);
+        req.uid() == 0 || req.uid() == uid || req.gid() == gid
+    }
+
+    /// Returns the listing of an open directory, or of its current contents if it wasn't
+    /// opened with `opendir`.
+    fn dir_listing(&mut self, ino: u64, fh: u64) -> std::result::Result<Arc<[DirListEntry]>, i32> {
+        if let Some(entries) = self.dir_handles.get(fh) {
+            return Ok(entries);
+        }
+        let Some(path) = self.get_path(ino) else {
+            debug!("[READDIR:{ino}] inode unknown");
+            return Err(ENOENT);
+        };
+        self.list_dir(&path, ino).map(Into::into).ok_or(ENOTDIR)
     }
 
     fn hash_name(name: &str) -> u64 {
@@ -117,7 +406,7 @@ impl ArhFuseSystem {
         hash.finish()
     }
 
-    fn make_dir_attr(&self, _dir: &DirNode, inode: u64) -> FileAttr {
+    fn make_dir_attr(&self, _dir: &DirNode, inode: u64, (uid, gid): (u32, u32)) -> FileAttr {
         FileAttr {
             ino: inode,
             size: 0,
@@ -129,15 +418,43 @@ impl ArhFuseSystem {
             kind: FileType::Directory,
             perm: 0o775,
             nlink: 2,
-            uid: self.uid,
-            gid: self.gid,
+            uid,
+            gid,
             rdev: 0,
             blksize: 0,
             flags: 0,
         }
     }
 
-    fn make_file_attr(&self, file: &FileMeta, inode: u64) -> FileAttr {
+    fn make_meta_attr(&self, node: &MetaNode, inode: u64, (uid, gid): (u32, u32)) -> FileAttr {
+        let (kind, size, perm) = if node.is_dir() {
+            (FileType::Directory, 0, 0o555)
+        } else if node.is_summary() {
+            (FileType::RegularFile, 0, 0o444)
+        } else {
+            let size = self.meta_contents(node).len() as u64;
+            (FileType::RegularFile, size, 0o444)
+        };
+        FileAttr {
+            ino: inode,
+            size,
+            blocks: 0,
+            atime: UNIX_EPOCH,
+            mtime: UNIX_EPOCH,
+            ctime: UNIX_EPOCH,
+            crtime: UNIX_EPOCH,
+            kind,
+            perm,
+            nlink: if node.is_dir() { 2 } else { 1 },
+            uid,
+            gid,
+            rdev: 0,
+            blksize: 0,
+            flags: 0,
+        }
+    }
+
+    fn make_file_attr(&self, file: &FileMeta, inode: u64, (uid, gid): (u32, u32)) -> FileAttr {
         let mut sz = file.uncompressed_size.into();
         if sz == 0 && file.compressed_size != 48 {
             sz = file.compressed_size.into();
@@ -155,8 +472,8 @@ impl ArhFuseSystem {
             // Qt marks files with nlink = 0 as deleted. Let's count the file itself as a hard link,
             // even if links aren't supported
             nlink: 1,
-            uid: self.uid,
-            gid: self.gid,
+            uid,
+            gid,
             rdev: 0,
             blksize: 0,
             flags: 0,
@@ -165,64 +482,97 @@ impl ArhFuseSystem {
 }
 
 impl Filesystem for ArhFuseSystem {
-    fn statfs(&mut self, _req: &Request, _ino: u64, reply: ReplyStatfs) {
-        let block_size = self.arh.block_size();
-        let max_size = u32::MAX.div_ceil(block_size) as u64;
-        reply.statfs(
-            max_size,
-            max_size,
-            max_size,
-            max_size,
-            max_size,
-            block_size,
-            ARH_PATH_MAX_LEN.try_into().unwrap(),
-            block_size,
-        )
-    }
-
-    fn lookup(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
-        let Some(name) = self.build_path(parent, name) else {
-            debug!("[LOOKUP] invalid parent inode {parent}");
-            reply.error(ENOENT);
-            return;
-        };
-        let name = fuse_err!(name, reply);
-        let ino = self.get_inode_and_save(name.clone()); // TODO this creates inodes for invalid files too
-        if let Some(dir) = self.arh.get_dir(&name) {
-            debug!("[LOOKUP:{name}] found directory with inode {ino}");
-            reply.entry(&TTL, &self.make_dir_attr(dir, ino), 0);
-            return;
+    fn init(&mut self, _req: &Request, config: &mut KernelConfig) -> std::result::Result<(), i32> {
+        // Let the kernel use readdirplus when it would otherwise send lookups for each entry
+        if config
+            .add_capabilities(FUSE_DO_READDIRPLUS | FUSE_READDIRPLUS_AUTO)
+            .is_err()
+        {
+            debug!("[INIT] readdirplus is not supported by the kernel");
         }
-        if let Some(file) = self.arh.get_file_info(&name) {
-            debug!("[LOOKUP:{name}] found file with inode {ino}");
-            reply.entry(&TTL, &self.make_file_attr(file, ino), 0);
-            return;
-        }
-        debug!("[LOOKUP:{name}] no match");
-        reply.error(ENOENT);
+        Ok(())
+    }
+
+    fn statfs(&mut self, _req: &Request, _ino: u64, reply: ReplyStatfs) {
+        isolate!(self, "STATFS", {
+            let block_size = self.arh.block_size();
+            let max_size = u32::MAX.div_ceil(block_size) as u64;
+            // With --max-grow, the free space is what is left of the budget, plus the gaps
+            // in the ARD file
+            let max_size = self
+                .ard
+                .as_ref()
+                .and_then(|ard| ard.writer.len_limit())
+                .map_or(max_size, |len| len / u64::from(block_size));
+            let stats = self
+                .arh
+                .get_dir(&ARH_PATH_ROOT)
+                .map(|root| root.stats)
+                .unwrap_or_default();
+            let free = max_size.saturating_sub(stats.compressed_size.div_ceil(block_size.into()));
+            reply.statfs(
+                max_size,
+                free,
+                free,
+                stats.file_count,
+                u64::from(u32::MAX).saturating_sub(stats.file_count),
+                block_size,
+                ARH_PATH_MAX_LEN.try_into().unwrap(),
+                block_size,
+            )
+        });
     }
 
-    fn getattr(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyAttr) {
-        let Some(name) = self.get_path(ino) else {
-            debug!("[GETATTR:{ino}] inode unknown");
+    fn lookup(&mut self, req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        isolate!(self, "LOOKUP", {
+            let _timer = self.metrics.time(Op::Lookup);
+            let Some(name) = self.build_path(parent, name) else {
+                debug!("[LOOKUP] invalid parent inode {parent}");
+                reply.error(ENOENT);
+                return;
+            };
+            let name = fuse_err!(name, reply);
+            // Only entries that exist get an inode, the kernel doesn't keep the others
+            if let Some(attr) = self.make_attr(&name, Self::hash_name(&name), self.owner(req)) {
+                let ino = self.get_inode_and_save(name.clone());
+                debug!("[LOOKUP:{name}] found {:?} with inode {ino}", attr.kind);
+                reply.entry(&self.opts.entry_ttl, &attr, 0);
+                return;
+            }
+            debug!("[LOOKUP:{name}] no match");
+            if self.opts.negative_ttl.is_zero() {
+                reply.error(ENOENT);
+                return;
+            }
+            // The kernel caches entries with inode 0 as negative lookups. Entries created
+            // through the mount replace them, and nothing else modifies the archive.
+            let attr = FileAttr {
+                ino: 0,
+                ..self.make_file_attr(&FileMeta::default(), 0, self.owner(req))
+            };
+            reply.entry(&self.opts.negative_ttl, &attr, 0);
+        });
+    }
+
+    fn getattr(&mut self, req: &Request<'_>, ino: u64, reply: ReplyAttr) {
+        isolate!(self, "GETATTR", {
+            let Some(name) = self.get_path(ino) else {
+                debug!("[GETATTR:{ino}] inode unknown");
+                reply.error(ENOENT);
+                return;
+            };
+            if let Some(attr) = self.make_attr(&name, ino, self.owner(req)) {
+                reply.attr(&self.opts.attr_ttl, &attr);
+                return;
+            }
+            debug!("[GETATTR:{name}] no match");
             reply.error(ENOENT);
-            return;
-        };
-        if let Some(dir) = self.arh.get_dir(name) {
-            reply.attr(&TTL, &self.make_dir_attr(dir, ino));
-            return;
-        }
-        if let Some(file) = self.arh.get_file_info(name) {
-            reply.attr(&TTL, &self.make_file_attr(file, ino));
-            return;
-        }
-        debug!("[GETATTR:{name}] no match");
-        reply.error(ENOENT);
+        });
     }
 
     fn setattr(
         &mut self,
-        _req: &Request,
+        req: &Request,
         ino: u64,
         _mode: Option<u32>,
         _uid: Option<u32>,
@@ -238,128 +588,186 @@ impl Filesystem for ArhFuseSystem {
         _flags: Option<u32>,
         reply: ReplyAttr,
     ) {
-        // We're only interested in truncate
-        if let (Some(fh), Some(sz)) = (fh.and_then(|fh| self.write_buffers.get_handle(fh)), size) {
-            fh.truncate(sz);
-        }
+        isolate!(self, "SETATTR", {
+            if size.is_some() && !self.can_write(req) {
+                reply.error(EACCES);
+                return;
+            }
+            // We're only interested in truncate
+            if let (Some(fh), Some(sz)) =
+                (fh.and_then(|fh| self.write_buffers.get_handle(fh)), size)
+            {
+                fh.truncate(sz);
+            }
 
-        let Some(name) = self.get_path(ino) else {
-            debug!("[SETATTR:{ino}] inode unknown");
+            let Some(name) = self.get_path(ino) else {
+                debug!("[SETATTR:{ino}] inode unknown");
+                reply.error(ENOENT);
+                return;
+            };
+
+            if let Some(file) = self.get_file_info(&name) {
+                reply.attr(
+                    &self.opts.attr_ttl,
+                    &self.make_file_attr(file, ino, self.owner(req)),
+                );
+                return;
+            }
             reply.error(ENOENT);
-            return;
-        };
+        });
+    }
 
-        if let Some(file) = self.arh.get_file_info(name) {
-            reply.attr(&TTL, &self.make_file_attr(file, ino));
-            return;
-        }
-        reply.error(ENOENT);
+    fn opendir(&mut self, _req: &Request, ino: u64, _flags: i32, reply: ReplyOpen) {
+        isolate!(self, "OPENDIR", {
+            let Some(path) = self.get_path(ino) else {
+                debug!("[OPENDIR:{ino}] inode unknown");
+                reply.error(ENOENT);
+                return;
+            };
+            let Some(entries) = self.list_dir(&path, ino) else {
+                reply.error(ENOTDIR);
+                return;
+            };
+            let fh = self.dir_handles.open(entries);
+            reply.opened(fh, 0);
+        });
     }
 
     fn readdir(
         &mut self,
         _req: &Request,
         ino: u64,
-        _fh: u64,
+        fh: u64,
         offset: i64,
         mut reply: ReplyDirectory,
     ) {
-        let Some(dir) = self.get_path(ino).and_then(|path| self.arh.get_dir(path)) else {
-            debug!("[READDIR:{ino}] inode unknown");
-            reply.error(ENOENT);
-            return;
-        };
+        isolate!(self, "READDIR", {
+            let entries = match self.dir_listing(ino, fh) {
+                Ok(entries) => entries,
+                Err(e) => {
+                    reply.error(e);
+                    return;
+                }
+            };
 
-        let DirEntry::Directory { children } = &dir.entry else {
-            reply.error(ENOTDIR);
-            return;
-        };
+            // See readdir(2), the offset is the position after the last entry that was sent
+            let skip = usize::try_from(offset).unwrap_or(0);
+            for (i, entry) in entries.iter().enumerate().skip(skip) {
+                if reply.add(entry.ino, (i + 1) as i64, entry.kind, &entry.name) {
+                    break;
+                }
+            }
 
-        let mut entries = vec![
-            (1, Self::hash_name(".") as i64, FileType::Directory, "."),
-            (1, Self::hash_name("..") as i64, FileType::Directory, ".."),
-        ];
+            reply.ok();
+        });
+    }
 
-        entries.extend(children.iter().map(|node| {
-            (
-                2,
-                Self::hash_name(&node.name) as i64,
-                match node.entry {
-                    DirEntry::File => FileType::RegularFile,
-                    DirEntry::Directory { .. } => FileType::Directory,
-                },
-                node.name.as_str(),
-            )
-        }));
+    fn readdirplus(
+        &mut self,
+        req: &Request,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        mut reply: ReplyDirectoryPlus,
+    ) {
+        isolate!(self, "READDIRPLUS", {
+            let entries = match self.dir_listing(ino, fh) {
+                Ok(entries) => entries,
+                Err(e) => {
+                    reply.error(e);
+                    return;
+                }
+            };
 
-        // See readdir(2), we need to skip over the already sent entries
-        let ofs_pos = entries
-            .iter()
-            .position(|e| e.1 == offset)
-            .map(|pos| pos as isize)
-            .unwrap_or(-1);
-        let ofs_skip = (ofs_pos + 1) as usize;
-
-        for entry in entries.into_iter().skip(ofs_skip) {
-            if reply.add(entry.0, entry.1, entry.2, entry.3) {
-                break;
+            let owner = self.owner(req);
+            let skip = usize::try_from(offset).unwrap_or(0);
+            for (i, entry) in entries.iter().enumerate().skip(skip) {
+                let Some(attr) = self.make_attr(&entry.path, entry.ino, owner) else {
+                    // Deleted since the directory was opened
+                    continue;
+                };
+                if reply.add(
+                    entry.ino,
+                    (i + 1) as i64,
+                    &entry.name,
+                    &self.opts.entry_ttl,
+                    &attr,
+                    0,
+                ) {
+                    break;
+                }
+                // Like lookup, except for "." and ".."
+                if i >= 2 {
+                    self.get_inode_and_save(entry.path.clone());
+                }
             }
-        }
 
-        reply.ok();
+            reply.ok();
+        });
+    }
+
+    fn releasedir(&mut self, _req: &Request, _ino: u64, fh: u64, _flags: i32, reply: ReplyEmpty) {
+        isolate!(self, "RELEASEDIR", {
+            self.dir_handles.release(fh);
+            reply.ok();
+        });
     }
 
     fn read(
         &mut self,
         _req: &Request,
         ino: u64,
-        _fh: u64,
+        fh: u64,
         offset: i64,
         size: u32,
         _flags: i32,
         _lock_owner: Option<u64>,
         reply: ReplyData,
     ) {
-        let Some(file) = self
-            .get_path(ino)
-            .and_then(|path| self.arh.get_file_info(path))
-        else {
-            debug!("[READ:{ino}] inode unknown");
-            reply.error(ENOENT);
-            return;
-        };
-        assert!(offset >= 0);
-        let Some(ard) = self.ard.as_mut() else {
-            reply.error(ENOTSUP);
-            return;
-        };
-        let data = fuse_err!(
-            ard.reader
-                .entry(file)
-                .skip_take(offset as u64, size.into())
-                .read(),
-            reply
-        );
-        reply.data(&data);
+        isolate!(self, "READ", {
+            let _timer = self.metrics.time(Op::Read);
+            let Some(path) = self.get_path(ino) else {
+                debug!("[READ:{ino}] inode unknown");
+                reply.error(ENOENT);
+                return;
+            };
+            assert!(offset >= 0);
+            if let Some(node) = MetaNode::resolve(&self.arh, &path) {
+                let data = match self.meta_handles.get(fh) {
+                    Some(data) => data,
+                    None => self.meta_contents(&node).into(),
+                };
+                let start = (offset as usize).min(data.len());
+                let end = start.saturating_add(size as usize).min(data.len());
+                reply.data(&data[start..end]);
+                return;
+            }
+            let Some(file) = self.get_file_info(&path).copied() else {
+                debug!("[READ:{ino}] inode unknown");
+                reply.error(ENOENT);
+                return;
+            };
+            let Some(ard) = self.ard.as_mut() else {
+                reply.error(ENOTSUP);
+                return;
+            };
+            let data = fuse_err!(
+                self.read_ahead.read(ino, &file, offset as u64, size, ard),
+                reply
+            );
+            reply.data(&data);
+        });
     }
 
     fn forget(&mut self, _req: &Request, ino: u64, nlookup: u64) {
-        let cnt = if let Some((_, cnt)) = self.inode_cache.get_mut(&ino) {
-            debug!("[FORGET] Decrementing inode count for {ino} (cnt -= {nlookup})");
-            *cnt = cnt.saturating_sub(nlookup);
-            *cnt
-        } else {
-            return;
-        };
-        if cnt == 0 {
-            debug!("[FORGET] Forgetting {ino} (cnt = 0)");
-            self.inode_cache.remove(&ino);
-        }
+        isolate!(self, "FORGET", {
+            self.inodes.forget(ino, nlookup);
+        });
     }
 
     fn mknod(
         &mut self,
-        _req: &Request,
+        req: &Request,
         parent: u64,
         name: &OsStr,
         _mode: u32,
@@ -367,127 +775,190 @@ impl Filesystem for ArhFuseSystem {
         _rdev: u32,
         reply: ReplyEntry,
     ) {
-        let Some(name) = self.build_path(parent, name) else {
-            debug!("[MKNOD] invalid parent inode {parent}");
-            reply.error(ENOENT);
-            return;
-        };
-        let name = fuse_err!(name, reply);
-        let inode = self.get_inode_and_save(name.clone());
-        let meta = *fuse_err!(self.arh.create_file(&name), reply);
-        reply.entry(&TTL, &self.make_file_attr(&meta, inode), 0);
+        isolate!(self, "MKNOD", {
+            let Some(name) = self.build_path(parent, name) else {
+                debug!("[MKNOD] invalid parent inode {parent}");
+                reply.error(ENOENT);
+                return;
+            };
+            let name = fuse_err!(name, reply);
+            if is_meta_path(&name) || !self.can_write(req) {
+                reply.error(EACCES);
+                return;
+            }
+            let meta = *fuse_err!(self.arh.create_file(&name), reply);
+            self.sync_after_change(false);
+            let inode = self.get_inode_and_save(name);
+            let attr = self.make_file_attr(&meta, inode, self.owner(req));
+            reply.entry(&self.opts.entry_ttl, &attr, 0);
+        });
     }
 
     fn mkdir(
         &mut self,
-        _req: &Request,
+        req: &Request,
         parent: u64,
         name: &OsStr,
         _mode: u32,
         _umask: u32,
         reply: ReplyEntry,
     ) {
-        let Some(name) = self.build_path(parent, name) else {
-            debug!("[MKDIR] invalid parent inode {parent}");
-            reply.error(ENOENT);
-            return;
-        };
-        let name = fuse_err!(name, reply);
-        if self.arh.exists(&name) {
-            debug!("[MKDIR] entry already exists {name}");
-            reply.error(EEXIST);
-            return;
-        }
-        // The ARH format has no concept of directories, we create a hidden file to generate
-        // the directory structure. Directories are automatically deleted when they are empty.
-        let placeholder = name.join(".fuse_ard_dir");
-        fuse_err!(self.arh.create_file(&placeholder), reply);
-        let inode = self.get_inode_and_save(placeholder);
-        let dir = self.arh.get_dir(&name).unwrap();
-        reply.entry(&TTL, &self.make_dir_attr(dir, inode), 0);
-    }
-
-    fn unlink(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
-        let Some(name) = self.build_path(parent, name) else {
-            debug!("[UNLINK] invalid parent inode {parent}");
-            reply.error(ENOENT);
-            return;
-        };
-        let name = fuse_err!(name, reply);
-        fuse_err!(self.arh.delete_file(&name), reply);
-        reply.ok();
+        isolate!(self, "MKDIR", {
+            let Some(name) = self.build_path(parent, name) else {
+                debug!("[MKDIR] invalid parent inode {parent}");
+                reply.error(ENOENT);
+                return;
+            };
+            let name = fuse_err!(name, reply);
+            if is_meta_path(&name) || !self.can_write(req) {
+                reply.error(EACCES);
+                return;
+            }
+            fuse_err!(self.arh.create_dir(&name), reply);
+            self.sync_after_change(false);
+            let inode = self.get_inode_and_save(name.clone());
+            let dir = self.arh.get_dir(&name).unwrap();
+            let attr = self.make_dir_attr(dir, inode, self.owner(req));
+            reply.entry(&self.opts.entry_ttl, &attr, 0);
+        });
     }
 
-    fn rmdir(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
-        let Some(name) = self.build_path(parent, name) else {
-            debug!("[RMDIR] invalid parent inode {parent}");
-            reply.error(ENOENT);
-            return;
-        };
-        let name = fuse_err!(name, reply);
-        if !self.is_fuse_dir_empty(&name) {
-            debug!("[RMDIR] dir {name} is not empty");
-            reply.error(ENOTEMPTY);
-            return;
-        }
-        // Recursive deletion is handled by the caller.
-        // We delete the hidden file we made if we created the directory
-        self.arh.delete_file(&name.join(".fuse_ard_dir")).ok();
-        fuse_err!(self.arh.delete_empty_dir(&name), reply);
-        reply.ok();
+    fn unlink(&mut self, req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        isolate!(self, "UNLINK", {
+            let Some(name) = self.build_path(parent, name) else {
+                debug!("[UNLINK] invalid parent inode {parent}");
+                reply.error(ENOENT);
+                return;
+            };
+            let name = fuse_err!(name, reply);
+            if !self.can_write(req) {
+                reply.error(EACCES);
+                return;
+            }
+            fuse_err!(self.arh.delete_file(&name), reply);
+            self.sync_after_change(false);
+            reply.ok();
+        });
+    }
+
+    fn rmdir(&mut self, req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        isolate!(self, "RMDIR", {
+            let Some(name) = self.build_path(parent, name) else {
+                debug!("[RMDIR] invalid parent inode {parent}");
+                reply.error(ENOENT);
+                return;
+            };
+            let name = fuse_err!(name, reply);
+            if !self.can_write(req) {
+                reply.error(EACCES);
+                return;
+            }
+            // Recursive deletion is handled by the caller
+            fuse_err!(self.arh.delete_empty_dir(&name), reply);
+            self.sync_after_change(false);
+            reply.ok();
+        });
     }
 
     fn rename(
         &mut self,
-        _req: &Request,
+        req: &Request,
         old_parent: u64,
         old_name: &OsStr,
         new_parent: u64,
         new_name: &OsStr,
-        _flags: u32,
+        flags: u32,
         reply: ReplyEmpty,
     ) {
-        let Some(old_name) = self.build_path(old_parent, old_name) else {
-            debug!("[RENAME] invalid parent inode {old_parent}");
-            reply.error(ENOENT);
-            return;
-        };
-        let old_name = fuse_err!(old_name, reply);
-        let Some(new_name) = self.build_path(new_parent, new_name) else {
-            debug!("[RENAME] invalid parent inode {new_parent}");
+        isolate!(self, "RENAME", {
+            let Some(old_name) = self.build_path(old_parent, old_name) else {
+                debug!("[RENAME] invalid parent inode {old_parent}");
+                reply.error(ENOENT);
+                return;
+            };
+            let old_name = fuse_err!(old_name, reply);
+            let Some(new_name) = self.build_path(new_parent, new_name) else {
+                debug!("[RENAME] invalid parent inode {new_parent}");
+                reply.error(ENOENT);
+                return;
+            };
+            let new_name = fuse_err!(new_name, reply);
+            if is_meta_path(&old_name) || is_meta_path(&new_name) || !self.can_write(req) {
+                reply.error(EACCES);
+                return;
+            }
+            if flags & RENAME_EXCHANGE != 0 {
+                // Only supported for files, which don't move in the ARD
+                if self.get_file_info(&old_name).is_none()
+                    || self.get_file_info(&new_name).is_none()
+                {
+                    debug!("[RENAME] exchange is only supported between files");
+                    reply.error(ENOTSUP);
+                    return;
+                }
+                fuse_err!(self.arh.exchange_files(&old_name, &new_name), reply);
+                self.sync_after_change(false);
+                reply.ok();
+                return;
+            }
+            if flags & RENAME_NOREPLACE != 0 && self.arh.exists(&new_name) {
+                reply.error(EEXIST);
+                return;
+            }
+            if self.arh.get_dir(&old_name).is_some() {
+                fuse_err!(self.arh.rename_dir(&old_name, &new_name), reply);
+                self.sync_after_change(false);
+                reply.ok();
+                return;
+            }
+            if self.get_file_info(&old_name).is_some() {
+                if self.arh.is_dir(&new_name) {
+                    reply.error(EISDIR);
+                    return;
+                }
+                fuse_err!(self.arh.rename_file_overwrite(&old_name, &new_name), reply);
+                self.sync_after_change(false);
+                reply.ok();
+                return;
+            }
+            debug!("[RENAME] no match {old_parent}");
             reply.error(ENOENT);
-            return;
-        };
-        let new_name = fuse_err!(new_name, reply);
-        if self.arh.get_dir(&old_name).is_some() {
-            fuse_err!(self.arh.rename_dir(&old_name, &new_name), reply);
-            reply.ok();
-            return;
-        }
-        if self.arh.get_file_info(&old_name).is_some() {
-            fuse_err!(self.arh.rename_file(&old_name, &new_name), reply);
-            reply.ok();
-            return;
-        }
-        debug!("[RENAME] no match {old_parent}");
+        });
     }
 
-    fn open(&mut self, _req: &Request, ino: u64, flags: i32, reply: ReplyOpen) {
-        if flags & O_RDWR != 0 || flags & O_WRONLY != 0 {
-            // We only care about writable fds
-            let Some(path) = self
+    fn open(&mut self, req: &Request, ino: u64, flags: i32, reply: ReplyOpen) {
+        isolate!(self, "OPEN", {
+            if flags & O_RDWR != 0 || flags & O_WRONLY != 0 {
+                // We only care about writable fds
+                if !self.can_write(req)
+                    || self.get_path(ino).is_some_and(|path| is_meta_path(&path))
+                {
+                    reply.error(EACCES);
+                    return;
+                }
+                let Some(path) = self
+                    .get_path(ino)
+                    .filter(|path| self.get_file_info(path).is_some())
+                else {
+                    debug!("[OPEN.W:{ino}] inode unknown");
+                    reply.error(ENOENT);
+                    return;
+                };
+                let fd = self.write_buffers.open(path);
+                reply.opened(fd, 0);
+                return;
+            }
+            let node = self
                 .get_path(ino)
-                .and_then(|path| self.arh.get_file_info(path).map(|_| path))
-            else {
-                debug!("[OPEN.W:{ino}] inode unknown");
-                reply.error(ENOENT);
+                .and_then(|path| MetaNode::resolve(&self.arh, &path));
+            if let Some(node) = node {
+                let contents = self.meta_contents(&node);
+                let fh = self.meta_handles.open(contents);
+                reply.opened(fh, FOPEN_DIRECT_IO);
                 return;
-            };
-            let fd = self.write_buffers.open(path.clone());
-            reply.opened(fd, 0);
-            return;
-        }
-        reply.opened(ino, 0)
+            }
+            reply.opened(ino, 0)
+        });
     }
 
     fn write(
@@ -502,32 +973,114 @@ impl Filesystem for ArhFuseSystem {
         _lock_owner: Option<u64>,
         reply: ReplyWrite,
     ) {
-        let Some(buf) = self.write_buffers.get_handle(fh) else {
-            debug!("[WRITE:{ino},{fh}] bad descriptor");
-            reply.error(EBADFD);
-            return;
-        };
-        buf.write(offset, data);
-        reply.written(data.len().try_into().unwrap());
+        isolate!(self, "WRITE", {
+            let _timer = self.metrics.time(Op::Write);
+            let Some(buf) = self.write_buffers.get_handle(fh) else {
+                debug!("[WRITE:{ino},{fh}] bad descriptor");
+                reply.error(EBADFD);
+                return;
+            };
+            let in_place = fuse_err!(
+                buf.write(offset, data, &mut self.arh, self.ard.as_mut()),
+                reply
+            );
+            if in_place {
+                self.read_ahead.release(ino);
+                if let Some(ard) = &mut self.ard {
+                    ard.reader.clear_cache();
+                }
+                self.sync_after_change(false);
+            }
+            reply.written(data.len().try_into().unwrap());
+        });
     }
 
     fn flush(&mut self, _req: &Request, _ino: u64, fh: u64, _owner: u64, reply: ReplyEmpty) {
-        let Some(buf) = self.write_buffers.get_handle(fh) else {
-            // Silently ignore (we only care about writable FDs getting close()d)
+        isolate!(self, "FLUSH", {
+            let _timer = self.metrics.time(Op::Flush);
+            let Some(buf) = self.write_buffers.get_handle(fh) else {
+                // Silently ignore (we only care about writable FDs getting close()d)
+                reply.ok();
+                return;
+            };
+            let Some(ard) = self.ard.as_mut() else {
+                reply.error(ENOTSUP);
+                return;
+            };
+            if fuse_err!(buf.flush(&mut self.arh, ard), reply) {
+                self.sync_after_change(true);
+            }
+            self.clear_read_caches();
             reply.ok();
-            return;
-        };
-        let Some(ard) = self.ard.as_mut() else {
-            reply.error(ENOTSUP);
-            return;
-        };
-        fuse_err!(buf.flush(&mut self.arh, ard), reply);
-        reply.ok();
+        });
+    }
+
+    fn fallocate(
+        &mut self,
+        req: &Request,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        length: i64,
+        mode: i32,
+        reply: ReplyEmpty,
+    ) {
+        isolate!(self, "FALLOCATE", {
+            if !self.can_write(req) {
+                reply.error(EACCES);
+                return;
+            }
+            // Punching holes, zeroing ranges, etc. are not supported
+            if mode != 0 {
+                reply.error(ENOTSUP);
+                return;
+            }
+            let Some(meta) = self
+                .get_path(ino)
+                .and_then(|path| self.get_file_info(&path))
+                .copied()
+            else {
+                debug!("[FALLOCATE:{ino}] inode unknown");
+                reply.error(ENOENT);
+                return;
+            };
+            let new_size = offset.saturating_add(length).try_into().unwrap_or(0);
+            if u64::from(meta.actual_size()) >= new_size {
+                reply.ok();
+                return;
+            }
+            // Reserving space discards the file's contents, so this only works for new files.
+            // Callers like posix_fallocate fall back to writing zeros.
+            let Some(ard) = self.ard.as_mut().filter(|_| meta.compressed_size == 0) else {
+                reply.error(ENOTSUP);
+                return;
+            };
+            fuse_err!(
+                ArdFileAllocator::new(&mut self.arh, &mut ard.writer).reserve(meta.id, new_size),
+                reply
+            );
+            fuse_err!(ard.writer.get_mut().flush().map_err(Error::from), reply);
+            self.clear_read_caches();
+            self.sync_after_change(false);
+            reply.ok();
+        });
     }
 
     fn fsync(&mut self, _req: &Request, _ino: u64, _fh: u64, only_data: bool, reply: ReplyEmpty) {
-        fuse_err!(self.sync(only_data), reply);
-        reply.ok();
+        isolate!(self, "FSYNC", {
+            // Write the changes of all open files together, so the ARH is only synced once
+            let flushed = match self.ard.as_mut() {
+                Some(ard) => self.write_buffers.flush_all(&mut self.arh, ard),
+                None => Ok(0),
+            };
+            // Other files may have been written before an error
+            if !matches!(flushed, Ok(0)) {
+                self.clear_read_caches();
+            }
+            fuse_err!(self.sync(only_data), reply);
+            fuse_err!(flushed, reply);
+            reply.ok();
+        });
     }
 
     fn fsyncdir(
@@ -538,29 +1091,40 @@ impl Filesystem for ArhFuseSystem {
         only_data: bool,
         reply: ReplyEmpty,
     ) {
-        fuse_err!(self.sync(only_data), reply);
-        reply.ok();
+        isolate!(self, "FSYNCDIR", {
+            fuse_err!(self.sync(only_data), reply);
+            reply.ok();
+        });
     }
 
     fn release(
         &mut self,
         _req: &Request,
-        _ino: u64,
+        ino: u64,
         fh: u64,
         _flags: i32,
         _lock_owner: Option<u64>,
         _flush: bool,
         reply: ReplyEmpty,
     ) {
-        self.write_buffers.release(fh);
-        reply.ok();
+        isolate!(self, "RELEASE", {
+            if self.get_path(ino).is_some_and(|path| is_meta_path(&path)) {
+                self.meta_handles.release(fh);
+                reply.ok();
+                return;
+            }
+            self.write_buffers.release(fh);
+            self.read_ahead.release(ino);
+            reply.ok();
+        });
     }
 
     fn destroy(&mut self) {
         if let Some(ard) = self.ard.as_mut() {
-            self.write_buffers
-                .flush_all(&mut self.arh, ard)
-                .expect("could not sync write buffers, data may be lost");
+            // e.g. past --max-grow: the other changes must still be saved
+            if let Err(e) = self.write_buffers.flush_all(&mut self.arh, ard) {
+                error!("could not write buffered changes, they are lost: {e}");
+            }
         }
         self.sync(false)
             .expect("could not sync file system, data may be lost");
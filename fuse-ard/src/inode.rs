@@ -0,0 +1,49 @@
+use std::collections::HashMap;
+
+use ardain::path::ArhPath;
+use log::debug;
+
+/// Maps inode numbers to paths, for inodes the kernel knows about.
+///
+/// Entries are kept until the kernel forgets them, so the table is bounded by the kernel's
+/// own inode cache, which it shrinks under memory pressure. Names that don't exist never
+/// get an entry.
+#[derive(Default)]
+pub struct InodeTable {
+    entries: HashMap<u64, InodeEntry>,
+}
+
+struct InodeEntry {
+    path: ArhPath,
+    /// The kernel's lookup count, see [`fuser::Filesystem::forget`]
+    lookups: u64,
+}
+
+impl InodeTable {
+    /// Records a lookup of `path` by the kernel, which must be matched by a later
+    /// [`Self::forget`].
+    pub fn lookup(&mut self, inode: u64, path: ArhPath) {
+        self.entries
+            .entry(inode)
+            .or_insert(InodeEntry { path, lookups: 0 })
+            .lookups += 1;
+    }
+
+    pub fn get(&self, inode: u64) -> Option<&ArhPath> {
+        self.entries.get(&inode).map(|entry| &entry.path)
+    }
+
+    /// Decrements the lookup count of the inode, and drops it once the kernel doesn't
+    /// reference it anymore.
+    pub fn forget(&mut self, inode: u64, nlookup: u64) {
+        let Some(entry) = self.entries.get_mut(&inode) else {
+            return;
+        };
+        debug!("[FORGET] Decrementing inode count for {inode} (cnt -= {nlookup})");
+        entry.lookups = entry.lookups.saturating_sub(nlookup);
+        if entry.lookups == 0 {
+            debug!("[FORGET] Forgetting {inode} (cnt = 0)");
+            self.entries.remove(&inode);
+        }
+    }
+}
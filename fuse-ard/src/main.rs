@@ -1,23 +1,42 @@
 use std::{
     fs::{File, OpenOptions},
     io::{BufReader, BufWriter},
+    sync::Arc,
+    time::Duration,
 };
 
 use anyhow::Result;
-use ardain::{ArdReader, ArdWriter};
-use clap::{arg, Command};
+use ardain::{
+    game::Game,
+    opts::SyncPolicy,
+    snapshot::{Snapshot, SnapshotWriter},
+    ArdReader, ArdWriter,
+};
+use clap::{
+    arg,
+    builder::{PossibleValuesParser, TypedValueParser},
+    Command,
+};
 use env_logger::Env;
-use fs::ArhFuseSystem;
+use fs::{ArhFuseSystem, FuseOptions, DEFAULT_TTL};
 use fuser::MountOption;
-use log::info;
+use log::{info, warn};
 
+mod dir;
 mod error;
 mod fs;
+mod inode;
+mod meta;
+mod metrics;
+mod readahead;
 mod write;
 
 pub struct StandardArdFile {
     pub reader: ArdReader<BufReader<File>>,
-    pub writer: ArdWriter<BufWriter<File>>,
+    pub writer: ArdWriter<BufWriter<SnapshotWriter<File>>>,
+    /// For reads in other threads, which must not move the cursor of the other handles (see
+    /// [`ardain::PositionalReader`])
+    pub shared: Arc<File>,
 }
 
 fn main() {
@@ -27,6 +46,27 @@ fn main() {
         .arg(arg!(--ard <FILE> "path to the .ard file. If absent, some operations won't be available. Note that the .ard file will always be overwritten unless --readonly is present!"))
         .arg(arg!(--arhout <FILE> "path to the .arh file to write modifications to. If absent, the main .arh file will be overwritten!"))
         .arg(arg!(-r --readonly "mount the archive as read-only"))
+        .arg(arg!(--"show-hidden" "show files that are marked as hidden (e.g. soft-deleted with ard-tools rm --soft)"))
+        .arg(arg!(--game <GAME> "the game the archive is from, which determines the compression types used for new files (default: xc3)")
+            .value_parser(PossibleValuesParser::new(Game::ALL.map(Game::name)).map(|s| s.parse::<Game>().unwrap())))
+        .arg(arg!(--"attr-ttl" <SECONDS> "how long the kernel may cache file attributes (default: 1)")
+            .value_parser(parse_seconds))
+        .arg(arg!(--"entry-ttl" <SECONDS> "how long the kernel may cache name lookups (default: 1)")
+            .value_parser(parse_seconds))
+        .arg(arg!(--"negative-ttl" <SECONDS> "how long the kernel may remember that a file doesn't exist, avoiding repeated lookups (default: 0, disabled)")
+            .value_parser(parse_seconds))
+        .arg(arg!(--"allow-other" "allow other users to access the mount. Requires user_allow_other in /etc/fuse.conf"))
+        .arg(arg!(--"owner-from-request" "report the user accessing a file as its owner, instead of the user that mounted the archive. Without this, other users can only read files. The kernel can't cache attributes with this, so --attr-ttl and --entry-ttl are ignored"))
+        .arg(arg!(--"read-cache" <MIB> "keep up to this many MiB of recently read file data in memory, so files that are read often (e.g. fonts) are only read and decompressed once (default: 0, disabled)")
+            .value_parser(clap::value_parser!(usize)))
+        .arg(arg!(--"grow-ahead" <MIB> "extend the .ard file this many MiB at a time when files are added, so it is less fragmented on disk. The unused space is removed when the archive is synced (default: 0, disabled)")
+            .value_parser(clap::value_parser!(u64)))
+        .arg(arg!(--"verify-writes" "read back every file after writing it to the .ard file, and fail with an I/O error if it doesn't match, e.g. for archives on unreliable external drives"))
+        .arg(arg!(--"verify-reads" "check compressed files against the hash stored in the archive when they are read. Corrupted files fail with an I/O error instead of returning bad data"))
+        .arg(arg!(--"sync-policy" <POLICY> "when changes are written to disk: after every change (always), when a file that was written to is closed (on-close), or only on fsync and unmount (manual). Each sync rewrites the whole .arh file, so manual is the fastest for large imports (default: on-close)")
+            .value_parser(SyncPolicy::value_parser()))
+        .arg(arg!(--"max-grow" <SIZE> "the most the .ard file may grow by while mounted, e.g. 2G (K, M and G suffixes are supported). Writes that need more space fail with \"no space left on device\", usually when the file is closed, and df shows the remaining space (default: unlimited)")
+            .value_parser(ardain::opts::parse_size))
         .arg(arg!(-d --debug "enable FUSE debugging and debug logs"));
     let matches = cmd.get_matches();
 
@@ -39,44 +79,121 @@ fn main() {
     .init();
 
     let (uid, gid) = if cfg!(unix) {
-        unsafe { (libc::geteuid(), libc::getuid()) }
+        unsafe { (libc::geteuid(), libc::getegid()) }
     } else {
         (0, 0)
     };
 
-    info!("File system will use uid={uid}, gid={gid}");
+    let owner_from_request = matches.get_flag("owner-from-request");
+    if owner_from_request {
+        info!("File system will report the requesting user as the owner of all files");
+    } else {
+        info!("File system will use uid={uid}, gid={gid}");
+    }
 
     let arh_path = matches.get_one::<String>("arh").unwrap();
     let arh = File::open(arh_path).unwrap();
+    let out_arh = matches.get_one::<String>("arhout").unwrap_or(arh_path);
+    let mut opts = FuseOptions {
+        show_hidden: matches.get_flag("show-hidden"),
+        game: matches.get_one::<Game>("game").copied().unwrap_or_default(),
+        owner_from_request,
+        read_only: matches.get_flag("readonly"),
+        attr_ttl: matches
+            .get_one::<Duration>("attr-ttl")
+            .copied()
+            .unwrap_or(DEFAULT_TTL),
+        entry_ttl: matches
+            .get_one::<Duration>("entry-ttl")
+            .copied()
+            .unwrap_or(DEFAULT_TTL),
+        negative_ttl: matches
+            .get_one::<Duration>("negative-ttl")
+            .copied()
+            .unwrap_or_default(),
+        verify_on_read: matches.get_flag("verify-reads"),
+        verify_writes: matches.get_flag("verify-writes"),
+        read_cache_size: matches
+            .get_one::<usize>("read-cache")
+            .map_or(0, |mib| mib.saturating_mul(1024 * 1024)),
+        grow_ahead: matches
+            .get_one::<u64>("grow-ahead")
+            .map_or(0, |mib| mib.saturating_mul(1024 * 1024)),
+        sync_policy: matches
+            .get_one::<SyncPolicy>("sync-policy")
+            .copied()
+            .unwrap_or_default(),
+        max_grow: matches.get_one::<u64>("max-grow").copied(),
+    };
+    if owner_from_request {
+        // The attributes depend on the user, so one user's must not be reused for another
+        if ["attr-ttl", "entry-ttl"]
+            .iter()
+            .any(|id| matches.value_source(id).is_some())
+        {
+            warn!("--attr-ttl and --entry-ttl are ignored with --owner-from-request");
+        }
+        opts.attr_ttl = Duration::ZERO;
+        opts.entry_ttl = Duration::ZERO;
+    }
     let ard = matches
         .get_one::<String>("ard")
-        .map(|path| StandardArdFile::new(path).unwrap());
-    let out_arh = matches.get_one::<String>("arhout").unwrap_or(arh_path);
-    let fs = ArhFuseSystem::load(arh, ard, out_arh, (uid, gid)).unwrap();
+        .map(|path| StandardArdFile::new(path, &opts).unwrap());
+    let fs = ArhFuseSystem::load(arh, ard, out_arh, (uid, gid), opts).unwrap();
 
     let mount_point = matches.get_one::<String>("mount_point").unwrap();
     let mut opts = vec![
         MountOption::NoExec,
         MountOption::NoAtime,
         MountOption::DefaultPermissions,
-        MountOption::CUSTOM("kernel_cache".to_string()),
     ];
+    if !owner_from_request {
+        opts.push(MountOption::CUSTOM("kernel_cache".to_string()));
+    }
     if debug {
         opts.push(MountOption::CUSTOM("debug".to_string()));
     }
     if matches.get_flag("readonly") {
         opts.push(MountOption::RO);
     }
+    if matches.get_flag("allow-other") {
+        opts.push(MountOption::AllowOther);
+    }
     fuser::mount2(fs, mount_point, &opts).unwrap();
 }
 
+fn parse_seconds(s: &str) -> Result<Duration, String> {
+    s.parse::<f64>()
+        .ok()
+        .and_then(|secs| Duration::try_from_secs_f64(secs).ok())
+        .ok_or_else(|| format!("invalid duration \"{s}\", expected a number of seconds"))
+}
+
 impl StandardArdFile {
-    pub fn new(path: &str) -> Result<Self> {
+    pub fn new(path: &str, opts: &FuseOptions) -> Result<Self> {
         let file = OpenOptions::new().read(true).write(true).open(path)?;
         let for_write = file.try_clone()?;
+        let shared = Arc::new(file.try_clone()?);
+        let snapshot = Snapshot::open(path)?;
+        if snapshot.is_some() {
+            info!(
+                "Recording overwritten data in {}",
+                Snapshot::dir_for(path).display()
+            );
+        }
+        let mut writer = ArdWriter::new(BufWriter::new(SnapshotWriter::new(for_write, snapshot)))
+            .grow_ahead(opts.grow_ahead)
+            .verify_writes(opts.verify_writes);
+        if let Some(max_grow) = opts.max_grow {
+            let max_len = file.metadata()?.len().saturating_add(max_grow);
+            info!("The .ard file may grow up to {max_len} bytes");
+            writer = writer.limit_len(max_len);
+        }
         Ok(Self {
-            reader: ArdReader::new(BufReader::new(file)),
-            writer: ArdWriter::new(BufWriter::new(for_write)),
+            reader: ArdReader::with_options(BufReader::new(file), &opts.arh_options())
+                .cache(opts.read_cache_size),
+            writer,
+            shared,
         })
     }
 }
@@ -0,0 +1,329 @@
+//! The virtual `/.ard-meta` directory, which exposes archive metadata as read-only files:
+//!
+//! - `stats.json`: totals for the whole archive, how the file table is used, and the counters
+//!   of the read cache
+//! - `files.csv`: one line per file, in the same format as `ard-tools manifest export --no-hash`
+//! - `files/<path>.meta`: the metadata of a single file, as JSON
+//! - `metrics`: the number and latency of FUSE operations, in the Prometheus text format
+//!
+//! Hidden files are always included, their flags say whether they are hidden. File contents
+//! are generated when the file is opened, and kept until it is closed, so reads at different
+//! offsets see the same version. They are read with direct I/O, as the size reported before
+//! opening may not match.
+
+use std::{collections::HashMap, fmt::Write, sync::Arc};
+
+use ardain::{
+    path::{ArhPath, ARH_PATH_ROOT},
+    ArhFileSystem, CacheStats, DirEntry, FileFlag, FileMeta,
+};
+use fuser::FileType;
+use serde::Serialize;
+
+use crate::metrics::Metrics;
+
+pub const META_DIR: &str = "/.ard-meta";
+pub const META_DIR_NAME: &str = ".ard-meta";
+const FILES_DIR: &str = "/.ard-meta/files";
+const META_EXT: &str = ".meta";
+
+pub enum MetaNode {
+    /// The metadata directory itself
+    Root,
+    /// A directory in `files/`, mirroring an archive directory
+    Dir(ArhPath),
+    Stats,
+    FileList,
+    Metrics,
+    /// The metadata of an archive file
+    File(ArhPath),
+}
+
+/// The contents of open metadata files.
+#[derive(Default)]
+pub struct MetaHandles {
+    open_files: HashMap<u64, Arc<[u8]>>,
+    next_fh: u64,
+}
+
+/// Returns whether `path` is in the metadata directory, where nothing can be created.
+pub fn is_meta_path(path: &str) -> bool {
+    path.strip_prefix(META_DIR)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+}
+
+impl MetaNode {
+    /// Returns the virtual node at `path`, or `None` if `path` is outside the metadata
+    /// directory or doesn't exist.
+    pub fn resolve(arh: &ArhFileSystem, path: &str) -> Option<Self> {
+        match path.strip_prefix(META_DIR)? {
+            "" => return Some(Self::Root),
+            "/stats.json" => return Some(Self::Stats),
+            "/files.csv" => return Some(Self::FileList),
+            "/metrics" => return Some(Self::Metrics),
+            _ => {}
+        }
+        let rest = path.strip_prefix(FILES_DIR)?;
+        if rest.is_empty() {
+            return Some(Self::Dir(ARH_PATH_ROOT.clone()));
+        }
+        if !rest.starts_with('/') {
+            return None;
+        }
+        if let Some(file) = rest.strip_suffix(META_EXT) {
+            let file = ArhPath::normalize(file).ok()?;
+            if arh.is_file(&file) {
+                return Some(Self::File(file));
+            }
+        }
+        let dir = ArhPath::normalize(rest).ok()?;
+        arh.is_dir(&dir).then_some(Self::Dir(dir))
+    }
+
+    pub fn is_dir(&self) -> bool {
+        matches!(self, Self::Root | Self::Dir(_))
+    }
+
+    /// Returns whether the contents cover the whole archive. They are only generated when
+    /// the file is opened, so its size is reported as 0, like files in `/proc`.
+    pub fn is_summary(&self) -> bool {
+        matches!(self, Self::Stats | Self::FileList | Self::Metrics)
+    }
+
+    /// Lists the names and types of the children of a directory node.
+    pub fn children(&self, arh: &ArhFileSystem) -> Vec<(String, FileType)> {
+        match self {
+            Self::Root => vec![
+                ("files".to_string(), FileType::Directory),
+                ("files.csv".to_string(), FileType::RegularFile),
+                ("metrics".to_string(), FileType::RegularFile),
+                ("stats.json".to_string(), FileType::RegularFile),
+            ],
+            Self::Dir(dir) => {
+                let Some(DirEntry::Directory { children }) = arh.get_dir(dir).map(|d| &d.entry)
+                else {
+                    return Vec::new();
+                };
+                children
+                    .values()
+                    .map(|node| match node.entry {
+                        DirEntry::File => {
+                            (format!("{}{META_EXT}", node.name), FileType::RegularFile)
+                        }
+                        DirEntry::Directory { .. } => (node.name.clone(), FileType::Directory),
+                    })
+                    .collect()
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    /// Generates the contents of a file node. `cache` is included in the stats, if the ARD
+    /// reader has a cache.
+    pub fn contents(
+        &self,
+        arh: &ArhFileSystem,
+        cache: Option<CacheStats>,
+        metrics: &Metrics,
+    ) -> Vec<u8> {
+        match self {
+            Self::Root | Self::Dir(_) => Vec::new(),
+            Self::Metrics => metrics.render().into_bytes(),
+            Self::Stats => {
+                let stats = arh.get_dir(&ARH_PATH_ROOT).unwrap().stats;
+                let alloc = arh.alloc_stats();
+                let usage = arh.file_table_usage();
+                let stats = Stats {
+                    files: stats.file_count,
+                    hidden_files: all_files(arh)
+                        .filter(|(_, meta)| meta.is_flag(FileFlag::Hidden))
+                        .count(),
+                    directories: stats.dir_count,
+                    compressed_size: stats.compressed_size,
+                    uncompressed_size: stats.uncompressed_size,
+                    block_size: arh.block_size(),
+                    file_table: FileTableStats {
+                        entries: usage.entries,
+                        live: usage.live,
+                        recycled: usage.recycled,
+                        unreferenced: usage.unreferenced,
+                    },
+                    allocation: AllocationStats {
+                        in_place: alloc.counts.in_place,
+                        reused: alloc.counts.reused,
+                        appended: alloc.counts.appended,
+                        occupied_bytes: alloc.occupied_bytes,
+                        rounding_waste: alloc.rounding_waste,
+                        free_bytes: alloc.free_bytes,
+                        free_runs: alloc.free_runs,
+                        largest_free_run: alloc.largest_free_run,
+                        fragmentation: (alloc.fragmentation() * 1000.0).round() / 1000.0,
+                    },
+                    read_cache: cache.map(|cache| ReadCacheStats {
+                        hits: cache.hits,
+                        misses: cache.misses,
+                        evictions: cache.evictions,
+                        entries: cache.entries,
+                        size: cache.size,
+                        capacity: cache.capacity,
+                    }),
+                };
+                to_json(&stats)
+            }
+            Self::FileList => {
+                // The columns of `ard-tools manifest export`, with an empty hash
+                let mut out = "path,id,offset,compressed_size,uncompressed_size,size,flags,hash\n"
+                    .to_string();
+                for (path, meta) in all_files(arh) {
+                    let entry = FileEntry::new(&path, meta);
+                    writeln!(
+                        out,
+                        "{},{},{},{},{},{},{},",
+                        csv_field(entry.path),
+                        entry.id,
+                        entry.offset,
+                        entry.compressed_size,
+                        entry.uncompressed_size,
+                        entry.size,
+                        entry.flags.join("|")
+                    )
+                    .unwrap();
+                }
+                out.into_bytes()
+            }
+            Self::File(path) => match arh.get_file_info(path) {
+                Some(meta) => to_json(&FileEntry::new(path, meta)),
+                None => Vec::new(),
+            },
+        }
+    }
+}
+
+/// The format of `stats.json`
+#[derive(Serialize)]
+struct Stats {
+    files: u64,
+    hidden_files: usize,
+    directories: u64,
+    compressed_size: u64,
+    uncompressed_size: u64,
+    block_size: u32,
+    file_table: FileTableStats,
+    allocation: AllocationStats,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    read_cache: Option<ReadCacheStats>,
+}
+
+/// See [`FileTableUsage`](ardain::FileTableUsage)
+#[derive(Serialize)]
+struct FileTableStats {
+    entries: usize,
+    live: usize,
+    recycled: Vec<u32>,
+    unreferenced: Vec<u32>,
+}
+
+/// See [`AllocStats`](ardain::AllocStats)
+#[derive(Serialize)]
+struct AllocationStats {
+    in_place: u64,
+    reused: u64,
+    appended: u64,
+    occupied_bytes: u64,
+    rounding_waste: u64,
+    free_bytes: u64,
+    free_runs: usize,
+    largest_free_run: u64,
+    fragmentation: f64,
+}
+
+/// See [`CacheStats`]
+#[derive(Serialize)]
+struct ReadCacheStats {
+    hits: u64,
+    misses: u64,
+    evictions: u64,
+    entries: usize,
+    size: usize,
+    capacity: usize,
+}
+
+/// The metadata of a file, in `files/<path>.meta` and as a line of `files.csv`
+#[derive(Serialize)]
+struct FileEntry<'a> {
+    path: &'a str,
+    id: u32,
+    offset: u64,
+    compressed_size: u32,
+    uncompressed_size: u32,
+    /// The size of the file after decompression
+    size: u32,
+    flags: Vec<&'static str>,
+}
+
+impl<'a> FileEntry<'a> {
+    fn new(path: &'a ArhPath, meta: &FileMeta) -> Self {
+        Self {
+            path,
+            id: meta.id,
+            offset: meta.offset,
+            compressed_size: meta.compressed_size,
+            uncompressed_size: meta.uncompressed_size,
+            size: meta.actual_size(),
+            flags: flag_names(meta),
+        }
+    }
+}
+
+impl MetaHandles {
+    pub fn open(&mut self, contents: Vec<u8>) -> u64 {
+        self.next_fh += 1;
+        self.open_files.insert(self.next_fh, contents.into());
+        self.next_fh
+    }
+
+    pub fn get(&self, fh: u64) -> Option<Arc<[u8]>> {
+        self.open_files.get(&fh).cloned()
+    }
+
+    pub fn release(&mut self, fh: u64) {
+        self.open_files.remove(&fh);
+    }
+}
+
+/// Returns all files in the archive, including hidden files, sorted by path.
+fn all_files(arh: &ArhFileSystem) -> impl Iterator<Item = (ArhPath, &FileMeta)> {
+    let mut paths = arh.get_dir(&ARH_PATH_ROOT).unwrap().children_paths();
+    paths.sort();
+    paths.into_iter().filter_map(|path| {
+        let path = ArhPath::normalize(path).ok()?;
+        let meta = arh.get_file_info(&path)?;
+        Some((path, meta))
+    })
+}
+
+fn flag_names(meta: &FileMeta) -> Vec<&'static str> {
+    [
+        (FileFlag::Hidden, "hidden"),
+        (FileFlag::HasXbc1Header, "xbc1"),
+    ]
+    .into_iter()
+    .filter(|&(flag, _)| meta.is_flag(flag))
+    .map(|(_, name)| name)
+    .collect()
+}
+
+fn to_json(value: &impl Serialize) -> Vec<u8> {
+    let mut out = serde_json::to_vec_pretty(value).unwrap();
+    out.push(b'\n');
+    out
+}
+
+/// Quotes a CSV field if needed, like `ard-tools manifest export`
+fn csv_field(text: &str) -> String {
+    if text.contains([',', '"', '\n']) {
+        format!("\"{}\"", text.replace('"', "\"\""))
+    } else {
+        text.to_string()
+    }
+}
@@ -0,0 +1,111 @@
+//! Counters and latencies of FUSE operations, served in the Prometheus text format by
+//! `/.ard-meta/metrics`.
+
+use std::{
+    fmt::Write,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Instant,
+};
+
+/// The operations that are measured
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    Lookup,
+    Read,
+    Write,
+    Flush,
+}
+
+/// The upper bounds of the latency histogram buckets, in seconds
+const BUCKETS: [f64; 8] = [0.0001, 0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 1.0];
+
+#[derive(Default)]
+pub struct Metrics {
+    ops: [OpMetrics; Op::ALL.len()],
+}
+
+#[derive(Default)]
+struct OpMetrics {
+    count: AtomicU64,
+    total_nanos: AtomicU64,
+    /// The number of operations that took longer than the previous bound, but at most the
+    /// bound at the same index in [`BUCKETS`]. Slower operations are only in `count`.
+    buckets: [AtomicU64; BUCKETS.len()],
+}
+
+/// Measures an operation until it is dropped, see [`Metrics::time`].
+pub struct OpTimer {
+    metrics: Arc<Metrics>,
+    op: Op,
+    start: Instant,
+}
+
+impl Op {
+    const ALL: [Op; 4] = [Op::Lookup, Op::Read, Op::Write, Op::Flush];
+
+    fn name(self) -> &'static str {
+        match self {
+            Op::Lookup => "lookup",
+            Op::Read => "read",
+            Op::Write => "write",
+            Op::Flush => "flush",
+        }
+    }
+}
+
+impl Metrics {
+    /// Starts measuring an operation. It is recorded when the returned timer is dropped, so
+    /// handlers that return early are measured too.
+    pub fn time(self: &Arc<Self>, op: Op) -> OpTimer {
+        OpTimer {
+            metrics: self.clone(),
+            op,
+            start: Instant::now(),
+        }
+    }
+
+    /// Formats the metrics in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        const NAME: &str = "fuse_ard_operation_duration_seconds";
+        let mut out = format!(
+            "# HELP {NAME} Time spent handling FUSE operations.\n# TYPE {NAME} histogram\n"
+        );
+        for op in Op::ALL {
+            let metrics = &self.ops[op as usize];
+            let name = op.name();
+            let mut cumulative = 0;
+            for (bound, bucket) in BUCKETS.iter().zip(&metrics.buckets) {
+                cumulative += bucket.load(Ordering::Relaxed);
+                writeln!(
+                    out,
+                    "{NAME}_bucket{{op=\"{name}\",le=\"{bound}\"}} {cumulative}"
+                )
+                .unwrap();
+            }
+            // Loaded last, so it is never less than the buckets
+            let count = metrics.count.load(Ordering::Relaxed);
+            let total = metrics.total_nanos.load(Ordering::Relaxed) as f64 / 1e9;
+            writeln!(out, "{NAME}_bucket{{op=\"{name}\",le=\"+Inf\"}} {count}").unwrap();
+            writeln!(out, "{NAME}_sum{{op=\"{name}\"}} {total}").unwrap();
+            writeln!(out, "{NAME}_count{{op=\"{name}\"}} {count}").unwrap();
+        }
+        out
+    }
+}
+
+impl Drop for OpTimer {
+    fn drop(&mut self) {
+        let elapsed = self.start.elapsed();
+        let metrics = &self.metrics.ops[self.op as usize];
+        let secs = elapsed.as_secs_f64();
+        if let Some(i) = BUCKETS.iter().position(|&bound| secs <= bound) {
+            metrics.buckets[i].fetch_add(1, Ordering::Relaxed);
+        }
+        let nanos = u64::try_from(elapsed.as_nanos()).unwrap_or(u64::MAX);
+        metrics.total_nanos.fetch_add(nanos, Ordering::Relaxed);
+        metrics.count.fetch_add(1, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,173 @@
+//! Read-ahead for files that are read sequentially.
+//!
+//! The kernel splits reads into small requests, and each one has to go through the driver.
+//! When a file is read sequentially, the next chunk is read in the background while the
+//! previous one is being consumed, so streaming a large file isn't limited by the latency
+//! of each request.
+//!
+//! Compressed files can only be decompressed in full, so their contents are kept until the
+//! file is closed instead.
+
+use std::{
+    collections::HashMap,
+    fs::File,
+    io,
+    sync::{
+        mpsc::{self, Receiver},
+        Arc,
+    },
+    thread,
+};
+
+use ardain::{error::Result, ArdReader, FileMeta, PositionalReader};
+use log::debug;
+
+use crate::StandardArdFile;
+
+/// The size of the first chunk that is read ahead. It doubles on every chunk, up to
+/// [`MAX_CHUNK_SIZE`].
+const MIN_CHUNK_SIZE: u64 = 128 * 1024;
+const MAX_CHUNK_SIZE: u64 = 8 * 1024 * 1024;
+
+#[derive(Default)]
+pub struct ReadAhead {
+    streams: HashMap<u64, Stream>,
+}
+
+struct Stream {
+    file: FileMeta,
+    /// Where the next read starts if the file is read sequentially
+    next_offset: u64,
+    /// Data that was already read, starting at `cached_offset`
+    cached: Vec<u8>,
+    cached_offset: u64,
+    /// The chunk that is being read in the background, right after `cached`
+    pending: Option<Receiver<Result<Vec<u8>>>>,
+    chunk_size: u64,
+}
+
+impl ReadAhead {
+    /// Reads part of a file, from the data that was read ahead if possible.
+    pub fn read(
+        &mut self,
+        ino: u64,
+        file: &FileMeta,
+        offset: u64,
+        size: u32,
+        ard: &mut StandardArdFile,
+    ) -> Result<Vec<u8>> {
+        let stream = self
+            .streams
+            .entry(ino)
+            .and_modify(|s| {
+                // The file was replaced since it was last read
+                if s.file != *file {
+                    *s = Stream::new(*file);
+                }
+            })
+            .or_insert_with(|| Stream::new(*file));
+        let end = offset.saturating_add(size.into());
+        let sequential = offset == stream.next_offset;
+
+        if !stream.covers(offset, end) {
+            stream.wait_pending(offset)?;
+        }
+        if !stream.covers(offset, end) {
+            // Random access, or the first read
+            stream.pending = None;
+            let (start, take) = if file.uncompressed_size != 0 {
+                // Compressed files must be decompressed in full anyway
+                (0, u64::MAX)
+            } else if sequential {
+                (offset, u64::from(size).max(stream.chunk_size))
+            } else {
+                (offset, size.into())
+            };
+            stream.cached = ard.reader.entry(file).skip_take(start, take).read()?;
+            stream.cached_offset = start;
+        }
+
+        let start = usize::try_from(offset - stream.cached_offset)?.min(stream.cached.len());
+        let len = stream.cached.len() - start;
+        let data = stream.cached[start..start + len.min(size as usize)].to_vec();
+        stream.next_offset = offset + data.len() as u64;
+        if sequential && stream.pending.is_none() && !stream.cached_to_end() {
+            stream.prefetch(&ard.shared, ard.reader.verifies_on_read());
+        }
+        Ok(data)
+    }
+
+    /// Drops the data read ahead for a file, e.g. once it is closed or modified.
+    pub fn release(&mut self, ino: u64) {
+        self.streams.remove(&ino);
+    }
+
+    /// Drops all data read ahead, e.g. because the ARD file was modified.
+    pub fn clear(&mut self) {
+        self.streams.clear();
+    }
+}
+
+impl Stream {
+    fn new(file: FileMeta) -> Self {
+        Self {
+            file,
+            next_offset: 0,
+            cached: Vec::new(),
+            cached_offset: 0,
+            pending: None,
+            chunk_size: MIN_CHUNK_SIZE,
+        }
+    }
+
+    fn cached_end(&self) -> u64 {
+        self.cached_offset + self.cached.len() as u64
+    }
+
+    fn cached_to_end(&self) -> bool {
+        self.cached_end() >= self.file.actual_size().into()
+    }
+
+    /// Returns whether the range can be read from the cached data.
+    fn covers(&self, start: u64, end: u64) -> bool {
+        start >= self.cached_offset && (end <= self.cached_end() || self.cached_to_end())
+    }
+
+    /// Waits for the chunk being read in the background, and appends it to the cached data
+    /// (dropping what comes before `offset`), if it's useful for a read at `offset`.
+    fn wait_pending(&mut self, offset: u64) -> Result<()> {
+        if offset < self.cached_offset || offset > self.cached_end() {
+            return Ok(());
+        }
+        let Some(pending) = self.pending.take() else {
+            return Ok(());
+        };
+        // The sender is only dropped without sending if the thread panicked
+        let chunk = pending
+            .recv()
+            .map_err(|_| io::Error::other("the read-ahead thread stopped"))??;
+        let consumed = usize::try_from(offset - self.cached_offset)?;
+        self.cached.drain(..consumed);
+        self.cached_offset = offset;
+        self.cached.extend_from_slice(&chunk);
+        self.chunk_size = (self.chunk_size * 2).min(MAX_CHUNK_SIZE);
+        Ok(())
+    }
+
+    /// Starts reading the chunk after the cached data in the background.
+    fn prefetch(&mut self, ard: &Arc<File>, verify: bool) {
+        let (file, offset, size) = (self.file, self.cached_end(), self.chunk_size);
+        debug!(
+            "[READAHEAD] Reading {size} bytes at {offset} (file {})",
+            file.id
+        );
+        let ard = Arc::clone(ard);
+        let (tx, rx) = mpsc::sync_channel(1);
+        thread::spawn(move || {
+            let mut reader = ArdReader::new(PositionalReader::new(&ard)).verify_on_read(verify);
+            tx.send(reader.entry(&file).skip_take(offset, size).read())
+                .ok();
+        });
+        self.pending = Some(rx);
+    }
+}
@@ -2,8 +2,15 @@
 //!
 //! Files stored in ARD files are potentially compressed, so we can't write them in chunks.
 //! We hold onto their data until the user calls `close` or `fsync`.
+//!
+//! Files stored raw are the exception: writes that don't change their size go directly to
+//! the ARD file. If buffered changes leave the size unchanged after all (e.g. a truncate to
+//! the same size), only the changed ranges are written when the file is flushed.
+//!
+//! Buffered writes are kept as a map of extents, merging writes that overlap or touch, so
+//! many small writes (e.g. from a hex editor) end up in a few large buffers.
 
-use std::io::Write;
+use std::{collections::BTreeMap, io::Write};
 
 use anyhow::Result;
 use ardain::{
@@ -22,12 +29,12 @@ pub struct FileBuffers {
 
 pub struct FileBuffer {
     path: ArhPath,
-    operations: Vec<Operation>,
-}
-
-enum Operation {
-    Truncate { new_size: u64 },
-    Write { offset: u64, data: Box<[u8]> },
+    /// Written data by offset. Extents never overlap or touch.
+    extents: BTreeMap<u64, Vec<u8>>,
+    /// The file was truncated to this size at some point, so data stored after it is gone
+    cut: Option<u64>,
+    /// The size of the file since it was last truncated, including later writes
+    size: Option<u64>,
 }
 
 impl FileBuffers {
@@ -39,7 +46,9 @@ impl FileBuffers {
                     i,
                     FileBuffer {
                         path,
-                        operations: Vec::new(),
+                        extents: BTreeMap::new(),
+                        cut: None,
+                        size: None,
                     },
                 );
                 i.try_into().unwrap()
@@ -58,72 +67,293 @@ impl FileBuffers {
         self.open_files.get_mut(usize::try_from(fd).ok()?)
     }
 
-    pub fn flush_all(&mut self, arh: &mut ArhFileSystem, ard: &mut StandardArdFile) -> Result<()> {
-        for file in &mut self.open_files {
-            file.flush(arh, ard)?;
+    /// Writes the changes of every file at once, e.g. on `fsync`. Files are written in the
+    /// order of their current data in the ARD file, to limit seeking on HDDs, and the data
+    /// is made visible to readers once at the end. Returns the number of files written.
+    ///
+    /// If a file can't be written, the other files still are, and the first error is
+    /// returned once the written data is visible to readers.
+    pub fn flush_all(
+        &mut self,
+        arh: &mut ArhFileSystem,
+        ard: &mut StandardArdFile,
+    ) -> Result<usize> {
+        let mut dirty: Vec<_> = self
+            .open_files
+            .iter_mut()
+            .filter(|file| !file.is_clean())
+            .collect();
+        dirty.sort_by_key(|file| arh.get_file_info(&file.path).map(|meta| meta.offset));
+        let mut written = 0;
+        let mut error = None;
+        for file in dirty {
+            match file.write_back(arh, ard) {
+                Ok(true) => written += 1,
+                Ok(false) => {}
+                // e.g. past --max-grow, which may still leave room for smaller files
+                Err(e) => {
+                    error.get_or_insert(e);
+                }
+            }
+        }
+        if written != 0 {
+            ard.writer.get_mut().flush()?;
+        }
+        match error {
+            Some(e) => Err(e),
+            None => Ok(written),
         }
-        Ok(())
     }
 }
 
 impl FileBuffer {
-    pub fn write(&mut self, offset: i64, data: &[u8]) {
-        self.operations.push(Operation::Write {
-            data: data.to_vec().into_boxed_slice(),
-            offset: offset.try_into().unwrap(),
-        })
+    /// Writes to the file, directly to the ARD file if possible. Returns whether the ARD
+    /// file was modified.
+    pub fn write(
+        &mut self,
+        offset: i64,
+        data: &[u8],
+        arh: &mut ArhFileSystem,
+        ard: Option<&mut StandardArdFile>,
+    ) -> Result<bool> {
+        let offset = offset.try_into()?;
+        // Buffered changes must be applied first
+        if let (true, Some(ard), Some(meta)) = (self.is_clean(), ard, arh.get_file_info(&self.path))
+        {
+            let id = meta.id;
+            if ArdFileAllocator::new(arh, &mut ard.writer).write_in_place(id, offset, data)? {
+                // Make the data visible to readers
+                ard.writer.get_mut().flush()?;
+                return Ok(true);
+            }
+        }
+        self.buffer_write(offset, data)?;
+        Ok(false)
     }
 
-    pub fn flush(&mut self, arh: &mut ArhFileSystem, ard: &mut StandardArdFile) -> Result<()> {
-        // Read the file, apply changes, then write back
+    /// Adds a write to the extent map, merging it with the extents it overlaps or touches.
+    fn buffer_write(&mut self, offset: u64, data: &[u8]) -> Result<()> {
+        if data.is_empty() {
+            // Past the end, it would leave an empty extent that grows the file
+            return Ok(());
+        }
+        let end = offset + u64::try_from(data.len())?;
+        if let Some(size) = &mut self.size {
+            *size = (*size).max(end);
+        }
+        // Continue the extent that contains the start of the data or ends right before it
+        let prev = self
+            .extents
+            .range(..=offset)
+            .next_back()
+            .filter(|(&start, buf)| start + buf.len() as u64 >= offset)
+            .map(|(&start, _)| start);
+        let (start, mut buf) = match prev {
+            Some(start) => (start, self.extents.remove(&start).unwrap()),
+            None => (offset, Vec::new()),
+        };
+        let pos = usize::try_from(offset - start)?;
+        if buf.len() < pos + data.len() {
+            buf.resize(pos + data.len(), 0);
+        }
+        buf[pos..pos + data.len()].copy_from_slice(data);
+        // Extents that start inside the data, or right after it, are merged in
+        let next: Vec<_> = self.extents.range(offset..=end).map(|(&s, _)| s).collect();
+        for next_start in next {
+            let next = self.extents.remove(&next_start).unwrap();
+            let skip = usize::try_from(end - next_start)?;
+            if let Some(rest) = next.get(skip..) {
+                buf.extend_from_slice(rest);
+            }
+        }
+        self.extents.insert(start, buf);
+        Ok(())
+    }
+
+    /// Whether there are no buffered changes
+    fn is_clean(&self) -> bool {
+        self.extents.is_empty() && self.size.is_none()
+    }
+
+    /// Writes the buffered changes to the ARD file. Returns whether anything was written.
+    pub fn flush(&mut self, arh: &mut ArhFileSystem, ard: &mut StandardArdFile) -> Result<bool> {
+        if !self.write_back(arh, ard)? {
+            return Ok(false);
+        }
+        // Make sure arh modifications get saved to disk
+        ard.writer.get_mut().flush()?;
+        Ok(true)
+    }
+
+    /// Writes the buffered changes to the ARD file, without flushing the writer. Returns
+    /// whether anything was written.
+    fn write_back(&mut self, arh: &mut ArhFileSystem, ard: &mut StandardArdFile) -> Result<bool> {
+        if self.is_clean() {
+            // Nothing was written, or everything was written in place
+            return Ok(false);
+        }
         let Some(meta) = arh.get_file_info(&self.path).copied() else {
             // Likely deleted but didn't call `close`
             warn!(
                 "[flush] dangling file descriptor (forgot to close()?): {}",
                 self.path
             );
-            return Ok(());
+            return Ok(false);
         };
-        let mut buf = ard.reader.entry(&meta).read()?;
-        for op in self.operations.drain(..) {
-            op.run(&mut buf)?;
+        // If the size didn't change, large raw files only need the changed ranges written
+        if let Some(patches) = self.patches(u64::from(meta.actual_size())) {
+            if ArdFileAllocator::new(arh, &mut ard.writer).patch_in_place(meta.id, &patches)? {
+                self.extents.clear();
+                (self.cut, self.size) = (None, None);
+                return Ok(true);
+            }
         }
+        // Otherwise, read the file, apply changes, then write it back
+        let mut buf = ard.reader.entry(&meta).read()?;
+        self.apply(&mut buf)?;
         // TODO make strategy configurable
         ArdFileAllocator::new(arh, &mut ard.writer).replace_file(
             meta.id,
             &buf,
             CompressionStrategy::Best,
         )?;
-        // Make sure arh modifications get saved to disk
-        ard.writer.get_mut().flush()?;
-        Ok(())
+        // Only now, so the changes can be written again if there was no space
+        self.extents.clear();
+        (self.cut, self.size) = (None, None);
+        Ok(true)
     }
 
     pub fn truncate(&mut self, new_size: u64) {
-        self.operations.push(Operation::Truncate { new_size });
+        self.cut = Some(self.cut.map_or(new_size, |cut| cut.min(new_size)));
+        self.size = Some(new_size);
+        // Drop or shorten the extents past the new end
+        let _ = self.extents.split_off(&new_size);
+        if let Some((&start, buf)) = self.extents.last_key_value() {
+            if start + buf.len() as u64 > new_size {
+                let len = (new_size - start) as usize;
+                self.extents.get_mut(&start).unwrap().truncate(len);
+            }
+        }
     }
-}
 
-impl Operation {
-    fn run(&self, buffer: &mut Vec<u8>) -> Result<()> {
-        match self {
-            Operation::Truncate { new_size } => buffer.resize(usize::try_from(*new_size)?, 0),
-            Operation::Write { offset, data } => {
-                let mut offset = usize::try_from(*offset)?;
-                let end = offset + data.len();
-                let max_len = buffer.len();
-                if offset < max_len {
-                    let first_area = &mut buffer[offset..end.min(max_len)];
-                    first_area.copy_from_slice(&data[..first_area.len()]);
-                    offset += first_area.len();
-                    if offset < end {
-                        buffer.extend_from_slice(&data[offset..]);
-                    }
-                } else {
-                    buffer.extend_from_slice(data);
-                }
+    /// Returns the buffered writes, if they don't change the size of a file that was `size`
+    /// bytes long.
+    fn patches(&self, size: u64) -> Option<Vec<(u64, &[u8])>> {
+        if self.cut.is_some_and(|cut| cut < size) || self.size.is_some_and(|s| s != size) {
+            return None;
+        }
+        let patches: Vec<_> = self
+            .extents
+            .iter()
+            .map(|(&offset, data)| (offset, data.as_slice()))
+            .collect();
+        let end = patches
+            .last()
+            .map_or(0, |(offset, data)| offset + data.len() as u64);
+        (end <= size).then_some(patches)
+    }
+
+    /// Applies the buffered changes to the file's previous contents.
+    fn apply(&self, buf: &mut Vec<u8>) -> Result<()> {
+        if let Some(cut) = self.cut {
+            buf.truncate(usize::try_from(cut)?);
+        }
+        if let Some(size) = self.size {
+            buf.resize(usize::try_from(size)?, 0);
+        }
+        for (&offset, data) in &self.extents {
+            let offset = usize::try_from(offset)?;
+            let end = offset + data.len();
+            if buf.len() < end {
+                // Writes past the end leave zeros in between
+                buf.resize(end, 0);
             }
+            buf[offset..end].copy_from_slice(data);
         }
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use ardain::path::ArhPath;
+
+    use super::FileBuffer;
+
+    fn buffer() -> FileBuffer {
+        FileBuffer {
+            path: ArhPath::default(),
+            extents: BTreeMap::new(),
+            cut: None,
+            size: None,
+        }
+    }
+
+    fn extents(file: &FileBuffer) -> Vec<(u64, &[u8])> {
+        file.extents
+            .iter()
+            .map(|(&start, buf)| (start, buf.as_slice()))
+            .collect()
+    }
+
+    #[test]
+    fn overlapping_writes() {
+        let mut file = buffer();
+        file.buffer_write(0, b"abcd").unwrap();
+        file.buffer_write(2, b"XYZ").unwrap();
+        file.buffer_write(8, b"01").unwrap();
+        assert_eq!(extents(&file), [(0, &b"abXYZ"[..]), (8, b"01")]);
+
+        // Covers the second extent entirely, and the end of the first one
+        file.buffer_write(4, b"------").unwrap();
+        assert_eq!(extents(&file), [(0, &b"abXY------"[..])]);
+
+        let mut buf = b"0123456789ab".to_vec();
+        file.apply(&mut buf).unwrap();
+        assert_eq!(buf, b"abXY------ab");
+    }
+
+    #[test]
+    fn touching_writes() {
+        let mut file = buffer();
+        file.buffer_write(0, b"ab").unwrap();
+        file.buffer_write(4, b"ef").unwrap();
+        assert_eq!(extents(&file), [(0, &b"ab"[..]), (4, b"ef")]);
+        file.buffer_write(2, b"cd").unwrap();
+        assert_eq!(extents(&file), [(0, &b"abcdef"[..])]);
+        file.buffer_write(6, b"g").unwrap();
+        assert_eq!(extents(&file), [(0, &b"abcdefg"[..])]);
+    }
+
+    #[test]
+    fn truncate_cuts_extents() {
+        let mut file = buffer();
+        file.buffer_write(0, b"abcdef").unwrap();
+        file.buffer_write(8, b"gh").unwrap();
+        file.truncate(4);
+        assert_eq!(extents(&file), [(0, &b"abcd"[..])]);
+
+        // Writing past the new end grows the file again, with zeros in between
+        file.buffer_write(6, b"x").unwrap();
+        assert_eq!(file.size, Some(7));
+        let mut buf = b"0123456789".to_vec();
+        file.apply(&mut buf).unwrap();
+        assert_eq!(buf, b"abcd\0\0x");
+        // The old contents past the cut are gone, so the file can't be patched in place
+        assert!(file.patches(10).is_none());
+    }
+
+    #[test]
+    fn empty_writes() {
+        let mut file = buffer();
+        file.buffer_write(100, b"").unwrap();
+        assert!(file.is_clean());
+
+        file.truncate(4);
+        file.buffer_write(100, b"").unwrap();
+        assert_eq!(file.size, Some(4));
+        assert!(file.extents.is_empty());
+    }
+}